@@ -0,0 +1,194 @@
+use crate::config::BackupWardenConfig;
+use crate::sink::PrunedBackup;
+use chrono::NaiveDate;
+use std::path::Path;
+use std::process::Command;
+use tracing::{error, warn};
+
+/// Raises an alert. It is always logged, and handed to `alert_command` (via the
+/// `BW_ALERT_MESSAGE` environment variable) when one is configured.
+pub fn send(config: &BackupWardenConfig, message: &str) {
+    error!(event = "alert", "ALERT: {}", message);
+
+    let Some(command) = &config.alert_command else {
+        return;
+    };
+    match shell(command).env("BW_ALERT_MESSAGE", message).status() {
+        Ok(status) if status.success() => (),
+        Ok(status) => warn!(event = "alert_command_failed", %status, "Alert command failed"),
+        Err(e) => warn!(event = "alert_command_failed", error = %e, "Failed to run alert command"),
+    }
+}
+
+/// Hands a backup removed by cleanup to `cleanup_command`, when one is
+/// configured, through `BW_PRUNED_PATH`, `BW_PRUNED_DATE` and `BW_PRUNED_BYTES`.
+pub fn cleanup_hook(config: &BackupWardenConfig, pruned: &PrunedBackup) {
+    let Some(command) = &config.cleanup_command else {
+        return;
+    };
+    run_hook(
+        "cleanup_command",
+        shell(command)
+            .env("BW_PRUNED_PATH", &pruned.path)
+            .env("BW_PRUNED_DATE", pruned.day.to_string())
+            .env("BW_PRUNED_BYTES", pruned.bytes.to_string()),
+    );
+}
+
+/// One location's backup, as handed to `post_backup_command`.
+pub struct BackupReport<'a> {
+    pub path: &'a Path,
+    /// Whether the backup into the location succeeded.
+    pub succeeded: bool,
+    pub files_copied: u64,
+    pub bytes: u64,
+}
+
+/// Runs `pre_backup_command`, when one is configured, before a backup taken on
+/// `date`, with `BW_WATCH_FOLDER` and `BW_DATE` set. The backup goes ahead
+/// whatever the command does.
+pub fn pre_backup_hook(config: &BackupWardenConfig, date: NaiveDate) {
+    let Some(command) = &config.pre_backup_command else {
+        return;
+    };
+    run_hook(
+        "pre_backup_command",
+        shell(command)
+            .env("BW_WATCH_FOLDER", &config.watch_folder)
+            .env("BW_DATE", date.to_string()),
+    );
+}
+
+/// Runs `post_backup_command`, when one is configured, once the backup taken on
+/// `date` into one location is done, successfully or not, describing it through
+/// `BW_WATCH_FOLDER`, `BW_BACKUP_PATH`, `BW_DATE`, `BW_FILES_COPIED`, `BW_BYTES`
+/// and `BW_STATUS` (`ok` or `failed`).
+pub fn post_backup_hook(config: &BackupWardenConfig, date: NaiveDate, report: &BackupReport) {
+    let Some(command) = &config.post_backup_command else {
+        return;
+    };
+    run_hook(
+        "post_backup_command",
+        shell(command)
+            .env("BW_WATCH_FOLDER", &config.watch_folder)
+            .env("BW_BACKUP_PATH", report.path)
+            .env("BW_DATE", date.to_string())
+            .env("BW_FILES_COPIED", report.files_copied.to_string())
+            .env("BW_BYTES", report.bytes.to_string())
+            .env("BW_STATUS", if report.succeeded { "ok" } else { "failed" }),
+    );
+}
+
+/// Runs a hook command, warning if it can't be started or fails. `setting` is
+/// the config setting it came from, for the log.
+fn run_hook(setting: &str, command: &mut Command) {
+    let event = format!("{}_failed", setting);
+    match command.status() {
+        Ok(status) if status.success() => (),
+        Ok(status) => warn!(event = %event, %status, "{} failed", setting),
+        Err(e) => warn!(event = %event, error = %e, "Failed to run {}", setting),
+    }
+}
+
+/// Builds a command that runs `command` through the platform shell.
+pub fn shell(command: &str) -> Command {
+    #[cfg(windows)]
+    {
+        let mut shell = Command::new("cmd");
+        shell.args(["/C", command]);
+        shell
+    }
+    #[cfg(not(windows))]
+    {
+        let mut shell = Command::new("sh");
+        shell.args(["-c", command]);
+        shell
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_send_passes_message_to_alert_command() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let output = temp_dir.path().join("alert.txt");
+        let config = BackupWardenConfig {
+            alert_command: Some(format!(
+                "printf '%s' \"$BW_ALERT_MESSAGE\" > '{}'",
+                output.display()
+            )),
+            ..Default::default()
+        };
+
+        send(&config, "all locations failed");
+        assert_eq!(
+            std::fs::read_to_string(&output).unwrap(),
+            "all locations failed"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_cleanup_hook_receives_pruned_backup() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let output = temp_dir.path().join("pruned.txt");
+        let config = BackupWardenConfig {
+            cleanup_command: Some(format!(
+                "printf '%s %s %s' \"$BW_PRUNED_DATE\" \"$BW_PRUNED_BYTES\" \"$BW_PRUNED_PATH\" > '{}'",
+                output.display()
+            )),
+            ..Default::default()
+        };
+
+        cleanup_hook(
+            &config,
+            &PrunedBackup {
+                day: chrono::NaiveDate::from_ymd_opt(2024, 1, 31).unwrap(),
+                path: "backups/2024-01-31".to_string(),
+                bytes: 4096,
+            },
+        );
+        assert_eq!(
+            std::fs::read_to_string(&output).unwrap(),
+            "2024-01-31 4096 backups/2024-01-31"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_post_backup_hook_receives_context() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let output = temp_dir.path().join("backup.txt");
+        let config = BackupWardenConfig {
+            watch_folder: "/data".to_string(),
+            post_backup_command: Some(format!(
+                "printf '%s %s %s %s %s %s' \"$BW_WATCH_FOLDER\" \"$BW_BACKUP_PATH\" \"$BW_DATE\" \"$BW_FILES_COPIED\" \"$BW_BYTES\" \"$BW_STATUS\" > '{}'",
+                output.display()
+            )),
+            ..Default::default()
+        };
+
+        post_backup_hook(
+            &config,
+            NaiveDate::from_ymd_opt(2024, 1, 31).unwrap(),
+            &BackupReport {
+                path: Path::new("/backups/2024-01-31/@01 PM"),
+                succeeded: true,
+                files_copied: 3,
+                bytes: 4096,
+            },
+        );
+        assert_eq!(
+            std::fs::read_to_string(&output).unwrap(),
+            "/data /backups/2024-01-31/@01 PM 2024-01-31 3 4096 ok"
+        );
+    }
+
+    #[test]
+    fn test_send_without_command_only_logs() {
+        send(&BackupWardenConfig::default(), "nothing to run");
+    }
+}
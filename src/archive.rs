@@ -0,0 +1,150 @@
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Packs everything under `src` into a gzip-compressed tar file at `archive`.
+///
+/// Entry paths are relative to `src`. The archive is written next to its final
+/// name and renamed into place, so an interrupted run never leaves a truncated
+/// archive behind. With `reproducible` the same files always make the same
+/// bytes: see [`append_reproducible`].
+pub fn write_tar_gz(src: &Path, archive: &Path, reproducible: bool) -> io::Result<()> {
+    let parent = match archive.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    fs::create_dir_all(parent)?;
+    let tmp = tempfile::NamedTempFile::new_in(parent)?;
+
+    let mut builder = tar::Builder::new(GzEncoder::new(tmp.as_file(), Compression::default()));
+    builder.follow_symlinks(false);
+    if reproducible {
+        append_reproducible(&mut builder, src, Path::new(""))?;
+    } else {
+        builder.append_dir_all("", src)?;
+    }
+    builder.into_inner()?.finish()?;
+
+    tmp.persist(archive).map_err(|e| e.error)?;
+    Ok(())
+}
+
+/// Adds what is under `dir` as `prefix` in name order, with every timestamp
+/// zeroed, no owner, and modes reduced to `0755` for folders and executables
+/// and `0644` for everything else, so nothing but names and contents ends up
+/// in the archive. The gzip header carries no timestamp or platform either way.
+fn append_reproducible<W: Write>(
+    builder: &mut tar::Builder<W>,
+    dir: &Path,
+    prefix: &Path,
+) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = entry.path();
+        let name = prefix.join(entry.file_name());
+        let metadata = fs::symlink_metadata(&path)?;
+        let mut header = tar::Header::new_gnu();
+        header.set_mtime(0);
+        header.set_uid(0);
+        header.set_gid(0);
+        if metadata.is_dir() {
+            header.set_entry_type(tar::EntryType::Directory);
+            header.set_mode(0o755);
+            header.set_size(0);
+            builder.append_data(&mut header, &name, io::empty())?;
+            append_reproducible(builder, &path, &name)?;
+        } else if metadata.file_type().is_symlink() {
+            header.set_entry_type(tar::EntryType::Symlink);
+            header.set_mode(0o777);
+            header.set_size(0);
+            builder.append_link(&mut header, &name, fs::read_link(&path)?)?;
+        } else if metadata.is_file() {
+            header.set_entry_type(tar::EntryType::Regular);
+            header.set_mode(if is_executable(&metadata) {
+                0o755
+            } else {
+                0o644
+            });
+            header.set_size(metadata.len());
+            builder.append_data(&mut header, &name, fs::File::open(&path)?)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn is_executable(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_metadata: &fs::Metadata) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_write_tar_gz_round_trips() {
+        let temp_dir = tempdir().unwrap();
+        let src = temp_dir.path().join("src");
+        fs::create_dir_all(src.join("nested")).unwrap();
+        fs::write(src.join("top.txt"), b"top").unwrap();
+        fs::write(src.join("nested").join("deep.txt"), b"deep").unwrap();
+
+        let archive = temp_dir.path().join("out").join("export.tar.gz");
+        write_tar_gz(&src, &archive, false).unwrap();
+
+        let unpacked = temp_dir.path().join("unpacked");
+        tar::Archive::new(GzDecoder::new(fs::File::open(&archive).unwrap()))
+            .unpack(&unpacked)
+            .unwrap();
+        assert_eq!(fs::read(unpacked.join("top.txt")).unwrap(), b"top");
+        assert_eq!(
+            fs::read(unpacked.join("nested").join("deep.txt")).unwrap(),
+            b"deep"
+        );
+    }
+
+    #[test]
+    fn test_reproducible_archive_ignores_times_and_order() {
+        let temp_dir = tempdir().unwrap();
+        let make = |name: &str, names: &[&str], mtime: i64| {
+            let src = temp_dir.path().join(name);
+            fs::create_dir_all(src.join("nested")).unwrap();
+            for file in names {
+                fs::write(src.join(file), file.as_bytes()).unwrap();
+                filetime::set_file_mtime(
+                    src.join(file),
+                    filetime::FileTime::from_unix_time(mtime, 0),
+                )
+                .unwrap();
+            }
+            let archive = temp_dir.path().join(format!("{}.tar.gz", name));
+            write_tar_gz(&src, &archive, true).unwrap();
+            fs::read(archive).unwrap()
+        };
+        let first = make("first", &["a.txt", "nested/b.txt", "z.txt"], 1_700_000_000);
+        let second = make("second", &["z.txt", "nested/b.txt", "a.txt"], 1_800_000_000);
+        assert_eq!(first, second);
+
+        let mut unpacked = Vec::new();
+        for entry in tar::Archive::new(GzDecoder::new(&first[..]))
+            .entries()
+            .unwrap()
+        {
+            let entry = entry.unwrap();
+            assert_eq!(entry.header().mtime().unwrap(), 0);
+            unpacked.push(entry.path().unwrap().to_string_lossy().into_owned());
+        }
+        assert_eq!(unpacked, ["a.txt", "nested", "nested/b.txt", "z.txt"]);
+    }
+}
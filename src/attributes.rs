@@ -0,0 +1,132 @@
+use std::io;
+use std::path::Path;
+
+/// Gives the file at `dest` the readonly, hidden, system and archive
+/// attributes of the file at `source`, which copying its contents leaves
+/// behind. Its other attributes are left as they are.
+#[cfg(windows)]
+pub fn copy_attributes(source: &Path, dest: &Path) -> io::Result<()> {
+    let carried = windows::get(source)? & windows::CARRIED;
+    let kept = windows::get(dest)? & !windows::CARRIED;
+    windows::set(dest, kept | carried)
+}
+
+/// Only Windows has these attributes, so there is nothing to copy.
+#[cfg(not(windows))]
+pub fn copy_attributes(_source: &Path, _dest: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Clears the archive attribute of the file at `path`, which Windows sets on
+/// every write, to mark it as backed up for tools that go by it.
+#[cfg(windows)]
+pub fn clear_archive_bit(path: &Path) -> io::Result<()> {
+    let attributes = windows::get(path)?;
+    if attributes & windows::ARCHIVE == 0 {
+        return Ok(());
+    }
+    windows::set(path, attributes & !windows::ARCHIVE)
+}
+
+/// Only Windows has an archive attribute, so there is nothing to clear.
+#[cfg(not(windows))]
+pub fn clear_archive_bit(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Clears the readonly attribute of the file at `path`, if there is one, since
+/// Windows won't delete or replace a readonly file. `copy_file` and
+/// `preserve_attributes` both carry it over from the source.
+#[cfg(windows)]
+pub fn clear_readonly(path: &Path) -> io::Result<()> {
+    use windows_sys::Win32::Storage::FileSystem::FILE_ATTRIBUTE_READONLY;
+
+    let attributes = match windows::get(path) {
+        Ok(attributes) => attributes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if attributes & FILE_ATTRIBUTE_READONLY == 0 {
+        return Ok(());
+    }
+    windows::set(path, attributes & !FILE_ATTRIBUTE_READONLY)
+}
+
+/// Elsewhere a file's own permissions don't stop it being removed.
+#[cfg(not(windows))]
+pub fn clear_readonly(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(windows)]
+mod windows {
+    use std::io;
+    use std::os::windows::ffi::OsStrExt;
+    use std::path::Path;
+    use windows_sys::Win32::Storage::FileSystem::{
+        GetFileAttributesW, SetFileAttributesW, FILE_ATTRIBUTE_ARCHIVE, FILE_ATTRIBUTE_HIDDEN,
+        FILE_ATTRIBUTE_READONLY, FILE_ATTRIBUTE_SYSTEM, INVALID_FILE_ATTRIBUTES,
+    };
+
+    pub const ARCHIVE: u32 = FILE_ATTRIBUTE_ARCHIVE;
+
+    /// The attributes `copy_attributes` carries over.
+    pub const CARRIED: u32 = FILE_ATTRIBUTE_READONLY
+        | FILE_ATTRIBUTE_HIDDEN
+        | FILE_ATTRIBUTE_SYSTEM
+        | FILE_ATTRIBUTE_ARCHIVE;
+
+    fn wide(path: &Path) -> Vec<u16> {
+        path.as_os_str().encode_wide().chain(Some(0)).collect()
+    }
+
+    pub fn get(path: &Path) -> io::Result<u32> {
+        let wide = wide(path);
+        // SAFETY: `wide` is NUL-terminated and outlives the call
+        let attributes = unsafe { GetFileAttributesW(wide.as_ptr()) };
+        if attributes == INVALID_FILE_ATTRIBUTES {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(attributes)
+    }
+
+    pub fn set(path: &Path, attributes: u32) -> io::Result<()> {
+        let wide = wide(path);
+        // SAFETY: `wide` is NUL-terminated and outlives the call
+        if unsafe { SetFileAttributesW(wide.as_ptr(), attributes) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, windows))]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+    use windows_sys::Win32::Storage::FileSystem::FILE_ATTRIBUTE_HIDDEN;
+
+    #[test]
+    fn test_copy_attributes_and_clear_archive_bit() {
+        let temp_dir = tempdir().unwrap();
+        let source = temp_dir.path().join("source.txt");
+        let dest = temp_dir.path().join("dest.txt");
+        fs::write(&source, b"main").unwrap();
+        fs::write(&dest, b"main").unwrap();
+        windows::set(
+            &source,
+            windows::get(&source).unwrap() | FILE_ATTRIBUTE_HIDDEN,
+        )
+        .unwrap();
+        windows::set(&dest, windows::get(&dest).unwrap() & !windows::ARCHIVE).unwrap();
+
+        copy_attributes(&source, &dest).unwrap();
+        let attributes = windows::get(&dest).unwrap();
+        assert_ne!(attributes & FILE_ATTRIBUTE_HIDDEN, 0);
+        assert_ne!(attributes & windows::ARCHIVE, 0);
+
+        clear_archive_bit(&source).unwrap();
+        assert_eq!(windows::get(&source).unwrap() & windows::ARCHIVE, 0);
+    }
+}
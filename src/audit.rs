@@ -0,0 +1,162 @@
+use crate::config::BackupWardenConfig;
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use tracing::warn;
+
+/// The `prev` of the first record, which has no line before it.
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Keeps jobs sharing one audit log from chaining onto the same line.
+static APPEND_LOCK: Mutex<()> = Mutex::new(());
+
+/// How an audited operation ended.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditOutcome {
+    Ok,
+    Failed,
+    /// Called off before it changed anything.
+    Aborted,
+}
+
+/// One line of the audit log.
+#[derive(Serialize, Deserialize)]
+struct Record {
+    at: String,
+    operation: String,
+    outcome: AuditOutcome,
+    detail: String,
+    /// The SHA-256 of the previous line, as hex.
+    prev: String,
+}
+
+/// What checking an audit log's hash chain found.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ChainCheck {
+    /// Every line chains onto the one before it.
+    Intact { records: usize },
+    /// `line` (counting from 1) is not a record or doesn't chain onto the line
+    /// before it, so the log was altered or truncated there.
+    Broken { line: usize, reason: String },
+}
+
+/// Appends a record of `operation` to the job's `audit_log`, when one is set.
+/// A log that can't be written is warned about but doesn't stop the operation.
+pub fn record(config: &BackupWardenConfig, operation: &str, outcome: AuditOutcome, detail: &str) {
+    let Some(path) = &config.audit_log else {
+        return;
+    };
+    if let Err(e) = append(Path::new(path), operation, outcome, detail) {
+        warn!(event = "audit_write_failed", path = %path, error = %e, "Failed to write audit log");
+    }
+}
+
+/// Appends a record to the log at `path`, chained onto its last line.
+pub fn append(path: &Path, operation: &str, outcome: AuditOutcome, detail: &str) -> io::Result<()> {
+    let _guard = APPEND_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let existing = match fs::read_to_string(path) {
+        Ok(existing) => existing,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+    let prev = existing
+        .lines()
+        .last()
+        .map_or_else(|| GENESIS.to_string(), line_hash);
+    let record = Record {
+        at: Local::now().to_rfc3339(),
+        operation: operation.to_string(),
+        outcome,
+        detail: detail.to_string(),
+        prev,
+    };
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    // A torn last line stays a line of its own, which verify then reports
+    let separator = if existing.is_empty() || existing.ends_with('\n') {
+        ""
+    } else {
+        "\n"
+    };
+    writeln!(file, "{}{}", separator, serde_json::to_string(&record)?)?;
+    file.sync_data()
+}
+
+/// Checks that every line of the log at `path` chains onto the one before it.
+/// A missing log is an intact, empty one. The chain can't show whether lines
+/// were cut off the end, only that no line was changed, removed or inserted
+/// before the last.
+pub fn verify(path: &Path) -> io::Result<ChainCheck> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+    let mut expected = GENESIS.to_string();
+    let mut records = 0;
+    for (i, line) in contents.lines().enumerate() {
+        let broken = |reason: &str| ChainCheck::Broken {
+            line: i + 1,
+            reason: reason.to_string(),
+        };
+        let Ok(record) = serde_json::from_str::<Record>(line) else {
+            return Ok(broken("not an audit record"));
+        };
+        if record.prev != expected {
+            return Ok(broken("doesn't match the hash of the line before it"));
+        }
+        expected = line_hash(line);
+        records += 1;
+    }
+    Ok(ChainCheck::Intact { records })
+}
+
+fn line_hash(line: &str) -> String {
+    Sha256::digest(line.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_chain_detects_edits() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("audit.log");
+        assert_eq!(verify(&path).unwrap(), ChainCheck::Intact { records: 0 });
+        append(&path, "backup", AuditOutcome::Ok, "into /a").unwrap();
+        append(&path, "cleanup", AuditOutcome::Ok, "removed /a/old").unwrap();
+        append(&path, "restore", AuditOutcome::Failed, "/a/new -> /b").unwrap();
+        assert_eq!(verify(&path).unwrap(), ChainCheck::Intact { records: 3 });
+
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::write(
+            &path,
+            contents.replacen("removed /a/old", "removed /a/x", 1),
+        )
+        .unwrap();
+        assert!(matches!(
+            verify(&path).unwrap(),
+            ChainCheck::Broken { line: 3, .. }
+        ));
+
+        let mut lines: Vec<&str> = contents.lines().collect();
+        lines.remove(1);
+        fs::write(&path, lines.join("\n")).unwrap();
+        assert!(matches!(
+            verify(&path).unwrap(),
+            ChainCheck::Broken { line: 2, .. }
+        ));
+    }
+}
@@ -0,0 +1,180 @@
+use crate::config::BackupWardenConfig;
+use crate::copy::{copy_dir_all, CopyOptions, DEFAULT_COPY_BUFFER_SIZE};
+use crate::error::{BackupWardenError, Result};
+use crate::filter::PathFilter;
+use crate::layout::BackupLayout;
+use crate::manifest::Manifest;
+use crate::sink::{BackupSink, LocalSink};
+use crate::size::format_bytes;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// The `copy_buffer_size` values `bench` tries.
+pub const BUFFER_SIZES: [usize; 5] = [
+    64 * 1024,
+    256 * 1024,
+    DEFAULT_COPY_BUFFER_SIZE,
+    4 * 1024 * 1024,
+    16 * 1024 * 1024,
+];
+
+/// What `bench` should do, parsed from its command-line arguments.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct BenchOptions {
+    pub folder: PathBuf,
+    /// Where the trial copies go; the system temp directory by default.
+    pub dest: Option<PathBuf>,
+}
+
+impl BenchOptions {
+    /// Parses `<folder> [--dest DIR]`.
+    pub fn parse(args: &[String]) -> Result<Self> {
+        Self::parse_args(args).map_err(BackupWardenError::Usage)
+    }
+
+    fn parse_args(args: &[String]) -> std::result::Result<Self, String> {
+        let mut folder = None;
+        let mut dest = None;
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--dest" => dest = Some(PathBuf::from(args.next().ok_or("--dest needs a folder")?)),
+                flag if flag.starts_with("--") => return Err(format!("unknown option {}", flag)),
+                path if folder.is_none() => folder = Some(PathBuf::from(path)),
+                extra => return Err(format!("unexpected argument {}", extra)),
+            }
+        }
+        Ok(BenchOptions {
+            folder: folder.ok_or("Usage: backup-warden bench <folder> [--dest DIR]")?,
+            dest,
+        })
+    }
+}
+
+/// How fast one trial went.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trial {
+    pub buffer_size: usize,
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+impl Trial {
+    pub fn bytes_per_sec(&self) -> u64 {
+        per_sec(self.bytes, self.elapsed)
+    }
+}
+
+fn per_sec(bytes: u64, elapsed: Duration) -> u64 {
+    (bytes as f64 / elapsed.as_secs_f64().max(1e-9)) as u64
+}
+
+/// Copies `options.folder` once per entry of [`BUFFER_SIZES`] through the same
+/// path backups take, printing the throughput of each and of hashing the copy
+/// for its manifest, then the fastest buffer size. Copy-on-write clones are
+/// off, so every trial really copies. Trial copies are removed as it goes.
+/// Returns the trials in the order they ran.
+pub fn run(options: &BenchOptions) -> Result<Vec<Trial>> {
+    let io_error = |path: &Path| {
+        let path = path.to_path_buf();
+        move |source| BackupWardenError::Io { path, source }
+    };
+    let dest_root = options.dest.clone().unwrap_or_else(std::env::temp_dir);
+    fs::create_dir_all(&dest_root).map_err(io_error(&dest_root))?;
+    let scratch = tempfile::tempdir_in(&dest_root).map_err(io_error(&dest_root))?;
+    let config = BackupWardenConfig {
+        watch_folder: options.folder.to_string_lossy().into_owned(),
+        ..Default::default()
+    };
+    let filter = PathFilter::allow_all();
+    let layout = BackupLayout::new(&config).map_err(BackupWardenError::Config)?;
+    let copy = |buffer_size: usize, dest: &Path| {
+        let mut sink = LocalSink::new(&dest.to_string_lossy(), layout.clone())
+            .with_copy_buffer_size(buffer_size);
+        sink.begin_folder(dest.to_path_buf())?;
+        let stats = copy_dir_all(
+            Path::new(&config.watch_folder),
+            &CopyOptions::new(&config, &filter),
+            &mut sink,
+        )?;
+        sink.finish_backup()?;
+        Ok(stats)
+    };
+
+    // An untimed first pass, so the first trial doesn't pay for a cold cache
+    let warm_up = scratch.path().join("warm-up");
+    let warm_up_stats =
+        copy(DEFAULT_COPY_BUFFER_SIZE, &warm_up).map_err(io_error(&options.folder))?;
+    let hash_started = Instant::now();
+    Manifest::build(&warm_up).map_err(io_error(&warm_up))?;
+    let hash_elapsed = hash_started.elapsed();
+    fs::remove_dir_all(&warm_up).map_err(io_error(&warm_up))?;
+
+    let mut trials = Vec::new();
+    for buffer_size in BUFFER_SIZES {
+        let dest = scratch.path().join(buffer_size.to_string());
+        let started = Instant::now();
+        let stats = copy(buffer_size, &dest).map_err(io_error(&options.folder))?;
+        let trial = Trial {
+            buffer_size,
+            bytes: stats.bytes_copied,
+            elapsed: started.elapsed(),
+        };
+        println!(
+            "copy_buffer_size {:>9}: {} in {:.2}s, {}/s",
+            buffer_size,
+            format_bytes(trial.bytes),
+            trial.elapsed.as_secs_f64(),
+            format_bytes(trial.bytes_per_sec())
+        );
+        fs::remove_dir_all(&dest).map_err(io_error(&dest))?;
+        trials.push(trial);
+    }
+
+    println!(
+        "SHA-256 manifest hashing: {}/s",
+        format_bytes(per_sec(warm_up_stats.bytes_copied, hash_elapsed))
+    );
+    if let Some(best) = trials.iter().max_by_key(|trial| trial.bytes_per_sec()) {
+        println!(
+            "Fastest: \"copy_buffer_size\": {}{}",
+            best.buffer_size,
+            if best.buffer_size == DEFAULT_COPY_BUFFER_SIZE {
+                " (the default)"
+            } else {
+                ""
+            }
+        );
+    }
+    Ok(trials)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_bench_runs_every_buffer_size_and_cleans_up() {
+        let temp_dir = tempdir().unwrap();
+        let folder = temp_dir.path().join("sample");
+        let dest = temp_dir.path().join("scratch");
+        fs::create_dir_all(folder.join("docs")).unwrap();
+        fs::write(folder.join("docs/a.txt"), vec![7; 100_000]).unwrap();
+
+        let options = BenchOptions::parse(&[
+            folder.to_str().unwrap().to_string(),
+            "--dest".to_string(),
+            dest.to_str().unwrap().to_string(),
+        ])
+        .unwrap();
+        let trials = run(&options).unwrap();
+        assert_eq!(
+            trials.iter().map(|t| t.buffer_size).collect::<Vec<_>>(),
+            BUFFER_SIZES
+        );
+        assert!(trials.iter().all(|t| t.bytes == 100_000));
+        assert_eq!(fs::read_dir(&dest).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,367 @@
+use crate::copy::{sync_dir, unlink_existing};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Folder in each backup location holding the chunks of every chunked file.
+pub const CHUNK_DIR_NAME: &str = "Chunks";
+
+/// Added to the name of a chunked file in a backup, which holds its chunk list
+/// instead of its contents.
+pub const CHUNK_LIST_SUFFIX: &str = ".bw-chunks";
+
+/// Files smaller than this are copied whole, since they would make one or two
+/// chunks at most.
+pub const CHUNKED_FILE_MIN_BYTES: u64 = 1024 * 1024;
+
+const MIN_CHUNK: usize = 256 * 1024;
+const AVG_CHUNK: usize = 1024 * 1024;
+const MAX_CHUNK: usize = 4 * 1024 * 1024;
+
+/// Cut-point masks for FastCDC's normalized chunking: a stricter one before
+/// the average size and a looser one after it, so most chunks land near it.
+/// They test the top bits, which depend on the last 64 bytes read.
+const MASK_STRICT: u64 = !0 << (64 - 22);
+const MASK_LOOSE: u64 = !0 << (64 - 18);
+
+/// The gear hash's random value for each byte, generated with SplitMix64 so
+/// chunk boundaries never change between builds.
+const GEAR: [u64; 256] = {
+    let mut table = [0; 256];
+    let mut state: u64 = 0;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+/// What a chunked file's entry in a backup holds.
+#[derive(Serialize, Deserialize)]
+struct ChunkList {
+    size: u64,
+    /// The SHA-256 of each chunk, as hex, in file order.
+    chunks: Vec<String>,
+}
+
+/// Where a chunked file's list is stored for the file at `path`.
+pub fn list_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(CHUNK_LIST_SUFFIX);
+    PathBuf::from(name)
+}
+
+/// The file a chunk list at `path` stands for, or `None` if it isn't one.
+pub fn chunked_path(path: &Path) -> Option<PathBuf> {
+    let name = path.file_name()?.to_str()?;
+    let original = name.strip_suffix(CHUNK_LIST_SUFFIX)?;
+    (!original.is_empty()).then(|| path.with_file_name(original))
+}
+
+/// The size of the file the chunk list at `list` stands for.
+pub fn listed_size(list: &Path) -> io::Result<u64> {
+    let chunk_list: ChunkList = serde_json::from_slice(&fs::read(list)?)?;
+    Ok(chunk_list.size)
+}
+
+/// Whether the file at `source` splits into exactly the chunks the list at
+/// `list` names, so storing it again would change nothing.
+pub fn matches_list(source: &Path, list: &Path) -> bool {
+    let matches = || -> io::Result<bool> {
+        let chunk_list: ChunkList = serde_json::from_slice(&fs::read(list)?)?;
+        let mut chunker = Chunker::new(fs::File::open(source)?);
+        let mut listed = chunk_list.chunks.iter();
+        while let Some(chunk) = chunker.next_chunk()? {
+            if listed.next() != Some(&hex(&Sha256::digest(&chunk))) {
+                return Ok(false);
+            }
+        }
+        Ok(listed.next().is_none())
+    };
+    matches().unwrap_or(false)
+}
+
+/// The chunks of a backup location, each stored once under its SHA-256 however
+/// many backups share it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkStore {
+    dir: PathBuf,
+    /// Whether each new chunk is flushed to disk before it is used.
+    fsync: bool,
+}
+
+impl ChunkStore {
+    /// The store of the backup location at `location`.
+    pub fn new(location: &Path) -> Self {
+        ChunkStore {
+            dir: location.join(CHUNK_DIR_NAME),
+            fsync: false,
+        }
+    }
+
+    /// Flushes each chunk and its folder to disk as it is stored, for `fsync`.
+    pub fn with_fsync(mut self, fsync: bool) -> Self {
+        self.fsync = fsync;
+        self
+    }
+
+    /// The store of the location the backup at `backup` is in, found as the
+    /// nearest folder above it holding one.
+    pub fn find(backup: &Path) -> Option<Self> {
+        backup
+            .ancestors()
+            .find(|dir| dir.join(CHUNK_DIR_NAME).is_dir())
+            .map(ChunkStore::new)
+    }
+
+    fn chunk_path(&self, hash: &str) -> PathBuf {
+        self.dir.join(&hash[..2]).join(hash)
+    }
+
+    /// Splits the file at `source` into content-defined chunks, stores those
+    /// not already in the store, and writes the file's chunk list to `list`.
+    /// Returns the bytes newly stored, which stay small when only part of a
+    /// large file changed since its last backup.
+    pub fn store_file(&self, source: &Path, list: &Path) -> io::Result<u64> {
+        let mut chunker = Chunker::new(fs::File::open(source)?);
+        let mut chunk_list = ChunkList {
+            size: 0,
+            chunks: Vec::new(),
+        };
+        let mut stored = 0;
+        while let Some(chunk) = chunker.next_chunk()? {
+            let hash = hex(&Sha256::digest(&chunk));
+            let path = self.chunk_path(&hash);
+            if !path.exists() {
+                let dir = path.parent().expect("chunk paths have a parent");
+                fs::create_dir_all(dir)?;
+                // Written aside and renamed, so a chunk is never seen half-written
+                let mut tmp = tempfile::NamedTempFile::new_in(dir)?;
+                tmp.write_all(&chunk)?;
+                if self.fsync {
+                    tmp.as_file().sync_all()?;
+                }
+                tmp.persist(&path).map_err(|e| e.error)?;
+                if self.fsync {
+                    sync_dir(dir)?;
+                }
+                stored += chunk.len() as u64;
+            }
+            chunk_list.size += chunk.len() as u64;
+            chunk_list.chunks.push(hash);
+        }
+        let raw = serde_json::to_vec(&chunk_list)?;
+        unlink_existing(list)?;
+        fs::write(list, &raw)?;
+        Ok(stored + raw.len() as u64)
+    }
+
+    /// Rebuilds the file whose chunk list is at `list` as `dest`, checking
+    /// every chunk against its hash. Returns the bytes written.
+    pub fn reassemble(&self, list: &Path, dest: &Path) -> io::Result<u64> {
+        let chunk_list: ChunkList = serde_json::from_slice(&fs::read(list)?)?;
+        unlink_existing(dest)?;
+        let mut writer = fs::File::create(dest)?;
+        let mut bytes = 0;
+        for hash in &chunk_list.chunks {
+            if !is_hash(hash) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} names an invalid chunk {:?}", list.display(), hash),
+                ));
+            }
+            let chunk = fs::read(self.chunk_path(hash))?;
+            if hex(&Sha256::digest(&chunk)) != *hash {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("chunk {} is corrupt", hash),
+                ));
+            }
+            writer.write_all(&chunk)?;
+            bytes += chunk.len() as u64;
+        }
+        if bytes != chunk_list.size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} chunks add up to {} bytes, not {}",
+                    list.display(),
+                    bytes,
+                    chunk_list.size
+                ),
+            ));
+        }
+        Ok(bytes)
+    }
+
+    /// Removes the chunks no chunk list under `roots` refers to any more, such
+    /// as those only pruned backups used. Returns how many chunks were removed
+    /// and their size. Nothing is removed if any chunk list can't be read.
+    pub fn collect_garbage(&self, roots: &[&Path]) -> io::Result<(usize, u64)> {
+        let mut referenced = HashSet::new();
+        for root in roots {
+            self.collect_references(root, &mut referenced)?;
+        }
+        let mut removed = (0, 0);
+        let Ok(prefixes) = fs::read_dir(&self.dir) else {
+            return Ok(removed);
+        };
+        for prefix in prefixes {
+            let prefix = prefix?;
+            if !prefix.file_type()?.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(prefix.path())? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let metadata = entry.metadata()?;
+                if metadata.is_file() && is_hash(&name) && !referenced.contains(&name) {
+                    fs::remove_file(entry.path())?;
+                    removed.0 += 1;
+                    removed.1 += metadata.len();
+                }
+            }
+        }
+        Ok(removed)
+    }
+
+    fn collect_references(&self, dir: &Path, referenced: &mut HashSet<String>) -> io::Result<()> {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let path = entry.path();
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() && path != self.dir {
+                self.collect_references(&path, referenced)?;
+            } else if file_type.is_file() && chunked_path(&path).is_some() {
+                let chunk_list: ChunkList = serde_json::from_slice(&fs::read(&path)?)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                referenced.extend(chunk_list.chunks);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Splits a stream into chunks with FastCDC, so an edit only changes the
+/// chunks around it and the rest of the file still matches earlier backups.
+struct Chunker<R> {
+    reader: R,
+    buffer: Vec<u8>,
+    eof: bool,
+}
+
+impl<R: Read> Chunker<R> {
+    fn new(reader: R) -> Self {
+        Chunker {
+            reader,
+            buffer: Vec::with_capacity(MAX_CHUNK),
+            eof: false,
+        }
+    }
+
+    fn next_chunk(&mut self) -> io::Result<Option<Vec<u8>>> {
+        while !self.eof && self.buffer.len() < MAX_CHUNK {
+            let start = self.buffer.len();
+            self.buffer.resize(MAX_CHUNK, 0);
+            match self.reader.read(&mut self.buffer[start..]) {
+                Ok(0) => {
+                    self.buffer.truncate(start);
+                    self.eof = true;
+                }
+                Ok(read) => self.buffer.truncate(start + read),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => self.buffer.truncate(start),
+                Err(e) => return Err(e),
+            }
+        }
+        if self.buffer.is_empty() {
+            return Ok(None);
+        }
+        let rest = self.buffer.split_off(cut_point(&self.buffer));
+        Ok(Some(std::mem::replace(&mut self.buffer, rest)))
+    }
+}
+
+/// Where the chunk at the start of `data` ends.
+fn cut_point(data: &[u8]) -> usize {
+    if data.len() <= MIN_CHUNK {
+        return data.len();
+    }
+    let end = data.len().min(MAX_CHUNK);
+    let normal = end.min(AVG_CHUNK);
+    let mut hash: u64 = 0;
+    for (i, &byte) in data.iter().enumerate().take(end).skip(MIN_CHUNK) {
+        hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+        let mask = if i < normal { MASK_STRICT } else { MASK_LOOSE };
+        if hash & mask == 0 {
+            return i + 1;
+        }
+    }
+    end
+}
+
+fn is_hash(name: &str) -> bool {
+    name.len() == 64 && name.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_edited_file_shares_chunks_and_reassembles() {
+        let temp_dir = tempdir().unwrap();
+        let store = ChunkStore::new(temp_dir.path());
+        let mut state: u32 = 1;
+        let mut contents: Vec<u8> = (0..6 * 1024 * 1024)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        let source = temp_dir.path().join("disk.img");
+        fs::write(&source, &contents).unwrap();
+        let first = temp_dir.path().join("first").join("disk.img.bw-chunks");
+        fs::create_dir_all(first.parent().unwrap()).unwrap();
+        assert!(store.store_file(&source, &first).unwrap() > contents.len() as u64);
+
+        contents[3 * 1024 * 1024] ^= 0xff;
+        fs::write(&source, &contents).unwrap();
+        let second = temp_dir.path().join("second").join("disk.img.bw-chunks");
+        fs::create_dir_all(second.parent().unwrap()).unwrap();
+        assert!(store.store_file(&source, &second).unwrap() <= MAX_CHUNK as u64 * 2);
+
+        assert_eq!(
+            chunked_path(&second),
+            Some(second.with_file_name("disk.img"))
+        );
+        let restored = temp_dir.path().join("restored.img");
+        store.reassemble(&second, &restored).unwrap();
+        assert_eq!(fs::read(&restored).unwrap(), contents);
+
+        fs::remove_dir_all(first.parent().unwrap()).unwrap();
+        let (removed, _) = store.collect_garbage(&[temp_dir.path()]).unwrap();
+        assert!(removed >= 1);
+        store.reassemble(&second, &restored).unwrap();
+        assert_eq!(fs::read(&restored).unwrap(), contents);
+    }
+}
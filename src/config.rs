@@ -1,8 +1,1176 @@
-use serde::{ Deserialize, Serialize };
+use crate::control::default_control_dir;
+use crate::copy::{
+    CaseCollisionPolicy, CopyOrder, ErrorLimits, SymlinkPolicy, UnreadableDirPolicy,
+    DEFAULT_COPY_BUFFER_SIZE, DEFAULT_MAX_DEPTH, DEFAULT_MIN_FREE_BYTES, NETWORK_COPY_BUFFER_SIZE,
+};
+use crate::disk;
+use crate::error::{BackupWardenError, Result};
+use crate::filter::PathFilter;
+use crate::layout::BackupLayout;
+use crate::logging::LogFormat;
+use crate::manifest::ManifestFormat;
+use crate::schedule::{MonthlyRetention, NETWORK_POLL_INTERVAL, POLL_INTERVAL};
+use crate::signature::{load_signing_key, parse_public_key};
+use crate::sink::Retention;
+use crate::watch::{WatchEventKind, WatchMode};
+use chrono::{NaiveDate, Weekday};
+use ed25519_dalek::SigningKey;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Name of the copy of the effective config written at the root of every backup.
+pub const CONFIG_SNAPSHOT_FILE_NAME: &str = "backup_warden.config.json";
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct BackupWardenConfig {
     pub watch_folder: String,
     pub backup_locations: Vec<String>,
+    #[serde(default)]
+    pub location_roles: HashMap<String, LocationRole>,
     pub retention_days: usize,
+    #[serde(default)]
+    pub retention_mode: RetentionMode,
+    #[serde(default)]
+    pub max_age_days: Option<u64>,
+    #[serde(default)]
+    pub min_retained: usize,
+    #[serde(default)]
+    pub include_patterns: Vec<String>,
+    #[serde(default)]
+    pub exclude_patterns: Vec<String>,
+    #[serde(default)]
+    pub min_file_age_secs: Option<u64>,
+    #[serde(default)]
+    pub max_file_age_secs: Option<u64>,
+    #[serde(default)]
+    pub min_backup_interval_secs: u64,
+    #[serde(default)]
+    pub maintain_mirror: bool,
+    #[serde(default)]
+    pub incremental_compare: IncrementalCompare,
+    #[serde(default)]
+    pub skip_unchanged: bool,
+    #[serde(default)]
+    pub on_total_failure: TotalFailurePolicy,
+    #[serde(default)]
+    pub alert_command: Option<String>,
+    #[serde(default)]
+    pub path_template: Option<String>,
+    #[serde(default)]
+    pub low_priority: bool,
+    #[serde(default)]
+    pub control_dir: Option<String>,
+    #[serde(default)]
+    pub log_format: LogFormat,
+    #[serde(default)]
+    pub watch_mode: WatchMode,
+    #[serde(default)]
+    pub watch_events: Option<Vec<WatchEventKind>>,
+    #[serde(default)]
+    pub watch_extensions: Option<Vec<String>>,
+    #[serde(default)]
+    pub staging_dir: Option<String>,
+    #[serde(default)]
+    pub monitor_free_space: bool,
+    #[serde(default)]
+    pub min_free_bytes: Option<u64>,
+    #[serde(default)]
+    pub max_source_bytes: Option<u64>,
+    #[serde(default)]
+    pub max_source_files: Option<u64>,
+    #[serde(default)]
+    pub case_collision_policy: CaseCollisionPolicy,
+    #[serde(default)]
+    pub symlink_policy: SymlinkPolicy,
+    #[serde(default)]
+    pub copy_order: CopyOrder,
+    #[serde(default)]
+    pub unreadable_dir_policy: UnreadableDirPolicy,
+    #[serde(default)]
+    pub max_errors: Option<u64>,
+    #[serde(default)]
+    pub max_error_ratio: Option<f64>,
+    #[serde(default)]
+    pub retry_backup_on_partial: bool,
+    #[serde(default)]
+    pub retry_backup_delay_secs: Option<u64>,
+    #[serde(default)]
+    pub copy_buffer_size: Option<usize>,
+    #[serde(default)]
+    pub network_profile: Option<bool>,
+    #[serde(default)]
+    pub fsync: FsyncMode,
+    #[serde(default)]
+    pub snapshot_on_first_run: bool,
+    #[serde(default)]
+    pub use_vss: bool,
+    #[serde(default)]
+    pub copy_ads: bool,
+    #[serde(default)]
+    pub preserve_attributes: bool,
+    #[serde(default)]
+    pub clear_archive_bit: bool,
+    #[serde(default)]
+    pub cleanup_order: CleanupOrder,
+    #[serde(default)]
+    pub cleanup_interval_hours: Option<u64>,
+    #[serde(default)]
+    pub alert_if_older_than_hours: Option<u64>,
+    #[serde(default)]
+    pub pre_backup_command: Option<String>,
+    #[serde(default)]
+    pub post_backup_command: Option<String>,
+    #[serde(default)]
+    pub cleanup_command: Option<String>,
+    #[serde(default)]
+    pub audit_log: Option<String>,
+    #[serde(default)]
+    pub report_dir: Option<String>,
+    #[serde(default)]
+    pub mode: WardenMode,
+    #[serde(default)]
+    pub verify_interval_secs: Option<u64>,
+    #[serde(default)]
+    pub preserve_dir_times: Option<bool>,
+    #[serde(default)]
+    pub destination_mode: Option<String>,
+    #[serde(default)]
+    pub allow_reflink: Option<bool>,
+    #[serde(default)]
+    pub chunking: bool,
+    #[serde(default)]
+    pub reproducible_archives: bool,
+    #[serde(default)]
+    pub selective_backups: bool,
+    #[serde(default)]
+    pub striping: bool,
+    #[serde(default)]
+    pub max_depth: Option<usize>,
+    #[serde(default)]
+    pub link_monthly_from_daily: bool,
+    #[serde(default)]
+    pub monthly_strategy: MonthlyStrategy,
+    #[serde(default)]
+    pub monthly_retention_mode: MonthlyRetentionMode,
+    #[serde(default)]
+    pub monthly_keep_months: Option<usize>,
+    #[serde(default)]
+    pub monthly_keep_years: Option<u32>,
+    #[serde(default)]
+    pub weekly_snapshot_day: Option<Weekday>,
+    #[serde(default)]
+    pub weekly_retention: Option<usize>,
+    #[serde(default)]
+    pub backup_timeout_secs: Option<u64>,
+    #[serde(default)]
+    pub split_top_level: bool,
+    #[serde(default)]
+    pub date_format: Option<String>,
+    #[serde(default)]
+    pub merkle_root: bool,
+    #[serde(default)]
+    pub manifest_format: ManifestFormat,
+    #[serde(default)]
+    pub signing_key_path: Option<String>,
+    #[serde(default)]
+    pub signing_public_key: Option<String>,
+}
+
+/// How often a verify-only warden re-checks the backups when not configured.
+pub const DEFAULT_VERIFY_INTERVAL_SECS: u64 = 24 * 60 * 60;
+
+/// How long a backup waits before its retry with `retry_backup_on_partial`
+/// when not configured.
+pub const DEFAULT_RETRY_BACKUP_DELAY_SECS: u64 = 60;
+
+/// How many weekly snapshots are kept when not configured.
+pub const DEFAULT_WEEKLY_RETENTION: usize = 8;
+
+/// Name given to the job when the config uses the single-job form.
+pub const DEFAULT_JOB_NAME: &str = "default";
+
+/// One named entry in the `jobs` form of the config.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JobConfig {
+    pub name: String,
+    #[serde(flatten)]
+    pub config: BackupWardenConfig,
+}
+
+/// The whole config file. It is either a single job, as it has always been, or
+/// `{"jobs": [...]}` with each job's settings plus process-wide settings
+/// (`log_format`, `low_priority`, `control_dir`) at the top level.
+#[derive(Debug)]
+pub struct WardenConfig {
+    pub jobs: Vec<JobConfig>,
+    pub log_format: LogFormat,
+    pub low_priority: bool,
+    pub control_dir: Option<String>,
+    /// How many jobs may run a backup at the same time.
+    pub global_backup_concurrency: usize,
+}
+
+/// Backups that may run at once when `global_backup_concurrency` isn't set.
+pub const DEFAULT_BACKUP_CONCURRENCY: usize = 1;
+
+fn default_backup_concurrency() -> usize {
+    DEFAULT_BACKUP_CONCURRENCY
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JobsFile {
+    jobs: Vec<JobConfig>,
+    #[serde(default)]
+    log_format: LogFormat,
+    #[serde(default)]
+    low_priority: bool,
+    #[serde(default)]
+    control_dir: Option<String>,
+    #[serde(default = "default_backup_concurrency")]
+    global_backup_concurrency: usize,
+}
+
+impl WardenConfig {
+    /// Parses either form of the config file.
+    pub fn parse(raw: &str) -> Result<Self> {
+        let value: serde_json::Value =
+            serde_json::from_str(raw).map_err(|e| parse_error(raw, e))?;
+        if value.get("jobs").is_none() {
+            let config: BackupWardenConfig =
+                serde_json::from_str(raw).map_err(|e| parse_error(raw, e))?;
+            return Ok(WardenConfig {
+                log_format: config.log_format,
+                low_priority: config.low_priority,
+                control_dir: config.control_dir.clone(),
+                global_backup_concurrency: DEFAULT_BACKUP_CONCURRENCY,
+                jobs: vec![JobConfig {
+                    name: DEFAULT_JOB_NAME.to_string(),
+                    config,
+                }],
+            });
+        }
+
+        // serde can't reject unknown fields through JobConfig's flatten, so
+        // each job's settings are checked for them on their own first; other
+        // errors are left for the full parse, which knows where they are
+        for job in value["jobs"].as_array().into_iter().flatten() {
+            if let Some(settings) = job.as_object() {
+                let mut settings = settings.clone();
+                settings.remove("name");
+                match BackupWardenConfig::deserialize(serde_json::Value::Object(settings)) {
+                    Err(e) if e.to_string().starts_with("unknown field") => {
+                        return Err(parse_error(raw, e))
+                    }
+                    _ => (),
+                }
+            }
+        }
+        let mut file: JobsFile = serde_json::from_str(raw).map_err(|e| parse_error(raw, e))?;
+        for job in &mut file.jobs {
+            if job.config.log_format != LogFormat::default()
+                || job.config.low_priority
+                || job.config.control_dir.is_some()
+            {
+                return Err(invalid(format!(
+                    "job {}: log_format, low_priority and control_dir apply to the whole process and belong at the top level",
+                    job.name
+                )));
+            }
+            // So code that only sees one job's config still reads the real values
+            job.config.log_format = file.log_format;
+            job.config.low_priority = file.low_priority;
+            job.config.control_dir = file.control_dir.clone();
+        }
+        Ok(WardenConfig {
+            jobs: file.jobs,
+            log_format: file.log_format,
+            low_priority: file.low_priority,
+            control_dir: file.control_dir,
+            global_backup_concurrency: file.global_backup_concurrency,
+        })
+    }
+
+    /// Validates every job, and that jobs are uniquely named and never share a
+    /// backup location, since each job prunes and locks its locations on its own.
+    pub fn validate(&self) -> Result<()> {
+        if self.jobs.is_empty() {
+            return Err(invalid("jobs must list at least one job"));
+        }
+        if self.global_backup_concurrency == 0 {
+            return Err(invalid("global_backup_concurrency must be at least 1"));
+        }
+        let mut names = std::collections::HashSet::new();
+        let mut locations = std::collections::HashMap::new();
+        for job in &self.jobs {
+            if job.name.trim().is_empty() {
+                return Err(invalid("job names must not be empty"));
+            }
+            if !names.insert(job.name.as_str()) {
+                return Err(invalid(format!(
+                    "job name {} is used more than once",
+                    job.name
+                )));
+            }
+            job.config
+                .validate()
+                .map_err(|e| invalid(format!("job {}: {}", job.name, e)))?;
+            for location in &job.config.backup_locations {
+                if let Some(other) = locations.insert(resolve(location), job.name.as_str()) {
+                    if other != job.name {
+                        return Err(invalid(format!(
+                            "backup location {} is used by both job {} and job {}",
+                            location, other, job.name
+                        )));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The folder holding the pause marker, falling back to the default.
+    pub fn control_dir(&self) -> PathBuf {
+        self.control_dir
+            .as_ref()
+            .map_or_else(default_control_dir, PathBuf::from)
+    }
+
+    /// The job called `name`, or the only job when `name` is `None`.
+    pub fn job(&self, name: Option<&str>) -> Result<&JobConfig> {
+        match name {
+            Some(name) => self
+                .jobs
+                .iter()
+                .find(|job| job.name == name)
+                .ok_or_else(|| invalid(format!("no job named {}", name))),
+            None if self.jobs.len() == 1 => Ok(&self.jobs[0]),
+            None => Err(invalid("the config has several jobs; pick one with --job")),
+        }
+    }
+}
+
+/// When the monthly snapshot is taken.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum MonthlyStrategy {
+    /// On the last day of each month; missed if the machine is off that day.
+    #[default]
+    LastDayOfMonth,
+    /// With the first backup made in each new calendar month.
+    FirstBackupOfNewMonth,
+}
+
+/// Which limit decides how long monthly snapshots are kept.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MonthlyRetentionMode {
+    /// Keep every monthly snapshot.
+    #[default]
+    All,
+    /// Keep the snapshots of the newest `monthly_keep_months` months that have one.
+    Count,
+    /// Keep the snapshots taken within the last `monthly_keep_years` years.
+    Age,
+}
+
+/// Which limits decide how long daily backups are kept.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RetentionMode {
+    /// Keep the newest `retention_days` days that have backups.
+    #[default]
+    Count,
+    /// Keep days up to `max_age_days` calendar days old.
+    Age,
+    /// Apply both limits; a day goes as soon as either says so.
+    Both,
+}
+
+/// How a file already in the backup is found to be unchanged, so it isn't
+/// copied again: when the mirror is synced and when an interrupted backup
+/// resumes.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum IncrementalCompare {
+    /// Same size and modification time.
+    #[default]
+    Mtime,
+    /// Same size and contents, read in full on both sides. Slower, but right on
+    /// filesystems with coarse or unreliable mtimes, such as FAT and some
+    /// network shares.
+    Hash,
+}
+
+/// When what a backup writes is flushed to disk, so it survives a crash or
+/// power loss right after the backup is reported done.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum FsyncMode {
+    /// Leave it to the operating system, which may hold writes in its cache
+    /// for a while.
+    #[default]
+    Off,
+    /// Flush each file as soon as it is written, and the folders once the
+    /// backup is finished.
+    EachFile,
+    /// Flush the whole backup once it is finished.
+    EndOfBackup,
+}
+
+/// When a backup location is written.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LocationRole {
+    /// By every backup and snapshot.
+    #[default]
+    Mirror,
+    /// Only when the backup or snapshot failed for a mirror location, as a
+    /// standby for when the primary is unavailable.
+    Failover,
+}
+
+/// What to do when a backup fails for every backup location.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TotalFailurePolicy {
+    /// Log the failure and keep watching.
+    #[default]
+    Continue,
+    /// Terminate with a non-zero exit code.
+    Exit,
+    /// Raise an alert and keep watching.
+    Alert,
+}
+
+/// What the warden does with a job.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum WardenMode {
+    /// Watch the folder and back it up.
+    #[default]
+    Backup,
+    /// Never write anything; periodically check the existing backups against
+    /// their manifests and alert on corruption.
+    VerifyOnly,
+    /// Watch the folder and keep each changed file as a new version of its
+    /// own, instead of taking whole-folder backups.
+    PerFileVersions,
+}
+
+/// When old backups are pruned relative to the backup that triggers cleanup.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CleanupOrder {
+    /// Prune once the new backup is written.
+    #[default]
+    After,
+    /// Prune first, so the new backup has the reclaimed space.
+    Before,
+    /// Prune first only when a location lacks room for a full copy of
+    /// watch_folder, otherwise afterwards.
+    AsNeeded,
+}
+
+impl BackupWardenConfig {
+    /// Checks the config for mistakes that would make every backup fail or misbehave.
+    pub fn validate(&self) -> Result<()> {
+        if self.watch_folder.trim().is_empty() {
+            return Err(invalid("watch_folder must not be empty"));
+        }
+        if self.backup_locations.is_empty() {
+            return Err(invalid("backup_locations must list at least one location"));
+        }
+
+        let watch_folder = resolve(&self.watch_folder);
+        for location in &self.backup_locations {
+            if location.trim().is_empty() {
+                return Err(invalid("backup_locations must not contain empty paths"));
+            }
+            // A location inside the watched folder would back up its own backups
+            if resolve(location).starts_with(&watch_folder) {
+                return Err(invalid(format!(
+                    "backup location {} is inside watch_folder {}",
+                    location, self.watch_folder
+                )));
+            }
+        }
+        if let Some(unknown) = self
+            .location_roles
+            .keys()
+            .find(|location| !self.backup_locations.contains(location))
+        {
+            return Err(invalid(format!(
+                "location_roles names {}, which isn't in backup_locations",
+                unknown
+            )));
+        }
+        if self
+            .backup_locations
+            .iter()
+            .all(|location| self.location_role(location) == LocationRole::Failover)
+        {
+            return Err(invalid(
+                "backup_locations must have at least one mirror location, not only failover ones",
+            ));
+        }
+
+        if let Some(staging_dir) = &self.staging_dir {
+            if resolve(staging_dir).starts_with(&watch_folder) {
+                return Err(invalid(format!(
+                    "staging_dir {} is inside watch_folder {}",
+                    staging_dir, self.watch_folder
+                )));
+            }
+        }
+
+        // Each record written would otherwise trigger another backup
+        if let Some(audit_log) = &self.audit_log {
+            if resolve(audit_log).starts_with(&watch_folder) {
+                return Err(invalid(format!(
+                    "audit_log {} is inside watch_folder {}",
+                    audit_log, self.watch_folder
+                )));
+            }
+        }
+        if let Some(report_dir) = &self.report_dir {
+            if resolve(report_dir).starts_with(&watch_folder) {
+                return Err(invalid(format!(
+                    "report_dir {} is inside watch_folder {}",
+                    report_dir, self.watch_folder
+                )));
+            }
+        }
+
+        PathFilter::new(self).map_err(invalid)?;
+        BackupLayout::new(self).map_err(invalid)?;
+
+        if let Some(key_path) = &self.signing_key_path {
+            if !self.merkle_root {
+                return Err(invalid(
+                    "signing_key_path needs merkle_root, which is what gets signed",
+                ));
+            }
+            // A key stored with the backups would let anyone who can alter them re-sign them
+            let key_path = resolve(key_path);
+            for folder in std::iter::once(&self.watch_folder).chain(&self.backup_locations) {
+                if key_path.starts_with(resolve(folder)) {
+                    return Err(invalid(format!(
+                        "signing_key_path must be outside watch_folder and every backup location, but is inside {}",
+                        folder
+                    )));
+                }
+            }
+        }
+        if let Some(public_key) = &self.signing_public_key {
+            parse_public_key(public_key)
+                .map_err(|e| invalid(format!("signing_public_key: {}", e)))?;
+        }
+
+        if self.retention_mode != RetentionMode::Count && self.max_age_days.is_none() {
+            return Err(invalid(
+                "retention_mode \"age\" and \"both\" need max_age_days",
+            ));
+        }
+        match self.monthly_retention_mode {
+            MonthlyRetentionMode::Count if self.monthly_keep_months.is_none() => {
+                return Err(invalid(
+                    "monthly_retention_mode \"count\" needs monthly_keep_months",
+                ))
+            }
+            MonthlyRetentionMode::Age if self.monthly_keep_years.is_none() => {
+                return Err(invalid(
+                    "monthly_retention_mode \"age\" needs monthly_keep_years",
+                ))
+            }
+            _ => {}
+        }
+        if self.monthly_keep_months == Some(0) || self.monthly_keep_years == Some(0) {
+            return Err(invalid(
+                "monthly_keep_months and monthly_keep_years must be greater than zero",
+            ));
+        }
+        if self.max_source_bytes == Some(0) || self.max_source_files == Some(0) {
+            return Err(invalid(
+                "max_source_bytes and max_source_files must be greater than zero",
+            ));
+        }
+        if let (Some(min), Some(max)) = (self.min_file_age_secs, self.max_file_age_secs) {
+            if min > max {
+                return Err(invalid(
+                    "min_file_age_secs must not be greater than max_file_age_secs",
+                ));
+            }
+        }
+        if self.max_age_days == Some(0) {
+            return Err(invalid("max_age_days must be greater than zero"));
+        }
+        if self.copy_buffer_size == Some(0) {
+            return Err(invalid("copy_buffer_size must be greater than zero"));
+        }
+        if self.backup_timeout_secs == Some(0) {
+            return Err(invalid("backup_timeout_secs must be greater than zero"));
+        }
+        if self.weekly_retention == Some(0) {
+            return Err(invalid("weekly_retention must be greater than zero"));
+        }
+        if self.cleanup_interval_hours == Some(0) {
+            return Err(invalid("cleanup_interval_hours must be greater than zero"));
+        }
+        if self.alert_if_older_than_hours == Some(0) {
+            return Err(invalid(
+                "alert_if_older_than_hours must be greater than zero",
+            ));
+        }
+        if self
+            .max_error_ratio
+            .is_some_and(|ratio| !(0.0..=1.0).contains(&ratio))
+        {
+            return Err(invalid("max_error_ratio must be between 0 and 1"));
+        }
+        if let Some(mode) = &self.destination_mode {
+            let Some(bits) = parse_mode(mode) else {
+                return Err(invalid(format!(
+                    "destination_mode must be octal permission bits such as \"0600\", got {:?}",
+                    mode
+                )));
+            };
+            // The warden reads its backups back to verify, heal and restore them
+            if bits & 0o400 == 0 {
+                return Err(invalid(format!(
+                    "destination_mode {:?} must let the owner read, such as \"0600\"",
+                    mode
+                )));
+            }
+        }
+        if self.verify_interval_secs == Some(0) {
+            return Err(invalid("verify_interval_secs must be greater than zero"));
+        }
+        if self.watch_events.as_ref().is_some_and(Vec::is_empty) {
+            return Err(invalid("watch_events must list at least one event kind"));
+        }
+        if let Some(extensions) = &self.watch_extensions {
+            if extensions.is_empty() {
+                return Err(invalid("watch_extensions must list at least one extension"));
+            }
+            if let Some(bad) = extensions
+                .iter()
+                .find(|ext| ext.trim_start_matches('.').is_empty() || ext.contains(['/', '\\']))
+            {
+                return Err(invalid(format!(
+                    "watch_extensions: invalid extension {:?}",
+                    bad
+                )));
+            }
+        }
+        if self.on_total_failure == TotalFailurePolicy::Alert && self.alert_command.is_none() {
+            return Err(invalid(
+                "on_total_failure is \"alert\" but no alert_command is set",
+            ));
+        }
+        if self.striping {
+            if self.backup_locations.len() < 2 {
+                return Err(invalid(
+                    "striping needs at least two backup_locations to spread backups across",
+                ));
+            }
+            if self
+                .backup_locations
+                .iter()
+                .any(|location| self.location_role(location) == LocationRole::Failover)
+            {
+                return Err(invalid(
+                    "a striped backup needs every location, so none can be failover",
+                ));
+            }
+            // Each of these writes whole copies into single locations
+            for (set, name) in [
+                (self.maintain_mirror, "maintain_mirror"),
+                (self.selective_backups, "selective_backups"),
+                (
+                    self.mode == WardenMode::PerFileVersions,
+                    "mode \"per-file-versions\"",
+                ),
+            ] {
+                if set {
+                    return Err(invalid(format!("striping can't be used with {}", name)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The folder holding the pause marker, falling back to the default.
+    pub fn control_dir(&self) -> PathBuf {
+        self.control_dir
+            .as_ref()
+            .map_or_else(default_control_dir, PathBuf::from)
+    }
+
+    /// The limits cleanup applies as of `today`, per retention_mode.
+    pub fn retention(&self, today: NaiveDate) -> Retention {
+        Retention {
+            keep_days: (self.retention_mode != RetentionMode::Age).then_some(self.retention_days),
+            max_age_days: match self.retention_mode {
+                RetentionMode::Count => None,
+                RetentionMode::Age | RetentionMode::Both => self.max_age_days,
+            },
+            min_retained: self.min_retained,
+            today,
+        }
+    }
+
+    /// How often cleanup runs on its own between backups, when it does.
+    pub fn cleanup_interval(&self) -> Option<Duration> {
+        self.cleanup_interval_hours
+            .map(|hours| Duration::from_secs(hours * 60 * 60))
+    }
+
+    /// How many problems a copy may work around before the backup fails.
+    pub fn error_limits(&self) -> ErrorLimits {
+        ErrorLimits {
+            max_errors: self.max_errors,
+            max_error_ratio: self.max_error_ratio,
+        }
+    }
+
+    /// How long to wait before retrying a backup that left files out, or `None`
+    /// when `retry_backup_on_partial` is off.
+    pub fn partial_retry_delay(&self) -> Option<std::time::Duration> {
+        self.retry_backup_on_partial.then(|| {
+            std::time::Duration::from_secs(
+                self.retry_backup_delay_secs
+                    .unwrap_or(DEFAULT_RETRY_BACKUP_DELAY_SECS),
+            )
+        })
+    }
+
+    /// The permission bits backup files are given, when `destination_mode` is
+    /// set and valid.
+    pub fn destination_mode(&self) -> Option<u32> {
+        self.destination_mode.as_deref().and_then(parse_mode)
+    }
+
+    /// The buffer size for file copies, falling back to the default, or to a
+    /// larger one with the network profile.
+    pub fn copy_buffer_size(&self) -> usize {
+        self.copy_buffer_size.unwrap_or_else(|| {
+            if self.network_profile() {
+                NETWORK_COPY_BUFFER_SIZE
+            } else {
+                DEFAULT_COPY_BUFFER_SIZE
+            }
+        })
+    }
+
+    /// Whether to use the defaults suited to network shares: `network_profile`
+    /// when set, or else whether watch_folder or a backup location is on a
+    /// network filesystem. A path that can't be checked counts as local.
+    pub fn network_profile(&self) -> bool {
+        self.network_profile.unwrap_or_else(|| {
+            std::iter::once(&self.watch_folder)
+                .chain(&self.backup_locations)
+                .any(|path| disk::is_network_filesystem(Path::new(path)).unwrap_or(false))
+        })
+    }
+
+    /// How often the poll watcher rescans watch_folder.
+    pub fn poll_interval(&self) -> std::time::Duration {
+        if self.network_profile() {
+            NETWORK_POLL_INTERVAL
+        } else {
+            POLL_INTERVAL
+        }
+    }
+
+    /// How much space `monitor_free_space` leaves free, falling back to the default.
+    pub fn min_free_bytes(&self) -> u64 {
+        self.min_free_bytes.unwrap_or(DEFAULT_MIN_FREE_BYTES)
+    }
+
+    /// How long monthly snapshots are kept, per monthly_retention_mode.
+    pub fn monthly_retention(&self) -> MonthlyRetention {
+        match self.monthly_retention_mode {
+            MonthlyRetentionMode::All => MonthlyRetention::All,
+            MonthlyRetentionMode::Count => self
+                .monthly_keep_months
+                .map_or(MonthlyRetention::All, MonthlyRetention::LastMonths),
+            MonthlyRetentionMode::Age => self
+                .monthly_keep_years
+                .map_or(MonthlyRetention::All, MonthlyRetention::WithinYears),
+        }
+    }
+
+    /// How many weekly snapshots each location keeps, falling back to the default.
+    pub fn weekly_retention(&self) -> usize {
+        self.weekly_retention.unwrap_or(DEFAULT_WEEKLY_RETENTION)
+    }
+
+    /// How deep below watch_folder copies go, falling back to the default.
+    pub fn max_depth(&self) -> usize {
+        self.max_depth.unwrap_or(DEFAULT_MAX_DEPTH)
+    }
+
+    /// Whether directory modification times are copied, which they are unless
+    /// turned off.
+    pub fn preserve_dir_times(&self) -> bool {
+        self.preserve_dir_times.unwrap_or(true)
+    }
+
+    /// The key backups are signed with, read from signing_key_path.
+    pub fn signing_key(&self) -> Result<Option<SigningKey>> {
+        self.signing_key_path
+            .as_ref()
+            .map(|path| {
+                load_signing_key(Path::new(path)).map_err(|source| BackupWardenError::Io {
+                    path: path.into(),
+                    source,
+                })
+            })
+            .transpose()
+    }
+
+    /// Whether files may be cloned copy-on-write, which they are unless turned off.
+    pub fn allow_reflink(&self) -> bool {
+        self.allow_reflink.unwrap_or(true)
+    }
+
+    /// How long a verify-only warden waits between checks.
+    pub fn verify_interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(
+            self.verify_interval_secs
+                .unwrap_or(DEFAULT_VERIFY_INTERVAL_SECS),
+        )
+    }
+
+    /// The role `location` has, mirror unless location_roles says otherwise.
+    pub fn location_role(&self, location: &str) -> LocationRole {
+        self.location_roles
+            .get(location)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// The backup locations in the order they are written: the mirror ones as
+    /// listed, then the failover ones.
+    pub fn locations_in_write_order(&self) -> Vec<&String> {
+        let mut locations: Vec<&String> = self.backup_locations.iter().collect();
+        locations.sort_by_key(|location| self.location_role(location) == LocationRole::Failover);
+        locations
+    }
+
+    /// Drops backup_locations that resolve to the same place as an earlier entry,
+    /// whether through a different spelling or a symlink, so each physical
+    /// destination is written once. Returns the removed entries.
+    pub fn dedupe_backup_locations(&mut self) -> Vec<String> {
+        let mut seen = std::collections::HashSet::new();
+        let mut removed = Vec::new();
+        self.backup_locations.retain(|location| {
+            if seen.insert(resolve(location)) {
+                true
+            } else {
+                removed.push(location.clone());
+                false
+            }
+        });
+        removed
+    }
+}
+
+/// Reads Unix permission bits written in octal, with or without a leading `0`
+/// or `0o`.
+fn parse_mode(raw: &str) -> Option<u32> {
+    let digits = raw.strip_prefix("0o").unwrap_or(raw);
+    u32::from_str_radix(digits, 8)
+        .ok()
+        .filter(|mode| !digits.is_empty() && *mode <= 0o777)
+}
+
+fn invalid(reason: impl Into<String>) -> BackupWardenError {
+    BackupWardenError::Config(reason.into())
+}
+
+/// Describes why the config file `raw` failed to parse. A misspelled setting
+/// would otherwise silently fall back to its default, so unknown fields are
+/// rejected and reported by name with the byte offset of their first use,
+/// rather than with serde's list of every valid field.
+fn parse_error(raw: &str, error: serde_json::Error) -> BackupWardenError {
+    let message = error.to_string();
+    if let Some(field) = message
+        .strip_prefix("unknown field `")
+        .and_then(|rest| rest.split_once('`'))
+        .map(|(field, _)| field)
+    {
+        let quoted = format!("\"{}\"", field);
+        let offset = raw.match_indices(&quoted).find_map(|(offset, _)| {
+            raw[offset + quoted.len()..]
+                .trim_start()
+                .starts_with(':')
+                .then_some(offset)
+        });
+        return invalid(match offset {
+            Some(offset) => format!("unknown field {:?} at byte {}", field, offset),
+            None => format!("unknown field {:?}", field),
+        });
+    }
+    invalid(message)
+}
+
+/// Canonicalizes `path` when it exists so that `..` and symlinks compare correctly.
+fn resolve(path: &str) -> PathBuf {
+    Path::new(path)
+        .canonicalize()
+        .unwrap_or_else(|_| PathBuf::from(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn config_with(watch_folder: &Path, backup_location: &Path) -> BackupWardenConfig {
+        BackupWardenConfig {
+            watch_folder: watch_folder.to_str().unwrap().to_string(),
+            backup_locations: vec![backup_location.to_str().unwrap().to_string()],
+            retention_days: 30,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_validate_accepts_separate_folders() {
+        let temp_dir = tempdir().unwrap();
+        let config = config_with(
+            &temp_dir.path().join("watch"),
+            &temp_dir.path().join("backup"),
+        );
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate_rejects_location_inside_watch_folder() {
+        let temp_dir = tempdir().unwrap();
+        let watch_folder = temp_dir.path().join("watch");
+        std::fs::create_dir_all(watch_folder.join("backups")).unwrap();
+
+        let config = config_with(&watch_folder, &watch_folder.join("backups"));
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_requires_alert_command_for_alert_policy() {
+        let mut config = config_with(Path::new("watch"), Path::new("backup"));
+        config.on_total_failure = TotalFailurePolicy::Alert;
+        assert!(config.validate().is_err());
+
+        config.alert_command = Some("notify-send backup-warden".to_string());
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate_rejects_signing_key_inside_backup_location() {
+        let temp_dir = tempdir().unwrap();
+        let backup = temp_dir.path().join("backup");
+        let mut config = config_with(&temp_dir.path().join("watch"), &backup);
+        config.merkle_root = true;
+        config.signing_key_path = Some(backup.join("key").to_str().unwrap().to_string());
+        assert!(config.validate().is_err());
+
+        config.signing_key_path = Some(temp_dir.path().join("key").to_str().unwrap().to_string());
+        assert!(config.validate().is_ok());
+        config.merkle_root = false;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_dedupe_removes_differently_spelled_duplicates() {
+        let temp_dir = tempdir().unwrap();
+        let backup = temp_dir.path().join("backup");
+        let other = temp_dir.path().join("other");
+        std::fs::create_dir_all(&backup).unwrap();
+        std::fs::create_dir_all(&other).unwrap();
+
+        let mut config = config_with(Path::new("watch"), &backup);
+        config
+            .backup_locations
+            .push(format!("{}/", backup.display()));
+        config
+            .backup_locations
+            .push(other.to_str().unwrap().to_string());
+        config
+            .backup_locations
+            .push(backup.join(".").to_str().unwrap().to_string());
+
+        let removed = config.dedupe_backup_locations();
+        assert_eq!(removed.len(), 2);
+        assert_eq!(
+            config.backup_locations,
+            [backup.to_str().unwrap(), other.to_str().unwrap()]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_dedupe_removes_symlinked_duplicate() {
+        let temp_dir = tempdir().unwrap();
+        let backup = temp_dir.path().join("backup");
+        let link = temp_dir.path().join("link");
+        std::fs::create_dir_all(&backup).unwrap();
+        std::os::unix::fs::symlink(&backup, &link).unwrap();
+
+        let mut config = config_with(Path::new("watch"), &backup);
+        config
+            .backup_locations
+            .push(link.to_str().unwrap().to_string());
+
+        assert_eq!(config.dedupe_backup_locations(), [link.to_str().unwrap()]);
+        assert_eq!(config.backup_locations, [backup.to_str().unwrap()]);
+    }
+
+    #[test]
+    fn test_parse_cleanup_order() {
+        let config: BackupWardenConfig = serde_json::from_str(
+            r#"{"watch_folder": "w", "backup_locations": ["b"], "retention_days": 3, "cleanup_order": "as-needed"}"#,
+        )
+        .unwrap();
+        assert_eq!(config.cleanup_order, CleanupOrder::AsNeeded);
+        assert_eq!(
+            BackupWardenConfig::default().cleanup_order,
+            CleanupOrder::After
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_unknown_fields() {
+        let raw = r#"{"watch_folder": "w", "backup_locations": ["b"], "retention_day": 3}"#;
+        let error = WardenConfig::parse(raw).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            r#"unknown field "retention_day" at byte 49"#
+        );
+        assert_eq!(error.exit_code(), 2);
+
+        let raw = r#"{"jobs": [{"name": "a", "watch_folder": "w", "backup_locations": ["b"], "retention_days": 3, "exclude_pattern": []}]}"#;
+        assert_eq!(
+            WardenConfig::parse(raw).unwrap_err().to_string(),
+            r#"unknown field "exclude_pattern" at byte 93"#
+        );
+        let raw = r#"{"jobs": [], "low_prority": true}"#;
+        assert!(WardenConfig::parse(raw).is_err());
+    }
+
+    #[test]
+    fn test_parse_single_job_form() {
+        let config = WardenConfig::parse(
+            r#"{"watch_folder": "w", "backup_locations": ["b"], "retention_days": 3, "low_priority": true}"#,
+        )
+        .unwrap();
+        assert_eq!(config.jobs.len(), 1);
+        assert_eq!(config.jobs[0].name, DEFAULT_JOB_NAME);
+        assert_eq!(config.jobs[0].config.retention_days, 3);
+        assert!(config.low_priority);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_parse_jobs_form() {
+        let config = WardenConfig::parse(
+            r#"{
+                "log_format": "json",
+                "jobs": [
+                    {"name": "docs", "watch_folder": "docs", "backup_locations": ["b/docs"], "retention_days": 30},
+                    {"name": "code", "watch_folder": "code", "backup_locations": ["b/code"], "retention_days": 7}
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config.log_format, LogFormat::Json);
+        assert_eq!(config.jobs[1].name, "code");
+        assert_eq!(config.jobs[1].config.retention_days, 7);
+        assert_eq!(config.jobs[1].config.log_format, LogFormat::Json);
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            config.job(Some("docs")).unwrap().config.watch_folder,
+            "docs"
+        );
+        assert!(config.job(None).is_err());
+
+        assert!(WardenConfig::parse(
+            r#"{"jobs": [{"name": "a", "watch_folder": "a", "backup_locations": ["b"], "retention_days": 1, "low_priority": true}]}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_validate_rejects_duplicate_names_and_shared_locations() {
+        let job = |name: &str, watch: &str, location: &str| JobConfig {
+            name: name.to_string(),
+            config: config_with(Path::new(watch), Path::new(location)),
+        };
+        let mut config = WardenConfig {
+            jobs: vec![job("a", "w1", "b1"), job("a", "w2", "b2")],
+            log_format: LogFormat::Text,
+            low_priority: false,
+            control_dir: None,
+            global_backup_concurrency: 1,
+        };
+        assert!(config.validate().is_err());
+
+        config.jobs[1].name = "b".to_string();
+        assert!(config.validate().is_ok());
+
+        config.jobs[1].config.backup_locations = vec!["b1".to_string()];
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_rejects_missing_locations() {
+        let mut config = config_with(Path::new("watch"), Path::new("backup"));
+        config.backup_locations.clear();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_rejects_destination_mode_without_owner_read() {
+        let mut config = config_with(Path::new("watch"), Path::new("backup"));
+        for (mode, valid) in [
+            ("0600", true),
+            ("0o400", true),
+            ("0200", false),
+            ("0000", false),
+        ] {
+            config.destination_mode = Some(mode.to_string());
+            assert_eq!(config.validate().is_ok(), valid, "{}", mode);
+        }
+    }
+
+    #[test]
+    fn test_validate_rejects_striping_a_single_location() {
+        let mut config = config_with(Path::new("watch"), Path::new("backup"));
+        config.striping = true;
+        assert!(config.validate().is_err());
+
+        config.backup_locations.push("backup2".to_string());
+        assert!(config.validate().is_ok());
+        config.maintain_mirror = true;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_network_profile_adjusts_defaults() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut config = config_with(temp_dir.path(), temp_dir.path());
+        assert!(!config.network_profile());
+        assert_eq!(config.copy_buffer_size(), DEFAULT_COPY_BUFFER_SIZE);
+        assert_eq!(config.poll_interval(), POLL_INTERVAL);
+
+        config.network_profile = Some(true);
+        assert_eq!(config.copy_buffer_size(), NETWORK_COPY_BUFFER_SIZE);
+        assert_eq!(config.poll_interval(), NETWORK_POLL_INTERVAL);
+        config.copy_buffer_size = Some(4096);
+        assert_eq!(config.copy_buffer_size(), 4096);
+    }
 }
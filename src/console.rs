@@ -0,0 +1,23 @@
+use std::io;
+
+/// Gives a windowed (`windows_subsystem = "windows"`) build somewhere to print:
+/// the console of the program that started it, such as the `cmd` or PowerShell
+/// window it was run from, or else a new console window. Call it before
+/// anything is logged.
+#[cfg(windows)]
+pub fn attach() -> io::Result<()> {
+    use windows_sys::Win32::System::Console::{AllocConsole, AttachConsole, ATTACH_PARENT_PROCESS};
+
+    unsafe {
+        if AttachConsole(ATTACH_PARENT_PROCESS) == 0 && AllocConsole() == 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Other platforms always keep the console they were started from.
+#[cfg(not(windows))]
+pub fn attach() -> io::Result<()> {
+    Ok(())
+}
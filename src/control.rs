@@ -0,0 +1,82 @@
+use chrono::Local;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// Name of the marker file in the control directory whose presence pauses
+/// backups.
+pub const PAUSED_FILE_NAME: &str = ".paused";
+
+/// Where the pause marker goes when `control_dir` isn't set.
+pub fn default_control_dir() -> PathBuf {
+    std::env::temp_dir().join("backup-warden")
+}
+
+/// Pauses backups by creating the marker in `dir`. It records when it was made.
+pub fn pause(dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    fs::write(dir.join(PAUSED_FILE_NAME), Local::now().to_rfc3339())
+}
+
+/// Resumes backups by removing the marker from `dir`. Returns whether they were
+/// paused.
+pub fn resume(dir: &Path) -> io::Result<bool> {
+    match fs::remove_file(dir.join(PAUSED_FILE_NAME)) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn is_paused(dir: &Path) -> bool {
+    dir.join(PAUSED_FILE_NAME).exists()
+}
+
+/// Follows the pause marker for a running job, logging each time it appears
+/// or goes away.
+pub struct PauseWatch {
+    dir: PathBuf,
+    paused: bool,
+}
+
+impl PauseWatch {
+    pub fn new(dir: PathBuf) -> Self {
+        PauseWatch { dir, paused: false }
+    }
+
+    /// Whether backups are paused right now.
+    pub fn check(&mut self) -> bool {
+        let paused = is_paused(&self.dir);
+        if paused != self.paused {
+            let marker = self.dir.join(PAUSED_FILE_NAME);
+            if paused {
+                info!(event = "backups_paused", marker = %marker.display(), "Backups paused until {} is removed", marker.display());
+            } else {
+                info!(event = "backups_resumed", "Backups resumed");
+            }
+            self.paused = paused;
+        }
+        paused
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_pause_and_resume_toggle_marker() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path().join("control");
+        let mut watch = PauseWatch::new(dir.clone());
+        assert!(!watch.check());
+
+        pause(&dir).unwrap();
+        assert!(watch.check());
+        assert!(resume(&dir).unwrap());
+        assert!(!watch.check());
+        assert!(!resume(&dir).unwrap());
+    }
+}
@@ -0,0 +1,1478 @@
+use crate::attributes::clear_readonly;
+use crate::config::BackupWardenConfig;
+use crate::disk;
+use crate::filter::PathFilter;
+use crate::identity::{file_id, FileId};
+use crate::sink::BackupSink;
+use crate::size::format_bytes;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tracing::{info, warn};
+
+/// Buffer used for each file copy when `copy_buffer_size` isn't set. Large enough
+/// to keep round trips to a network share down, small enough not to matter for
+/// memory.
+pub const DEFAULT_COPY_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Buffer used for each file copy with the network profile when
+/// `copy_buffer_size` isn't set, so fewer round trips are made per file.
+pub const NETWORK_COPY_BUFFER_SIZE: usize = 8 * 1024 * 1024;
+
+/// The folder that files directly in the watch folder go into with
+/// `split_top_level`.
+pub const ROOT_UNIT_NAME: &str = "_root";
+
+/// How deep a copy descends when `max_depth` isn't set. Far beyond any real
+/// tree, but well short of exhausting the stack.
+pub const DEFAULT_MAX_DEPTH: usize = 256;
+
+/// How much space `monitor_free_space` leaves free on the destination when
+/// `min_free_bytes` isn't set.
+pub const DEFAULT_MIN_FREE_BYTES: u64 = 1024 * 1024 * 1024;
+
+/// How many files, and how many bytes, `monitor_free_space` lets a copy write
+/// between checks.
+const FREE_SPACE_CHECK_FILES: u64 = 1000;
+const FREE_SPACE_CHECK_BYTES: u64 = 256 * 1024 * 1024;
+
+/// What to do when two names in the same source folder differ only in case, and
+/// would overwrite each other on a case-insensitive destination.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CaseCollisionPolicy {
+    /// Copy both under their own names and log a warning.
+    #[default]
+    Warn,
+    /// Copy the later name with a `~2`, `~3`, ... suffix before its extension.
+    Rename,
+}
+
+/// What to do with symlinks, and on Windows junctions and mount points, found in
+/// the tree being copied.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SymlinkPolicy {
+    /// Copy whatever the link points to. A link back into a folder that is
+    /// already being copied is skipped, so loops terminate.
+    #[default]
+    Follow,
+    /// Leave links out of the backup.
+    Skip,
+    /// Copy the link itself, pointing at the same target.
+    Recreate,
+}
+
+/// What to do with a folder below the watch folder that can't be listed, such as
+/// one the warden has no read permission for.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum UnreadableDirPolicy {
+    /// Log it, leave it out of the backup and copy everything else.
+    #[default]
+    Skip,
+    /// Fail the backup.
+    Fail,
+}
+
+/// The order files are copied in.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CopyOrder {
+    /// Folder by folder, each in name order.
+    #[default]
+    Name,
+    /// Largest files first, across the whole tree.
+    SizeDesc,
+    /// Smallest files first, across the whole tree.
+    SizeAsc,
+}
+
+/// What a copy did, for logs and reports.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CopyStats {
+    /// Files stored in the backup, counting recreated links and files a resumed
+    /// backup already held.
+    pub files_copied: u64,
+    /// Folders created in the backup, including its root.
+    pub dirs_created: u64,
+    pub bytes_copied: u64,
+    /// Files and links left out: filtered, special files, broken or skipped
+    /// links, and links that couldn't be recreated.
+    pub files_skipped: u64,
+    /// Problems that were logged and worked around rather than failing the
+    /// copy, such as unreadable folders and timestamps that couldn't be set,
+    /// and files that couldn't be copied when error limits are set.
+    pub errors: u64,
+    /// The same problems by kind.
+    pub error_counts: ErrorCounts,
+    /// Files that couldn't be read or written and were left out, which only
+    /// happens when `max_errors` or `max_error_ratio` is set.
+    pub files_failed: u64,
+    /// Folders left out because they couldn't be listed.
+    pub unreadable_dirs: Vec<PathBuf>,
+}
+
+impl CopyStats {
+    /// Adds the counts of `other`, a copy done alongside this one.
+    pub fn absorb(&mut self, other: CopyStats) {
+        self.files_copied += other.files_copied;
+        self.dirs_created += other.dirs_created;
+        self.bytes_copied += other.bytes_copied;
+        self.files_skipped += other.files_skipped;
+        self.errors += other.errors;
+        self.error_counts.permission += other.error_counts.permission;
+        self.error_counts.not_found += other.error_counts.not_found;
+        self.error_counts.no_space += other.error_counts.no_space;
+        self.error_counts.other += other.error_counts.other;
+        self.files_failed += other.files_failed;
+        self.unreadable_dirs.extend(other.unreadable_dirs);
+    }
+
+    /// Counts `e` as a problem worked around during the copy.
+    fn record_error(&mut self, e: &io::Error) {
+        self.errors += 1;
+        self.error_counts.add(ErrorClass::of(e));
+    }
+}
+
+/// What kind of problem held up part of a copy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    Permission,
+    NotFound,
+    NoSpace,
+    Other,
+}
+
+impl ErrorClass {
+    pub fn of(e: &io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::PermissionDenied => ErrorClass::Permission,
+            io::ErrorKind::NotFound => ErrorClass::NotFound,
+            io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded => ErrorClass::NoSpace,
+            _ => ErrorClass::Other,
+        }
+    }
+}
+
+/// How many of a copy's problems fell into each [`ErrorClass`].
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ErrorCounts {
+    pub permission: u64,
+    pub not_found: u64,
+    pub no_space: u64,
+    pub other: u64,
+}
+
+impl ErrorCounts {
+    pub fn add(&mut self, class: ErrorClass) {
+        match class {
+            ErrorClass::Permission => self.permission += 1,
+            ErrorClass::NotFound => self.not_found += 1,
+            ErrorClass::NoSpace => self.no_space += 1,
+            ErrorClass::Other => self.other += 1,
+        }
+    }
+}
+
+impl fmt::Display for ErrorCounts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} permission denied, {} not found, {} out of space, {} other",
+            self.permission, self.not_found, self.no_space, self.other
+        )
+    }
+}
+
+/// How many problems a copy may work around before it counts as failed. With
+/// neither limit set a file that can't be copied fails the copy at once; with
+/// either, such files are left out and counted, and the copy fails at the end
+/// if it went over a limit.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ErrorLimits {
+    pub max_errors: Option<u64>,
+    /// The most problems allowed, as a fraction of the files the copy tried.
+    pub max_error_ratio: Option<f64>,
+}
+
+impl ErrorLimits {
+    fn is_set(&self) -> bool {
+        self.max_errors.is_some() || self.max_error_ratio.is_some()
+    }
+
+    /// Fails with a message giving the counts by kind if `stats` went over
+    /// a limit.
+    pub fn check(&self, stats: &CopyStats) -> io::Result<()> {
+        let attempted = (stats.files_copied + stats.files_failed).max(1);
+        let over = self.max_errors.is_some_and(|max| stats.errors > max)
+            || self
+                .max_error_ratio
+                .is_some_and(|max| stats.errors as f64 / attempted as f64 > max);
+        if !over {
+            return Ok(());
+        }
+        Err(io::Error::other(format!(
+            "{} errors in {} files ({}), over the error limit",
+            stats.errors, attempted, stats.error_counts
+        )))
+    }
+}
+
+/// Settings that shape how a tree is copied.
+#[derive(Clone)]
+pub struct CopyOptions<'a> {
+    pub filter: &'a PathFilter,
+    pub case_collision_policy: CaseCollisionPolicy,
+    pub symlink_policy: SymlinkPolicy,
+    pub unreadable_dir_policy: UnreadableDirPolicy,
+    /// Whether to carry each directory's modification time over to the backup.
+    pub preserve_dir_times: bool,
+    /// How many folders deep below the watch folder the copy may go.
+    pub max_depth: usize,
+    /// Whether files directly in the watch folder go into a `_root` folder,
+    /// leaving each top-level folder a self-contained unit.
+    pub split_top_level: bool,
+    pub copy_order: CopyOrder,
+    /// Backup location roots, which are never descended into even if a bind
+    /// mount or link routes the watch folder into one.
+    pub backup_roots: Vec<FileId>,
+    /// Set from another thread to stop the copy before its next entry.
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// The folders whose filesystems the copy lands on, and how many bytes
+    /// must stay free on each, when `monitor_free_space` is set.
+    pub free_space_floor: Option<(Vec<PathBuf>, u64)>,
+    pub error_limits: ErrorLimits,
+    /// Limits the copy to the changed files, for a selective backup written
+    /// over a link of the previous one.
+    pub selection: Option<&'a Selection>,
+}
+
+impl<'a> CopyOptions<'a> {
+    pub fn new(config: &BackupWardenConfig, filter: &'a PathFilter) -> Self {
+        CopyOptions {
+            filter,
+            case_collision_policy: config.case_collision_policy,
+            symlink_policy: config.symlink_policy,
+            unreadable_dir_policy: config.unreadable_dir_policy,
+            preserve_dir_times: config.preserve_dir_times(),
+            max_depth: config.max_depth(),
+            split_top_level: config.split_top_level,
+            copy_order: config.copy_order,
+            backup_roots: config
+                .backup_locations
+                .iter()
+                .filter_map(|location| file_id(Path::new(location)).ok())
+                .collect(),
+            cancel: None,
+            free_space_floor: None,
+            error_limits: config.error_limits(),
+            selection: None,
+        }
+    }
+
+    /// Copies only what `selection` covers, leaving the rest as it is.
+    pub fn with_selection(mut self, selection: &'a Selection) -> Self {
+        self.selection = Some(selection);
+        self
+    }
+
+    /// Makes the copy stop with a `TimedOut` error once `cancel` is set.
+    pub fn with_cancel(mut self, cancel: Arc<AtomicBool>) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    /// Makes the copy stop with a `StorageFull` error, every so often, once
+    /// any of `dests` has less than `min_free_bytes` free.
+    pub fn with_free_space_floor(mut self, dests: Vec<PathBuf>, min_free_bytes: u64) -> Self {
+        self.free_space_floor = Some((dests, min_free_bytes));
+        self
+    }
+
+    fn check_free_space(&self) -> io::Result<()> {
+        let Some((dests, min_free_bytes)) = &self.free_space_floor else {
+            return Ok(());
+        };
+        for dest in dests {
+            let free = disk::free_space(dest)?;
+            if free < *min_free_bytes {
+                return Err(io::Error::new(
+                    io::ErrorKind::StorageFull,
+                    format!(
+                        "stopped with {} free on {}, below min_free_bytes ({}), before the disk filled up",
+                        format_bytes(free),
+                        dest.display(),
+                        format_bytes(*min_free_bytes)
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancel
+            .as_ref()
+            .is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+    }
+}
+
+/// The paths that changed in a tree since its previous backup, so a selective
+/// backup only copies those.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Selection {
+    changed: BTreeSet<PathBuf>,
+    /// Changed folders the previous backup doesn't have, such as one moved in,
+    /// whose every file is copied.
+    new_dirs: BTreeSet<PathBuf>,
+}
+
+impl Selection {
+    /// The selection of the `changed` paths under `src`, whose previous backup
+    /// is `base`.
+    pub fn new(changed: &BTreeSet<PathBuf>, src: &Path, base: &Path) -> Self {
+        let changed: BTreeSet<PathBuf> = changed
+            .iter()
+            .filter(|path| path.starts_with(src) && *path != src)
+            .cloned()
+            .collect();
+        let new_dirs = changed
+            .iter()
+            .filter(|path| path.is_dir())
+            .filter(|path| {
+                let relative = path.strip_prefix(src).expect("filtered above");
+                !base.join(relative).is_dir()
+            })
+            .cloned()
+            .collect();
+        Selection { changed, new_dirs }
+    }
+
+    /// Whether the folder at `dir` holds anything to copy.
+    fn touches(&self, dir: &Path) -> bool {
+        let under = |set: &BTreeSet<PathBuf>| {
+            set.range(dir.to_path_buf()..)
+                .next()
+                .is_some_and(|path| path.starts_with(dir))
+        };
+        under(&self.changed) || self.covers(dir)
+    }
+
+    /// Whether the file or link at `path` is to be copied.
+    fn covers(&self, path: &Path) -> bool {
+        self.changed.contains(path) || path.ancestors().any(|dir| self.new_dirs.contains(dir))
+    }
+
+    /// The changed paths that are gone from the source, relative to `src`, to
+    /// be removed from the backup.
+    pub fn removed<'s>(&'s self, src: &'s Path) -> impl Iterator<Item = &'s Path> + 's {
+        self.changed
+            .iter()
+            .filter(|path| fs::symlink_metadata(path).is_err())
+            .filter_map(move |path| path.strip_prefix(src).ok())
+    }
+}
+
+/// Files and folder times held back until the whole tree has been walked, so
+/// the files can be copied in size order.
+#[derive(Default)]
+struct Deferred {
+    /// Each file's destination, source and metadata.
+    files: Vec<(PathBuf, PathBuf, fs::Metadata)>,
+    /// Each folder's destination and source, children before parents.
+    dirs: Vec<(PathBuf, PathBuf)>,
+}
+
+/// Recursively copies `src` into the backup `sink` has begun, returning what it
+/// copied and skipped. Without a size `copy_order` each folder is finished
+/// before the next is started; with one every folder is created first, then
+/// the files are copied in that order.
+pub fn copy_dir_all(
+    src: &Path,
+    options: &CopyOptions,
+    sink: &mut dyn BackupSink,
+) -> io::Result<CopyStats> {
+    let mut ancestors = Vec::new();
+    let mut stats = CopyStats::default();
+    let mut deferred = (options.copy_order != CopyOrder::Name).then(Deferred::default);
+    options.check_free_space()?;
+    copy_tree(
+        src,
+        Path::new(""),
+        options,
+        sink,
+        &mut ancestors,
+        &mut stats,
+        &mut deferred,
+    )?;
+    let Some(mut deferred) = deferred else {
+        options.error_limits.check(&stats)?;
+        return Ok(stats);
+    };
+    match options.copy_order {
+        CopyOrder::SizeDesc => deferred
+            .files
+            .sort_by_key(|(_, _, metadata)| std::cmp::Reverse(metadata.len())),
+        _ => deferred
+            .files
+            .sort_by_key(|(_, _, metadata)| metadata.len()),
+    }
+    for (dest, path, metadata) in &deferred.files {
+        if options.is_cancelled() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "backup cancelled"));
+        }
+        match sink.write_file(dest, path, metadata) {
+            Ok(bytes) => count_file(bytes, options, &mut stats)?,
+            Err(e) => skip_failed_file(path, e, options, &mut stats)?,
+        }
+    }
+    for (relative, src) in &deferred.dirs {
+        finish_dir(relative, src, sink, &mut stats);
+    }
+    options.error_limits.check(&stats)?;
+    Ok(stats)
+}
+
+/// Copies `src` to `relative` inside the backup. `ancestors` holds the identity
+/// of every directory being copied above `src`, so a followed link or bind mount
+/// pointing back up the tree is caught.
+fn copy_tree(
+    src: &Path,
+    relative: &Path,
+    options: &CopyOptions,
+    sink: &mut dyn BackupSink,
+    ancestors: &mut Vec<FileId>,
+    stats: &mut CopyStats,
+    deferred: &mut Option<Deferred>,
+) -> io::Result<()> {
+    let id = file_id(src)?;
+    if options.backup_roots.contains(&id) {
+        warn!(event = "backup_location_skipped", path = %src.display(), "Skipping folder that is a backup location");
+        return Ok(());
+    }
+    if ancestors.contains(&id) {
+        warn!(event = "link_loop_skipped", path = %src.display(), "Skipping link that loops back to a parent folder");
+        return Ok(());
+    }
+    // The watch folder itself is depth 0
+    if ancestors.len() > options.max_depth {
+        warn!(event = "max_depth_skipped", path = %src.display(), max_depth = options.max_depth, "Skipping folder nested more than {} levels deep", options.max_depth);
+        return Ok(());
+    }
+
+    // Listed before anything is created, so a skipped folder doesn't show up
+    // in the backup as an empty one
+    let mut entries = match fs::read_dir(src)
+        .and_then(|entries| entries.collect::<io::Result<Vec<_>>>())
+    {
+        Ok(entries) => entries,
+        // An unlistable watch folder fails the backup whatever the policy
+        Err(e)
+            if !ancestors.is_empty()
+                && options.unreadable_dir_policy == UnreadableDirPolicy::Skip =>
+        {
+            warn!(event = "unreadable_dir_skipped", path = %src.display(), error = %e, "Skipping folder that can't be listed");
+            stats.unreadable_dirs.push(src.to_path_buf());
+            stats.record_error(&e);
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    // Sorted so the same entry keeps its name whenever a collision is renamed
+    entries.sort_by_key(|entry| entry.file_name());
+    sink.create_dir(relative)?;
+    stats.dirs_created += 1;
+    ancestors.push(id);
+    let mut names = CaseFoldedNames::new(options.case_collision_policy, &entries);
+
+    for entry in entries {
+        if options.is_cancelled() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "backup cancelled"));
+        }
+        let path = entry.path();
+        let is_link = is_link(&fs::symlink_metadata(&path)?);
+        if is_link && options.symlink_policy != SymlinkPolicy::Follow {
+            // A kept link is a leaf in the backup, so it is filtered like a file
+            if options.symlink_policy == SymlinkPolicy::Skip || !options.filter.allows_file(&path) {
+                stats.files_skipped += 1;
+                continue;
+            }
+            let dest =
+                leaf_dir(relative, options, sink)?.join(names.claim(&entry.file_name(), &path));
+            if options
+                .selection
+                .is_some_and(|selection| !selection.covers(&path))
+            {
+                continue;
+            }
+            match sink.write_link(&dest, &path) {
+                Ok(()) => stats.files_copied += 1,
+                Err(e) => {
+                    warn!(event = "link_recreate_failed", path = %path.display(), error = %e, "Failed to recreate link, skipping it");
+                    stats.files_skipped += 1;
+                    stats.record_error(&e);
+                }
+            }
+            continue;
+        }
+
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(e) if is_link => {
+                warn!(event = "broken_link_skipped", path = %path.display(), error = %e, "Skipping link whose target is missing");
+                stats.files_skipped += 1;
+                continue;
+            }
+            Err(e) => {
+                skip_failed_file(&path, e, options, stats)?;
+                continue;
+            }
+        };
+        let wanted = if metadata.is_dir() {
+            options.filter.allows_dir(&path)
+        } else if metadata.is_file() {
+            options.filter.allows_file(&path) && options.filter.allows_age(&metadata)
+        } else {
+            // Named pipes, sockets and device files can block or fail on read
+            warn!(event = "special_file_skipped", path = %path.display(), "Skipping special file");
+            false
+        };
+        if !wanted {
+            // A filtered-out folder is not counted, since its contents were never looked at
+            if !metadata.is_dir() {
+                stats.files_skipped += 1;
+            }
+            continue;
+        }
+
+        let dest = if metadata.is_dir() {
+            relative.to_path_buf()
+        } else {
+            leaf_dir(relative, options, sink)?
+        }
+        .join(names.claim(&entry.file_name(), &path));
+        let selected = options.selection.is_none_or(|selection| {
+            if metadata.is_dir() {
+                selection.touches(&path)
+            } else {
+                selection.covers(&path)
+            }
+        });
+        if !selected {
+            continue;
+        }
+        if metadata.is_dir() {
+            copy_tree(&path, &dest, options, sink, ancestors, stats, deferred)?;
+        } else if let Some(deferred) = deferred {
+            deferred.files.push((dest, path, metadata));
+        } else {
+            match sink.write_file(&dest, &path, &metadata) {
+                Ok(bytes) => count_file(bytes, options, stats)?,
+                Err(e) => skip_failed_file(&path, e, options, stats)?,
+            }
+        }
+    }
+
+    ancestors.pop();
+    // Last, since writing the children updated the directory's times
+    if options.preserve_dir_times {
+        match deferred {
+            Some(deferred) => deferred
+                .dirs
+                .push((relative.to_path_buf(), src.to_path_buf())),
+            None => finish_dir(relative, src, sink, stats),
+        }
+    }
+    Ok(())
+}
+
+/// Counts a file of `bytes` as copied, checking the destination's free space
+/// whenever another batch of files or bytes has been written.
+fn count_file(bytes: u64, options: &CopyOptions, stats: &mut CopyStats) -> io::Result<()> {
+    let before = stats.bytes_copied;
+    stats.bytes_copied += bytes;
+    stats.files_copied += 1;
+    if stats.files_copied.is_multiple_of(FREE_SPACE_CHECK_FILES)
+        || before / FREE_SPACE_CHECK_BYTES != stats.bytes_copied / FREE_SPACE_CHECK_BYTES
+    {
+        options.check_free_space()?;
+    }
+    Ok(())
+}
+
+/// Leaves out a file that failed with `e` when error limits are set, or fails
+/// the copy with `e` when they aren't.
+fn skip_failed_file(
+    path: &Path,
+    e: io::Error,
+    options: &CopyOptions,
+    stats: &mut CopyStats,
+) -> io::Result<()> {
+    if !options.error_limits.is_set() {
+        return Err(e);
+    }
+    warn!(event = "file_copy_failed", path = %path.display(), error = %e, "Failed to copy file, skipping it");
+    stats.files_failed += 1;
+    stats.record_error(&e);
+    Ok(())
+}
+
+/// Carries the times of the source folder `src` over to `relative` in the backup.
+fn finish_dir(relative: &Path, src: &Path, sink: &mut dyn BackupSink, stats: &mut CopyStats) {
+    if let Err(e) = fs::metadata(src).and_then(|metadata| sink.finish_dir(relative, &metadata)) {
+        warn!(event = "dir_time_failed", path = %src.display(), error = %e, "Failed to copy directory timestamps");
+        stats.record_error(&e);
+    }
+}
+
+/// The folder that files and links directly inside `relative` go into: that
+/// folder itself, except at the top of a `split_top_level` backup, where they
+/// make up the `_root` unit.
+fn leaf_dir(
+    relative: &Path,
+    options: &CopyOptions,
+    sink: &mut dyn BackupSink,
+) -> io::Result<PathBuf> {
+    if options.split_top_level && relative.as_os_str().is_empty() {
+        sink.create_dir(Path::new(ROOT_UNIT_NAME))?;
+        Ok(PathBuf::from(ROOT_UNIT_NAME))
+    } else {
+        Ok(relative.to_path_buf())
+    }
+}
+
+/// Copies the file at `source` to `dest` through a `buffer_size` buffer, returning
+/// the number of bytes copied. Like `fs::copy`, it carries over the permissions.
+pub fn copy_file(source: &Path, dest: &Path, buffer_size: usize) -> io::Result<u64> {
+    let mut reader = fs::File::open(source)?;
+    let permissions = reader.metadata()?.permissions();
+    unlink_existing(dest)?;
+    let mut writer = fs::File::create(dest)?;
+
+    let mut buffer = vec![0; buffer_size];
+    let mut bytes = 0;
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&buffer[..read])?;
+        bytes += read as u64;
+    }
+    writer.set_permissions(permissions)?;
+    Ok(bytes)
+}
+
+/// Makes `dest` a copy-on-write clone of `source`, sharing its data blocks until
+/// either is changed, so the copy is near-instant and takes no extra space.
+/// Needs both on the same Btrfs, XFS or APFS filesystem; fails otherwise, and
+/// callers fall back to `copy_file`. Permissions are carried over like
+/// `copy_file` does.
+#[cfg(target_os = "linux")]
+pub fn reflink_file(source: &Path, dest: &Path) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let reader = fs::File::open(source)?;
+    unlink_existing(dest)?;
+    let writer = fs::File::create(dest)?;
+    // SAFETY: both descriptors are open for the duration of the call
+    if unsafe { libc::ioctl(writer.as_raw_fd(), libc::FICLONE as _, reader.as_raw_fd()) } != 0 {
+        let e = io::Error::last_os_error();
+        drop(writer);
+        let _ = fs::remove_file(dest);
+        return Err(e);
+    }
+    writer.set_permissions(reader.metadata()?.permissions())
+}
+
+#[cfg(target_os = "macos")]
+pub fn reflink_file(source: &Path, dest: &Path) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let to_c = |path: &Path| {
+        CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    };
+    let (source_c, dest_c) = (to_c(source)?, to_c(dest)?);
+    unlink_existing(dest)?;
+    // SAFETY: both are valid NUL-terminated paths; clonefile also copies the mode
+    if unsafe { libc::clonefile(source_c.as_ptr(), dest_c.as_ptr(), 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Copy-on-write clones are only implemented for Linux and macOS.
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn reflink_file(_source: &Path, _dest: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "copy-on-write clones are not supported on this platform",
+    ))
+}
+
+/// Removes the file at `path`, if any, before it is rewritten. Writing through
+/// the existing name would change every backup hard-linked to it as well.
+pub(crate) fn unlink_existing(path: &Path) -> io::Result<()> {
+    clear_readonly(path)?;
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Flushes the file at `path` to disk.
+pub fn sync_file(path: &Path) -> io::Result<()> {
+    sync_handle(path)
+}
+
+#[cfg(not(windows))]
+fn sync_handle(path: &Path) -> io::Result<()> {
+    fs::File::open(path)?.sync_all()
+}
+
+/// Windows only flushes handles with write access, which a read-only file
+/// refuses; those are left to the filesystem.
+#[cfg(windows)]
+fn sync_handle(path: &Path) -> io::Result<()> {
+    match fs::OpenOptions::new().write(true).open(path) {
+        Ok(file) => file.sync_all(),
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Flushes the folder at `path`, so the names created in it survive a crash
+/// along with their contents. Folders can't be opened to flush on Windows,
+/// where NTFS journals them anyway, so this does nothing there.
+pub fn sync_dir(path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    fs::File::open(path)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/// Flushes every file and folder under `root`, and `root` itself.
+pub fn sync_tree(root: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(root)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            sync_tree(&entry.path())?;
+        } else if file_type.is_file() {
+            sync_file(&entry.path())?;
+        }
+    }
+    sync_dir(root)
+}
+
+/// Recreates the finished backup at `src` as `dest` by hard-linking every file
+/// instead of copying it, so the two share storage. Fails if `dest` is on
+/// another filesystem or that filesystem has no hard links; callers fall back
+/// to a full copy. Folders get the times of their counterparts in `src`.
+pub fn link_tree(src: &Path, dest: &Path) -> io::Result<()> {
+    fs::create_dir_all(dest)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let target = dest.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            link_tree(&entry.path(), &target)?;
+        } else {
+            // Links recreated in the backup are linked as themselves, not followed
+            fs::hard_link(entry.path(), &target)?;
+        }
+    }
+    let mtime = filetime::FileTime::from_last_modification_time(&fs::metadata(src)?);
+    filetime::set_file_mtime(dest, mtime)
+}
+
+/// Whether `metadata`, as returned by `symlink_metadata`, is a link to somewhere
+/// else. On Windows the standard library reports every name-surrogate reparse
+/// point this way, which covers directory junctions and volume mount points as
+/// well as symlinks. Other reparse points, such as cloud placeholders, are
+/// ordinary files.
+fn is_link(metadata: &fs::Metadata) -> bool {
+    metadata.file_type().is_symlink()
+}
+
+/// The names already used in one destination folder, compared case-insensitively.
+struct CaseFoldedNames {
+    policy: CaseCollisionPolicy,
+    taken: HashSet<OsString>,
+    /// Every name in the source folder, so a rename never takes a name that a
+    /// later entry needs.
+    present: HashSet<OsString>,
+}
+
+impl CaseFoldedNames {
+    fn new(policy: CaseCollisionPolicy, entries: &[fs::DirEntry]) -> Self {
+        CaseFoldedNames {
+            policy,
+            taken: HashSet::new(),
+            present: entries.iter().map(|e| fold(&e.file_name())).collect(),
+        }
+    }
+
+    /// Returns the destination name for the entry `name` found at `path`.
+    fn claim(&mut self, name: &OsStr, path: &Path) -> OsString {
+        if self.taken.insert(fold(name)) {
+            return name.to_os_string();
+        }
+        match self.policy {
+            CaseCollisionPolicy::Warn => {
+                warn!(
+                    event = "case_collision",
+                    path = %path.display(),
+                    "Name differs from another only in case and may overwrite it on a case-insensitive destination"
+                );
+                name.to_os_string()
+            }
+            CaseCollisionPolicy::Rename => {
+                let renamed = (2..)
+                    .map(|n| with_suffix(name, n))
+                    .find(|candidate| {
+                        let folded = fold(candidate);
+                        !self.present.contains(&folded) && self.taken.insert(folded)
+                    })
+                    .unwrap();
+                info!(
+                    event = "case_collision_renamed",
+                    path = %path.display(),
+                    renamed = %renamed.to_string_lossy(),
+                    "Name differs from another only in case, renamed"
+                );
+                renamed
+            }
+        }
+    }
+}
+
+/// `name` in lower case. Names that aren't valid Unicode are kept as they are,
+/// so two of them never fold to the same replacement characters.
+fn fold(name: &OsStr) -> OsString {
+    match name.to_str() {
+        Some(name) => name.to_lowercase().into(),
+        None => name.to_os_string(),
+    }
+}
+
+/// `notes.txt` with suffix 2 becomes `notes~2.txt`; names without an extension
+/// just get the suffix appended.
+fn with_suffix(name: &OsStr, n: u32) -> OsString {
+    let path = Path::new(name);
+    let mut renamed = path.file_stem().unwrap_or(name).to_os_string();
+    renamed.push(format!("~{}", n));
+    if let Some(extension) = path.extension() {
+        renamed.push(".");
+        renamed.push(extension);
+    }
+    renamed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::BackupLayout;
+    use crate::ledger::{Ledger, LEDGER_FILE_NAME};
+    use crate::sink::LocalSink;
+    use tempfile::tempdir;
+
+    fn copy_to(config: &BackupWardenConfig, dst: &Path) -> CopyStats {
+        let filter = PathFilter::new(config).unwrap();
+        let layout = BackupLayout::new(config).unwrap();
+        let mut sink = LocalSink::new(dst.to_str().unwrap(), layout);
+        sink.begin_folder(dst.to_path_buf()).unwrap();
+        let stats = copy_dir_all(
+            Path::new(&config.watch_folder),
+            &CopyOptions::new(config, &filter),
+            &mut sink,
+        )
+        .unwrap();
+        sink.finish_backup().unwrap();
+        stats
+    }
+
+    #[test]
+    fn test_split_top_level_moves_root_files_into_unit() {
+        let temp_dir = tempdir().unwrap();
+        let src = temp_dir.path().join("src");
+        let dst = temp_dir.path().join("dst");
+        fs::create_dir_all(src.join("alpha").join("src")).unwrap();
+        fs::create_dir_all(src.join("beta")).unwrap();
+        fs::write(
+            src.join("alpha").join("src").join("main.rs"),
+            b"fn main() {}",
+        )
+        .unwrap();
+        fs::write(src.join("beta").join("notes.txt"), b"notes").unwrap();
+        fs::write(src.join("todo.txt"), b"todo").unwrap();
+
+        let config = BackupWardenConfig {
+            watch_folder: src.to_str().unwrap().to_string(),
+            split_top_level: true,
+            ..Default::default()
+        };
+        let stats = copy_to(&config, &dst);
+        assert_eq!((stats.files_copied, stats.dirs_created), (3, 4));
+
+        assert!(dst.join("alpha").join("src").join("main.rs").exists());
+        assert!(dst.join("beta").join("notes.txt").exists());
+        assert_eq!(
+            fs::read(dst.join(ROOT_UNIT_NAME).join("todo.txt")).unwrap(),
+            b"todo"
+        );
+        assert!(!dst.join("todo.txt").exists());
+    }
+
+    #[test]
+    fn test_copy_order_by_size() {
+        let temp_dir = tempdir().unwrap();
+        let src = temp_dir.path().join("src");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::write(src.join("a.txt"), b"aa").unwrap();
+        fs::write(src.join("b.txt"), b"b").unwrap();
+        fs::write(src.join("sub").join("c.txt"), b"ccc").unwrap();
+
+        let mut config = BackupWardenConfig {
+            watch_folder: src.to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let filter = PathFilter::new(&config).unwrap();
+        // The ledger of an unfinished backup lists its files in the order written
+        let mut order = |copy_order, dst: &Path| {
+            config.copy_order = copy_order;
+            let mut sink =
+                LocalSink::new(dst.to_str().unwrap(), BackupLayout::new(&config).unwrap());
+            sink.begin_folder(dst.to_path_buf()).unwrap();
+            let stats = copy_dir_all(
+                Path::new(&config.watch_folder),
+                &CopyOptions::new(&config, &filter),
+                &mut sink,
+            )
+            .unwrap();
+            assert_eq!((stats.files_copied, stats.bytes_copied), (3, 6));
+            let ledger = fs::read_to_string(dst.join(LEDGER_FILE_NAME)).unwrap();
+            ledger
+                .lines()
+                .map(|line| {
+                    let entry: serde_json::Value = serde_json::from_str(line).unwrap();
+                    let source = PathBuf::from(entry["source"].as_str().unwrap());
+                    source.file_name().unwrap().to_string_lossy().into_owned()
+                })
+                .collect::<Vec<_>>()
+        };
+        let dst = temp_dir.path();
+        assert_eq!(
+            order(CopyOrder::Name, &dst.join("1")),
+            ["a.txt", "b.txt", "c.txt"]
+        );
+        assert_eq!(
+            order(CopyOrder::SizeDesc, &dst.join("2")),
+            ["c.txt", "a.txt", "b.txt"]
+        );
+        assert_eq!(
+            order(CopyOrder::SizeAsc, &dst.join("3")),
+            ["b.txt", "a.txt", "c.txt"]
+        );
+    }
+
+    #[test]
+    fn test_copy_stops_below_free_space_floor() {
+        let temp_dir = tempdir().unwrap();
+        let src = temp_dir.path().join("src");
+        let dst = temp_dir.path().join("dst");
+        fs::create_dir_all(&src).unwrap();
+        fs::write(src.join("a.txt"), b"aaa").unwrap();
+
+        let config = BackupWardenConfig {
+            watch_folder: src.to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let filter = PathFilter::new(&config).unwrap();
+        let mut sink = LocalSink::new(dst.to_str().unwrap(), BackupLayout::new(&config).unwrap());
+        sink.begin_folder(dst.clone()).unwrap();
+        let options = CopyOptions::new(&config, &filter)
+            .with_free_space_floor(vec![temp_dir.path().to_path_buf()], u64::MAX);
+        let result = copy_dir_all(Path::new(&config.watch_folder), &options, &mut sink);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::StorageFull);
+        assert!(!dst.join("a.txt").exists());
+
+        let options = CopyOptions::new(&config, &filter)
+            .with_free_space_floor(vec![temp_dir.path().to_path_buf()], 0);
+        copy_dir_all(Path::new(&config.watch_folder), &options, &mut sink).unwrap();
+        assert!(dst.join("a.txt").exists());
+    }
+
+    #[test]
+    fn test_cancelled_copy_stops() {
+        let temp_dir = tempdir().unwrap();
+        let src = temp_dir.path().join("src");
+        let dst = temp_dir.path().join("dst");
+        fs::create_dir_all(&src).unwrap();
+        fs::write(src.join("a.txt"), b"aaa").unwrap();
+
+        let config = BackupWardenConfig {
+            watch_folder: src.to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let filter = PathFilter::new(&config).unwrap();
+        let mut sink = LocalSink::new(dst.to_str().unwrap(), BackupLayout::new(&config).unwrap());
+        sink.begin_folder(dst.clone()).unwrap();
+        let options =
+            CopyOptions::new(&config, &filter).with_cancel(Arc::new(AtomicBool::new(true)));
+
+        let err = copy_dir_all(Path::new(&config.watch_folder), &options, &mut sink).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(!dst.join("a.txt").exists());
+    }
+
+    #[test]
+    fn test_copy_dir_all_stops_at_max_depth() {
+        let temp_dir = tempdir().unwrap();
+        let src = temp_dir.path().join("src");
+        let dst = temp_dir.path().join("dst");
+        fs::create_dir_all(src.join("a").join("b").join("c")).unwrap();
+        fs::write(src.join("a").join("b").join("kept.txt"), b"kept").unwrap();
+        fs::write(src.join("a").join("b").join("c").join("deep.txt"), b"deep").unwrap();
+
+        let config = BackupWardenConfig {
+            watch_folder: src.to_str().unwrap().to_string(),
+            max_depth: Some(2),
+            ..Default::default()
+        };
+        copy_to(&config, &dst);
+
+        assert!(dst.join("a").join("b").join("kept.txt").exists());
+        assert!(!dst.join("a").join("b").join("c").exists());
+    }
+
+    #[test]
+    fn test_copy_dir_all_skips_files_outside_age_limits() {
+        let temp_dir = tempdir().unwrap();
+        let src = temp_dir.path().join("src");
+        let dst = temp_dir.path().join("dst");
+        fs::create_dir_all(src.join("archive")).unwrap();
+        fs::write(src.join("recent.txt"), b"recent").unwrap();
+        fs::write(src.join("archive").join("old.txt"), b"old").unwrap();
+        let old = filetime::FileTime::from_unix_time(
+            filetime::FileTime::now().unix_seconds() - 7 * 24 * 3600,
+            0,
+        );
+        filetime::set_file_mtime(src.join("archive").join("old.txt"), old).unwrap();
+
+        let config = BackupWardenConfig {
+            watch_folder: src.to_str().unwrap().to_string(),
+            max_file_age_secs: Some(24 * 3600),
+            ..Default::default()
+        };
+        let stats = copy_to(&config, &dst);
+
+        assert!(dst.join("recent.txt").exists());
+        assert!(!dst.join("archive").join("old.txt").exists());
+        assert_eq!(stats.files_skipped, 1);
+    }
+
+    #[test]
+    fn test_copy_dir_all_preserves_directory_mtimes() {
+        let temp_dir = tempdir().unwrap();
+        let src = temp_dir.path().join("src");
+        let dst = temp_dir.path().join("dst");
+        fs::create_dir_all(src.join("docs").join("old")).unwrap();
+        fs::write(src.join("docs").join("old").join("a.txt"), b"aaa").unwrap();
+        let past = filetime::FileTime::from_unix_time(1_600_000_000, 0);
+        for dir in [src.join("docs").join("old"), src.join("docs"), src.clone()] {
+            filetime::set_file_mtime(&dir, past).unwrap();
+        }
+
+        let config = BackupWardenConfig {
+            watch_folder: src.to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        copy_to(&config, &dst);
+
+        for dir in [dst.join("docs").join("old"), dst.join("docs"), dst.clone()] {
+            let mtime =
+                filetime::FileTime::from_last_modification_time(&fs::metadata(&dir).unwrap());
+            assert!(
+                (mtime.unix_seconds() - past.unix_seconds()).abs() <= 2,
+                "{} has mtime {}",
+                dir.display(),
+                mtime
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_copy_dir_all_skips_unreadable_dir() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = tempdir().unwrap();
+        let src = temp_dir.path().join("src");
+        let dst = temp_dir.path().join("dst");
+        let locked = src.join("locked");
+        fs::create_dir_all(&locked).unwrap();
+        fs::write(locked.join("secret.txt"), b"secret").unwrap();
+        fs::write(src.join("open.txt"), b"open").unwrap();
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).unwrap();
+        if fs::read_dir(&locked).is_ok() {
+            // Running as root, which can list it anyway
+            fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+            return;
+        }
+
+        let mut config = BackupWardenConfig {
+            watch_folder: src.to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let stats = copy_to(&config, &dst);
+        assert_eq!(stats.unreadable_dirs, std::slice::from_ref(&locked));
+        assert_eq!((stats.files_copied, stats.errors), (1, 1));
+        assert!(dst.join("open.txt").exists());
+        assert!(!dst.join("locked").exists());
+
+        config.unreadable_dir_policy = UnreadableDirPolicy::Fail;
+        let filter = PathFilter::new(&config).unwrap();
+        let mut sink = LocalSink::new(dst.to_str().unwrap(), BackupLayout::new(&config).unwrap());
+        sink.begin_folder(dst.clone()).unwrap();
+        let result = copy_dir_all(
+            Path::new(&config.watch_folder),
+            &CopyOptions::new(&config, &filter),
+            &mut sink,
+        );
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn test_error_limits_decide_failure() {
+        let temp_dir = tempdir().unwrap();
+        let src = temp_dir.path().join("src");
+        let dst = temp_dir.path().join("dst");
+        fs::create_dir_all(&src).unwrap();
+        fs::write(src.join("bad.txt"), b"bad").unwrap();
+        fs::write(src.join("good.txt"), b"good").unwrap();
+        // A folder where the file should go can't be replaced by it
+        fs::create_dir_all(dst.join("bad.txt").join("inner")).unwrap();
+
+        let mut config = BackupWardenConfig {
+            watch_folder: src.to_str().unwrap().to_string(),
+            max_errors: Some(1),
+            ..Default::default()
+        };
+        let stats = copy_to(&config, &dst);
+        assert_eq!((stats.files_copied, stats.files_failed), (1, 1));
+        assert_eq!(stats.errors, 1);
+        assert_eq!(fs::read(dst.join("good.txt")).unwrap(), b"good");
+
+        config.max_errors = None;
+        config.max_error_ratio = Some(0.25);
+        let filter = PathFilter::new(&config).unwrap();
+        let mut sink = LocalSink::new(dst.to_str().unwrap(), BackupLayout::new(&config).unwrap());
+        sink.begin_folder(dst.clone()).unwrap();
+        let err = copy_dir_all(
+            Path::new(&config.watch_folder),
+            &CopyOptions::new(&config, &filter),
+            &mut sink,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("over the error limit"), "{}", err);
+
+        let mut stats = CopyStats {
+            files_copied: 7,
+            ..Default::default()
+        };
+        for _ in 0..3 {
+            stats.files_failed += 1;
+            stats.record_error(&io::Error::from(io::ErrorKind::StorageFull));
+        }
+        assert_eq!(stats.error_counts.no_space, 3);
+        let limits = |max_errors, max_error_ratio| ErrorLimits {
+            max_errors,
+            max_error_ratio,
+        };
+        assert!(limits(Some(3), None).check(&stats).is_ok());
+        assert!(limits(Some(2), None).check(&stats).is_err());
+        assert!(limits(None, Some(0.3)).check(&stats).is_ok());
+        assert!(limits(None, Some(0.2)).check(&stats).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_copy_dir_all_keeps_non_utf8_names() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let temp_dir = tempdir().unwrap();
+        let src = temp_dir.path().join("src");
+        let dst = temp_dir.path().join("dst");
+        let dir = OsStr::from_bytes(b"caf\xe9");
+        let first = OsStr::from_bytes(b"r\xe9sum\xe9.txt");
+        let second = OsStr::from_bytes(b"r\xe8sum\xe8.txt");
+        let unicode = "Cafe\u{301} \u{1F600}.txt";
+        fs::create_dir_all(src.join(dir)).unwrap();
+        fs::write(src.join(dir).join(first), b"first").unwrap();
+        fs::write(src.join(dir).join(second), b"second").unwrap();
+        fs::write(src.join(unicode), b"unicode").unwrap();
+
+        let config = BackupWardenConfig {
+            watch_folder: src.to_str().unwrap().to_string(),
+            case_collision_policy: CaseCollisionPolicy::Rename,
+            ..Default::default()
+        };
+        let stats = copy_to(&config, &dst);
+        assert_eq!(stats.files_copied, 3);
+        assert_eq!(fs::read(dst.join(dir).join(first)).unwrap(), b"first");
+        assert_eq!(fs::read(dst.join(dir).join(second)).unwrap(), b"second");
+        assert_eq!(fs::read(dst.join(unicode)).unwrap(), b"unicode");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_copy_dir_all_skips_fifo() {
+        let temp_dir = tempdir().unwrap();
+        let src = temp_dir.path().join("src");
+        let dst = temp_dir.path().join("dst");
+        fs::create_dir_all(&src).unwrap();
+
+        fs::write(src.join("file.txt"), b"contents").unwrap();
+        fs::write(src.join("empty.txt"), b"").unwrap();
+        let status = std::process::Command::new("mkfifo")
+            .arg(src.join("pipe"))
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let config = BackupWardenConfig {
+            watch_folder: src.to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let stats = copy_to(&config, &dst);
+        assert_eq!((stats.files_copied, stats.files_skipped), (2, 1));
+        assert_eq!(stats.bytes_copied, 8);
+
+        assert_eq!(fs::read(dst.join("file.txt")).unwrap(), b"contents");
+        assert!(dst.join("empty.txt").exists());
+        assert!(!dst.join("pipe").exists());
+    }
+
+    #[test]
+    fn test_copy_dir_all_resumes_from_ledger() {
+        let temp_dir = tempdir().unwrap();
+        let src = temp_dir.path().join("src");
+        let dst = temp_dir.path().join("dst");
+        fs::create_dir_all(&src).unwrap();
+        fs::write(src.join("done.txt"), b"aaaa").unwrap();
+        fs::write(src.join("todo.txt"), b"bbbb").unwrap();
+
+        // An earlier attempt confirmed done.txt and then failed
+        let mut ledger = Ledger::open(&dst).unwrap();
+        fs::write(dst.join("done.txt"), b"XXXX").unwrap();
+        ledger
+            .confirm(
+                &src.join("done.txt"),
+                &fs::metadata(src.join("done.txt")).unwrap(),
+            )
+            .unwrap();
+        drop(ledger);
+
+        let config = BackupWardenConfig {
+            watch_folder: src.to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let stats = copy_to(&config, &dst);
+
+        // done.txt is counted but not copied again
+        assert_eq!(stats.files_copied, 2);
+        assert_eq!(stats.bytes_copied, 4);
+        assert_eq!(fs::read(dst.join("done.txt")).unwrap(), b"XXXX");
+        assert_eq!(fs::read(dst.join("todo.txt")).unwrap(), b"bbbb");
+        assert!(!dst.join(LEDGER_FILE_NAME).exists());
+    }
+
+    #[test]
+    fn test_copy_file_with_small_buffer() {
+        let temp_dir = tempdir().unwrap();
+        let source = temp_dir.path().join("source.bin");
+        let dest = temp_dir.path().join("dest.bin");
+        let contents: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        fs::write(&source, &contents).unwrap();
+
+        assert_eq!(copy_file(&source, &dest, 7).unwrap(), 1000);
+        assert_eq!(fs::read(&dest).unwrap(), contents);
+    }
+
+    #[test]
+    fn test_reflink_file_clones_or_fails_cleanly() {
+        let temp_dir = tempdir().unwrap();
+        let source = temp_dir.path().join("source.bin");
+        let dest = temp_dir.path().join("dest.bin");
+        fs::write(&source, b"contents").unwrap();
+
+        // Whether it works depends on the filesystem the tests run on
+        match reflink_file(&source, &dest) {
+            Ok(()) => assert_eq!(fs::read(&dest).unwrap(), b"contents"),
+            Err(_) => assert!(!dest.exists()),
+        }
+    }
+
+    #[test]
+    fn test_with_suffix_keeps_extension() {
+        assert_eq!(with_suffix(OsStr::new("notes.txt"), 2), "notes~2.txt");
+        assert_eq!(with_suffix(OsStr::new("README"), 3), "README~3");
+        assert_eq!(with_suffix(OsStr::new(".profile"), 2), ".profile~2");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_copy_dir_all_renames_case_collisions() {
+        let temp_dir = tempdir().unwrap();
+        let src = temp_dir.path().join("src");
+        let dst = temp_dir.path().join("dst");
+        fs::create_dir_all(&src).unwrap();
+        fs::write(src.join("README"), b"upper").unwrap();
+        fs::write(src.join("readme"), b"lower").unwrap();
+        fs::write(src.join("readme~2"), b"taken").unwrap();
+
+        let config = BackupWardenConfig {
+            watch_folder: src.to_str().unwrap().to_string(),
+            case_collision_policy: CaseCollisionPolicy::Rename,
+            ..Default::default()
+        };
+        copy_to(&config, &dst);
+
+        let mut names: Vec<_> = fs::read_dir(&dst)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name != crate::manifest::MANIFEST_FILE_NAME)
+            .collect();
+        names.sort();
+        assert_eq!(names, ["README", "readme~2", "readme~3"]);
+        assert_eq!(fs::read(dst.join("README")).unwrap(), b"upper");
+        assert_eq!(fs::read(dst.join("readme~2")).unwrap(), b"taken");
+        assert_eq!(fs::read(dst.join("readme~3")).unwrap(), b"lower");
+    }
+
+    fn copy_with_policy(src: &Path, dst: &Path, symlink_policy: SymlinkPolicy) -> CopyStats {
+        let config = BackupWardenConfig {
+            watch_folder: src.to_str().unwrap().to_string(),
+            symlink_policy,
+            ..Default::default()
+        };
+        copy_to(&config, dst)
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_policies() {
+        let temp_dir = tempdir().unwrap();
+        let src = temp_dir.path().join("src");
+        fs::create_dir_all(src.join("data")).unwrap();
+        fs::write(src.join("data").join("file.txt"), b"contents").unwrap();
+        std::os::unix::fs::symlink("data", src.join("link")).unwrap();
+        std::os::unix::fs::symlink("..", src.join("data").join("up")).unwrap();
+        std::os::unix::fs::symlink("missing", src.join("broken")).unwrap();
+
+        let followed = temp_dir.path().join("follow");
+        let stats = copy_with_policy(&src, &followed, SymlinkPolicy::Follow);
+        assert_eq!((stats.files_copied, stats.files_skipped), (2, 1));
+        assert_eq!(
+            fs::read(followed.join("link").join("file.txt")).unwrap(),
+            b"contents"
+        );
+        assert!(!followed.join("data").join("up").exists());
+        assert!(!followed.join("broken").exists());
+
+        let skipped = temp_dir.path().join("skip");
+        let stats = copy_with_policy(&src, &skipped, SymlinkPolicy::Skip);
+        assert_eq!((stats.files_copied, stats.files_skipped), (1, 3));
+        assert!(skipped.join("data").join("file.txt").exists());
+        assert!(fs::symlink_metadata(skipped.join("link")).is_err());
+
+        let recreated = temp_dir.path().join("recreate");
+        let stats = copy_with_policy(&src, &recreated, SymlinkPolicy::Recreate);
+        assert_eq!((stats.files_copied, stats.files_skipped), (4, 0));
+        assert_eq!(
+            fs::read_link(recreated.join("link")).unwrap(),
+            Path::new("data")
+        );
+        assert_eq!(
+            fs::read_link(recreated.join("broken")).unwrap(),
+            Path::new("missing")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_copy_dir_all_skips_backup_location_reached_through_link() {
+        let temp_dir = tempdir().unwrap();
+        let src = temp_dir.path().join("src");
+        let location = temp_dir.path().join("backup");
+        fs::create_dir_all(&src).unwrap();
+        fs::create_dir_all(&location).unwrap();
+        fs::write(src.join("file.txt"), b"contents").unwrap();
+        std::os::unix::fs::symlink(&location, src.join("backups")).unwrap();
+
+        let config = BackupWardenConfig {
+            watch_folder: src.to_str().unwrap().to_string(),
+            backup_locations: vec![location.to_str().unwrap().to_string()],
+            ..Default::default()
+        };
+        let dst = location.join("copy");
+        copy_to(&config, &dst);
+
+        assert!(dst.join("file.txt").exists());
+        assert!(!dst.join("backups").exists());
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_junctions_follow_and_skip() {
+        let temp_dir = tempdir().unwrap();
+        let src = temp_dir.path().join("src");
+        fs::create_dir_all(src.join("data")).unwrap();
+        fs::write(src.join("data").join("file.txt"), b"contents").unwrap();
+        let status = std::process::Command::new("cmd")
+            .arg("/C")
+            .arg("mklink")
+            .arg("/J")
+            .arg(src.join("junction"))
+            .arg(src.join("data"))
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let status = std::process::Command::new("cmd")
+            .arg("/C")
+            .arg("mklink")
+            .arg("/J")
+            .arg(src.join("data").join("up"))
+            .arg(&src)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let followed = temp_dir.path().join("follow");
+        copy_with_policy(&src, &followed, SymlinkPolicy::Follow);
+        assert_eq!(
+            fs::read(followed.join("junction").join("file.txt")).unwrap(),
+            b"contents"
+        );
+        assert!(!followed.join("data").join("up").exists());
+
+        let skipped = temp_dir.path().join("skip");
+        copy_with_policy(&src, &skipped, SymlinkPolicy::Skip);
+        assert!(skipped.join("data").join("file.txt").exists());
+        assert!(!skipped.join("junction").exists());
+    }
+}
@@ -0,0 +1,154 @@
+use crate::config::{BackupWardenConfig, CONFIG_SNAPSHOT_FILE_NAME};
+use crate::error::{BackupWardenError, Result};
+use crate::manifest::{read_root, Manifest};
+use crate::range::DateWindow;
+use crate::restore::restore_points;
+use std::io;
+use std::path::Path;
+
+/// Compares two backups by their manifests, printing each one's Merkle root and
+/// every file added, removed or changed from `old` to `new`. Returns whether
+/// they hold the same files.
+pub fn run(old: &Path, new: &Path) -> Result<bool> {
+    let old_manifest = load(old)?;
+    let new_manifest = load(new)?;
+    println!(
+        "Old: {} (root {})",
+        old.display(),
+        root_of(old, &old_manifest)
+    );
+    println!(
+        "New: {} (root {})",
+        new.display(),
+        root_of(new, &new_manifest)
+    );
+
+    let diff = old_manifest.diff(&new_manifest);
+    if diff.is_empty() {
+        println!("Identical");
+        return Ok(true);
+    }
+    for path in &diff.added {
+        println!("+ {}", path);
+    }
+    for path in &diff.removed {
+        println!("- {}", path);
+    }
+    for path in &diff.changed {
+        println!("~ {}", path);
+    }
+    println!(
+        "{} added, {} removed, {} changed",
+        diff.added.len(),
+        diff.removed.len(),
+        diff.changed.len()
+    );
+    Ok(false)
+}
+
+/// Compares the oldest and newest daily backups from a day in `window`, taken
+/// from the first location holding at least two, as [`run`] does.
+pub fn run_window(config: &BackupWardenConfig, window: &DateWindow) -> Result<bool> {
+    let points = restore_points(config, window)?;
+    for location in &config.backup_locations {
+        let daily: Vec<_> = points
+            .iter()
+            .filter(|point| point.snapshot.is_none() && &point.location == location)
+            .collect();
+        if let [newest, .., oldest] = daily.as_slice() {
+            return run(&oldest.path, &newest.path);
+        }
+    }
+    Err(BackupWardenError::NoBackups)
+}
+
+/// Compares a backup with a live folder, such as the one it was restored into,
+/// printing the files only in the backup, only in the live folder, and in both
+/// but different. The backup's manifest is used when it has one; the live
+/// folder is always re-hashed. Returns whether they hold the same files.
+pub fn compare_to_source(backup: &Path, live: &Path) -> Result<bool> {
+    let io_error = |path: &Path| {
+        let path = path.to_path_buf();
+        move |source| BackupWardenError::Io { path, source }
+    };
+    let backup_manifest = match Manifest::load(backup).map_err(io_error(backup))? {
+        Some(manifest) => manifest,
+        None => Manifest::build(backup).map_err(io_error(backup))?,
+    };
+    let live_manifest = Manifest::build(live).map_err(io_error(live))?;
+
+    let mut diff = backup_manifest.diff(&live_manifest);
+    // The config snapshot is written into every backup and never restored as data
+    diff.removed
+        .retain(|path| path != CONFIG_SNAPSHOT_FILE_NAME);
+    diff.added.retain(|path| path != CONFIG_SNAPSHOT_FILE_NAME);
+    diff.changed
+        .retain(|path| path != CONFIG_SNAPSHOT_FILE_NAME);
+    println!("Backup: {}", backup.display());
+    println!("Live: {}", live.display());
+    if diff.is_empty() {
+        println!("Match");
+        return Ok(true);
+    }
+    for (heading, paths) in [
+        ("Only in backup", &diff.removed),
+        ("Only in live", &diff.added),
+        ("Differing", &diff.changed),
+    ] {
+        if paths.is_empty() {
+            continue;
+        }
+        println!("{} ({}):", heading, paths.len());
+        for path in paths {
+            println!("  {}", path);
+        }
+    }
+    println!("No match");
+    Ok(false)
+}
+
+fn load(backup: &Path) -> Result<Manifest> {
+    let io_error = |source| BackupWardenError::Io {
+        path: backup.to_path_buf(),
+        source,
+    };
+    Manifest::load(backup).map_err(io_error)?.ok_or_else(|| {
+        io_error(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no manifest; the backup was made before manifests were written",
+        ))
+    })
+}
+
+/// The stored root, or the one computed from the manifest when the backup was
+/// made without `merkle_root`.
+fn root_of(backup: &Path, manifest: &Manifest) -> String {
+    match read_root(backup) {
+        Ok(Some(root)) => root,
+        _ => manifest.merkle_root(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_compare_to_source_detects_drift() {
+        let temp_dir = tempdir().unwrap();
+        let backup = temp_dir.path().join("backup");
+        let live = temp_dir.path().join("live");
+        for root in [&backup, &live] {
+            fs::create_dir_all(root.join("docs")).unwrap();
+            fs::write(root.join("docs/a.txt"), b"aaa").unwrap();
+        }
+        fs::write(backup.join(CONFIG_SNAPSHOT_FILE_NAME), b"{}").unwrap();
+        Manifest::write(&backup).unwrap();
+        assert!(compare_to_source(&backup, &live).unwrap());
+
+        fs::write(live.join("docs/a.txt"), b"changed").unwrap();
+        assert!(!compare_to_source(&backup, &live).unwrap());
+    }
+}
@@ -0,0 +1,140 @@
+use std::io;
+use std::path::Path;
+
+/// Returns the number of bytes available to this process on the filesystem holding `path`.
+#[cfg(unix)]
+pub fn free_space(path: &Path) -> io::Result<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Returns the number of bytes available to this process on the volume holding `path`.
+#[cfg(windows)]
+pub fn free_space(path: &Path) -> io::Result<u64> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Storage::FileSystem::GetDiskFreeSpaceExW;
+
+    let wide: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut available = 0u64;
+    let ok = unsafe {
+        GetDiskFreeSpaceExW(
+            wide.as_ptr(),
+            &mut available,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    };
+    if ok == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(available)
+}
+
+/// Filesystem types `statfs` reports for network filesystems: NFS, SMB, CIFS,
+/// SMB2, Coda, AFS, kAFS, 9P and Ceph.
+#[cfg(target_os = "linux")]
+const NETWORK_FS_MAGICS: [u32; 9] = [
+    0x6969,
+    0x517B,
+    0xFF53_4D42,
+    0xFE53_4D42,
+    0x7375_7245,
+    0x5346_414F,
+    0x6B41_4653,
+    0x0102_1997,
+    0x00C3_6400,
+];
+
+/// Whether `path` is on a network filesystem, as the kernel's mount info
+/// tells by the filesystem's type.
+#[cfg(target_os = "linux")]
+pub fn is_network_filesystem(path: &Path) -> io::Result<bool> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // The type's width differs between targets; the magics all fit in 32 bits
+    #[allow(clippy::unnecessary_cast)]
+    Ok(NETWORK_FS_MAGICS.contains(&(stat.f_type as u32)))
+}
+
+/// Whether `path` is on a network filesystem, as the kernel's mount info
+/// tells by the filesystem's name.
+#[cfg(target_os = "macos")]
+pub fn is_network_filesystem(path: &Path) -> io::Result<bool> {
+    use std::ffi::{CStr, CString};
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: statfs NUL-terminates the name within the array
+    let name = unsafe { CStr::from_ptr(stat.f_fstypename.as_ptr()) };
+    Ok(matches!(
+        name.to_bytes(),
+        b"nfs" | b"smbfs" | b"afpfs" | b"webdav" | b"cifs"
+    ))
+}
+
+/// Whether `path` is on a network drive or share.
+#[cfg(windows)]
+pub fn is_network_filesystem(path: &Path) -> io::Result<bool> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Storage::FileSystem::{GetDriveTypeW, GetVolumePathNameW};
+
+    const DRIVE_REMOTE: u32 = 4;
+    let wide: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut root = vec![0u16; 1024];
+    if unsafe { GetVolumePathNameW(wide.as_ptr(), root.as_mut_ptr(), root.len() as u32) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { GetDriveTypeW(root.as_ptr()) } == DRIVE_REMOTE)
+}
+
+/// Network filesystems are only recognized on Linux, macOS and Windows.
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+pub fn is_network_filesystem(path: &Path) -> io::Result<bool> {
+    path.metadata().map(|_| false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_free_space_reports_for_existing_path() {
+        let temp_dir = tempdir().unwrap();
+        assert!(free_space(temp_dir.path()).unwrap() > 0);
+    }
+
+    #[test]
+    fn test_temp_dir_is_not_on_network() {
+        let temp_dir = tempdir().unwrap();
+        assert!(!is_network_filesystem(temp_dir.path()).unwrap());
+        assert!(is_network_filesystem(&temp_dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn test_free_space_errors_for_missing_path() {
+        let temp_dir = tempdir().unwrap();
+        assert!(free_space(&temp_dir.path().join("missing")).is_err());
+    }
+}
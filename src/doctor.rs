@@ -0,0 +1,242 @@
+use crate::config::{BackupWardenConfig, WardenConfig};
+use crate::disk::free_space;
+use crate::layout::BackupLayout;
+use crate::size::{directory_size, format_bytes};
+use chrono::{Datelike, Local};
+use std::fs;
+use std::path::Path;
+
+/// Collects pass/fail lines for the doctor report.
+struct Report {
+    failures: usize,
+    /// Whether passing checks are printed too, not only failures.
+    verbose: bool,
+}
+
+impl Report {
+    fn check(&mut self, name: &str, result: Result<String, String>) {
+        match result {
+            Ok(detail) if self.verbose => println!("[PASS] {}: {}", name, detail),
+            Ok(_) => (),
+            Err(detail) => {
+                self.failures += 1;
+                println!("[FAIL] {}: {}", name, detail);
+            }
+        }
+    }
+}
+
+/// Runs the self-test against the raw embedded config, printing a pass/fail report.
+/// Returns `true` when every check passed.
+pub fn run(raw_config: &str) -> bool {
+    let mut report = Report {
+        failures: 0,
+        verbose: true,
+    };
+    println!("Backup Warden doctor");
+    diagnose(&mut report, raw_config);
+
+    if report.failures == 0 {
+        println!("All checks passed.");
+    } else {
+        println!("{} check(s) failed.", report.failures);
+    }
+    report.failures == 0
+}
+
+/// Runs the same checks as `run` for `--validate-only`, printing only the
+/// ones that fail. Returns the process exit code: 2 if the config doesn't
+/// parse or validate, 1 if a check of the folders or clock failed, else 0.
+pub fn validate_only(raw_config: &str) -> i32 {
+    let mut report = Report {
+        failures: 0,
+        verbose: false,
+    };
+    if !diagnose(&mut report, raw_config) {
+        2
+    } else if report.failures > 0 {
+        1
+    } else {
+        0
+    }
+}
+
+/// Checks the config and then every job's folders and the clock, recording
+/// each result in `report`. Returns whether the config parsed and validated.
+fn diagnose(report: &mut Report, raw_config: &str) -> bool {
+    let config = match WardenConfig::parse(raw_config) {
+        Ok(config) => {
+            report.check("Config parses", Ok("ok".to_string()));
+            config
+        }
+        Err(e) => {
+            report.check("Config parses", Err(e.to_string()));
+            return false;
+        }
+    };
+    let validated = config.validate();
+    let valid = validated.is_ok();
+    report.check(
+        "Config validates",
+        validated
+            .map(|_| "ok".to_string())
+            .map_err(|e| e.to_string()),
+    );
+
+    for job in &config.jobs {
+        if config.jobs.len() > 1 && report.verbose {
+            println!("Job: {}", job.name);
+        }
+        let watch_size = check_watch_folder(report, &job.config);
+        for location in &job.config.backup_locations {
+            check_backup_location(report, location, watch_size);
+        }
+        check_clock(report, &job.config);
+    }
+    valid
+}
+
+fn check_watch_folder(report: &mut Report, config: &BackupWardenConfig) -> Option<u64> {
+    let watch_folder = Path::new(&config.watch_folder);
+    let result = fs::read_dir(watch_folder)
+        .map_err(|e| format!("{} ({})", config.watch_folder, e))
+        .and_then(|_| {
+            directory_size(watch_folder).map_err(|e| format!("{} ({})", config.watch_folder, e))
+        });
+
+    match result {
+        Ok(size) => {
+            report.check(
+                "watch_folder readable",
+                Ok(format!("{} ({})", config.watch_folder, format_bytes(size))),
+            );
+            Some(size)
+        }
+        Err(e) => {
+            report.check("watch_folder readable", Err(e));
+            None
+        }
+    }
+}
+
+fn check_backup_location(report: &mut Report, location: &str, watch_size: Option<u64>) {
+    let path = Path::new(location);
+    if !path.is_dir() {
+        report.check(
+            "backup_location writable",
+            Err(format!("{} does not exist", location)),
+        );
+        return;
+    }
+
+    // Dropping the temp file deletes it again, so the probe leaves nothing behind
+    report.check(
+        "backup_location writable",
+        tempfile::NamedTempFile::new_in(path)
+            .map(|_| location.to_string())
+            .map_err(|e| format!("{} ({})", location, e)),
+    );
+
+    let space = free_space(path).map_err(|e| format!("{} ({})", location, e));
+    let result = match (space, watch_size) {
+        (Ok(free), Some(needed)) if free < needed => Err(format!(
+            "{} has {} free but watch_folder needs {}",
+            location,
+            format_bytes(free),
+            format_bytes(needed)
+        )),
+        (Ok(free), _) => Ok(format!("{} has {} free", location, format_bytes(free))),
+        (Err(e), _) => Err(e),
+    };
+    report.check("backup_location free space", result);
+}
+
+fn check_clock(report: &mut Report, config: &BackupWardenConfig) {
+    let now = Local::now();
+    let today = now.date_naive();
+
+    // An unset RTC typically reports a date around 1970 or the firmware's build year
+    let clock = if today.year() < 2020 {
+        Err(format!("system clock reads {}, which looks unset", now))
+    } else {
+        Ok(format!(
+            "{} (UTC{})",
+            now.format("%Y-%m-%d %H:%M"),
+            now.format("%:z")
+        ))
+    };
+    report.check("Clock", clock);
+
+    let Ok(layout) = BackupLayout::new(config) else {
+        return;
+    };
+    for location in &config.backup_locations {
+        if let Some(newest) = layout.newest_day(Path::new(location)) {
+            let result = if newest > today {
+                Err(format!(
+                    "{} has a backup dated {}, which is in the future",
+                    location, newest
+                ))
+            } else {
+                Ok(format!("{} newest backup {}", location, newest))
+            };
+            report.check("Backup dates", result);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn raw_config(watch_folder: &Path, backup_location: &Path) -> String {
+        serde_json::json!({
+            "watch_folder": watch_folder,
+            "backup_locations": [backup_location],
+            "retention_days": 30,
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn test_doctor_passes_for_valid_setup() {
+        let temp_dir = tempdir().unwrap();
+        let watch_folder = temp_dir.path().join("watch");
+        let backup_location = temp_dir.path().join("backup");
+        fs::create_dir_all(&watch_folder).unwrap();
+        fs::create_dir_all(&backup_location).unwrap();
+
+        assert!(run(&raw_config(&watch_folder, &backup_location)));
+        // The write probe must not leave anything behind
+        assert_eq!(fs::read_dir(&backup_location).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_doctor_fails_for_missing_folders() {
+        let temp_dir = tempdir().unwrap();
+        assert!(!run(&raw_config(
+            &temp_dir.path().join("watch"),
+            &temp_dir.path().join("backup"),
+        )));
+    }
+
+    #[test]
+    fn test_doctor_fails_for_unparsable_config() {
+        assert!(!run("{ not json"));
+    }
+
+    #[test]
+    fn test_validate_only_exit_codes() {
+        let temp_dir = tempdir().unwrap();
+        let watch_folder = temp_dir.path().join("watch");
+        let backup_location = temp_dir.path().join("backup");
+        let raw = raw_config(&watch_folder, &backup_location);
+        assert_eq!(validate_only(&raw), 1);
+        fs::create_dir_all(&watch_folder).unwrap();
+        fs::create_dir_all(&backup_location).unwrap();
+        assert_eq!(validate_only(&raw), 0);
+        assert_eq!(validate_only(&raw_config(&watch_folder, &watch_folder)), 2);
+        assert_eq!(validate_only("{ not json"), 2);
+    }
+}
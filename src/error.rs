@@ -0,0 +1,65 @@
+use std::io;
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// Everything that can stop the warden or one of its commands.
+#[derive(Debug, Error)]
+pub enum BackupWardenError {
+    /// The config couldn't be parsed or failed validation.
+    #[error("{0}")]
+    Config(String),
+    /// A command was given arguments it doesn't understand.
+    #[error("{0}")]
+    Usage(String),
+    /// Reading or writing `path` failed outside a backup or cleanup.
+    #[error("{}: {source}", path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    /// Copying the watch folder into `location` failed.
+    #[error("backup into {location} failed: {source}")]
+    Copy {
+        location: String,
+        #[source]
+        source: io::Error,
+    },
+    /// Removing old backups from `location` failed.
+    #[error("cleanup of {location} failed: {source}")]
+    Cleanup {
+        location: String,
+        #[source]
+        source: io::Error,
+    },
+    /// There were no backups to work on.
+    #[error("no backups found in the requested range")]
+    NoBackups,
+    /// The backup locations are locked by another run.
+    #[error("{0}")]
+    Locked(String),
+    /// The watcher for `path` couldn't be started.
+    #[error("failed to watch {}: {source}", path.display())]
+    Watch {
+        path: PathBuf,
+        #[source]
+        source: notify::Error,
+    },
+    /// The thread a backup with a timeout runs on couldn't be started.
+    #[error("failed to start the backup thread: {0}")]
+    Spawn(#[source] io::Error),
+}
+
+impl BackupWardenError {
+    /// The process exit code for this error: 2 for a bad config or command
+    /// line, which retrying won't fix, and 1 for everything else.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            BackupWardenError::Config(_) | BackupWardenError::Usage(_) => 2,
+            _ => 1,
+        }
+    }
+}
+
+/// Shorthand for results whose error is a [`BackupWardenError`].
+pub type Result<T, E = BackupWardenError> = std::result::Result<T, E>;
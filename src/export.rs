@@ -0,0 +1,242 @@
+use crate::archive;
+use crate::chunks::ChunkStore;
+use crate::config::BackupWardenConfig;
+use crate::copy::{copy_dir_all, CopyOptions};
+use crate::error::{BackupWardenError, Result};
+use crate::filter::PathFilter;
+use crate::layout::BackupLayout;
+use crate::lock::RunLock;
+use crate::range::{DateRange, DateWindow};
+use crate::sink::{BackupSink, LocalSink};
+use chrono::{NaiveDate, NaiveTime};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// What `export` should produce, parsed from its command-line arguments.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ExportOptions {
+    pub dest: PathBuf,
+    pub job: Option<String>,
+    /// The days to export; `--from` and `--to` are older names for
+    /// `--since` and `--until`.
+    pub range: DateRange,
+    pub archive: bool,
+}
+
+impl ExportOptions {
+    /// Parses `<dest> [--job NAME] [--since DATE] [--until DATE] [--archive]`.
+    pub fn parse(args: &[String]) -> Result<Self> {
+        Self::parse_args(args).map_err(BackupWardenError::Usage)
+    }
+
+    fn parse_args(args: &[String]) -> std::result::Result<Self, String> {
+        let mut dest = None;
+        let mut job = None;
+        let mut range = DateRange::default();
+        let mut archive = false;
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let flag = match arg.as_str() {
+                "--from" => "--since",
+                "--to" => "--until",
+                flag => flag,
+            };
+            if range.take(flag, &mut args)? {
+                continue;
+            }
+            match arg.as_str() {
+                "--job" => job = Some(args.next().ok_or("--job needs a name")?.clone()),
+                "--archive" => archive = true,
+                flag if flag.starts_with("--") => return Err(format!("unknown option {}", flag)),
+                path if dest.is_none() => dest = Some(PathBuf::from(path)),
+                extra => return Err(format!("unexpected argument {}", extra)),
+            }
+        }
+
+        Ok(ExportOptions {
+            dest: dest.ok_or("missing destination")?,
+            job,
+            range,
+            archive,
+        })
+    }
+}
+
+/// Copies the newest backup of each day in range into `dest/<date>`, or into a
+/// single `.tar.gz` at `dest` when `archive` is set. Returns how many days were
+/// exported.
+///
+/// When several locations hold a backup for the same day, the newest one wins,
+/// with ties going to the location listed first.
+pub fn run(config: &BackupWardenConfig, options: &ExportOptions) -> Result<usize> {
+    let layout = BackupLayout::new(config).map_err(BackupWardenError::Config)?;
+    let window = options.range.resolve(&layout)?;
+    // Keeps a running backup from changing the folders while they are copied
+    let _lock = RunLock::acquire(&config.backup_locations).map_err(BackupWardenError::Locked)?;
+
+    let days = newest_per_day(config, &layout, &window);
+    if days.is_empty() {
+        return Err(BackupWardenError::NoBackups);
+    }
+
+    if options.archive {
+        let staging_parent = match options.dest.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let io_error = |source| BackupWardenError::Io {
+            path: staging_parent.to_path_buf(),
+            source,
+        };
+        fs::create_dir_all(staging_parent).map_err(io_error)?;
+        let staging = tempfile::tempdir_in(staging_parent).map_err(io_error)?;
+        copy_days(config, &layout, &days, staging.path())?;
+        archive::write_tar_gz(staging.path(), &options.dest, config.reproducible_archives)
+            .map_err(|source| BackupWardenError::Io {
+                path: options.dest.clone(),
+                source,
+            })?;
+    } else {
+        copy_days(config, &layout, &days, &options.dest)?;
+    }
+    Ok(days.len())
+}
+
+fn newest_per_day(
+    config: &BackupWardenConfig,
+    layout: &BackupLayout,
+    window: &DateWindow,
+) -> BTreeMap<NaiveDate, (NaiveTime, PathBuf)> {
+    let mut days: BTreeMap<NaiveDate, (NaiveTime, PathBuf)> = BTreeMap::new();
+    for location in &config.backup_locations {
+        let Ok(entries) = fs::read_dir(layout.daily_root(Path::new(location))) else {
+            continue;
+        };
+        for entry in entries.filter_map(Result::ok) {
+            let Some(date) = layout.parse_day(&entry.file_name().to_string_lossy()) else {
+                continue;
+            };
+            if !window.includes(date) {
+                continue;
+            }
+            let Some(newest) = layout.backups_in_day(&entry.path()).pop() else {
+                continue;
+            };
+            match days.get(&date) {
+                Some((time, _)) if *time >= newest.0 => (),
+                _ => {
+                    days.insert(date, newest);
+                }
+            }
+        }
+    }
+    days
+}
+
+fn copy_days(
+    config: &BackupWardenConfig,
+    layout: &BackupLayout,
+    days: &BTreeMap<NaiveDate, (NaiveTime, PathBuf)>,
+    dest: &Path,
+) -> Result<()> {
+    let filter = PathFilter::allow_all();
+    let options = CopyOptions::new(config, &filter);
+    let signing_key = config.signing_key()?;
+    for (date, (_, backup)) in days {
+        let target = dest.join(date.format("%Y-%m-%d").to_string());
+        let failed = |source| BackupWardenError::Io {
+            path: backup.clone(),
+            source,
+        };
+        let mut sink = LocalSink::new(&dest.to_string_lossy(), layout.clone())
+            .with_copy_buffer_size(config.copy_buffer_size())
+            .with_merkle_root(config.merkle_root)
+            .with_manifest_format(config.manifest_format)
+            .with_reflink(config.allow_reflink())
+            .with_signing_key(signing_key.clone())
+            .with_reassembly(ChunkStore::find(backup));
+        sink.begin_folder(target).map_err(failed)?;
+        copy_dir_all(backup, &options, &mut sink).map_err(failed)?;
+        sink.finish_backup().map_err(failed)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn args(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let options =
+            ExportOptions::parse(&args(&["out", "--from", "2024-01-02", "--archive"])).unwrap();
+        assert_eq!(options.dest, Path::new("out"));
+        assert_eq!(options.job, None);
+        assert_eq!(options.range.since.as_deref(), Some("2024-01-02"));
+        assert_eq!(options.range.until, None);
+        assert!(options.archive);
+
+        assert_eq!(
+            ExportOptions::parse(&args(&["out", "--job", "docs"]))
+                .unwrap()
+                .job
+                .as_deref(),
+            Some("docs")
+        );
+        assert!(ExportOptions::parse(&args(&[])).is_err());
+        assert!(ExportOptions::parse(&args(&["out", "--since"])).is_err());
+        assert!(ExportOptions::parse(&args(&["out", "other"])).is_err());
+    }
+
+    #[test]
+    fn test_export_copies_newest_backup_per_day_in_range() {
+        let temp_dir = tempdir().unwrap();
+        let location = temp_dir.path().join("backup");
+        let daily_root = location.join("Past 30 Days");
+        for (day, hour, contents) in [
+            ("2024-01-01", "@09 PM", "jan 1"),
+            ("2024-01-02", "@09 AM", "jan 2 morning"),
+            ("2024-01-02", "@01 PM", "jan 2 evening"),
+            ("2024-01-03", "@10 AM", "jan 3"),
+        ] {
+            let backup = daily_root.join(day).join(hour);
+            fs::create_dir_all(&backup).unwrap();
+            fs::write(backup.join("notes.txt"), contents).unwrap();
+        }
+
+        let config = BackupWardenConfig {
+            watch_folder: "dummy".to_string(),
+            backup_locations: vec![location.to_str().unwrap().to_string()],
+            retention_days: 30,
+            ..Default::default()
+        };
+        let dest = temp_dir.path().join("export");
+        let options = ExportOptions {
+            dest: dest.clone(),
+            job: None,
+            range: DateRange {
+                since: Some("2024-01-02".to_string()),
+                until: None,
+            },
+            archive: false,
+        };
+
+        assert_eq!(run(&config, &options).unwrap(), 2);
+        assert!(!dest.join("2024-01-01").exists());
+        assert_eq!(
+            fs::read_to_string(dest.join("2024-01-02").join("notes.txt")).unwrap(),
+            "jan 2 evening"
+        );
+        assert_eq!(
+            fs::read_to_string(dest.join("2024-01-03").join("notes.txt")).unwrap(),
+            "jan 3"
+        );
+    }
+}
@@ -0,0 +1,177 @@
+use crate::config::BackupWardenConfig;
+use glob::{MatchOptions, Pattern};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// Decides which paths under the watch folder get copied.
+///
+/// Patterns are matched against the path relative to the watch folder, using `/`
+/// as the separator on every platform. Excludes always win: a path matching any
+/// exclude pattern is skipped, and an excluded directory is not descended into.
+/// When include patterns are given, only files matching at least one of them are
+/// copied; directories are always descended into so nested matches are found.
+/// Files can also be left out by how long ago they were last modified.
+pub struct PathFilter {
+    root: PathBuf,
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+    min_file_age: Option<Duration>,
+    max_file_age: Option<Duration>,
+}
+
+impl PathFilter {
+    pub fn new(config: &BackupWardenConfig) -> Result<Self, String> {
+        Ok(PathFilter {
+            root: PathBuf::from(&config.watch_folder),
+            include: compile(&config.include_patterns)?,
+            exclude: compile(&config.exclude_patterns)?,
+            min_file_age: config.min_file_age_secs.map(Duration::from_secs),
+            max_file_age: config.max_file_age_secs.map(Duration::from_secs),
+        })
+    }
+
+    /// A filter that lets everything through, for copying trees that were
+    /// already filtered when they were backed up.
+    pub fn allow_all() -> Self {
+        PathFilter {
+            root: PathBuf::new(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            min_file_age: None,
+            max_file_age: None,
+        }
+    }
+
+    /// Whether the directory at `path` should be descended into.
+    pub fn allows_dir(&self, path: &Path) -> bool {
+        let relative = self.relative(path);
+        !matches_any(&self.exclude, &relative)
+    }
+
+    /// Whether the file at `path` should be copied.
+    pub fn allows_file(&self, path: &Path) -> bool {
+        let relative = self.relative(path);
+        if matches_any(&self.exclude, &relative) {
+            return false;
+        }
+        self.include.is_empty() || matches_any(&self.include, &relative)
+    }
+
+    /// Whether a file with `metadata` is old enough for `min_file_age_secs`
+    /// and recent enough for `max_file_age_secs`. Files whose modification
+    /// time can't be read are let through.
+    pub fn allows_age(&self, metadata: &fs::Metadata) -> bool {
+        match metadata.modified() {
+            Ok(modified) => self.allows_modified(modified, SystemTime::now()),
+            Err(_) => true,
+        }
+    }
+
+    fn allows_modified(&self, modified: SystemTime, now: SystemTime) -> bool {
+        if self.min_file_age.is_none() && self.max_file_age.is_none() {
+            return true;
+        }
+        // A modification time in the future counts as just now
+        let age = now.duration_since(modified).unwrap_or_default();
+        self.min_file_age.is_none_or(|min| age >= min)
+            && self.max_file_age.is_none_or(|max| age <= max)
+    }
+
+    fn relative(&self, path: &Path) -> String {
+        let relative = path.strip_prefix(&self.root).unwrap_or(path);
+        relative
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+}
+
+fn compile(patterns: &[String]) -> Result<Vec<Pattern>, String> {
+    patterns
+        .iter()
+        .map(|p| Pattern::new(p).map_err(|e| format!("invalid pattern {:?}: {}", p, e)))
+        .collect()
+}
+
+fn matches_any(patterns: &[Pattern], relative: &str) -> bool {
+    patterns
+        .iter()
+        .any(|p| p.matches_with(relative, MATCH_OPTIONS))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(include: &[&str], exclude: &[&str]) -> PathFilter {
+        let config = BackupWardenConfig {
+            watch_folder: "/watch".to_string(),
+            include_patterns: include.iter().map(|p| p.to_string()).collect(),
+            exclude_patterns: exclude.iter().map(|p| p.to_string()).collect(),
+            ..Default::default()
+        };
+        PathFilter::new(&config).unwrap()
+    }
+
+    #[test]
+    fn test_include_only() {
+        let filter = filter(&["**/*.docx"], &[]);
+        assert!(filter.allows_file(Path::new("/watch/report.docx")));
+        assert!(filter.allows_file(Path::new("/watch/a/b/report.docx")));
+        assert!(!filter.allows_file(Path::new("/watch/a/notes.txt")));
+        assert!(filter.allows_dir(Path::new("/watch/a")));
+    }
+
+    #[test]
+    fn test_exclude_only() {
+        let filter = filter(&[], &["**/*.tmp", "build"]);
+        assert!(filter.allows_file(Path::new("/watch/a/notes.txt")));
+        assert!(!filter.allows_file(Path::new("/watch/a/scratch.tmp")));
+        assert!(!filter.allows_dir(Path::new("/watch/build")));
+        assert!(filter.allows_dir(Path::new("/watch/src/build")));
+    }
+
+    #[test]
+    fn test_exclude_wins_over_include() {
+        let filter = filter(&["**/*.docx"], &["drafts/**"]);
+        assert!(filter.allows_file(Path::new("/watch/final/report.docx")));
+        assert!(!filter.allows_file(Path::new("/watch/drafts/report.docx")));
+        assert!(!filter.allows_file(Path::new("/watch/final/notes.txt")));
+    }
+
+    #[test]
+    fn test_file_age_limits() {
+        let config = BackupWardenConfig {
+            watch_folder: "/watch".to_string(),
+            min_file_age_secs: Some(60),
+            max_file_age_secs: Some(3600),
+            ..Default::default()
+        };
+        let filter = PathFilter::new(&config).unwrap();
+        let now = SystemTime::now();
+        let ago = |secs| now - Duration::from_secs(secs);
+        assert!(!filter.allows_modified(ago(10), now));
+        assert!(filter.allows_modified(ago(60), now));
+        assert!(filter.allows_modified(ago(3600), now));
+        assert!(!filter.allows_modified(ago(3601), now));
+        assert!(!filter.allows_modified(now + Duration::from_secs(60), now));
+        assert!(PathFilter::allow_all().allows_modified(ago(1_000_000), now));
+    }
+
+    #[test]
+    fn test_invalid_pattern_is_rejected() {
+        let config = BackupWardenConfig {
+            exclude_patterns: vec!["[".to_string()],
+            ..Default::default()
+        };
+        assert!(PathFilter::new(&config).is_err());
+    }
+}
@@ -0,0 +1,232 @@
+use crate::attributes::clear_readonly;
+use crate::config::BackupWardenConfig;
+use crate::error::{BackupWardenError, Result};
+use crate::layout::BackupLayout;
+use crate::lock::RunLock;
+use crate::manifest::{Manifest, ManifestEntry};
+use crate::range::DateWindow;
+use crate::verify::backup_roots;
+use filetime::{set_file_mtime, FileTime};
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use tracing::{error, info, warn};
+
+/// What `heal` should do, parsed from its command-line arguments.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct HealOptions {
+    pub job: Option<String>,
+    /// Replace the damaged files; without it they are only listed.
+    pub apply: bool,
+}
+
+impl HealOptions {
+    /// Parses `[--job NAME] [--apply]`.
+    pub fn parse(args: &[String]) -> Result<Self> {
+        let mut options = HealOptions::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--job" => {
+                    options.job = Some(
+                        args.next()
+                            .ok_or_else(|| {
+                                BackupWardenError::Usage("--job needs a name".to_string())
+                            })?
+                            .clone(),
+                    )
+                }
+                "--apply" => options.apply = true,
+                other => {
+                    return Err(BackupWardenError::Usage(format!(
+                        "unexpected argument {}\nUsage: backup-warden heal [--job NAME] [--apply]",
+                        other
+                    )))
+                }
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// What one `heal` run found.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct HealReport {
+    /// Files missing or changed since their backup's manifest was written.
+    pub damaged: usize,
+    /// Damaged files replaced from another location, or that would be
+    /// without `apply`.
+    pub healed: usize,
+    /// Damaged files no other location has an intact copy of.
+    pub unhealable: usize,
+}
+
+/// Checks every backup in every location against its manifest, like `verify`,
+/// and replaces each missing or changed file with the copy of the same backup
+/// in another location, at the same path below it, whose contents still match
+/// the manifest. Backups without a manifest are left alone.
+pub fn run(config: &BackupWardenConfig, options: &HealOptions) -> Result<HealReport> {
+    let layout = BackupLayout::new(config).map_err(BackupWardenError::Config)?;
+    // Keeps a running backup from rewriting files while they are replaced
+    let _lock = options
+        .apply
+        .then(|| RunLock::acquire(&config.backup_locations))
+        .transpose()
+        .map_err(BackupWardenError::Locked)?;
+
+    let mut report = HealReport::default();
+    for location in &config.backup_locations {
+        let location = Path::new(location);
+        for root in backup_roots(&layout, location, &DateWindow::default()) {
+            let manifest = match Manifest::load(&root) {
+                Ok(Some(manifest)) => manifest,
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!(event = "manifest_unreadable", path = %root.display(), error = %e, "Failed to read manifest, not healing {}", root.display());
+                    continue;
+                }
+            };
+            let relative = root.strip_prefix(location).unwrap_or(&root);
+            for entry in &manifest.files {
+                let Some(problem) = entry.check(&root) else {
+                    continue;
+                };
+                report.damaged += 1;
+                let donor = config
+                    .backup_locations
+                    .iter()
+                    .map(Path::new)
+                    .filter(|other| *other != location)
+                    .map(|other| other.join(relative))
+                    .find(|other_root| entry.check(other_root).is_none());
+                let Some(donor) = donor else {
+                    error!(event = "heal_unavailable", path = %root.display(), problem = %problem, "No intact copy of {} in another location", entry.path);
+                    println!("{}: {}, no intact copy", root.display(), problem);
+                    report.unhealable += 1;
+                    continue;
+                };
+                if options.apply {
+                    heal_file(&donor, &root, entry).map_err(|source| BackupWardenError::Io {
+                        path: root.join(&entry.path),
+                        source,
+                    })?;
+                    info!(event = "file_healed", path = %root.display(), file = %entry.path, from = %donor.display(), "Healed {} from {}", entry.path, donor.display());
+                    println!(
+                        "{}: {}, healed from {}",
+                        root.display(),
+                        problem,
+                        donor.display()
+                    );
+                } else {
+                    println!(
+                        "{}: {}, can be healed from {}",
+                        root.display(),
+                        problem,
+                        donor.display()
+                    );
+                }
+                report.healed += 1;
+            }
+        }
+    }
+    info!(
+        event = "heal_finished",
+        damaged = report.damaged,
+        healed = report.healed,
+        unhealable = report.unhealable,
+        apply = options.apply,
+        "{} damaged file(s), {} {}, {} without an intact copy",
+        report.damaged,
+        report.healed,
+        if options.apply { "healed" } else { "healable" },
+        report.unhealable
+    );
+    Ok(report)
+}
+
+/// Replaces the file `entry` describes in the backup at `root` with its
+/// intact copy in the backup at `donor`, then checks the result.
+fn heal_file(donor: &Path, root: &Path, entry: &ManifestEntry) -> io::Result<()> {
+    let source = donor.join(&entry.path);
+    let dest = root.join(&entry.path);
+    let dir = dest.parent().expect("manifest paths have a parent");
+    fs::create_dir_all(dir)?;
+    let metadata = fs::metadata(&source)?;
+    // Written aside and renamed over the damaged file, so it is never seen
+    // half-written and other backups hard-linked to it keep their own copy
+    let mut tmp = tempfile::NamedTempFile::new_in(dir)?;
+    io::copy(&mut fs::File::open(&source)?, tmp.as_file_mut())?;
+    tmp.as_file_mut().flush()?;
+    tmp.as_file().sync_all()?;
+    tmp.as_file().set_permissions(metadata.permissions())?;
+    // Windows won't replace a readonly file
+    clear_readonly(&dest)?;
+    tmp.persist(&dest).map_err(|e| e.error)?;
+    set_file_mtime(&dest, FileTime::from_last_modification_time(&metadata))?;
+    match entry.check(root) {
+        None => Ok(()),
+        Some(problem) => Err(io::Error::other(problem)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Local, TimeZone};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_heal_replaces_damaged_files_from_another_location() {
+        let temp_dir = tempdir().unwrap();
+        let first = temp_dir.path().join("first");
+        let second = temp_dir.path().join("second");
+        let config = BackupWardenConfig {
+            backup_locations: vec![
+                first.to_str().unwrap().to_string(),
+                second.to_str().unwrap().to_string(),
+            ],
+            ..Default::default()
+        };
+        let layout = BackupLayout::new(&config).unwrap();
+        let time = Local.with_ymd_and_hms(2024, 1, 31, 13, 0, 0).unwrap();
+        let backups = [
+            layout.backup_path(&first, &time),
+            layout.backup_path(&second, &time),
+        ];
+        for backup in &backups {
+            fs::create_dir_all(backup.join("docs")).unwrap();
+            fs::write(backup.join("docs/a.txt"), b"aaa").unwrap();
+            fs::write(backup.join("b.txt"), b"bbb").unwrap();
+            fs::write(backup.join("c.txt"), b"ccc").unwrap();
+            Manifest::write(backup).unwrap();
+        }
+        fs::remove_dir_all(backups[0].join("docs")).unwrap();
+        fs::write(backups[0].join("b.txt"), b"bxb").unwrap();
+        // Damaged everywhere, so it can't be healed
+        for backup in &backups {
+            fs::write(backup.join("c.txt"), b"cxc").unwrap();
+        }
+
+        let mut options = HealOptions::default();
+        let expected = HealReport {
+            damaged: 4,
+            healed: 2,
+            unhealable: 2,
+        };
+        assert_eq!(run(&config, &options).unwrap(), expected);
+        assert!(!backups[0].join("docs/a.txt").exists());
+
+        options.apply = true;
+        assert_eq!(run(&config, &options).unwrap(), expected);
+        assert_eq!(fs::read(backups[0].join("docs/a.txt")).unwrap(), b"aaa");
+        assert_eq!(fs::read(backups[0].join("b.txt")).unwrap(), b"bbb");
+        assert_eq!(
+            run(&config, &options).unwrap(),
+            HealReport {
+                damaged: 2,
+                healed: 0,
+                unhealable: 2,
+            }
+        );
+    }
+}
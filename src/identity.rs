@@ -0,0 +1,70 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Identifies a file or directory independently of the path used to reach it:
+/// the device (volume) it lives on and its inode (file index) there. Two paths
+/// with the same id are the same object, even through bind mounts or links.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FileId {
+    device: u64,
+    inode: u64,
+}
+
+/// The id of whatever `path` points to, following links.
+#[cfg(unix)]
+pub fn file_id(path: &Path) -> io::Result<FileId> {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = fs::metadata(path)?;
+    Ok(FileId {
+        device: metadata.dev(),
+        inode: metadata.ino(),
+    })
+}
+
+/// The id of whatever `path` points to, following links.
+#[cfg(windows)]
+pub fn file_id(path: &Path) -> io::Result<FileId> {
+    use std::os::windows::fs::OpenOptionsExt;
+    use std::os::windows::io::AsRawHandle;
+    use windows_sys::Win32::Storage::FileSystem::{
+        GetFileInformationByHandle, BY_HANDLE_FILE_INFORMATION, FILE_FLAG_BACKUP_SEMANTICS,
+    };
+
+    // Backup semantics lets the handle be opened on a directory; no access rights
+    // are needed to query its identity
+    let file = fs::OpenOptions::new()
+        .access_mode(0)
+        .custom_flags(FILE_FLAG_BACKUP_SEMANTICS)
+        .open(path)?;
+    let mut info: BY_HANDLE_FILE_INFORMATION = unsafe { std::mem::zeroed() };
+    if unsafe { GetFileInformationByHandle(file.as_raw_handle() as isize, &mut info) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(FileId {
+        device: u64::from(info.dwVolumeSerialNumber),
+        inode: u64::from(info.nFileIndexHigh) << 32 | u64::from(info.nFileIndexLow),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_same_directory_through_different_paths() {
+        let temp_dir = tempdir().unwrap();
+        let a = temp_dir.path().join("a");
+        let b = temp_dir.path().join("b");
+        fs::create_dir_all(&a).unwrap();
+        fs::create_dir_all(&b).unwrap();
+
+        assert_eq!(
+            file_id(&a).unwrap(),
+            file_id(&b.join("..").join("a")).unwrap()
+        );
+        assert_ne!(file_id(&a).unwrap(), file_id(&b).unwrap());
+    }
+}
@@ -0,0 +1,288 @@
+use crate::filter::PathFilter;
+use crate::identity::{file_id, FileId};
+use crate::manifest::hash_file;
+use crate::signature::parse_hex;
+use crate::sink::BackupSink;
+use filetime::FileTime;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Name of the file at the root of each backup listing the watch folder's files
+/// as they were when it was taken, with `skip_unchanged`.
+pub const FILE_INDEX_NAME: &str = "backup_warden.files.idx";
+
+/// Leads every file index, so other files are never mistaken for one.
+const FILE_INDEX_MAGIC: &[u8] = b"BWFIX\x01";
+
+/// One file in the watch folder as a backup saw it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexEntry {
+    /// Path relative to the watch folder, `/`-separated.
+    pub path: String,
+    pub size: u64,
+    pub mtime_secs: i64,
+    pub mtime_nanos: u32,
+    /// The SHA-256 of the contents, with `incremental_compare` set to `"hash"`.
+    pub sha256: Option<[u8; 32]>,
+}
+
+/// Every file under the watch folder with its size and mtime, sorted by path,
+/// so telling whether anything changed since a backup is one comparison.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FileIndex {
+    pub files: Vec<IndexEntry>,
+}
+
+impl FileIndex {
+    /// Lists the files under `src` that `filter` lets through, following links
+    /// to folders when `follow_links` is set and hashing each file when
+    /// `with_hashes` is. Only metadata is read otherwise. A file whose path,
+    /// size and mtime match an entry of `previous` with a hash keeps that
+    /// hash instead of being read again.
+    pub fn scan(
+        src: &Path,
+        filter: &PathFilter,
+        follow_links: bool,
+        with_hashes: bool,
+        previous: Option<&FileIndex>,
+    ) -> io::Result<FileIndex> {
+        let mut index = FileIndex::default();
+        let scan = Scan {
+            filter,
+            follow_links,
+            with_hashes,
+            previous,
+        };
+        scan.dir(src, "", &mut Vec::new(), &mut index.files)?;
+        index.files.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(index)
+    }
+
+    /// Lays the index out as the magic, then per file a little-endian `u32`
+    /// path length, the UTF-8 path, a `u64` size, an `i64` mtime in seconds, a
+    /// `u32` of nanoseconds, and a flag byte followed by the 32-byte SHA-256 when
+    /// it is 1. Uncompressed, so it can be read in place.
+    pub fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        let mut out = FILE_INDEX_MAGIC.to_vec();
+        for entry in &self.files {
+            let path_len = u32::try_from(entry.path.len())
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "path too long"))?;
+            out.extend_from_slice(&path_len.to_le_bytes());
+            out.extend_from_slice(entry.path.as_bytes());
+            out.extend_from_slice(&entry.size.to_le_bytes());
+            out.extend_from_slice(&entry.mtime_secs.to_le_bytes());
+            out.extend_from_slice(&entry.mtime_nanos.to_le_bytes());
+            match &entry.sha256 {
+                Some(sha256) => {
+                    out.push(1);
+                    out.extend_from_slice(sha256);
+                }
+                None => out.push(0),
+            }
+        }
+        Ok(out)
+    }
+
+    pub fn from_bytes(raw: &[u8]) -> io::Result<FileIndex> {
+        let invalid = |reason: &str| io::Error::new(io::ErrorKind::InvalidData, reason.to_string());
+        let mut rest = raw
+            .strip_prefix(FILE_INDEX_MAGIC)
+            .ok_or_else(|| invalid("not a file index"))?;
+        let mut index = FileIndex::default();
+        while !rest.is_empty() {
+            let path_len = u32::from_le_bytes(take(&mut rest)?) as usize;
+            let path = rest
+                .get(..path_len)
+                .ok_or_else(|| invalid("file index is truncated"))?;
+            let path = String::from_utf8(path.to_vec())
+                .map_err(|_| invalid("file index holds a path that isn't UTF-8"))?;
+            rest = &rest[path_len..];
+            let size = u64::from_le_bytes(take(&mut rest)?);
+            let mtime_secs = i64::from_le_bytes(take(&mut rest)?);
+            let mtime_nanos = u32::from_le_bytes(take(&mut rest)?);
+            let sha256 = match take::<1>(&mut rest)? {
+                [0] => None,
+                [1] => Some(take::<32>(&mut rest)?),
+                _ => return Err(invalid("file index holds an invalid hash flag")),
+            };
+            index.files.push(IndexEntry {
+                path,
+                size,
+                mtime_secs,
+                mtime_nanos,
+                sha256,
+            });
+        }
+        Ok(index)
+    }
+}
+
+/// Stores `index` at the root of the backup `sink` is writing.
+pub fn write_index(sink: &mut dyn BackupSink, index: &FileIndex) -> io::Result<()> {
+    sink.write_contents(Path::new(FILE_INDEX_NAME), &index.to_bytes()?)
+}
+
+/// The index stored in the finished backup at `backup`, or `None` if it has
+/// none, such as a backup taken without `skip_unchanged`.
+pub fn read_index(backup: &Path) -> io::Result<Option<FileIndex>> {
+    match fs::read(backup.join(FILE_INDEX_NAME)) {
+        Ok(raw) => FileIndex::from_bytes(&raw).map(Some),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn take<const N: usize>(rest: &mut &[u8]) -> io::Result<[u8; N]> {
+    let bytes: [u8; N] = rest
+        .get(..N)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "file index is truncated"))?;
+    *rest = &rest[N..];
+    Ok(bytes)
+}
+
+struct Scan<'a> {
+    filter: &'a PathFilter,
+    follow_links: bool,
+    with_hashes: bool,
+    previous: Option<&'a FileIndex>,
+}
+
+impl Scan<'_> {
+    /// The hash `previous` recorded for the file at `path`, if it had the
+    /// same size and mtime then.
+    fn known_hash(&self, path: &str, size: u64, mtime: FileTime) -> Option<[u8; 32]> {
+        let files = &self.previous?.files;
+        let entry = &files[files.binary_search_by(|f| f.path.as_str().cmp(path)).ok()?];
+        (entry.size == size
+            && entry.mtime_secs == mtime.unix_seconds()
+            && entry.mtime_nanos == mtime.nanoseconds())
+        .then_some(entry.sha256)
+        .flatten()
+    }
+
+    /// Adds the files under `dir`, whose path in the index is `prefix`.
+    /// `ancestors` holds the folders being scanned above it, so a followed
+    /// link back up the tree isn't walked forever.
+    fn dir(
+        &self,
+        dir: &Path,
+        prefix: &str,
+        ancestors: &mut Vec<FileId>,
+        files: &mut Vec<IndexEntry>,
+    ) -> io::Result<()> {
+        let id = file_id(dir)?;
+        if ancestors.contains(&id) {
+            return Ok(());
+        }
+        ancestors.push(id);
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let relative = if prefix.is_empty() {
+                name
+            } else {
+                format!("{}/{}", prefix, name)
+            };
+            let link_metadata = fs::symlink_metadata(&path)?;
+            let metadata = if link_metadata.file_type().is_symlink() && self.follow_links {
+                match fs::metadata(&path) {
+                    Ok(metadata) => metadata,
+                    // A broken link is left out of the backup too
+                    Err(_) => continue,
+                }
+            } else {
+                link_metadata
+            };
+            if metadata.is_dir() {
+                if self.filter.allows_dir(&path) {
+                    self.dir(&path, &relative, ancestors, files)?;
+                }
+                continue;
+            }
+            if !self.filter.allows_file(&path) {
+                continue;
+            }
+            let mtime = FileTime::from_last_modification_time(&metadata);
+            let sha256 = if self.with_hashes && metadata.is_file() {
+                match self.known_hash(&relative, metadata.len(), mtime) {
+                    Some(sha256) => Some(sha256),
+                    None => Some(
+                        parse_hex(&hash_file(&path)?)
+                            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+                    ),
+                }
+            } else {
+                None
+            };
+            files.push(IndexEntry {
+                path: relative,
+                size: metadata.len(),
+                mtime_secs: mtime.unix_seconds(),
+                mtime_nanos: mtime.nanoseconds(),
+                sha256,
+            });
+        }
+        ancestors.pop();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BackupWardenConfig;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_index_round_trips_and_sees_changes() {
+        let temp_dir = tempdir().unwrap();
+        let src = temp_dir.path().join("src");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::write(src.join("a.txt"), b"a").unwrap();
+        fs::write(src.join("sub").join("b.txt"), b"bb").unwrap();
+        fs::write(src.join("skip.tmp"), b"tmp").unwrap();
+        let config = BackupWardenConfig {
+            watch_folder: src.to_str().unwrap().to_string(),
+            exclude_patterns: vec!["**/*.tmp".to_string()],
+            ..Default::default()
+        };
+        let filter = PathFilter::new(&config).unwrap();
+
+        let index = FileIndex::scan(&src, &filter, true, true, None).unwrap();
+        let paths: Vec<&str> = index.files.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(paths, ["a.txt", "sub/b.txt"]);
+        assert!(index.files.iter().all(|f| f.sha256.is_some()));
+        assert_eq!(
+            FileIndex::from_bytes(&index.to_bytes().unwrap()).unwrap(),
+            index
+        );
+
+        let plain = FileIndex::scan(&src, &filter, true, false, None).unwrap();
+        assert_eq!(
+            FileIndex::from_bytes(&plain.to_bytes().unwrap()).unwrap(),
+            plain
+        );
+        fs::write(src.join("sub").join("b.txt"), b"bbb").unwrap();
+        assert_ne!(
+            FileIndex::scan(&src, &filter, true, false, None).unwrap(),
+            plain
+        );
+        assert!(FileIndex::from_bytes(b"not an index").is_err());
+
+        // Hashes are taken over from the previous index for unchanged files
+        let mut previous = index.clone();
+        for entry in &mut previous.files {
+            entry.sha256 = Some([0; 32]);
+        }
+        let rescanned = FileIndex::scan(&src, &filter, true, true, Some(&previous)).unwrap();
+        let hashes: Vec<bool> = rescanned
+            .files
+            .iter()
+            .map(|f| f.sha256 == Some([0; 32]))
+            .collect();
+        assert_eq!(hashes, [true, false]);
+    }
+}
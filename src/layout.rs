@@ -0,0 +1,435 @@
+use crate::config::BackupWardenConfig;
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, NaiveDate, NaiveTime, TimeZone};
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+/// The layout used before path templates existed: `Past 30 Days/<date>/@<hour>`.
+pub const DEFAULT_PATH_TEMPLATE: &str = "Past 30 Days/{date}/@{hour}";
+
+/// How `{date}` is written unless `date_format` says otherwise.
+pub const DEFAULT_DATE_FORMAT: &str = "%Y-%m-%d";
+const HOUR_FORMAT: &str = "%I %p";
+const MINUTE_FORMAT: &str = "%M";
+
+/// Characters Windows refuses in file names; we reject them everywhere so a config
+/// works the same on every platform.
+const UNSAFE_CHARS: &[char] = &['<', '>', ':', '"', '|', '?', '*', '\\'];
+
+/// Where backups go inside a backup location, built from `path_template`.
+///
+/// The template is a `/`-separated relative path. The segment holding `{date}` is
+/// the dated level that cleanup prunes; segments before it may only use the fixed
+/// placeholders `{host}` and `{watch_name}`, and `{hour}` (plus the optional
+/// `{minute}`) must come after it so each backup gets its own folder.
+#[derive(Debug, Clone)]
+pub struct BackupLayout {
+    segments: Vec<String>,
+    date_index: usize,
+    date_format: String,
+    host: String,
+    watch_name: String,
+}
+
+impl BackupLayout {
+    pub fn new(config: &BackupWardenConfig) -> Result<Self, String> {
+        let watch_name = Path::new(&config.watch_folder)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let template = config
+            .path_template
+            .as_deref()
+            .unwrap_or(DEFAULT_PATH_TEMPLATE);
+        let layout = Self::parse(template, &host_name(), &watch_name)?;
+        match &config.date_format {
+            Some(date_format) => layout.with_date_format(date_format),
+            None => Ok(layout),
+        }
+    }
+
+    /// Writes `{date}` with the strftime-style `date_format` instead of
+    /// `%Y-%m-%d`. Cleanup reads dates back through the same format, so it must
+    /// name the year, month and day unambiguously; formats that don't, or that
+    /// produce characters a folder name can't hold, are rejected.
+    fn with_date_format(mut self, date_format: &str) -> Result<Self, String> {
+        let invalid =
+            |reason: &str| Err(format!("invalid date_format {:?}: {}", date_format, reason));
+
+        if StrftimeItems::new(date_format).any(|item| matches!(item, Item::Error)) {
+            return invalid("not a valid strftime format");
+        }
+        let mut samples: Vec<NaiveDate> = NaiveDate::from_ymd_opt(2024, 1, 1)
+            .unwrap()
+            .iter_days()
+            .take(366)
+            .collect();
+        samples.extend(
+            [(1999, 12, 31), (2000, 2, 29), (2100, 3, 1)]
+                .iter()
+                .filter_map(|&(y, m, d)| NaiveDate::from_ymd_opt(y, m, d)),
+        );
+        for date in samples {
+            // A date has no time or zone to fill in, which chrono reports as
+            // a formatting error rather than by panicking here
+            let mut name = String::new();
+            if write!(name, "{}", date.format(date_format)).is_err() {
+                return invalid("names something other than the year, month and day");
+            }
+            if let Some(c) = name.chars().find(|c| UNSAFE_CHARS.contains(c) || *c == '/') {
+                return invalid(&format!("produces unsafe character {:?}", c));
+            }
+            if NaiveDate::parse_from_str(&name, date_format).ok() != Some(date) {
+                return invalid(&format!(
+                    "{} can't be read back as a date; include the year, month and day",
+                    name
+                ));
+            }
+        }
+        self.date_format = date_format.to_string();
+        Ok(self)
+    }
+
+    fn parse(template: &str, host: &str, watch_name: &str) -> Result<Self, String> {
+        let invalid =
+            |reason: &str| Err(format!("invalid path_template {:?}: {}", template, reason));
+
+        if template.starts_with('/') {
+            return invalid("must be a relative path");
+        }
+        if let Some(c) = template.chars().find(|c| UNSAFE_CHARS.contains(c)) {
+            return invalid(&format!("contains unsafe character {:?}", c));
+        }
+
+        let segments: Vec<String> = template.split('/').map(str::to_string).collect();
+        for segment in &segments {
+            if segment.is_empty() || segment == "." || segment == ".." {
+                return invalid("contains an empty, \".\" or \"..\" segment");
+            }
+            if let Some(placeholder) = unknown_placeholder(segment) {
+                return invalid(&format!("unknown placeholder {{{}}}", placeholder));
+            }
+        }
+
+        let date_segments: Vec<usize> = (0..segments.len())
+            .filter(|&i| segments[i].contains("{date}"))
+            .collect();
+        let date_index = match date_segments[..] {
+            [index] => index,
+            [] => return invalid("must contain {date}"),
+            _ => return invalid("must contain {date} only once"),
+        };
+        if segments[..=date_index]
+            .iter()
+            .any(|s| s.contains("{hour}") || s.contains("{minute}"))
+        {
+            return invalid("{hour} and {minute} must come after the {date} segment");
+        }
+        if !segments[date_index + 1..]
+            .iter()
+            .any(|s| s.contains("{hour}"))
+        {
+            return invalid("must contain {hour} after the {date} segment");
+        }
+
+        Ok(BackupLayout {
+            segments,
+            date_index,
+            date_format: DEFAULT_DATE_FORMAT.to_string(),
+            host: sanitize(host),
+            watch_name: sanitize(watch_name),
+        })
+    }
+
+    /// The folder a backup taken at `time` goes into.
+    pub fn backup_path<Tz: TimeZone>(&self, location: &Path, time: &DateTime<Tz>) -> PathBuf
+    where
+        Tz::Offset: std::fmt::Display,
+    {
+        let mut path = location.to_path_buf();
+        for segment in &self.segments {
+            path.push(
+                self.fill(segment)
+                    .replace("{date}", &time.format(&self.date_format).to_string())
+                    .replace("{hour}", &time.format(HOUR_FORMAT).to_string())
+                    .replace("{minute}", &time.format(MINUTE_FORMAT).to_string()),
+            );
+        }
+        path
+    }
+
+    /// The folder holding one subfolder per day, which cleanup prunes.
+    pub fn daily_root(&self, location: &Path) -> PathBuf {
+        let mut path = location.to_path_buf();
+        for segment in &self.segments[..self.date_index] {
+            path.push(self.fill(segment));
+        }
+        path
+    }
+
+    /// The name of the day folder for `date`.
+    pub fn day_folder_name(&self, date: NaiveDate) -> String {
+        self.fill(&self.segments[self.date_index])
+            .replace("{date}", &date.format(&self.date_format).to_string())
+    }
+
+    /// Parses a date written in `date_format`.
+    pub fn parse_date(&self, value: &str) -> Option<NaiveDate> {
+        NaiveDate::parse_from_str(value, &self.date_format).ok()
+    }
+
+    /// Parses the date out of a day folder name, or `None` if it isn't one.
+    pub fn parse_day(&self, folder_name: &str) -> Option<NaiveDate> {
+        let pattern = self.fill(&self.segments[self.date_index]);
+        let (prefix, suffix) = pattern.split_once("{date}")?;
+        let date = folder_name.strip_prefix(prefix)?.strip_suffix(suffix)?;
+        NaiveDate::parse_from_str(date, &self.date_format).ok()
+    }
+
+    /// The newest day folder date under `location`, if any.
+    pub fn newest_day(&self, location: &Path) -> Option<NaiveDate> {
+        std::fs::read_dir(self.daily_root(location))
+            .ok()?
+            .filter_map(Result::ok)
+            .filter_map(|entry| self.parse_day(&entry.file_name().to_string_lossy()))
+            .max()
+    }
+
+    /// The backups inside the day folder `day_dir` with the time each was taken,
+    /// sorted oldest first. Folders that don't match the template are ignored.
+    pub fn backups_in_day(&self, day_dir: &Path) -> Vec<(NaiveTime, PathBuf)> {
+        let depth = self.segments.len() - self.date_index - 1;
+        let mut backups = Vec::new();
+        self.collect_backups(day_dir, &mut Vec::new(), depth, &mut backups);
+        backups.sort();
+        backups
+    }
+
+    fn collect_backups(
+        &self,
+        dir: &Path,
+        names: &mut Vec<String>,
+        depth: usize,
+        backups: &mut Vec<(NaiveTime, PathBuf)>,
+    ) {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return;
+        };
+        for entry in entries.filter_map(Result::ok) {
+            if !entry.file_type().map(|ft| ft.is_dir()).unwrap_or(false) {
+                continue;
+            }
+            names.push(entry.file_name().to_string_lossy().into_owned());
+            if names.len() == depth {
+                if let Some(time) = self.parse_time(names) {
+                    backups.push((time, entry.path()));
+                }
+            } else {
+                self.collect_backups(&entry.path(), names, depth, backups);
+            }
+            names.pop();
+        }
+    }
+
+    /// Parses the time of day out of the folder names below a day folder.
+    fn parse_time(&self, names: &[String]) -> Option<NaiveTime> {
+        let mut format = String::new();
+        let mut value = String::new();
+        for (segment, name) in self.segments[self.date_index + 1..].iter().zip(names) {
+            format.push_str(
+                &self
+                    .fill(segment)
+                    .replace('%', "%%")
+                    .replace("{hour}", HOUR_FORMAT)
+                    .replace("{minute}", MINUTE_FORMAT),
+            );
+            format.push('/');
+            value.push_str(name);
+            value.push('/');
+        }
+        // chrono needs a minute to build a time
+        if !self.segments.iter().any(|s| s.contains("{minute}")) {
+            format.push_str(MINUTE_FORMAT);
+            value.push_str("00");
+        }
+        NaiveTime::parse_from_str(&value, &format).ok()
+    }
+
+    /// Substitutes the placeholders that are fixed for the lifetime of the process.
+    fn fill(&self, segment: &str) -> String {
+        segment
+            .replace("{host}", &self.host)
+            .replace("{watch_name}", &self.watch_name)
+    }
+}
+
+fn unknown_placeholder(segment: &str) -> Option<String> {
+    const KNOWN: [&str; 5] = ["date", "hour", "minute", "host", "watch_name"];
+
+    let mut rest = segment;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..].find('}')? + start;
+        let name = &rest[start + 1..end];
+        if !KNOWN.contains(&name) {
+            return Some(name.to_string());
+        }
+        rest = &rest[end + 1..];
+    }
+    None
+}
+
+fn sanitize(value: &str) -> String {
+    let cleaned: String = value
+        .chars()
+        .map(|c| {
+            if UNSAFE_CHARS.contains(&c) || c == '/' {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+    if cleaned.is_empty() || cleaned == "." || cleaned == ".." {
+        "_".to_string()
+    } else {
+        cleaned
+    }
+}
+
+#[cfg(unix)]
+fn host_name() -> String {
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        return "unknown-host".to_string();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+#[cfg(windows)]
+fn host_name() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_else(|_| "unknown-host".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout(template: &str) -> Result<BackupLayout, String> {
+        BackupLayout::parse(template, "workstation", "Fluid Editor")
+    }
+
+    fn time(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<chrono::Utc> {
+        chrono::Utc.with_ymd_and_hms(y, m, d, h, min, 0).unwrap()
+    }
+
+    #[test]
+    fn test_default_template_matches_legacy_layout() {
+        let layout = layout(DEFAULT_PATH_TEMPLATE).unwrap();
+        let location = Path::new("/backups");
+
+        assert_eq!(
+            layout.backup_path(location, &time(2024, 1, 31, 13, 5)),
+            Path::new("/backups/Past 30 Days/2024-01-31/@01 PM")
+        );
+        assert_eq!(
+            layout.daily_root(location),
+            Path::new("/backups/Past 30 Days")
+        );
+    }
+
+    #[test]
+    fn test_custom_template_with_fixed_prefix() {
+        let layout = layout("{host}/{watch_name}/day-{date}/{hour}-{minute}").unwrap();
+        let location = Path::new("/backups");
+
+        assert_eq!(
+            layout.backup_path(location, &time(2024, 1, 31, 9, 45)),
+            Path::new("/backups/workstation/Fluid Editor/day-2024-01-31/09 AM-45")
+        );
+        assert_eq!(
+            layout.daily_root(location),
+            Path::new("/backups/workstation/Fluid Editor")
+        );
+        assert_eq!(
+            layout.parse_day("day-2024-01-31"),
+            NaiveDate::from_ymd_opt(2024, 1, 31)
+        );
+        assert_eq!(layout.parse_day("2024-01-31"), None);
+        assert_eq!(
+            layout.day_folder_name(NaiveDate::from_ymd_opt(2024, 2, 1).unwrap()),
+            "day-2024-02-01"
+        );
+    }
+
+    #[test]
+    fn test_rejects_ambiguous_or_unsafe_templates() {
+        assert!(layout("{hour}").is_err());
+        assert!(layout("{date}").is_err());
+        assert!(layout("{date}-{hour}").is_err());
+        assert!(layout("{hour}/{date}/x").is_err());
+        assert!(layout("{date}/{date}/{hour}").is_err());
+        assert!(layout("/abs/{date}/{hour}").is_err());
+        assert!(layout("../{date}/{hour}").is_err());
+        assert!(layout("x//{date}/{hour}").is_err());
+        assert!(layout("{date}/{hour}:{minute}").is_err());
+        assert!(layout("{user}/{date}/{hour}").is_err());
+    }
+
+    #[test]
+    fn test_custom_date_format_round_trips() {
+        let layout = layout(DEFAULT_PATH_TEMPLATE)
+            .unwrap()
+            .with_date_format("%d %b %Y")
+            .unwrap();
+        let date = NaiveDate::from_ymd_opt(2024, 1, 31).unwrap();
+
+        assert_eq!(layout.day_folder_name(date), "31 Jan 2024");
+        assert_eq!(layout.parse_day("31 Jan 2024"), Some(date));
+        assert_eq!(layout.parse_day("2024-01-31"), None);
+        assert_eq!(
+            layout.backup_path(Path::new("/backups"), &time(2024, 1, 31, 13, 5)),
+            Path::new("/backups/Past 30 Days/31 Jan 2024/@01 PM")
+        );
+    }
+
+    #[test]
+    fn test_rejects_ambiguous_date_formats() {
+        let with = |format: &str| {
+            layout(DEFAULT_PATH_TEMPLATE)
+                .unwrap()
+                .with_date_format(format)
+        };
+        assert!(with("%m-%d").is_err());
+        assert!(with("%Y-%m").is_err());
+        assert!(with("%Y/%m/%d").is_err());
+        assert!(with("%Y-%m-%Q").is_err());
+        assert!(with("%Y-%m-%d %H").is_err());
+        assert!(with("%Y%m%d").is_ok());
+    }
+
+    #[test]
+    fn test_backups_in_day_parses_times() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let layout = layout("{date}/h{hour}/m{minute}").unwrap();
+        let day = temp_dir.path().join("2024-01-31");
+        for (hour, minute) in [("01 PM", "05"), ("11 AM", "30"), ("01 PM", "45")] {
+            std::fs::create_dir_all(day.join(format!("h{}", hour)).join(format!("m{}", minute)))
+                .unwrap();
+        }
+        std::fs::create_dir_all(day.join("manual").join("copy")).unwrap();
+
+        let times: Vec<_> = layout
+            .backups_in_day(&day)
+            .into_iter()
+            .map(|(time, _)| time.format("%H:%M").to_string())
+            .collect();
+        assert_eq!(times, ["11:30", "13:05", "13:45"]);
+    }
+
+    #[test]
+    fn test_placeholder_values_are_made_filesystem_safe() {
+        let layout = BackupLayout::parse("{host}/{date}/{hour}", "bad:host", "w").unwrap();
+        assert_eq!(layout.daily_root(Path::new("/b")), Path::new("/b/bad_host"));
+    }
+}
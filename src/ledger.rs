@@ -0,0 +1,286 @@
+use crate::chunks;
+use crate::config::IncrementalCompare;
+use crate::manifest::same_contents;
+use filetime::FileTime;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+/// Name of the ledger kept in a backup folder while its copy is in progress.
+pub const LEDGER_FILE_NAME: &str = ".backup_warden_ledger";
+
+/// One confirmed file: the source it came from and the source's size and mtime at
+/// the time it was copied. A source whose path isn't UTF-8 is kept as its raw
+/// bytes in `source_bytes` instead.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source_bytes: Option<Vec<u8>>,
+    len: u64,
+    mtime_secs: i64,
+    mtime_nanos: u32,
+}
+
+/// Records which files of an in-progress backup have been fully written.
+///
+/// If a backup fails part-way (e.g. a network share drops), the ledger stays
+/// behind in the backup folder and the next attempt into that folder skips every
+/// file that was confirmed and hasn't changed in the source since. A completed
+/// backup removes its ledger, so a leftover ledger also marks an incomplete backup.
+pub struct Ledger {
+    path: PathBuf,
+    file: File,
+    confirmed: HashMap<PathBuf, (u64, FileTime)>,
+    compare: IncrementalCompare,
+}
+
+impl Ledger {
+    /// Opens the ledger in `backup_path`, loading what a previous attempt confirmed.
+    pub fn open(backup_path: &Path) -> io::Result<Ledger> {
+        fs::create_dir_all(backup_path)?;
+        let path = backup_path.join(LEDGER_FILE_NAME);
+
+        let mut confirmed = HashMap::new();
+        let mut torn = false;
+        if let Ok(file) = File::open(&path) {
+            // A crash can leave a torn last line; it simply doesn't count as confirmed
+            let mut reader = BufReader::new(file);
+            let mut line = String::new();
+            while reader.read_line(&mut line)? > 0 {
+                torn = !line.ends_with('\n');
+                if let Ok(entry) = serde_json::from_str::<Entry>(&line) {
+                    if let Some(source) = decode_path(entry.source, entry.source_bytes) {
+                        let mtime = FileTime::from_unix_time(entry.mtime_secs, entry.mtime_nanos);
+                        confirmed.insert(source, (entry.len, mtime));
+                    }
+                }
+                line.clear();
+            }
+        }
+
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        // Ends the torn line so the next entry doesn't run on from it
+        if torn {
+            writeln!(file)?;
+        }
+        Ok(Ledger {
+            path,
+            file,
+            confirmed,
+            compare: IncrementalCompare::default(),
+        })
+    }
+
+    /// Decides whether a confirmed file is unchanged by `compare`.
+    pub fn with_compare(mut self, compare: IncrementalCompare) -> Self {
+        self.compare = compare;
+        self
+    }
+
+    /// Whether `source` was already copied to `dest` by an earlier attempt and
+    /// hasn't changed since. A `dest` that is a chunk list is checked against
+    /// the file it lists.
+    pub fn is_confirmed(&self, source: &Path, metadata: &fs::Metadata, dest: &Path) -> bool {
+        let Some(&(len, mtime)) = self.confirmed.get(source) else {
+            return false;
+        };
+        let chunked = chunks::chunked_path(dest).is_some();
+        let stored_len = if chunked {
+            chunks::listed_size(dest)
+        } else {
+            fs::metadata(dest).map(|m| m.len())
+        };
+        let copied = len == metadata.len() && stored_len.is_ok_and(|stored| stored == len);
+        copied
+            && match self.compare {
+                IncrementalCompare::Mtime => {
+                    mtime == FileTime::from_last_modification_time(metadata)
+                }
+                IncrementalCompare::Hash if chunked => chunks::matches_list(source, dest),
+                IncrementalCompare::Hash => same_contents(source, dest),
+            }
+    }
+
+    /// Records that `source` has been fully copied. A path that can't be
+    /// recorded on this platform isn't, so that file is copied again on resume.
+    pub fn confirm(&mut self, source: &Path, metadata: &fs::Metadata) -> io::Result<()> {
+        let Some((source, source_bytes)) = encode_path(source) else {
+            return Ok(());
+        };
+        let mtime = FileTime::from_last_modification_time(metadata);
+        let entry = Entry {
+            source,
+            source_bytes,
+            len: metadata.len(),
+            mtime_secs: mtime.unix_seconds(),
+            mtime_nanos: mtime.nanoseconds(),
+        };
+        writeln!(self.file, "{}", serde_json::to_string(&entry)?)
+    }
+
+    /// Marks the backup complete by removing the ledger.
+    pub fn finish(self) -> io::Result<()> {
+        drop(self.file);
+        fs::remove_file(&self.path)
+    }
+}
+
+fn encode_path(path: &Path) -> Option<(Option<String>, Option<Vec<u8>>)> {
+    if let Some(path) = path.to_str() {
+        return Some((Some(path.to_string()), None));
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        Some((None, Some(path.as_os_str().as_bytes().to_vec())))
+    }
+    #[cfg(not(unix))]
+    None
+}
+
+fn decode_path(source: Option<String>, source_bytes: Option<Vec<u8>>) -> Option<PathBuf> {
+    #[cfg(unix)]
+    if let Some(bytes) = source_bytes {
+        use std::os::unix::ffi::OsStringExt;
+        return Some(PathBuf::from(std::ffi::OsString::from_vec(bytes)));
+    }
+    #[cfg(not(unix))]
+    let _ = source_bytes;
+    source.map(PathBuf::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_confirmed_files_survive_reopen() {
+        let temp_dir = tempdir().unwrap();
+        let source = temp_dir.path().join("source.txt");
+        let dest = temp_dir.path().join("backup").join("source.txt");
+        fs::write(&source, b"data").unwrap();
+
+        let mut ledger = Ledger::open(&temp_dir.path().join("backup")).unwrap();
+        let metadata = fs::metadata(&source).unwrap();
+        assert!(!ledger.is_confirmed(&source, &metadata, &dest));
+        fs::copy(&source, &dest).unwrap();
+        ledger.confirm(&source, &metadata).unwrap();
+        drop(ledger);
+
+        let ledger = Ledger::open(&temp_dir.path().join("backup")).unwrap();
+        assert!(ledger.is_confirmed(&source, &metadata, &dest));
+
+        // A changed source must be copied again
+        fs::write(&source, b"changed data").unwrap();
+        let metadata = fs::metadata(&source).unwrap();
+        assert!(!ledger.is_confirmed(&source, &metadata, &dest));
+    }
+
+    #[test]
+    fn test_hash_compare_ignores_mtime() {
+        let temp_dir = tempdir().unwrap();
+        let source = temp_dir.path().join("source.txt");
+        let dest = temp_dir.path().join("backup").join("source.txt");
+        fs::write(&source, b"data").unwrap();
+        let mtime = FileTime::from_unix_time(1_700_000_000, 0);
+        filetime::set_file_mtime(&source, mtime).unwrap();
+
+        let mut ledger = Ledger::open(&temp_dir.path().join("backup")).unwrap();
+        fs::copy(&source, &dest).unwrap();
+        ledger
+            .confirm(&source, &fs::metadata(&source).unwrap())
+            .unwrap();
+        drop(ledger);
+        let ledger = Ledger::open(&temp_dir.path().join("backup"))
+            .unwrap()
+            .with_compare(IncrementalCompare::Hash);
+
+        // Touched but not changed, then changed under the same size and mtime
+        filetime::set_file_mtime(&source, FileTime::from_unix_time(1_800_000_000, 0)).unwrap();
+        assert!(ledger.is_confirmed(&source, &fs::metadata(&source).unwrap(), &dest));
+        fs::write(&source, b"DATA").unwrap();
+        filetime::set_file_mtime(&source, mtime).unwrap();
+        let metadata = fs::metadata(&source).unwrap();
+        assert!(!ledger.is_confirmed(&source, &metadata, &dest));
+        assert!(Ledger::open(&temp_dir.path().join("backup"))
+            .unwrap()
+            .is_confirmed(&source, &metadata, &dest));
+    }
+
+    #[test]
+    fn test_chunked_file_confirmed_by_its_listed_size() {
+        let temp_dir = tempdir().unwrap();
+        let source = temp_dir.path().join("disk.img");
+        let dest = chunks::list_path(&temp_dir.path().join("backup").join("disk.img"));
+        fs::write(&source, vec![7; 3 * 1024 * 1024]).unwrap();
+        let store = chunks::ChunkStore::new(temp_dir.path());
+
+        let mut ledger = Ledger::open(&temp_dir.path().join("backup")).unwrap();
+        store.store_file(&source, &dest).unwrap();
+        let metadata = fs::metadata(&source).unwrap();
+        ledger.confirm(&source, &metadata).unwrap();
+        drop(ledger);
+        let ledger = Ledger::open(&temp_dir.path().join("backup")).unwrap();
+        assert!(ledger.is_confirmed(&source, &metadata, &dest));
+        let ledger = ledger.with_compare(IncrementalCompare::Hash);
+        assert!(ledger.is_confirmed(&source, &metadata, &dest));
+
+        // Changed in place, keeping its size and mtime
+        let mtime = FileTime::from_last_modification_time(&metadata);
+        let mut contents = fs::read(&source).unwrap();
+        contents[0] = 8;
+        fs::write(&source, contents).unwrap();
+        filetime::set_file_mtime(&source, mtime).unwrap();
+        let metadata = fs::metadata(&source).unwrap();
+        assert!(!ledger.is_confirmed(&source, &metadata, &dest));
+    }
+
+    #[test]
+    fn test_finish_removes_ledger() {
+        let temp_dir = tempdir().unwrap();
+        let ledger = Ledger::open(temp_dir.path()).unwrap();
+        assert!(temp_dir.path().join(LEDGER_FILE_NAME).exists());
+        ledger.finish().unwrap();
+        assert!(!temp_dir.path().join(LEDGER_FILE_NAME).exists());
+    }
+
+    #[test]
+    fn test_torn_line_is_ignored() {
+        let temp_dir = tempdir().unwrap();
+        fs::write(
+            temp_dir.path().join(LEDGER_FILE_NAME),
+            "{\"source\":\"a\",\"le",
+        )
+        .unwrap();
+        let ledger = Ledger::open(temp_dir.path()).unwrap();
+        assert!(ledger.confirmed.is_empty());
+    }
+
+    #[test]
+    fn test_entry_after_torn_line_survives_reopen() {
+        let temp_dir = tempdir().unwrap();
+        let source = temp_dir.path().join("source.txt");
+        let dest = temp_dir.path().join("backup").join("source.txt");
+        fs::write(&source, b"data").unwrap();
+        fs::create_dir_all(temp_dir.path().join("backup")).unwrap();
+        fs::write(
+            temp_dir.path().join("backup").join(LEDGER_FILE_NAME),
+            "{\"source\":\"a\",\"le",
+        )
+        .unwrap();
+
+        let mut ledger = Ledger::open(&temp_dir.path().join("backup")).unwrap();
+        let metadata = fs::metadata(&source).unwrap();
+        fs::copy(&source, &dest).unwrap();
+        ledger.confirm(&source, &metadata).unwrap();
+        drop(ledger);
+
+        let ledger = Ledger::open(&temp_dir.path().join("backup")).unwrap();
+        assert!(ledger.is_confirmed(&source, &metadata, &dest));
+    }
+}
@@ -0,0 +1,45 @@
+pub mod alert;
+pub mod archive;
+pub mod attributes;
+pub mod audit;
+pub mod bench;
+pub mod chunks;
+pub mod config;
+pub mod console;
+pub mod control;
+pub mod copy;
+pub mod diff;
+pub mod disk;
+pub mod doctor;
+pub mod error;
+pub mod export;
+pub mod filter;
+pub mod heal;
+pub mod identity;
+pub mod index;
+pub mod layout;
+pub mod ledger;
+pub mod lock;
+pub mod logging;
+pub mod manifest;
+pub mod migrate;
+pub mod mirror;
+pub mod orphans;
+pub mod priority;
+pub mod range;
+pub mod report;
+pub mod restore;
+pub mod schedule;
+pub mod signature;
+pub mod sink;
+pub mod size;
+pub mod slots;
+pub mod state;
+pub mod status;
+pub mod streams;
+pub mod stripes;
+pub mod throttle;
+pub mod verify;
+pub mod versions;
+pub mod vss;
+pub mod watch;
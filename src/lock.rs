@@ -0,0 +1,313 @@
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+
+/// Name of the lock file created at the root of each backup location while a run is in progress.
+pub const LOCK_FILE_NAME: &str = ".backup_warden.lock";
+
+/// How long a lock file without a pid in it is taken to be still being written
+/// rather than left by a crash, since the pid goes in after the file is created.
+const PID_WRITE_GRACE: Duration = Duration::from_secs(10);
+
+/// Locations held by this process, so threads never race into the same destination
+/// even before the lock file is written.
+fn held() -> &'static Mutex<HashSet<PathBuf>> {
+    static HELD: OnceLock<Mutex<HashSet<PathBuf>>> = OnceLock::new();
+    HELD.get_or_init(|| Mutex::new(HashSet::new()))
+}
+
+/// Exclusive hold on a set of backup locations, released on drop.
+pub struct RunLock {
+    lock_files: Vec<PathBuf>,
+}
+
+impl RunLock {
+    /// Locks every location or none. Fails if another run, in this process or another,
+    /// currently holds any of them. Locations that cannot be written to at all are
+    /// left unlocked.
+    pub fn acquire(locations: &[String]) -> Result<RunLock, String> {
+        let mut lock = RunLock {
+            lock_files: Vec::new(),
+        };
+        for location in locations {
+            let lock_file = Path::new(location).join(LOCK_FILE_NAME);
+            if !held().lock().unwrap().insert(lock_file.clone()) {
+                return Err(format!("a backup into {} is already running", location));
+            }
+            match create_lock_file(&lock_file) {
+                Ok(()) => lock.lock_files.push(lock_file),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists && lock_file.is_file() => {
+                    held().lock().unwrap().remove(&lock_file);
+                    return Err(match read_pid(&lock_file) {
+                        Some(pid) => format!("{} is locked by process {}", location, pid),
+                        None => format!("{} is locked by another process", location),
+                    });
+                }
+                // Nothing can race into a location we cannot write to, and the
+                // backup itself reports the failure
+                Err(_) => {
+                    held().lock().unwrap().remove(&lock_file);
+                }
+            }
+        }
+        Ok(lock)
+    }
+}
+
+impl Drop for RunLock {
+    fn drop(&mut self) {
+        let mut held = held().lock().unwrap();
+        for lock_file in &self.lock_files {
+            let _ = fs::remove_file(lock_file);
+            held.remove(lock_file);
+        }
+    }
+}
+
+/// Marks the one daemon running for a set of watch folders, released on drop.
+pub struct InstanceLock {
+    lock_file: PathBuf,
+}
+
+impl InstanceLock {
+    /// Claims the daemon for `watch_folders`, with a lock file in `dir` named
+    /// after them. Fails, naming the owner, if an instance that is still
+    /// running holds it; a lock left by one that crashed is taken over.
+    pub fn acquire(dir: &Path, watch_folders: &[&str]) -> Result<InstanceLock, String> {
+        let mut hasher = Sha256::new();
+        for folder in watch_folders {
+            hasher.update(folder.as_bytes());
+            hasher.update([0]);
+        }
+        let key: String = hasher.finalize()[..8]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let lock_file = dir.join(format!("backup_warden.{}.instance.lock", key));
+        let failed = |e: io::Error| format!("can't create {}: {}", lock_file.display(), e);
+        loop {
+            match create_lock_file(&lock_file) {
+                Ok(()) => return Ok(InstanceLock { lock_file }),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => (),
+                Err(e) => return Err(failed(e)),
+            }
+            match read_pid(&lock_file) {
+                Some(pid) if process_alive(pid) => {
+                    return Err(format!(
+                        "backup-warden is already running for this config as process {} ({})",
+                        pid,
+                        lock_file.display()
+                    ))
+                }
+                None if pid_pending(&lock_file) => {
+                    return Err(format!(
+                        "another backup-warden is starting for this config ({})",
+                        lock_file.display()
+                    ))
+                }
+                _ => {
+                    info!(event = "stale_lock_removed", path = %lock_file.display(), "Removing stale lock file");
+                    match fs::remove_file(&lock_file) {
+                        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(failed(e)),
+                        _ => (),
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.lock_file);
+    }
+}
+
+fn create_lock_file(path: &Path) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+    write!(file, "{}", std::process::id())
+}
+
+fn read_pid(path: &Path) -> Option<u32> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// Whether the lock file at `path`, which has no pid in it, was created too
+/// recently for its owner to have finished writing one.
+fn pid_pending(path: &Path) -> bool {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .is_ok_and(|modified| {
+            SystemTime::now()
+                .duration_since(modified)
+                .map_or(true, |age| age < PID_WRITE_GRACE)
+        })
+}
+
+/// Removes lock files left behind by processes that are no longer running.
+pub fn clear_stale_locks(locations: &[String]) {
+    for location in locations {
+        let lock_file = Path::new(location).join(LOCK_FILE_NAME);
+        if !lock_file.exists() {
+            continue;
+        }
+        let stale = match read_pid(&lock_file) {
+            Some(pid) => !process_alive(pid),
+            // An unreadable lock is either being written or left by a crash
+            // mid-write
+            None => !pid_pending(&lock_file),
+        };
+        if stale {
+            info!(event = "stale_lock_removed", path = %lock_file.display(), "Removing stale lock file");
+            if let Err(e) = fs::remove_file(&lock_file) {
+                warn!(event = "stale_lock_remove_failed", path = %lock_file.display(), error = %e, "Failed to remove stale lock file");
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+pub fn process_alive(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    if pid <= 0 {
+        return false;
+    }
+    // Signal 0 performs the permission and existence checks without sending anything
+    let signalled = unsafe { libc::kill(pid, 0) == 0 };
+    signalled || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(windows)]
+pub fn process_alive(pid: u32) -> bool {
+    use windows_sys::Win32::Foundation::{CloseHandle, STILL_ACTIVE};
+    use windows_sys::Win32::System::Threading::{
+        GetExitCodeProcess, OpenProcess, PROCESS_QUERY_LIMITED_INFORMATION,
+    };
+
+    unsafe {
+        let handle = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid);
+        if handle == 0 {
+            return false;
+        }
+        let mut exit_code = 0u32;
+        let ok = GetExitCodeProcess(handle, &mut exit_code);
+        CloseHandle(handle);
+        ok != 0 && exit_code == STILL_ACTIVE as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_second_acquire_fails_until_released() {
+        let temp_dir = tempdir().unwrap();
+        let locations = vec![temp_dir.path().to_str().unwrap().to_string()];
+
+        let lock = RunLock::acquire(&locations).unwrap();
+        assert!(temp_dir.path().join(LOCK_FILE_NAME).exists());
+        assert!(RunLock::acquire(&locations).is_err());
+
+        drop(lock);
+        assert!(!temp_dir.path().join(LOCK_FILE_NAME).exists());
+        assert!(RunLock::acquire(&locations).is_ok());
+    }
+
+    #[test]
+    fn test_failed_acquire_releases_earlier_locations() {
+        let temp_dir = tempdir().unwrap();
+        let free = temp_dir.path().join("free");
+        let busy = temp_dir.path().join("busy");
+        fs::create_dir_all(&free).unwrap();
+        fs::create_dir_all(&busy).unwrap();
+        fs::write(busy.join(LOCK_FILE_NAME), std::process::id().to_string()).unwrap();
+
+        let locations = vec![
+            free.to_str().unwrap().to_string(),
+            busy.to_str().unwrap().to_string(),
+        ];
+        assert!(RunLock::acquire(&locations).is_err());
+        assert!(!free.join(LOCK_FILE_NAME).exists());
+    }
+
+    #[test]
+    fn test_unwritable_location_does_not_block_others() {
+        let temp_dir = tempdir().unwrap();
+        let good = temp_dir.path().join("good");
+        let bad = temp_dir.path().join("bad");
+        fs::write(&bad, b"not a directory").unwrap();
+
+        let locations = vec![
+            bad.to_str().unwrap().to_string(),
+            good.to_str().unwrap().to_string(),
+        ];
+        let _lock = RunLock::acquire(&locations).unwrap();
+        assert!(good.join(LOCK_FILE_NAME).exists());
+    }
+
+    #[test]
+    fn test_instance_lock_reports_live_owner_and_takes_over_stale() {
+        let temp_dir = tempdir().unwrap();
+        let lock = InstanceLock::acquire(temp_dir.path(), &["/data"]).unwrap();
+        let error = InstanceLock::acquire(temp_dir.path(), &["/data"])
+            .err()
+            .unwrap();
+        assert!(error.contains(&std::process::id().to_string()));
+        // Other watch folders are another daemon's business
+        drop(InstanceLock::acquire(temp_dir.path(), &["/other"]).unwrap());
+
+        let lock_file = lock.lock_file.clone();
+        drop(lock);
+        assert!(!lock_file.exists());
+        fs::write(&lock_file, "999999999").unwrap();
+        drop(InstanceLock::acquire(temp_dir.path(), &["/data"]).unwrap());
+    }
+
+    #[test]
+    fn test_instance_lock_without_pid_is_held_until_grace_passes() {
+        let temp_dir = tempdir().unwrap();
+        let lock_file = InstanceLock::acquire(temp_dir.path(), &["/data"])
+            .unwrap()
+            .lock_file
+            .clone();
+        // As left by another instance between creating the file and writing to it
+        fs::write(&lock_file, "").unwrap();
+        assert!(InstanceLock::acquire(temp_dir.path(), &["/data"]).is_err());
+        assert!(lock_file.exists());
+
+        let old = SystemTime::now() - PID_WRITE_GRACE * 2;
+        filetime::set_file_mtime(&lock_file, filetime::FileTime::from_system_time(old)).unwrap();
+        drop(InstanceLock::acquire(temp_dir.path(), &["/data"]).unwrap());
+    }
+
+    #[test]
+    fn test_clear_stale_locks_keeps_live_owner() {
+        let temp_dir = tempdir().unwrap();
+        let stale = temp_dir.path().join("stale");
+        let live = temp_dir.path().join("live");
+        fs::create_dir_all(&stale).unwrap();
+        fs::create_dir_all(&live).unwrap();
+        fs::write(stale.join(LOCK_FILE_NAME), "999999999").unwrap();
+        fs::write(live.join(LOCK_FILE_NAME), std::process::id().to_string()).unwrap();
+
+        clear_stale_locks(&[
+            stale.to_str().unwrap().to_string(),
+            live.to_str().unwrap().to_string(),
+        ]);
+
+        assert!(!stale.join(LOCK_FILE_NAME).exists());
+        assert!(live.join(LOCK_FILE_NAME).exists());
+    }
+}
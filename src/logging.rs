@@ -0,0 +1,85 @@
+use serde::{Deserialize, Serialize};
+use tracing::Subscriber;
+use tracing_subscriber::fmt::MakeWriter;
+
+/// How log events are written to stdout.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines.
+    #[default]
+    Text,
+    /// One JSON object per line, with the event's fields (`event`, `location`,
+    /// `bytes`, `error`, ...) at the top level for log pipelines.
+    Json,
+}
+
+/// Installs the global log subscriber. Later calls are ignored, so it is safe to
+/// call from every entry point.
+pub fn init(format: LogFormat) {
+    let _ = tracing::subscriber::set_global_default(subscriber(format, std::io::stdout));
+}
+
+fn subscriber<W>(format: LogFormat, writer: W) -> Box<dyn Subscriber + Send + Sync>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let builder = tracing_subscriber::fmt()
+        .with_target(false)
+        .with_writer(writer);
+    match format {
+        LogFormat::Text => Box::new(builder.finish()),
+        LogFormat::Json => Box::new(
+            builder
+                .json()
+                .flatten_event(true)
+                .with_current_span(false)
+                .with_span_list(false)
+                .finish(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{self, Write};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_json_format_emits_one_object_per_event() {
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let subscriber = subscriber(LogFormat::Json, move || writer.clone());
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(
+                event = "copy_finished",
+                location = "/backups",
+                bytes = 42u64,
+                "Backup finished"
+            );
+        });
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let line: serde_json::Value = serde_json::from_str(output.trim()).unwrap();
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["event"], "copy_finished");
+        assert_eq!(line["location"], "/backups");
+        assert_eq!(line["bytes"], 42);
+        assert!(line["timestamp"].is_string());
+    }
+}
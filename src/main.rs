@@ -1,147 +1,1889 @@
 #![cfg_attr(all(windows, not(debug_assertions)), windows_subsystem = "windows")]
 
-mod config;
-
-use chrono::{Datelike, Local};
-use config::BackupWardenConfig;
-use notify::{Config as NotifyConfig, Event, EventKind, PollWatcher, RecursiveMode, Watcher};
+use backup_warden::alert;
+use backup_warden::audit::{self, AuditOutcome};
+use backup_warden::bench;
+use backup_warden::chunks::ChunkStore;
+use backup_warden::config::{
+    BackupWardenConfig, CleanupOrder, IncrementalCompare, LocationRole, TotalFailurePolicy,
+    WardenConfig, WardenMode, CONFIG_SNAPSHOT_FILE_NAME,
+};
+use backup_warden::console;
+use backup_warden::control::{self, PauseWatch};
+use backup_warden::copy::{
+    copy_dir_all, link_tree, CaseCollisionPolicy, CopyOptions, CopyStats, Selection, SymlinkPolicy,
+};
+use backup_warden::diff;
+use backup_warden::disk;
+use backup_warden::doctor;
+use backup_warden::error::{BackupWardenError, Result};
+use backup_warden::export;
+use backup_warden::filter::PathFilter;
+use backup_warden::heal;
+use backup_warden::index::{self, FileIndex};
+use backup_warden::layout::BackupLayout;
+use backup_warden::ledger::LEDGER_FILE_NAME;
+use backup_warden::lock::{self, InstanceLock, RunLock};
+use backup_warden::logging;
+use backup_warden::migrate;
+use backup_warden::mirror::{sync_mirror, MIRROR_DIR_NAME};
+use backup_warden::orphans;
+use backup_warden::priority;
+use backup_warden::range::{DateWindow, RangeOptions};
+use backup_warden::report::{self, BackupSummary, LocationSummary};
+use backup_warden::restore;
+use backup_warden::schedule::{self, SnapshotKind};
+use backup_warden::sink::{self, BackupSink, LocalSink};
+use backup_warden::size::{self, directory_size, format_bytes, SourceTotals};
+use backup_warden::slots::{BackupSlot, BackupSlots};
+use backup_warden::state::{RunMetrics, UsageSample, WardenState};
+use backup_warden::status;
+use backup_warden::stripes::StripedSink;
+use backup_warden::throttle::BackupThrottle;
+use backup_warden::verify;
+use backup_warden::versions;
+use backup_warden::vss::ShadowCopy;
+use backup_warden::watch::{self, ChangedPaths, WatchMode};
+use chrono::{DateTime, Local};
+use ed25519_dalek::SigningKey;
+use notify::{
+    Config as NotifyConfig, Event, PollWatcher, RecommendedWatcher, RecursiveMode, Watcher,
+};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::fs;
-use std::path::Path;
-use std::sync::mpsc::channel;
-use std::time::Duration;
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{error, info, info_span, warn};
 
 const CONFIG: &str = include_str!("../backup_warden_config.json");
 
+/// How long the main loop waits for events before re-checking the monthly snapshot
+/// and the pause marker.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long to hold off a backup that could not take the run lock.
+const LOCK_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// How many backup locations a run succeeded and failed for.
+#[derive(Debug, Default)]
+struct BackupOutcome {
+    succeeded: usize,
+    failed: usize,
+    /// Whether the run was called off before copying, having already alerted.
+    aborted: bool,
+    /// The newest backup of each location that is up to date after the run.
+    backups: HashMap<String, PathBuf>,
+}
+
+impl BackupOutcome {
+    fn is_total_failure(&self) -> bool {
+        self.failed > 0 && self.succeeded == 0
+    }
+}
+
 fn main() {
-    let config: BackupWardenConfig = serde_json::from_str(CONFIG).expect("Failed to load config");
+    let mut args: Vec<String> = std::env::args().collect();
+    // Accepted anywhere on the command line, alongside any other command
+    let before = args.len();
+    args.retain(|arg| arg != "--foreground" && arg != "--console");
+    if args.len() != before {
+        if let Err(e) = console::attach() {
+            eprintln!("Failed to attach a console: {}", e);
+        }
+    }
+    match run(&args) {
+        Ok(0) => (),
+        Ok(code) => std::process::exit(code),
+        Err(e) => {
+            error!(event = "fatal", error = %e, "{}", e);
+            eprintln!("Error: {}", e);
+            std::process::exit(e.exit_code());
+        }
+    }
+}
 
+/// Runs the command in `args`, or the daemon when there is none, and returns
+/// the process exit code.
+fn run(args: &[String]) -> Result<i32> {
+    if let Some(command) = args.get(1) {
+        match command.as_str() {
+            "doctor" => return Ok(if doctor::run(CONFIG) { 0 } else { 1 }),
+            "--validate-only" => return Ok(doctor::validate_only(CONFIG)),
+            "--once" => {
+                let config = load_config()?;
+                apply_priority(&config);
+                return run_all_once(&config);
+            }
+            "backup-now" => {
+                let config = load_config()?;
+                let (job, full) = parse_backup_now(&args[2..])?;
+                apply_priority(&config);
+                let jobs = match job {
+                    Some(name) => vec![config.job(Some(name))?],
+                    None => config.jobs.iter().collect(),
+                };
+                let mut worst = 0;
+                for job in jobs {
+                    let _span = info_span!("job", job = %job.name).entered();
+                    worst = worst.max(run_once(&job.config, full)?);
+                }
+                return Ok(worst);
+            }
+            "--print-schedule" => {
+                let config = load_config()?;
+                let now = Local::now();
+                for (i, job) in config.jobs.iter().enumerate() {
+                    if config.jobs.len() > 1 {
+                        if i > 0 {
+                            println!();
+                        }
+                        println!("Job: {}", job.name);
+                    }
+                    schedule::print(&job.config, now)?;
+                }
+                return Ok(0);
+            }
+            "status" => {
+                let config = load_config()?;
+                for (i, job) in config.jobs.iter().enumerate() {
+                    if config.jobs.len() > 1 {
+                        if i > 0 {
+                            println!();
+                        }
+                        println!("Job: {}", job.name);
+                    }
+                    status::run(&job.config)?;
+                }
+                return Ok(0);
+            }
+            "export" => {
+                let config = load_config()?;
+                let options = export::ExportOptions::parse(&args[2..])?;
+                let days = export::run(&config.job(options.job.as_deref())?.config, &options)?;
+                println!("Exported {} day(s)", days);
+                return Ok(0);
+            }
+            "bench" => {
+                bench::run(&bench::BenchOptions::parse(&args[2..])?)?;
+                return Ok(0);
+            }
+            "restore" => {
+                let config = load_config()?;
+                let options = restore::RestoreOptions::parse(&args[2..])?;
+                match restore::run(&config.job(options.job.as_deref())?.config, &options)? {
+                    Some(stats) => println!("Restored {} file(s)", stats.files_copied),
+                    None => println!("Nothing restored"),
+                }
+                return Ok(0);
+            }
+            "migrate" => {
+                let config = load_config()?;
+                let options = migrate::MigrateOptions::parse(&args[2..])?;
+                let report = migrate::run(&config.job(options.job.as_deref())?.config, &options)?;
+                if options.apply {
+                    println!("Moved {} backup(s)", report.moved);
+                } else {
+                    println!(
+                        "Would move {} backup(s); run again with --apply to move them",
+                        report.moved
+                    );
+                }
+                return Ok(if report.conflicts + report.unmapped > 0 {
+                    1
+                } else {
+                    0
+                });
+            }
+            "gc-orphans" => {
+                let config = load_config()?;
+                let options = orphans::GcOptions::parse(&args[2..])?;
+                let report = orphans::run(&config.job(options.job.as_deref())?.config, &options)?;
+                if options.apply {
+                    println!(
+                        "Removed {} orphan(s), {}",
+                        report.orphans,
+                        format_bytes(report.bytes)
+                    );
+                } else {
+                    println!(
+                        "Found {} orphan(s), {}; run again with --apply to remove them",
+                        report.orphans,
+                        format_bytes(report.bytes)
+                    );
+                }
+                return Ok(0);
+            }
+            "heal" => {
+                let config = load_config()?;
+                let options = heal::HealOptions::parse(&args[2..])?;
+                let report = heal::run(&config.job(options.job.as_deref())?.config, &options)?;
+                if options.apply {
+                    println!(
+                        "Healed {} of {} damaged file(s)",
+                        report.healed, report.damaged
+                    );
+                } else {
+                    println!(
+                        "Found {} damaged file(s), {} healable; run again with --apply to heal them",
+                        report.damaged, report.healed
+                    );
+                }
+                return Ok(if report.unhealable > 0 { 1 } else { 0 });
+            }
+            "audit-verify" => {
+                let config = load_config()?;
+                let job = match &args[2..] {
+                    [] => None,
+                    [flag, name] if flag == "--job" => Some(name.as_str()),
+                    _ => {
+                        return Err(BackupWardenError::Usage(
+                            "Usage: backup-warden audit-verify [--job NAME]".to_string(),
+                        ))
+                    }
+                };
+                let Some(path) = &config.job(job)?.config.audit_log else {
+                    return Err(BackupWardenError::Config(
+                        "audit-verify needs audit_log to be set".to_string(),
+                    ));
+                };
+                let check =
+                    audit::verify(Path::new(path)).map_err(|source| BackupWardenError::Io {
+                        path: path.into(),
+                        source,
+                    })?;
+                return Ok(match check {
+                    audit::ChainCheck::Intact { records } => {
+                        println!("{} is intact: {} record(s)", path, records);
+                        0
+                    }
+                    audit::ChainCheck::Broken { line, reason } => {
+                        println!("{} is broken at line {}: {}", path, line, reason);
+                        1
+                    }
+                });
+            }
+            "pause" => {
+                let dir = load_config()?.control_dir();
+                control::pause(&dir).map_err(|source| BackupWardenError::Io {
+                    path: dir.clone(),
+                    source,
+                })?;
+                println!(
+                    "Backups paused; run `backup-warden resume` to resume them ({})",
+                    dir.join(control::PAUSED_FILE_NAME).display()
+                );
+                return Ok(0);
+            }
+            "resume" => {
+                let dir = load_config()?.control_dir();
+                let was_paused = control::resume(&dir).map_err(|source| BackupWardenError::Io {
+                    path: dir.clone(),
+                    source,
+                })?;
+                println!(
+                    "{}",
+                    if was_paused {
+                        "Backups resumed"
+                    } else {
+                        "Backups were not paused"
+                    }
+                );
+                return Ok(0);
+            }
+            "diff" => {
+                let same = match &args[2..] {
+                    [old, new] if !old.starts_with("--") && !new.starts_with("--") => {
+                        diff::run(Path::new(old), Path::new(new))?
+                    }
+                    range_args => {
+                        let config = load_config()?;
+                        let options = RangeOptions::parse(
+                            range_args,
+                            "Usage: backup-warden diff <old backup> <new backup> | diff [--job NAME] [--since DATE] [--until DATE]",
+                        )?;
+                        let (job, window) = job_window(&config, &options)?;
+                        diff::run_window(job, &window)?
+                    }
+                };
+                return Ok(if same { 0 } else { 1 });
+            }
+            "list" => {
+                let config = load_config()?;
+                let options = RangeOptions::parse(
+                    &args[2..],
+                    "Usage: backup-warden list [--job NAME] [--since DATE] [--until DATE]",
+                )?;
+                let (job, window) = job_window(&config, &options)?;
+                println!("{} backup(s)", restore::list(job, &window)?);
+                return Ok(0);
+            }
+            "verify" => {
+                let config = load_config()?;
+                let options = RangeOptions::parse(
+                    &args[2..],
+                    "Usage: backup-warden verify [--job NAME] [--since DATE] [--until DATE]",
+                )?;
+                let (job, window) = job_window(&config, &options)?;
+                let report = verify::verify_backups(job, &window)?;
+                println!(
+                    "Verified {} backup(s): {} corrupt, {} without a manifest",
+                    report.verified, report.corrupt, report.unverifiable
+                );
+                return Ok(if report.corrupt > 0 { 1 } else { 0 });
+            }
+            "compare-to-source" => {
+                let [backup, live] = &args[2..] else {
+                    return Err(BackupWardenError::Usage(
+                        "Usage: backup-warden compare-to-source <backup> <live folder>".to_string(),
+                    ));
+                };
+                return Ok(
+                    if diff::compare_to_source(Path::new(backup), Path::new(live))? {
+                        0
+                    } else {
+                        1
+                    },
+                );
+            }
+            _ => {
+                return Err(BackupWardenError::Usage(format!(
+                    "Unknown command: {}\nUsage: backup-warden [--foreground] [doctor | --validate-only | status | --once | backup-now [--full] [--job NAME] | --print-schedule | pause | resume | restore [<backup> <target>] [--interactive] [--job NAME] | migrate [--from-template T] [--from-date-format F] [--job NAME] [--apply] | gc-orphans [--job NAME] [--apply] | heal [--job NAME] [--apply] | audit-verify [--job NAME] | bench <folder> [--dest DIR] | list [--job NAME] [--since DATE] [--until DATE] | verify [--job NAME] [--since DATE] [--until DATE] | diff <old> <new> | diff [--job NAME] [--since DATE] [--until DATE] | compare-to-source <backup> <live> | export <dest> [--job NAME] [--since DATE] [--until DATE] [--archive]]",
+                    command
+                )));
+            }
+        }
+    }
+
+    let config = load_config()?;
+    let watch_folders: Vec<&str> = config
+        .jobs
+        .iter()
+        .map(|job| job.config.watch_folder.as_str())
+        .collect();
+    let _instance = InstanceLock::acquire(&config.control_dir(), &watch_folders)
+        .map_err(BackupWardenError::Locked)?;
+    // Before the watchers start, so their polling threads inherit the priority
+    apply_priority(&config);
+
+    let slots = Arc::new(BackupSlots::new(config.global_backup_concurrency));
+    let all_locations: Vec<String> = config
+        .jobs
+        .iter()
+        .flat_map(|job| job.config.backup_locations.iter().cloned())
+        .collect();
+    let handles: Vec<_> = config
+        .jobs
+        .into_iter()
+        .map(|job| {
+            let slots = slots.clone();
+            let locations: Vec<&str> = all_locations.iter().map(String::as_str).collect();
+            let ignored = watch::ignored_prefixes(&job.config.watch_folder, &locations);
+            std::thread::Builder::new()
+                .name(format!("job {}", job.name))
+                .spawn(move || {
+                    let _span = info_span!("job", job = %job.name).entered();
+                    // A job that cannot run takes the daemon down, as a lone job always has
+                    let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                        watch_job(&job.config, &slots, &ignored)
+                    }));
+                    match result {
+                        Ok(Ok(())) => (),
+                        Ok(Err(e)) => {
+                            error!(event = "job_failed", error = %e, "{}", e);
+                            std::process::exit(e.exit_code());
+                        }
+                        Err(_) => std::process::exit(1),
+                    }
+                })
+                .expect("Failed to start job thread")
+        })
+        .collect();
+    for handle in handles {
+        let _ = handle.join();
+    }
+    Ok(0)
+}
+
+/// Watches one job's folder and backs it up on changes, forever. Every copy
+/// waits for one of the `slots` shared by all jobs. Changes under the `ignored`
+/// backup locations never trigger a backup.
+fn watch_job(config: &BackupWardenConfig, slots: &BackupSlots, ignored: &[PathBuf]) -> Result<()> {
+    match config.mode {
+        WardenMode::Backup => (),
+        WardenMode::VerifyOnly => return verify_job(config, slots),
+        WardenMode::PerFileVersions => return versions_job(config, slots, ignored),
+    }
     let (tx, rx) = channel();
+    let _watcher = start_watcher(config, tx)?;
+
+    let mut throttle = BackupThrottle::new(Duration::from_secs(config.min_backup_interval_secs));
+    let mut pause = PauseWatch::new(config.control_dir());
+    let mut changes = ChangedPaths::unknown();
+    lock::clear_stale_locks(&config.backup_locations);
+
+    // Whether a backup ran since the last check for a monthly snapshot
+    let mut backed_up = false;
+    // Every backup cleans up too, so the cleanup timer restarts with each one
+    let mut last_cleanup = Instant::now();
+    let mut age_alerted = HashSet::new();
+    // Check for existing backup folders and create initial backup if none exist
+    if !schedule::backup_folders_exist(config)? {
+        if pause.check() {
+            // Taken once backups resume
+            throttle.request();
+        } else {
+            info!(
+                event = "initial_backup",
+                "No backup folders found, creating initial backup"
+            );
+            run_backup(config, slots, &mut throttle, &mut changes)?;
+            backed_up = true;
+        }
+    }
+    if config.snapshot_on_first_run && !pause.check() && !schedule::snapshots_exist(config) {
+        info!(
+            event = "initial_snapshot",
+            "No monthly snapshots found, creating a baseline snapshot"
+        );
+        let _slot = wait_for_slot(slots);
+        if let Some(outcome) = create_monthly_snapshot(config, Local::now().date_naive())? {
+            handle_outcome(config, "Baseline snapshot", &outcome);
+        }
+    }
+
+    loop {
+        let timeout = throttle
+            .time_until_ready(Instant::now())
+            .map_or(IDLE_TIMEOUT, |wait| wait.min(IDLE_TIMEOUT));
+        match rx.recv_timeout(timeout) {
+            Ok(Ok(event)) => handle_event(&event, config, ignored, &mut throttle, &mut changes),
+            Ok(Err(e)) => warn!(event = "watch_error", error = %e, "Watch error"),
+            Err(_) => (),
+        }
+
+        // Changes keep being collected while paused and are backed up on resume
+        let paused = pause.check();
+        if paused && throttle.is_ready(Instant::now()) {
+            throttle.postpone(Instant::now() + IDLE_TIMEOUT);
+        }
+
+        if throttle.is_ready(Instant::now()) {
+            // Fold everything that queued up meanwhile into this one backup
+            while let Ok(result) = rx.try_recv() {
+                match result {
+                    Ok(event) => handle_event(&event, config, ignored, &mut throttle, &mut changes),
+                    Err(e) => warn!(event = "watch_error", error = %e, "Watch error"),
+                }
+            }
+            run_backup(config, slots, &mut throttle, &mut changes)?;
+            backed_up = true;
+            last_cleanup = Instant::now();
+        }
+
+        // Enforces age-based retention even while nothing changes
+        if !paused
+            && config
+                .cleanup_interval()
+                .is_some_and(|interval| last_cleanup.elapsed() >= interval)
+        {
+            let _slot = wait_for_slot(slots);
+            if scheduled_cleanup(config)? {
+                last_cleanup = Instant::now();
+            }
+        }
 
+        // Create a monthly snapshot when monthly_strategy says one is due
+        let today = Local::now().date_naive();
+        if !paused && schedule::snapshot_due(config, today, backed_up) {
+            let _slot = wait_for_slot(slots);
+            if let Some(outcome) = create_monthly_snapshot(config, today)? {
+                handle_outcome(config, "Monthly snapshot", &outcome);
+            }
+        }
+        if !paused && schedule::weekly_snapshot_due(config, today) {
+            let _slot = wait_for_slot(slots);
+            if let Some(outcome) = create_weekly_snapshot(config, today)? {
+                handle_outcome(config, "Weekly snapshot", &outcome);
+            }
+        }
+        if !paused {
+            backed_up = false;
+        }
+        check_backup_age(config, &mut age_alerted);
+    }
+}
+
+/// Starts watching the job's folder, sending its events to `tx`. A native
+/// watcher that can't watch the folder, as on some network shares, falls back
+/// to polling.
+fn start_watcher(
+    config: &BackupWardenConfig,
+    tx: Sender<notify::Result<Event>>,
+) -> Result<Box<dyn Watcher>> {
+    let watch_folder = Path::new(&config.watch_folder);
+    let watch_error = |source| BackupWardenError::Watch {
+        path: watch_folder.to_path_buf(),
+        source,
+    };
+    if config.watch_mode == WatchMode::Native {
+        let native =
+            RecommendedWatcher::new(tx.clone(), NotifyConfig::default()).and_then(|mut watcher| {
+                watcher.watch(watch_folder, RecursiveMode::Recursive)?;
+                Ok(watcher)
+            });
+        match native {
+            Ok(watcher) => return Ok(Box::new(watcher)),
+            Err(e) => {
+                warn!(event = "native_watch_failed", error = %e, "Native change notifications unavailable, polling instead")
+            }
+        }
+    }
+
+    // Hashing every file on each rescan is far too costly over the network,
+    // so there changes are told by size and mtime alone
+    let network = config.network_profile();
+    if network {
+        info!(
+            event = "network_profile",
+            "Using the network profile: rescanning every {} minutes by size and mtime",
+            config.poll_interval().as_secs() / 60
+        );
+    }
     let mut watcher = PollWatcher::new(
         tx,
         NotifyConfig::default()
-            .with_poll_interval(Duration::from_secs(3600))
-            .with_compare_contents(true),
+            .with_poll_interval(config.poll_interval())
+            .with_compare_contents(!network),
     )
-    .expect("Failed to create PollWatcher");
-
+    .map_err(watch_error)?;
     watcher
-        .watch(Path::new(&config.watch_folder), RecursiveMode::Recursive)
-        .expect("Failed to watch folder");
+        .watch(watch_folder, RecursiveMode::Recursive)
+        .map_err(watch_error)?;
+    Ok(Box::new(watcher))
+}
 
-    // Check for existing backup folders and create initial backup if none exist
-    if !backup_folders_exist(&config) {
-        println!("No backup folders found, creating initial backup...");
-        backup_folder(&config);
+/// The job `options` picks and the days its `--since`/`--until` cover.
+fn job_window<'a>(
+    config: &'a WardenConfig,
+    options: &RangeOptions,
+) -> Result<(&'a BackupWardenConfig, DateWindow)> {
+    let job = &config.job(options.job.as_deref())?.config;
+    let layout = BackupLayout::new(job).map_err(BackupWardenError::Config)?;
+    Ok((job, options.range.resolve(&layout)?))
+}
+
+fn load_config() -> Result<WardenConfig> {
+    let mut config = WardenConfig::parse(CONFIG)?;
+    logging::init(config.log_format);
+    config.validate()?;
+    for job in &mut config.jobs {
+        for duplicate in job.config.dedupe_backup_locations() {
+            warn!(event = "duplicate_location", job = %job.name, location = %duplicate, "Ignoring duplicate backup location");
+        }
+    }
+    Ok(config)
+}
+
+fn apply_priority(config: &WardenConfig) {
+    if config.low_priority {
+        if let Err(e) = priority::lower_priority() {
+            warn!(event = "priority_failed", error = %e, "Failed to lower process priority");
+        }
+    }
+}
+
+/// Runs `run_once` for every job and returns the worst exit code.
+fn run_all_once(config: &WardenConfig) -> Result<i32> {
+    let mut worst = 0;
+    for job in &config.jobs {
+        let _span = info_span!("job", job = %job.name).entered();
+        worst = worst.max(run_once(&job.config, false)?);
+    }
+    Ok(worst)
+}
+
+/// Parses the `[--full] [--job NAME]` of `backup-now`.
+fn parse_backup_now(args: &[String]) -> Result<(Option<&str>, bool)> {
+    let usage = || {
+        BackupWardenError::Usage(
+            "Usage: backup-warden backup-now [--full] [--job NAME]".to_string(),
+        )
+    };
+    let mut job = None;
+    let mut full = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--full" => full = true,
+            "--job" => job = Some(args.next().ok_or_else(usage)?.as_str()),
+            _ => return Err(usage()),
+        }
     }
+    Ok((job, full))
+}
 
+/// Makes a single backup and returns the process exit code: non-zero when the
+/// backup could not run or failed for every location. A `full` backup copies
+/// everything into every location, even where `skip_unchanged` finds nothing
+/// changed.
+fn run_once(config: &BackupWardenConfig, full: bool) -> Result<i32> {
+    match config.mode {
+        WardenMode::Backup => (),
+        WardenMode::VerifyOnly => return Ok(if verify_once(config)? { 0 } else { 1 }),
+        WardenMode::PerFileVersions => {
+            let filter = PathFilter::new(config).map_err(BackupWardenError::Config)?;
+            return Ok(if store_changed_versions(config, &filter) {
+                0
+            } else {
+                1
+            });
+        }
+    }
+    lock::clear_stale_locks(&config.backup_locations);
+    Ok(match backup_with_timeout(config, None, full)? {
+        Some(outcome) => {
+            handle_outcome(config, "Backup", &outcome);
+            if outcome.is_total_failure() || outcome.aborted {
+                1
+            } else {
+                0
+            }
+        }
+        None => 1,
+    })
+}
+
+/// Re-checks the job's backups every `verify_interval_secs`, forever, without
+/// watching or writing anything.
+fn verify_job(config: &BackupWardenConfig, slots: &BackupSlots) -> Result<()> {
+    let mut age_alerted = HashSet::new();
     loop {
-        match rx.recv_timeout(Duration::from_secs(60)) {
-            Ok(Ok(event)) => {
-                handle_event(&event, &config);
+        {
+            let _slot = wait_for_slot(slots);
+            verify_once(config)?;
+        }
+        // Backup ages are checked more often than the backups are verified
+        let verified = Instant::now();
+        while let Some(left) = config.verify_interval().checked_sub(verified.elapsed()) {
+            check_backup_age(config, &mut age_alerted);
+            std::thread::sleep(left.min(IDLE_TIMEOUT));
+        }
+    }
+}
+
+/// Keeps a version of each file that changes, in every location, forever. Saves
+/// are coalesced by `min_backup_interval_secs` like backups, and files changed
+/// while the warden wasn't running are picked up when it starts.
+fn versions_job(
+    config: &BackupWardenConfig,
+    slots: &BackupSlots,
+    ignored: &[PathBuf],
+) -> Result<()> {
+    let filter = PathFilter::new(config).map_err(BackupWardenError::Config)?;
+    let (tx, rx) = channel();
+    let _watcher = start_watcher(config, tx)?;
+    let mut throttle = BackupThrottle::new(Duration::from_secs(config.min_backup_interval_secs));
+    let mut pause = PauseWatch::new(config.control_dir());
+    let mut changed = BTreeSet::new();
+    if pause.check() {
+        throttle.request();
+    } else {
+        let _slot = wait_for_slot(slots);
+        store_changed_versions(config, &filter);
+    }
+
+    loop {
+        let timeout = throttle
+            .time_until_ready(Instant::now())
+            .map_or(IDLE_TIMEOUT, |wait| wait.min(IDLE_TIMEOUT));
+        let mut collect = |result: notify::Result<Event>| match result {
+            Ok(event) => {
+                if !watch::is_ignored(&event.paths, ignored)
+                    && !watch::is_own_write(&event.paths)
+                    && watch::matches_extensions(&event.paths, config.watch_extensions.as_deref())
+                    && watch::triggers_backup(config.watch_events.as_deref(), &event.kind)
+                {
+                    changed.extend(event.paths);
+                    throttle.request();
+                }
             }
-            Ok(Err(e)) => println!("Watch error: {:?}", e),
-            Err(_) => (),
+            Err(e) => warn!(event = "watch_error", error = %e, "Watch error"),
+        };
+        if let Ok(result) = rx.recv_timeout(timeout) {
+            collect(result);
+        }
+        while let Ok(result) = rx.try_recv() {
+            collect(result);
         }
 
-        // Check if today is the last day of the month and create a monthly snapshot
-        let today = Local::now().date_naive();
-        if is_last_day_of_month(today) {
-            create_monthly_snapshot(&config, today);
+        if pause.check() {
+            if throttle.is_ready(Instant::now()) {
+                throttle.postpone(Instant::now() + IDLE_TIMEOUT);
+            }
+            continue;
+        }
+        if throttle.is_ready(Instant::now()) {
+            let _slot = wait_for_slot(slots);
+            if changed.is_empty() {
+                // Requested while paused at startup
+                store_changed_versions(config, &filter);
+            } else {
+                store_versions(config, &filter, &std::mem::take(&mut changed));
+            }
+            throttle.mark_done(Instant::now());
+        }
+    }
+}
+
+/// Stores a new version of each of the `changed` paths that is still a file
+/// the filter lets through, in every location.
+fn store_versions(config: &BackupWardenConfig, filter: &PathFilter, changed: &BTreeSet<PathBuf>) {
+    let watch_folder = Path::new(&config.watch_folder);
+    let now = Local::now();
+    let _writing = own_writes(config);
+    for path in changed {
+        let Some(relative) = versions::versioned_path(watch_folder, filter, path) else {
+            continue;
+        };
+        for location in &config.backup_locations {
+            match versions::store_version(Path::new(location), &relative, path, &now) {
+                Ok(Some(version)) => {
+                    info!(event = "version_stored", location = %location, path = %relative.display(), version = %version.display(), "Stored a new version of {}", relative.display())
+                }
+                Ok(None) => (),
+                Err(e) => {
+                    warn!(event = "version_failed", location = %location, path = %relative.display(), error = %e, "Failed to store a version of {}", relative.display())
+                }
+            }
         }
     }
 }
 
-fn backup_folders_exist(config: &BackupWardenConfig) -> bool {
+/// Stores a version of every file that changed since its newest version, in
+/// every location. Returns whether any location succeeded.
+fn store_changed_versions(config: &BackupWardenConfig, filter: &PathFilter) -> bool {
+    let now = Local::now();
+    let _writing = own_writes(config);
+    let mut succeeded = false;
     for location in &config.backup_locations {
-        let past_30_days_path = Path::new(location).join("Past 30 Days");
-        if past_30_days_path.exists() && past_30_days_path.is_dir() {
-            return true;
+        match versions::store_changed(
+            Path::new(&config.watch_folder),
+            Path::new(location),
+            filter,
+            &now,
+        ) {
+            Ok(stored) => {
+                info!(event = "versions_stored", location = %location, stored, "Stored {} new file versions in {}", stored, location);
+                succeeded = true;
+            }
+            Err(e) => {
+                error!(event = "versions_failed", location = %location, error = %e, "Failed to store file versions in {}", location)
+            }
         }
     }
-    false
+    succeeded
 }
 
-fn handle_event(event: &Event, config: &BackupWardenConfig) {
-    match event.kind {
-        EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_) => {
-            backup_folder(config);
+/// Alerts for each location whose newest backup is older than
+/// `alert_if_older_than_hours`, once until it gets a newer backup. Only
+/// watches: it never starts a backup, for setups where something else takes
+/// them.
+fn check_backup_age(config: &BackupWardenConfig, alerted: &mut HashSet<String>) {
+    let Some(hours) = config.alert_if_older_than_hours else {
+        return;
+    };
+    let Ok(layout) = BackupLayout::new(config) else {
+        return;
+    };
+    let stale = schedule::stale_locations(config, &layout, Local::now().naive_local());
+    alerted.retain(|location| stale.iter().any(|(stale, _)| stale == location));
+    for (location, newest) in stale {
+        if !alerted.insert(location.clone()) {
+            continue;
         }
-        _ => (),
+        let message = match newest {
+            Some(taken) => format!(
+                "Newest backup in {} was taken {}, more than {} hours ago",
+                location,
+                taken.format("%Y-%m-%d %H:%M"),
+                hours
+            ),
+            None => format!(
+                "{} has no backups, though one is expected every {} hours",
+                location, hours
+            ),
+        };
+        warn!(event = "backup_too_old", location = %location, max_age_hours = hours, "{}", message);
+        alert::send(config, &message);
     }
 }
 
-fn backup_folder(config: &BackupWardenConfig) {
-    let now = Local::now();
-    let date = now.format("%Y-%m-%d").to_string();
-    let hour = now.format("%I %p").to_string(); // Format hour as "HH AM/PM"
+/// Checks every backup against its manifest, alerting when any is corrupt.
+/// Returns whether all of them were intact.
+fn verify_once(config: &BackupWardenConfig) -> Result<bool> {
+    let report = verify::verify_backups(config, &DateWindow::default())?;
+    if report.corrupt > 0 {
+        alert::send(
+            config,
+            &format!(
+                "{} of {} verified backup(s) are corrupt",
+                report.corrupt, report.verified
+            ),
+        );
+    }
+    Ok(report.corrupt == 0)
+}
 
-    for location in &config.backup_locations {
-        let daily_path = Path::new(location).join("Past 30 Days").join(&date);
-        let backup_path = daily_path.join(format!("@{}", hour));
-        fs::create_dir_all(&backup_path).expect("Failed to create backup directory");
+/// Runs a backup, or keeps it pending for a retry if another run holds the lock.
+/// The `changes` seen so far go into it, and collecting starts over once it ran.
+fn run_backup(
+    config: &BackupWardenConfig,
+    slots: &BackupSlots,
+    throttle: &mut BackupThrottle,
+    changes: &mut ChangedPaths,
+) -> Result<()> {
+    let _slot = wait_for_slot(slots);
+    match backup_with_timeout(config, Some(changes), false)? {
+        Some(outcome) => {
+            throttle.mark_done(Instant::now());
+            handle_outcome(config, "Backup", &outcome);
+            changes.reset(outcome.backups);
+        }
+        None => throttle.postpone(Instant::now() + LOCK_RETRY_DELAY),
+    }
+    Ok(())
+}
+
+/// Runs `backup_folder`, giving up after `backup_timeout_secs` so a hung mount
+/// can't wedge the job. On timeout the copy is told to stop before its next
+/// file and left to wind down on its own; it keeps the run lock until it does,
+/// so later backups wait for it rather than racing it. Returns `None` when the
+/// backup didn't finish, like one that couldn't take the lock.
+fn backup_with_timeout(
+    config: &BackupWardenConfig,
+    changes: Option<&ChangedPaths>,
+    full: bool,
+) -> Result<Option<BackupOutcome>> {
+    let Some(timeout) = config.backup_timeout_secs.map(Duration::from_secs) else {
+        return backup_folder(config, None, changes, full);
+    };
+    let cancel = Arc::new(AtomicBool::new(false));
+    let (tx, rx) = channel();
+    let worker_config = config.clone();
+    let worker_cancel = cancel.clone();
+    let worker_changes = changes.cloned();
+    let span = tracing::Span::current();
+    std::thread::Builder::new()
+        .name("backup".to_string())
+        .spawn(move || {
+            let _span = span.entered();
+            let _ = tx.send(backup_folder(
+                &worker_config,
+                Some(worker_cancel),
+                worker_changes.as_ref(),
+                full,
+            ));
+        })
+        .map_err(BackupWardenError::Spawn)?;
 
-        copy_dir_all(&config.watch_folder, &backup_path).expect("Failed to copy files");
+    match rx.recv_timeout(timeout) {
+        Ok(outcome) => outcome,
+        Err(RecvTimeoutError::Timeout) => {
+            cancel.store(true, Ordering::Relaxed);
+            error!(
+                event = "backup_timed_out",
+                timeout_secs = timeout.as_secs(),
+                "Backup did not finish within {}s, cancelling it",
+                timeout.as_secs()
+            );
+            alert::send(
+                config,
+                &format!(
+                    "Backup did not finish within {}s and was cancelled",
+                    timeout.as_secs()
+                ),
+            );
+            Ok(None)
+        }
+        // The worker panicked; its panic message is already logged
+        Err(RecvTimeoutError::Disconnected) => Ok(None),
     }
+}
 
-    cleanup_old_backups(config);
+/// Claims a backup slot, logging when the job has to queue behind other jobs.
+fn wait_for_slot(slots: &BackupSlots) -> BackupSlot<'_> {
+    slots.try_acquire().unwrap_or_else(|| {
+        info!(
+            event = "backup_queued",
+            "Waiting for other jobs' backups to finish"
+        );
+        slots.acquire()
+    })
 }
 
-fn create_monthly_snapshot(config: &BackupWardenConfig, date: chrono::NaiveDate) {
-    let date_str = date.format("%Y-%m-%d").to_string();
+/// Whether `location` is a failover location the run can leave alone, having
+/// succeeded for every mirror location so far.
+fn failover_not_needed(
+    config: &BackupWardenConfig,
+    location: &str,
+    outcome: &BackupOutcome,
+) -> bool {
+    if config.location_role(location) != LocationRole::Failover {
+        return false;
+    }
+    if outcome.failed > 0 {
+        warn!(event = "failover_used", location = %location, "Writing failover location {}, since a mirror location failed", location);
+        return false;
+    }
+    info!(event = "failover_skipped", location = %location, "Skipping failover location {}, every mirror location succeeded", location);
+    true
+}
 
-    for location in &config.backup_locations {
-        let monthly_snapshots_path = Path::new(location)
-            .join("Monthly Snapshots")
-            .join(&date_str);
-        fs::create_dir_all(&monthly_snapshots_path)
-            .expect("Failed to create monthly snapshot directory");
+/// Applies the on_total_failure policy when a run failed for every location.
+fn handle_outcome(config: &BackupWardenConfig, what: &str, outcome: &BackupOutcome) {
+    if !outcome.is_total_failure() {
+        return;
+    }
 
-        copy_dir_all(&config.watch_folder, &monthly_snapshots_path)
-            .expect("Failed to copy files to monthly snapshot");
+    let message = format!(
+        "{} failed for all {} backup locations",
+        what, outcome.failed
+    );
+    match config.on_total_failure {
+        TotalFailurePolicy::Continue => error!(event = "total_failure", "{}", message),
+        TotalFailurePolicy::Exit => {
+            error!(event = "total_failure", "{}, exiting", message);
+            std::process::exit(1);
+        }
+        TotalFailurePolicy::Alert => alert::send(config, &message),
     }
 }
 
-fn copy_dir_all(src: &str, dst: &Path) -> std::io::Result<()> {
-    fs::create_dir_all(dst)?;
+/// Requests a backup for `event` when it is one the job watches for. Whatever
+/// it changed goes into `changes`, even when it triggers no backup, since the
+/// next backup has to include it.
+fn handle_event(
+    event: &Event,
+    config: &BackupWardenConfig,
+    ignored: &[PathBuf],
+    throttle: &mut BackupThrottle,
+    changes: &mut ChangedPaths,
+) {
+    if watch::is_ignored(&event.paths, ignored) || watch::is_own_write(&event.paths) {
+        return;
+    }
+    changes.record(event);
+    if !watch::matches_extensions(&event.paths, config.watch_extensions.as_deref()) {
+        return;
+    }
+    if watch::triggers_backup(config.watch_events.as_deref(), &event.kind) {
+        throttle.request();
+    }
+}
 
-    for entry in fs::read_dir(src)? {
-        let entry = entry?;
-        let path = entry.path();
-        let dest_path = dst.join(entry.file_name());
+/// Marks every location and the staging folder as being written by the warden
+/// until the returned guard is dropped, so no job's watcher takes the writes
+/// for changes.
+fn own_writes(config: &BackupWardenConfig) -> watch::SelfWriteGuard {
+    watch::writing_into(
+        config
+            .backup_locations
+            .iter()
+            .chain(&config.staging_dir)
+            .map(PathBuf::from),
+    )
+}
 
-        if path.is_dir() {
-            copy_dir_all(&path.to_string_lossy(), &dest_path)?;
+/// Backs up watch_folder into every location. Returns `None` without copying
+/// anything if another backup currently holds the run lock. With
+/// `selective_backups`, a location whose last backup the `changes` are known
+/// since only gets the changed files copied. A `full` backup skips neither.
+/// With `striping` one backup is spread across all the locations instead.
+fn backup_folder(
+    config: &BackupWardenConfig,
+    cancel: Option<Arc<AtomicBool>>,
+    changes: Option<&ChangedPaths>,
+    full: bool,
+) -> Result<Option<BackupOutcome>> {
+    let _lock = match RunLock::acquire(&config.backup_locations) {
+        Ok(lock) => lock,
+        Err(e) => {
+            info!(event = "backup_postponed", reason = %e, "Backup postponed");
+            return Ok(None);
+        }
+    };
+    let _writing = own_writes(config);
+    let filter = PathFilter::new(config).map_err(BackupWardenError::Config)?;
+    let layout = BackupLayout::new(config).map_err(BackupWardenError::Config)?;
+    let signing_key = config.signing_key()?;
+    let now = Local::now();
+    // Before cleanup, so a runaway source can't push good backups out
+    if let Some(reason) = source_over_limits(config, &filter) {
+        audit::record(config, "backup", AuditOutcome::Aborted, &reason);
+        alert::send(config, &format!("Backup aborted: {}", reason));
+        return Ok(Some(BackupOutcome {
+            aborted: true,
+            ..Default::default()
+        }));
+    }
+    let cleanup_first = match config.cleanup_order {
+        CleanupOrder::After => false,
+        CleanupOrder::Before => true,
+        CleanupOrder::AsNeeded => low_on_space(config, &filter),
+    };
+    if cleanup_first {
+        cleanup_old_backups(config)?;
+    }
+    // Before the shadow copy, so whatever the hook writes is part of it
+    alert::pre_backup_hook(config, now.date_naive());
+    let shadow = shadow_copy(config);
+    let source = shadow
+        .as_ref()
+        .map_or(&*config.watch_folder, ShadowCopy::source);
+
+    let mut outcome = BackupOutcome::default();
+    let mut added = Vec::new();
+    let started = Instant::now();
+    let mut summaries = Vec::new();
+    // Even a full backup stores the index, for the next ones to compare with
+    let index = source_index(config, &layout, source, &filter);
+    if full {
+        info!(
+            event = "full_backup",
+            "Full backup requested, copying into every location"
+        );
+    }
+    for location in backup_targets(config) {
+        if failover_not_needed(config, location, &outcome) {
+            continue;
+        }
+        if let Some(index) = index
+            .as_ref()
+            .filter(|index| !full && unchanged_since_last_backup(&layout, location, index))
+        {
+            info!(event = "backup_skipped_unchanged", location = %location, files = index.files.len(), "Nothing changed since the last backup into {}, skipping it", location);
+            if let Some((_, base)) = changes.and_then(|changes| changes.since(location)) {
+                outcome.backups.insert(location.clone(), base.to_path_buf());
+            }
+            outcome.succeeded += 1;
+            continue;
+        }
+        let selective = changes
+            .and_then(|changes| selective_backup(config, &layout, location, source, changes, &now));
+        let location_started = Instant::now();
+        let mut sink: Box<dyn BackupSink> = if config.striping {
+            Box::new(striped_sink(config, &layout, &signing_key))
         } else {
-            fs::copy(&path, &dest_path)?;
+            Box::new(location_sink(config, location, &layout, &signing_key))
+        };
+        let options = location_options(config, &backup_parts(config, location), &filter);
+        let options = match &cancel {
+            Some(cancel) => options.with_cancel(cancel.clone()),
+            None => options,
+        };
+        let mut attempt = || {
+            backup_to_location(
+                config,
+                source,
+                location,
+                sink.as_mut(),
+                &now,
+                &options,
+                index.as_ref(),
+                selective.as_ref(),
+            )
+        };
+        let mut result = attempt();
+        if let Some(delay) = retry_delay(config, location, &result, cancel.as_deref()) {
+            std::thread::sleep(delay);
+            result = attempt();
+        }
+        let stats = result.as_ref().ok();
+        alert::post_backup_hook(
+            config,
+            now.date_naive(),
+            &alert::BackupReport {
+                path: &layout.backup_path(Path::new(location), &now),
+                succeeded: stats.is_some(),
+                files_copied: stats.map_or(0, |stats| stats.files_copied),
+                bytes: stats.map_or(0, |stats| stats.bytes_copied),
+            },
+        );
+        summaries.push(LocationSummary {
+            location: location.clone(),
+            succeeded: stats.is_some(),
+            files: stats.map_or(0, |stats| stats.files_copied),
+            bytes: stats.map_or(0, |stats| stats.bytes_copied),
+            duration_secs: location_started.elapsed().as_secs_f64(),
+            errors: stats.map_or(0, |stats| stats.errors),
+            error_counts: stats.map_or_else(Default::default, |stats| stats.error_counts),
+            error: result.as_ref().err().map(|e| e.to_string()),
+        });
+        match result {
+            Ok(stats) => {
+                let bytes = stats.bytes_copied;
+                audit::record(
+                    config,
+                    "backup",
+                    AuditOutcome::Ok,
+                    &format!("into {}, {} copied", sink.name(), format_bytes(bytes)),
+                );
+                let parts = backup_parts(config, location);
+                // Files are spread by hash, so the parts grow about evenly
+                let part_bytes = bytes / parts.len() as u64;
+                for part in parts {
+                    added.push((part, part_bytes));
+                    outcome
+                        .backups
+                        .insert(part.clone(), layout.backup_path(Path::new(part), &now));
+                }
+                outcome.succeeded += 1;
+            }
+            Err(source) => {
+                let e = BackupWardenError::Copy {
+                    location: location.clone(),
+                    source,
+                };
+                error!(event = "backup_failed", location = %location, error = %e, "Backup failed");
+                audit::record(config, "backup", AuditOutcome::Failed, &e.to_string());
+                outcome.failed += 1;
+            }
         }
     }
-    Ok(())
+
+    drop(shadow);
+    if !cleanup_first {
+        cleanup_old_backups(config)?;
+    }
+    for (location, bytes) in added {
+        record_usage(location, bytes);
+    }
+    report::record(
+        config,
+        &BackupSummary {
+            started_at: now.to_rfc3339(),
+            finished_at: Local::now().to_rfc3339(),
+            watch_folder: config.watch_folder.clone(),
+            duration_secs: started.elapsed().as_secs_f64(),
+            locations: summaries,
+        },
+    );
+    Ok(Some(outcome))
 }
 
-fn cleanup_old_backups(config: &BackupWardenConfig) {
-    for location in &config.backup_locations {
-        let past_30_days_path = Path::new(location).join("Past 30 Days");
-        let mut daily_folders: Vec<_> = fs::read_dir(&past_30_days_path)
-            .expect("Failed to read backup directory")
-            .filter_map(Result::ok)
-            .filter(|e| e.file_type().map(|ft| ft.is_dir()).unwrap_or(false))
-            .collect();
+/// How long to wait before backing up into `location` once more, with
+/// `retry_backup_on_partial`, when the first attempt failed or left files out.
+/// The retry goes into the same backup folder, so a failed attempt resumes
+/// from its ledger and only the files still missing are copied.
+fn retry_delay(
+    config: &BackupWardenConfig,
+    location: &str,
+    result: &std::io::Result<CopyStats>,
+    cancel: Option<&AtomicBool>,
+) -> Option<Duration> {
+    let delay = config.partial_retry_delay()?;
+    if cancel.is_some_and(|cancel| cancel.load(Ordering::Relaxed)) {
+        return None;
+    }
+    let reason = match result {
+        Ok(stats) if stats.files_failed > 0 => {
+            format!("{} files were left out", stats.files_failed)
+        }
+        Ok(_) => return None,
+        Err(e) => e.to_string(),
+    };
+    warn!(event = "backup_retrying", location = %location, reason = %reason, delay_secs = delay.as_secs(), "Backup into {} was partial ({}), retrying in {}s", location, reason, delay.as_secs());
+    Some(delay)
+}
 
-        daily_folders.sort_by_key(|entry| entry.path());
+/// Why the watch folder is too big to back up under `max_source_bytes` and
+/// `max_source_files`, or `None` when it is within them or they aren't set.
+fn source_over_limits(config: &BackupWardenConfig, filter: &PathFilter) -> Option<String> {
+    if config.max_source_bytes.is_none() && config.max_source_files.is_none() {
+        return None;
+    }
+    let totals = match size::source_totals(Path::new(&config.watch_folder), filter) {
+        Ok(totals) => totals,
+        Err(e) => {
+            // The copy itself will report the folder being unreadable
+            warn!(event = "source_scan_failed", error = %e, "Failed to measure watch folder");
+            return None;
+        }
+    };
+    if let Some(max) = config.max_source_bytes.filter(|max| totals.bytes > *max) {
+        return Some(format!(
+            "watch_folder holds {}, more than max_source_bytes ({})",
+            format_bytes(totals.bytes),
+            format_bytes(max)
+        ));
+    }
+    if let Some(max) = config.max_source_files.filter(|max| totals.files > *max) {
+        return Some(format!(
+            "watch_folder holds {} files, more than max_source_files ({})",
+            totals.files, max
+        ));
+    }
+    None
+}
+
+/// Whether any location has less free space than a full copy of what
+/// `filter` lets through of watch_folder needs.
+fn low_on_space(config: &BackupWardenConfig, filter: &PathFilter) -> bool {
+    let Ok(SourceTotals { bytes: needed, .. }) =
+        size::source_totals(Path::new(&config.watch_folder), filter)
+    else {
+        return false;
+    };
+    config
+        .backup_locations
+        .iter()
+        .any(|location| disk::free_space(Path::new(location)).is_ok_and(|free| free < needed))
+}
+
+/// Shadows the watch folder's volume when `use_vss` is set. If that fails
+/// (it needs administrator rights) the backup copies the live files instead.
+fn shadow_copy(config: &BackupWardenConfig) -> Option<ShadowCopy> {
+    if !config.use_vss {
+        return None;
+    }
+    match ShadowCopy::create(&config.watch_folder) {
+        Ok(shadow) => Some(shadow),
+        Err(e) => {
+            warn!(event = "vss_unavailable", error = %e, "Shadow copy failed, copying live files");
+            None
+        }
+    }
+}
+
+/// Remembers how much space `location` uses now that the backup and cleanup
+/// are done, for the growth estimate in `status`.
+fn record_usage(location: &str, added_bytes: u64) {
+    let path = Path::new(location);
+    let used_bytes = match directory_size(path) {
+        Ok(used_bytes) => used_bytes,
+        Err(e) => {
+            warn!(event = "usage_record_failed", location = %location, error = %e, "Failed to measure backup location");
+            return;
+        }
+    };
+    let sample = UsageSample {
+        at: Local::now().to_rfc3339(),
+        added_bytes,
+        used_bytes,
+    };
+    if let Err(e) = WardenState::update(path, |state| state.record_usage(sample)) {
+        warn!(event = "state_save_failed", location = %location, error = %e, "Failed to save state");
+    }
+}
+
+/// A backup that copies only the files `selection` covers into `dest`, with
+/// everything else linked from `base`.
+struct SelectiveBackup {
+    selection: Selection,
+    base: PathBuf,
+    dest: PathBuf,
+}
+
+/// How to back `location` up selectively with `selective_backups`, or `None`
+/// for a full backup: `changes` must start from a finished backup of it, and
+/// the new backup must go either into that folder or into one not started
+/// yet. Layouts where a file's name in the backup depends on more than its own
+/// path always get a full backup.
+fn selective_backup(
+    config: &BackupWardenConfig,
+    layout: &BackupLayout,
+    location: &str,
+    source: &str,
+    changes: &ChangedPaths,
+    now: &DateTime<Local>,
+) -> Option<SelectiveBackup> {
+    if !config.selective_backups
+        || config.split_top_level
+        || config.case_collision_policy == CaseCollisionPolicy::Rename
+    {
+        return None;
+    }
+    let (changed, base) = changes.since(location)?;
+    if !base.is_dir() || base.join(LEDGER_FILE_NAME).exists() {
+        return None;
+    }
+    let dest = layout.backup_path(Path::new(location), now);
+    if dest != base && dest.exists() {
+        return None;
+    }
+    // Events name paths under the watch folder, while a shadow copy is read instead
+    let watch_folder = Path::new(&config.watch_folder);
+    let changed: BTreeSet<PathBuf> = changed
+        .iter()
+        .filter_map(|path| path.strip_prefix(watch_folder).ok())
+        .map(|relative| Path::new(source).join(relative))
+        .collect();
+    Some(SelectiveBackup {
+        selection: Selection::new(&changed, Path::new(source), base),
+        base: base.to_path_buf(),
+        dest,
+    })
+}
+
+/// Links the backup `sink` has begun to the base of `selective` and removes
+/// from it the changed files that are gone. Into the base itself only the
+/// removals are needed. Returns whether the selective backup can go ahead.
+fn begin_selective(
+    sink: &mut dyn BackupSink,
+    location: &str,
+    source: &str,
+    selective: &SelectiveBackup,
+) -> std::io::Result<bool> {
+    let base = &selective.base;
+    if selective.dest != *base {
+        if let Err(e) = sink.link_from(base) {
+            warn!(event = "selective_backup_unavailable", location = %location, error = %e, "Can't link the last backup into {}, taking a full backup", location);
+            return Ok(false);
+        }
+    }
+    for removed in selective.selection.removed(Path::new(source)) {
+        sink.remove(removed)?;
+    }
+    info!(event = "selective_backup", location = %location, base = %base.display(), "Copying only the changed files into {}", location);
+    Ok(true)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn backup_to_location(
+    config: &BackupWardenConfig,
+    source: &str,
+    location: &str,
+    sink: &mut dyn BackupSink,
+    now: &DateTime<Local>,
+    options: &CopyOptions,
+    index: Option<&FileIndex>,
+    selective: Option<&SelectiveBackup>,
+) -> std::io::Result<CopyStats> {
+    sink.begin_backup(now)?;
+    let started = Instant::now();
+    let selective_options;
+    let options = match selective {
+        Some(selective) if begin_selective(sink, location, source, selective)? => {
+            selective_options = options.clone().with_selection(&selective.selection);
+            &selective_options
+        }
+        _ => options,
+    };
+    let stats = copy_backup(config, source, sink, options, index)?;
+    record_metrics(location, "Backup", started, &stats, |state, metrics| {
+        state.last_backup = Some(metrics)
+    });
+
+    if config.maintain_mirror {
+        let mirror_path = Path::new(location).join(MIRROR_DIR_NAME);
+        sync_mirror(
+            Path::new(source),
+            &mirror_path,
+            options.filter,
+            config.incremental_compare,
+        )?;
+    }
+    Ok(stats)
+}
+
+/// Takes the monthly snapshot into every location, then removes the monthly
+/// snapshots `monthly_retention_mode` no longer keeps from the locations it
+/// succeeded in.
+fn create_monthly_snapshot(
+    config: &BackupWardenConfig,
+    date: chrono::NaiveDate,
+) -> Result<Option<BackupOutcome>> {
+    create_snapshot(config, date, SnapshotKind::Monthly)
+}
 
-        if daily_folders.len() > config.retention_days {
-            let excess = daily_folders.len() - config.retention_days;
-            for entry in &daily_folders[..excess] {
-                fs::remove_dir_all(entry.path()).expect("Failed to remove old backup");
+/// Takes the weekly snapshot into every location, then removes the weekly
+/// snapshots beyond `weekly_retention` from the locations it succeeded in.
+fn create_weekly_snapshot(
+    config: &BackupWardenConfig,
+    date: chrono::NaiveDate,
+) -> Result<Option<BackupOutcome>> {
+    create_snapshot(config, date, SnapshotKind::Weekly)
+}
+
+fn create_snapshot(
+    config: &BackupWardenConfig,
+    date: chrono::NaiveDate,
+    kind: SnapshotKind,
+) -> Result<Option<BackupOutcome>> {
+    let _lock = match RunLock::acquire(&config.backup_locations) {
+        Ok(lock) => lock,
+        Err(e) => {
+            info!(event = "snapshot_postponed", kind = kind.label(), reason = %e, "{} postponed", kind.label());
+            return Ok(None);
+        }
+    };
+    let _writing = own_writes(config);
+    let filter = PathFilter::new(config).map_err(BackupWardenError::Config)?;
+    let layout = BackupLayout::new(config).map_err(BackupWardenError::Config)?;
+    let signing_key = config.signing_key()?;
+    let date_str = date.format("%Y-%m-%d").to_string();
+    let shadow = shadow_copy(config);
+    let source = shadow
+        .as_ref()
+        .map_or(&*config.watch_folder, ShadowCopy::source);
+
+    let mut outcome = BackupOutcome::default();
+    for location in backup_targets(config) {
+        if failover_not_needed(config, location, &outcome) {
+            continue;
+        }
+        let snapshot_relative = Path::new(kind.dir_name()).join(&date_str);
+        let parts = backup_parts(config, location);
+        let started = Instant::now();
+        let dailies = (kind == SnapshotKind::Monthly && config.link_monthly_from_daily)
+            .then(|| {
+                parts
+                    .iter()
+                    .map(|part| newest_finished_backup(&layout, Path::new(part), date))
+                    .collect::<Option<Vec<_>>>()
+            })
+            .flatten()
+            // The parts of a striped snapshot must come from the same backup
+            .filter(|dailies| {
+                let mut relative = dailies
+                    .iter()
+                    .zip(&parts)
+                    .map(|(daily, part)| daily.strip_prefix(part).ok());
+                let first = relative.next().flatten();
+                relative.all(|other| other == first)
+            });
+        let linked = dailies.and_then(|dailies| {
+            for (daily, part) in dailies.iter().zip(&parts) {
+                if let Err(e) = link_tree(daily, &Path::new(part).join(&snapshot_relative)) {
+                    warn!(event = "snapshot_link_failed", location = %part, error = %e, "Failed to link monthly snapshot from the daily backup, copying instead");
+                    for part in &parts {
+                        let _ = fs::remove_dir_all(Path::new(part).join(&snapshot_relative));
+                    }
+                    return None;
+                }
+            }
+            info!(event = "snapshot_linked", location = %location, from = %dailies[0].display(), "Monthly snapshot linked from {}", dailies[0].display());
+            Some(())
+        });
+        let result = match linked {
+            Some(()) => Ok(CopyStats::default()),
+            None if config.striping => {
+                let mut sink = striped_sink(config, &layout, &signing_key);
+                sink.begin_folder(&snapshot_relative).and_then(|()| {
+                    copy_backup(
+                        config,
+                        source,
+                        &mut sink,
+                        &location_options(config, &parts, &filter),
+                        None,
+                    )
+                })
             }
+            None => {
+                let mut sink = location_sink(config, location, &layout, &signing_key);
+                sink.begin_folder(Path::new(location).join(&snapshot_relative))
+                    .and_then(|()| {
+                        copy_backup(
+                            config,
+                            source,
+                            &mut sink,
+                            &location_options(config, &parts, &filter),
+                            None,
+                        )
+                    })
+            }
+        };
+        match result {
+            Ok(stats) => {
+                record_metrics(
+                    location,
+                    kind.label(),
+                    started,
+                    &stats,
+                    |state, metrics| match kind {
+                        SnapshotKind::Monthly => {
+                            state.last_snapshot = Some(metrics);
+                            state.last_snapshot_month = Some(schedule::snapshot_month(date));
+                        }
+                        SnapshotKind::Weekly => {
+                            state.last_weekly_snapshot_week = Some(schedule::snapshot_week(date))
+                        }
+                    },
+                );
+                audit::record(
+                    config,
+                    "snapshot",
+                    AuditOutcome::Ok,
+                    &format!("{} {} into {}", kind.dir_name(), date_str, location),
+                );
+                for part in parts {
+                    prune_snapshots(config, part, kind, date);
+                }
+                outcome.succeeded += 1;
+            }
+            Err(source) => {
+                let e = BackupWardenError::Copy {
+                    location: location.clone(),
+                    source,
+                };
+                error!(event = "snapshot_failed", kind = kind.label(), location = %location, error = %e, "{} failed", kind.label());
+                audit::record(config, "snapshot", AuditOutcome::Failed, &e.to_string());
+                outcome.failed += 1;
+            }
+        }
+    }
+    Ok(Some(outcome))
+}
+
+/// Removes the snapshots of `kind` in `location` beyond `weekly_retention` or
+/// `monthly_retention_mode`, as of `date`.
+fn prune_snapshots(
+    config: &BackupWardenConfig,
+    location: &str,
+    kind: SnapshotKind,
+    date: chrono::NaiveDate,
+) {
+    let pruned = match kind {
+        SnapshotKind::Weekly => {
+            schedule::prune_weekly_snapshots(Path::new(location), config.weekly_retention())
+        }
+        SnapshotKind::Monthly => {
+            schedule::cleanup_monthly(Path::new(location), config.monthly_retention(), date)
+        }
+    };
+    match pruned {
+        Ok(removed) => {
+            for path in &removed {
+                info!(event = "snapshot_removed", kind = kind.label(), location = %location, path = %path.display(), "Removed {} {}", kind.label(), path.display());
+                audit::record(
+                    config,
+                    "cleanup",
+                    AuditOutcome::Ok,
+                    &format!("removed {}", path.display()),
+                );
+            }
+            if !removed.is_empty() {
+                collect_chunks(config, location);
+            }
+        }
+        Err(e) => {
+            warn!(event = "snapshot_cleanup_failed", kind = kind.label(), location = %location, error = %e, "Failed to remove old snapshots");
+            audit::record(config, "cleanup", AuditOutcome::Failed, &e.to_string());
+        }
+    }
+}
+
+/// The newest backup taken on `date` in `location` that ran to completion, which
+/// is the newest without a ledger left in it.
+fn newest_finished_backup(
+    layout: &BackupLayout,
+    location: &Path,
+    date: chrono::NaiveDate,
+) -> Option<std::path::PathBuf> {
+    let day_dir = layout
+        .daily_root(location)
+        .join(layout.day_folder_name(date));
+    layout
+        .backups_in_day(&day_dir)
+        .into_iter()
+        .rev()
+        .map(|(_, path)| path)
+        .find(|path| !path.join(LEDGER_FILE_NAME).exists())
+}
+
+/// The sink daily backups and monthly snapshots are written into `location`
+/// through, set up from `config`.
+fn location_sink(
+    config: &BackupWardenConfig,
+    location: &str,
+    layout: &BackupLayout,
+    signing_key: &Option<SigningKey>,
+) -> LocalSink {
+    LocalSink::new(location, layout.clone())
+        .with_copy_buffer_size(config.copy_buffer_size())
+        .with_merkle_root(config.merkle_root)
+        .with_manifest_format(config.manifest_format)
+        .with_reflink(config.allow_reflink())
+        .with_ads(config.copy_ads)
+        .with_attributes(config.preserve_attributes)
+        .with_clear_archive_bit(config.clear_archive_bit)
+        .with_chunking(config.chunking)
+        .with_fsync(config.fsync)
+        .with_incremental_compare(config.incremental_compare)
+        .with_destination_mode(config.destination_mode())
+        .with_signing_key(signing_key.clone())
+        .with_staging_dir(config.staging_dir.as_ref().map(PathBuf::from))
+}
+
+/// The sink a backup is spread across every location through, with
+/// `striping`.
+fn striped_sink(
+    config: &BackupWardenConfig,
+    layout: &BackupLayout,
+    signing_key: &Option<SigningKey>,
+) -> StripedSink {
+    StripedSink::new(
+        config
+            .backup_locations
+            .iter()
+            .map(|location| location_sink(config, location, layout, signing_key))
+            .collect(),
+    )
+}
+
+/// The locations backups are taken into one after another, in write order.
+/// With `striping` a backup goes into all of them at once, so there is only
+/// the first, standing for the rest.
+fn backup_targets(config: &BackupWardenConfig) -> Vec<&String> {
+    if config.striping {
+        config.backup_locations.iter().take(1).collect()
+    } else {
+        config.locations_in_write_order()
+    }
+}
+
+/// The locations a backup taken into the target `location` writes to.
+fn backup_parts<'a>(config: &'a BackupWardenConfig, location: &'a String) -> Vec<&'a String> {
+    if config.striping {
+        config.backup_locations.iter().collect()
+    } else {
+        vec![location]
+    }
+}
+
+/// The copy options for backing up into `locations`, the parts of one backup.
+/// With `monitor_free_space` the copy keeps checking the disks it writes to:
+/// the staging folder, if there is one, or else every location.
+fn location_options<'a>(
+    config: &BackupWardenConfig,
+    locations: &[&String],
+    filter: &'a PathFilter,
+) -> CopyOptions<'a> {
+    let options = CopyOptions::new(config, filter);
+    if !config.monitor_free_space {
+        return options;
+    }
+    let dests = match &config.staging_dir {
+        Some(staging_dir) => vec![PathBuf::from(staging_dir)],
+        None => locations.iter().map(PathBuf::from).collect(),
+    };
+    options.with_free_space_floor(dests, config.min_free_bytes())
+}
+
+/// Copies `source` into the backup `sink` has begun and finishes it, storing
+/// `index` with it when given. Daily backups and monthly snapshots both go
+/// through here, so they apply the same include, exclude, link and depth rules
+/// from `options`.
+fn copy_backup(
+    config: &BackupWardenConfig,
+    source: &str,
+    sink: &mut dyn BackupSink,
+    options: &CopyOptions,
+    index: Option<&FileIndex>,
+) -> std::io::Result<CopyStats> {
+    let stats = copy_dir_all(Path::new(source), options, sink)?;
+    write_config_snapshot(config, sink)?;
+    if let Some(index) = index {
+        index::write_index(sink, index)?;
+    }
+    sink.finish_backup()?;
+    Ok(stats)
+}
+
+/// Records the settings that produced a backup at its root. Written after the
+/// copy, so it replaces any file of the same name from the watch folder.
+fn write_config_snapshot(
+    config: &BackupWardenConfig,
+    sink: &mut dyn BackupSink,
+) -> std::io::Result<()> {
+    let json = serde_json::to_vec_pretty(config).map_err(std::io::Error::other)?;
+    sink.write_contents(Path::new(CONFIG_SNAPSHOT_FILE_NAME), &json)
+}
+
+/// Lists the files in `source` for `skip_unchanged`. `None` when that is off or
+/// the listing failed, in which case every location is backed up. With
+/// `incremental_compare: "hash"` only the files whose size or mtime differ
+/// from the newest backup's index are hashed.
+fn source_index(
+    config: &BackupWardenConfig,
+    layout: &BackupLayout,
+    source: &str,
+    filter: &PathFilter,
+) -> Option<FileIndex> {
+    if !config.skip_unchanged {
+        return None;
+    }
+    let with_hashes = config.incremental_compare == IncrementalCompare::Hash;
+    let previous = with_hashes
+        .then(|| {
+            config
+                .backup_locations
+                .iter()
+                .find_map(|location| newest_index(layout, location))
+        })
+        .flatten();
+    let scanned = FileIndex::scan(
+        Path::new(source),
+        filter,
+        config.symlink_policy == SymlinkPolicy::Follow,
+        with_hashes,
+        previous.as_ref(),
+    );
+    match scanned {
+        Ok(index) => Some(index),
+        Err(e) => {
+            warn!(event = "file_index_failed", error = %e, "Failed to list the watch folder for change detection, backing up anyway");
+            None
         }
     }
 }
 
-fn is_last_day_of_month(date: chrono::NaiveDate) -> bool {
-    let next_day = date + chrono::Duration::days(1);
-    next_day.month() != date.month()
+/// Whether the newest finished backup in `location` was taken of exactly the
+/// files in `index`.
+fn unchanged_since_last_backup(layout: &BackupLayout, location: &str, index: &FileIndex) -> bool {
+    newest_index(layout, location).is_some_and(|previous| previous == *index)
+}
+
+/// The file index of the newest finished backup in `location`, if it has one.
+fn newest_index(layout: &BackupLayout, location: &str) -> Option<FileIndex> {
+    let location = Path::new(location);
+    let backup = layout
+        .newest_day(location)
+        .and_then(|day| newest_finished_backup(layout, location, day))?;
+    index::read_index(&backup).ok().flatten()
+}
+
+/// Logs how long a copy into `location` took and remembers it for `status`.
+fn record_metrics(
+    location: &str,
+    what: &str,
+    started: Instant,
+    stats: &CopyStats,
+    store: impl FnOnce(&mut WardenState, RunMetrics),
+) {
+    let bytes = stats.bytes_copied;
+    let mut metrics = RunMetrics::new(Local::now().to_rfc3339(), started.elapsed(), bytes);
+    metrics.unreadable_dirs = stats
+        .unreadable_dirs
+        .iter()
+        .map(|dir| dir.display().to_string())
+        .collect();
+    if !metrics.unreadable_dirs.is_empty() {
+        warn!(
+            event = "unreadable_dirs_summary",
+            kind = what,
+            location = %location,
+            count = metrics.unreadable_dirs.len(),
+            dirs = %metrics.unreadable_dirs.join(", "),
+            "{} to {} left out {} folder(s) that couldn't be listed",
+            what,
+            location,
+            metrics.unreadable_dirs.len()
+        );
+    }
+    info!(
+        event = "copy_finished",
+        kind = what,
+        location = %location,
+        bytes,
+        files = stats.files_copied,
+        dirs = stats.dirs_created,
+        skipped = stats.files_skipped,
+        errors = stats.errors,
+        error_counts = %stats.error_counts,
+        failed = stats.files_failed,
+        duration_secs = metrics.duration_secs,
+        bytes_per_sec = metrics.bytes_per_sec(),
+        "{} to {} took {:.1}s: {} files, {} at {}/s, {} skipped, {} errors",
+        what,
+        location,
+        metrics.duration_secs,
+        stats.files_copied,
+        format_bytes(bytes),
+        format_bytes(metrics.bytes_per_sec()),
+        stats.files_skipped,
+        stats.errors
+    );
+    if let Err(e) = WardenState::update(Path::new(location), |state| store(state, metrics)) {
+        warn!(event = "state_save_failed", location = %location, error = %e, "Failed to save state");
+    }
+}
+
+/// Removes the chunks only removed backups used from `location`, with
+/// `chunking`. Chunk lists in the staging folder count too, so a staged backup
+/// that was interrupted keeps its chunks.
+fn collect_chunks(config: &BackupWardenConfig, location: &str) {
+    if !config.chunking {
+        return;
+    }
+    let mut roots = vec![Path::new(location)];
+    roots.extend(config.staging_dir.as_deref().map(Path::new));
+    match ChunkStore::new(Path::new(location)).collect_garbage(&roots) {
+        Ok((0, _)) => (),
+        Ok((count, bytes)) => {
+            info!(event = "chunks_removed", location = %location, count, bytes, "Removed {} unused chunk(s), {}", count, format_bytes(bytes))
+        }
+        Err(e) => {
+            warn!(event = "chunk_cleanup_failed", location = %location, error = %e, "Failed to remove unused chunks")
+        }
+    }
+}
+
+/// Runs cleanup on its own, for `cleanup_interval_hours`. Returns whether it
+/// ran; it is postponed while a backup or restore holds the locations.
+fn scheduled_cleanup(config: &BackupWardenConfig) -> Result<bool> {
+    let _lock = match RunLock::acquire(&config.backup_locations) {
+        Ok(lock) => lock,
+        Err(e) => {
+            info!(event = "cleanup_postponed", reason = %e, "Scheduled cleanup postponed");
+            return Ok(false);
+        }
+    };
+    let _writing = own_writes(config);
+    info!(event = "scheduled_cleanup", "Running scheduled cleanup");
+    cleanup_old_backups(config)?;
+    Ok(true)
+}
+
+fn cleanup_old_backups(config: &BackupWardenConfig) -> Result<()> {
+    let layout = BackupLayout::new(config).map_err(BackupWardenError::Config)?;
+    for location in &config.backup_locations {
+        let mut sink = LocalSink::new(location, layout.clone())
+            .with_copy_buffer_size(config.copy_buffer_size());
+        match sink::prune_old_backups(&mut sink, &config.retention(Local::now().date_naive())) {
+            Ok(pruned) => {
+                for backup in &pruned {
+                    audit::record(
+                        config,
+                        "cleanup",
+                        AuditOutcome::Ok,
+                        &format!(
+                            "removed {}, {} freed",
+                            backup.path,
+                            format_bytes(backup.bytes)
+                        ),
+                    );
+                    alert::cleanup_hook(config, backup);
+                }
+                if !pruned.is_empty() {
+                    collect_chunks(config, location);
+                }
+            }
+            Err(source) => {
+                let e = BackupWardenError::Cleanup {
+                    location: location.clone(),
+                    source,
+                };
+                warn!(event = "cleanup_failed", location = %location, error = %e, "Failed to read backup directory");
+                audit::record(config, "cleanup", AuditOutcome::Failed, &e.to_string());
+            }
+        }
+    }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -151,19 +1893,6 @@ mod tests {
     use std::fs::{self};
     use tempfile::tempdir;
 
-    #[test]
-    fn test_is_last_day_of_month() {
-        assert!(is_last_day_of_month(
-            NaiveDate::from_ymd_opt(2024, 1, 31).unwrap()
-        ));
-        assert!(!is_last_day_of_month(
-            NaiveDate::from_ymd_opt(2024, 1, 30).unwrap()
-        ));
-        assert!(is_last_day_of_month(
-            NaiveDate::from_ymd_opt(2024, 2, 29).unwrap()
-        ));
-    }
-
     #[test]
     fn test_backup_folder_creation() {
         let temp_dir = tempdir().unwrap();
@@ -178,9 +1907,10 @@ mod tests {
             watch_folder: watch_folder.to_str().unwrap().to_string(),
             backup_locations: vec![backup_location.to_str().unwrap().to_string()],
             retention_days: 30,
+            ..Default::default()
         };
 
-        backup_folder(&config);
+        backup_folder(&config, None, None, false).unwrap();
 
         let date = Local::now().format("%Y-%m-%d").to_string();
         let daily_path = past_30_days.join(&date);
@@ -188,6 +1918,14 @@ mod tests {
         let backup_path = daily_path.join(format!("@{}", hour));
 
         assert!(backup_path.exists());
+        assert!(WardenState::load(&backup_location).last_backup.is_some());
+
+        let snapshot: BackupWardenConfig = serde_json::from_str(
+            &fs::read_to_string(backup_path.join(CONFIG_SNAPSHOT_FILE_NAME)).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(snapshot.watch_folder, config.watch_folder);
+        assert_eq!(snapshot.retention_days, 30);
     }
 
     #[test]
@@ -210,9 +1948,10 @@ mod tests {
             watch_folder: "dummy".to_string(),
             backup_locations: vec![backup_location.to_str().unwrap().to_string()],
             retention_days: 30,
+            ..Default::default()
         };
 
-        cleanup_old_backups(&config);
+        cleanup_old_backups(&config).unwrap();
 
         let remaining_backups: Vec<_> = fs::read_dir(&past_30_days)
             .unwrap()
@@ -222,6 +1961,304 @@ mod tests {
         assert_eq!(remaining_backups.len(), 30);
     }
 
+    #[test]
+    fn test_scheduled_cleanup_prunes_by_age() {
+        let temp_dir = tempdir().unwrap();
+        let backup_location = temp_dir.path().join("backup_location");
+        let layout_root = backup_location.join("Past 30 Days");
+        let today = Local::now().date_naive();
+        for days_ago in [0, 3, 10] {
+            let day = today - chrono::Duration::days(days_ago);
+            fs::create_dir_all(layout_root.join(day.format("%Y-%m-%d").to_string())).unwrap();
+        }
+        let config = BackupWardenConfig {
+            watch_folder: "dummy".to_string(),
+            backup_locations: vec![backup_location.to_str().unwrap().to_string()],
+            retention_mode: backup_warden::config::RetentionMode::Age,
+            max_age_days: Some(7),
+            cleanup_interval_hours: Some(6),
+            ..Default::default()
+        };
+
+        assert!(scheduled_cleanup(&config).unwrap());
+        assert_eq!(fs::read_dir(&layout_root).unwrap().count(), 2);
+
+        let _held = RunLock::acquire(&config.backup_locations).unwrap();
+        assert!(!scheduled_cleanup(&config).unwrap());
+    }
+
+    #[test]
+    fn test_backup_folder_applies_patterns() {
+        let temp_dir = tempdir().unwrap();
+        let watch_folder = temp_dir.path().join("watch_folder");
+        let backup_location = temp_dir.path().join("backup_location");
+
+        fs::create_dir_all(watch_folder.join("docs")).unwrap();
+        fs::create_dir_all(watch_folder.join("cache")).unwrap();
+        fs::write(watch_folder.join("docs").join("report.docx"), b"report").unwrap();
+        fs::write(watch_folder.join("docs").join("notes.txt"), b"notes").unwrap();
+        fs::write(watch_folder.join("cache").join("data.docx"), b"cached").unwrap();
+
+        let config = BackupWardenConfig {
+            watch_folder: watch_folder.to_str().unwrap().to_string(),
+            backup_locations: vec![backup_location.to_str().unwrap().to_string()],
+            retention_days: 30,
+            include_patterns: vec!["**/*.docx".to_string()],
+            exclude_patterns: vec!["cache".to_string()],
+            ..Default::default()
+        };
+
+        backup_folder(&config, None, None, false).unwrap();
+
+        let date = Local::now().format("%Y-%m-%d").to_string();
+        let hour = Local::now().format("%I %p").to_string();
+        let backup_path = backup_location
+            .join("Past 30 Days")
+            .join(&date)
+            .join(format!("@{}", hour));
+
+        assert!(backup_path.join("docs").join("report.docx").exists());
+        assert!(!backup_path.join("docs").join("notes.txt").exists());
+        assert!(!backup_path.join("cache").exists());
+    }
+
+    #[test]
+    fn test_backup_folder_reports_failed_locations() {
+        let temp_dir = tempdir().unwrap();
+        let watch_folder = temp_dir.path().join("watch_folder");
+        let good_location = temp_dir.path().join("good");
+        // A regular file where a directory is expected makes the location unusable
+        let bad_location = temp_dir.path().join("bad");
+        fs::create_dir_all(&watch_folder).unwrap();
+        fs::write(&bad_location, b"not a directory").unwrap();
+
+        let mut config = BackupWardenConfig {
+            watch_folder: watch_folder.to_str().unwrap().to_string(),
+            backup_locations: vec![bad_location.to_str().unwrap().to_string()],
+            retention_days: 30,
+            ..Default::default()
+        };
+        let outcome = backup_folder(&config, None, None, false).unwrap().unwrap();
+        assert_eq!((outcome.succeeded, outcome.failed), (0, 1));
+        assert!(outcome.is_total_failure());
+        assert_eq!(run_once(&config, false).unwrap(), 1);
+
+        config
+            .backup_locations
+            .push(good_location.to_str().unwrap().to_string());
+        let outcome = backup_folder(&config, None, None, false).unwrap().unwrap();
+        assert_eq!((outcome.succeeded, outcome.failed), (1, 1));
+        assert!(!outcome.is_total_failure());
+    }
+
+    #[test]
+    fn test_failover_location_written_only_when_mirror_fails() {
+        let temp_dir = tempdir().unwrap();
+        let watch_folder = temp_dir.path().join("watch_folder");
+        let primary = temp_dir.path().join("primary");
+        let standby = temp_dir.path().join("standby");
+        fs::create_dir_all(&watch_folder).unwrap();
+        fs::write(watch_folder.join("file.txt"), b"contents").unwrap();
+
+        let standby_str = standby.to_str().unwrap().to_string();
+        let config = BackupWardenConfig {
+            watch_folder: watch_folder.to_str().unwrap().to_string(),
+            backup_locations: vec![standby_str.clone(), primary.to_str().unwrap().to_string()],
+            location_roles: [(standby_str, LocationRole::Failover)].into(),
+            retention_days: 30,
+            ..Default::default()
+        };
+        config.validate().unwrap();
+        let outcome = backup_folder(&config, None, None, false).unwrap().unwrap();
+        assert_eq!((outcome.succeeded, outcome.failed), (1, 0));
+        assert!(primary.join("Past 30 Days").exists());
+        assert!(!standby.join("Past 30 Days").exists());
+
+        fs::remove_dir_all(&primary).unwrap();
+        // A regular file where a directory is expected makes the location unusable
+        fs::write(&primary, b"not a directory").unwrap();
+        let outcome = backup_folder(&config, None, None, false).unwrap().unwrap();
+        assert_eq!((outcome.succeeded, outcome.failed), (1, 1));
+        assert!(standby.join("Past 30 Days").exists());
+    }
+
+    #[test]
+    fn test_skip_unchanged_compares_file_index() {
+        let temp_dir = tempdir().unwrap();
+        let watch_folder = temp_dir.path().join("watch_folder");
+        let backup_location = temp_dir.path().join("backup_location");
+        fs::create_dir_all(&watch_folder).unwrap();
+        fs::write(watch_folder.join("file.txt"), b"v1").unwrap();
+
+        let config = BackupWardenConfig {
+            watch_folder: watch_folder.to_str().unwrap().to_string(),
+            backup_locations: vec![backup_location.to_str().unwrap().to_string()],
+            retention_days: 30,
+            skip_unchanged: true,
+            ..Default::default()
+        };
+        let layout = BackupLayout::new(&config).unwrap();
+        backup_folder(&config, None, None, false).unwrap().unwrap();
+        let backup = newest_finished_backup(
+            &layout,
+            &backup_location,
+            layout.newest_day(&backup_location).unwrap(),
+        )
+        .unwrap();
+        assert!(backup.join(index::FILE_INDEX_NAME).exists());
+
+        // Nothing changed, so the copy isn't made again
+        fs::remove_file(backup.join("file.txt")).unwrap();
+        let outcome = backup_folder(&config, None, None, false).unwrap().unwrap();
+        assert_eq!((outcome.succeeded, outcome.failed), (1, 0));
+        assert!(!backup.join("file.txt").exists());
+        // Unless a full backup is asked for
+        backup_folder(&config, None, None, true).unwrap().unwrap();
+        assert_eq!(fs::read(backup.join("file.txt")).unwrap(), b"v1");
+        assert!(backup.join(index::FILE_INDEX_NAME).exists());
+
+        fs::write(watch_folder.join("file.txt"), b"v2 longer").unwrap();
+        backup_folder(&config, None, None, false).unwrap().unwrap();
+        assert_eq!(fs::read(backup.join("file.txt")).unwrap(), b"v2 longer");
+    }
+
+    #[test]
+    fn test_selective_backup_links_unchanged_files() {
+        use backup_warden::identity::file_id;
+        use backup_warden::manifest::Manifest;
+        use notify::event::{CreateKind, DataChange, ModifyKind, RemoveKind};
+        use notify::EventKind;
+
+        let temp_dir = tempdir().unwrap();
+        let watch_folder = temp_dir.path().join("watch_folder");
+        let backup_location = temp_dir.path().join("backup_location");
+        fs::create_dir_all(&watch_folder).unwrap();
+        for name in ["same.txt", "changed.txt", "gone.txt"] {
+            fs::write(watch_folder.join(name), b"v1").unwrap();
+        }
+        let config = BackupWardenConfig {
+            watch_folder: watch_folder.to_str().unwrap().to_string(),
+            backup_locations: vec![backup_location.to_str().unwrap().to_string()],
+            retention_days: 30,
+            selective_backups: true,
+            ..Default::default()
+        };
+        let location = &config.backup_locations[0];
+        let layout = BackupLayout::new(&config).unwrap();
+        let mut changes = ChangedPaths::unknown();
+        let outcome = backup_folder(&config, None, Some(&changes), false)
+            .unwrap()
+            .unwrap();
+        // Moved to an earlier hour, so the next backup is a new folder
+        let base = layout.backup_path(
+            &backup_location,
+            &(Local::now() - chrono::Duration::hours(2)),
+        );
+        fs::create_dir_all(base.parent().unwrap()).unwrap();
+        fs::rename(&outcome.backups[location], &base).unwrap();
+        changes.reset(HashMap::from([(location.clone(), base.clone())]));
+
+        fs::write(watch_folder.join("changed.txt"), b"v2").unwrap();
+        fs::remove_file(watch_folder.join("gone.txt")).unwrap();
+        fs::create_dir_all(watch_folder.join("moved_in")).unwrap();
+        fs::write(watch_folder.join("moved_in").join("new.txt"), b"new").unwrap();
+        for (kind, name) in [
+            (
+                EventKind::Modify(ModifyKind::Data(DataChange::Content)),
+                "changed.txt",
+            ),
+            (EventKind::Remove(RemoveKind::File), "gone.txt"),
+            (EventKind::Create(CreateKind::Folder), "moved_in"),
+        ] {
+            changes.record(&Event::new(kind).add_path(watch_folder.join(name)));
+        }
+        let outcome = backup_folder(&config, None, Some(&changes), false)
+            .unwrap()
+            .unwrap();
+        let backup = &outcome.backups[location];
+        assert_ne!(*backup, base);
+        assert_eq!(
+            file_id(&backup.join("same.txt")).unwrap(),
+            file_id(&base.join("same.txt")).unwrap()
+        );
+        assert_eq!(fs::read(backup.join("changed.txt")).unwrap(), b"v2");
+        assert_eq!(fs::read(base.join("changed.txt")).unwrap(), b"v1");
+        assert!(!backup.join("gone.txt").exists());
+        assert!(base.join("gone.txt").exists());
+        assert_eq!(
+            fs::read(backup.join("moved_in").join("new.txt")).unwrap(),
+            b"new"
+        );
+        let manifest = Manifest::load(backup).unwrap().unwrap();
+        assert!(manifest.verify(backup).is_empty());
+        // The three files and the config snapshot
+        assert_eq!(manifest.files.len(), 4);
+    }
+
+    #[test]
+    fn test_backup_folder_aborts_when_source_over_limits() {
+        let temp_dir = tempdir().unwrap();
+        let watch_folder = temp_dir.path().join("watch_folder");
+        let backup_location = temp_dir.path().join("backup_location");
+        fs::create_dir_all(&watch_folder).unwrap();
+        fs::write(watch_folder.join("runaway.log"), vec![0; 2048]).unwrap();
+
+        let mut config = BackupWardenConfig {
+            watch_folder: watch_folder.to_str().unwrap().to_string(),
+            backup_locations: vec![backup_location.to_str().unwrap().to_string()],
+            retention_days: 30,
+            max_source_bytes: Some(1024),
+            ..Default::default()
+        };
+        let outcome = backup_folder(&config, None, None, false).unwrap().unwrap();
+        assert!(outcome.aborted);
+        assert!(!backup_location.join("Past 30 Days").exists());
+        assert_eq!(run_once(&config, false).unwrap(), 1);
+
+        config.max_source_bytes = None;
+        config.max_source_files = Some(1);
+        let outcome = backup_folder(&config, None, None, false).unwrap().unwrap();
+        assert_eq!((outcome.succeeded, outcome.aborted), (1, false));
+    }
+
+    #[test]
+    fn test_cleanup_uses_path_template_date_level() {
+        let temp_dir = tempdir().unwrap();
+        let backup_location = temp_dir.path().join("backup_location");
+        let daily_root = backup_location.join("archive");
+
+        for day in 1..=5 {
+            fs::create_dir_all(
+                daily_root
+                    .join(format!("day-2024-01-0{}", day))
+                    .join("01 PM"),
+            )
+            .unwrap();
+        }
+        fs::create_dir_all(daily_root.join("manual copy")).unwrap();
+
+        let config = BackupWardenConfig {
+            watch_folder: "dummy".to_string(),
+            backup_locations: vec![backup_location.to_str().unwrap().to_string()],
+            retention_days: 2,
+            path_template: Some("archive/day-{date}/{hour}".to_string()),
+            ..Default::default()
+        };
+
+        cleanup_old_backups(&config).unwrap();
+
+        let mut remaining: Vec<_> = fs::read_dir(&daily_root)
+            .unwrap()
+            .filter_map(Result::ok)
+            .map(|e| e.file_name().to_string_lossy().into_owned())
+            .collect();
+        remaining.sort();
+        assert_eq!(
+            remaining,
+            ["day-2024-01-04", "day-2024-01-05", "manual copy"]
+        );
+    }
+
     #[test]
     fn test_create_monthly_snapshot() {
         let temp_dir = tempdir().unwrap();
@@ -236,10 +2273,11 @@ mod tests {
             watch_folder: watch_folder.to_str().unwrap().to_string(),
             backup_locations: vec![backup_location.to_str().unwrap().to_string()],
             retention_days: 30,
+            ..Default::default()
         };
 
         let last_day_of_month = NaiveDate::from_ymd_opt(2024, 1, 31).unwrap();
-        create_monthly_snapshot(&config, last_day_of_month);
+        create_monthly_snapshot(&config, last_day_of_month).unwrap();
 
         let snapshot_folders: Vec<_> = fs::read_dir(&monthly_snapshots)
             .unwrap()
@@ -248,4 +2286,144 @@ mod tests {
 
         assert_eq!(snapshot_folders.len(), 1);
     }
+
+    #[test]
+    fn test_weekly_snapshot_keeps_weekly_retention() {
+        let temp_dir = tempdir().unwrap();
+        let watch_folder = temp_dir.path().join("watch_folder");
+        let backup_location = temp_dir.path().join("backup_location");
+        fs::create_dir_all(&watch_folder).unwrap();
+        fs::write(watch_folder.join("notes.txt"), b"keep").unwrap();
+
+        let config = BackupWardenConfig {
+            watch_folder: watch_folder.to_str().unwrap().to_string(),
+            backup_locations: vec![backup_location.to_str().unwrap().to_string()],
+            retention_days: 30,
+            weekly_snapshot_day: Some(chrono::Weekday::Sun),
+            weekly_retention: Some(2),
+            ..Default::default()
+        };
+        for d in [4, 11, 18] {
+            let sunday = NaiveDate::from_ymd_opt(2024, 2, d).unwrap();
+            create_weekly_snapshot(&config, sunday).unwrap().unwrap();
+        }
+
+        let weekly = backup_location.join(schedule::WEEKLY_SNAPSHOT_DIR_NAME);
+        let mut names: Vec<_> = fs::read_dir(&weekly)
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["2024-02-11", "2024-02-18"]);
+        assert!(weekly.join("2024-02-18/notes.txt").exists());
+        assert!(!backup_location.join(schedule::SNAPSHOT_DIR_NAME).exists());
+        assert_eq!(
+            WardenState::load(&backup_location).last_weekly_snapshot_week,
+            Some("2024-W07".to_string())
+        );
+    }
+
+    #[test]
+    fn test_monthly_snapshot_honors_exclude_patterns() {
+        let temp_dir = tempdir().unwrap();
+        let watch_folder = temp_dir.path().join("watch_folder");
+        let backup_location = temp_dir.path().join("backup_location");
+        fs::create_dir_all(watch_folder.join("cache")).unwrap();
+        fs::write(watch_folder.join("cache/big.bin"), b"cached").unwrap();
+        fs::write(watch_folder.join("notes.txt"), b"keep").unwrap();
+
+        let config = BackupWardenConfig {
+            watch_folder: watch_folder.to_str().unwrap().to_string(),
+            backup_locations: vec![backup_location.to_str().unwrap().to_string()],
+            retention_days: 30,
+            exclude_patterns: vec!["cache".to_string()],
+            ..Default::default()
+        };
+        let today = NaiveDate::from_ymd_opt(2024, 1, 31).unwrap();
+        create_monthly_snapshot(&config, today).unwrap().unwrap();
+
+        let snapshot = backup_location
+            .join(schedule::SNAPSHOT_DIR_NAME)
+            .join("2024-01-31");
+        assert!(snapshot.join("notes.txt").exists());
+        assert!(!snapshot.join("cache").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_monthly_snapshot_links_daily_backup() {
+        use std::os::unix::fs::MetadataExt;
+
+        let temp_dir = tempdir().unwrap();
+        let watch_folder = temp_dir.path().join("watch_folder");
+        let backup_location = temp_dir.path().join("backup_location");
+        fs::create_dir_all(watch_folder.join("docs")).unwrap();
+        fs::write(watch_folder.join("docs").join("a.txt"), b"aaa").unwrap();
+
+        let config = BackupWardenConfig {
+            watch_folder: watch_folder.to_str().unwrap().to_string(),
+            backup_locations: vec![backup_location.to_str().unwrap().to_string()],
+            retention_days: 30,
+            link_monthly_from_daily: true,
+            ..Default::default()
+        };
+        backup_folder(&config, None, None, false).unwrap().unwrap();
+        let today = Local::now().date_naive();
+        create_monthly_snapshot(&config, today).unwrap().unwrap();
+
+        let layout = BackupLayout::new(&config).unwrap();
+        let daily = newest_finished_backup(&layout, &backup_location, today).unwrap();
+        let snapshot = backup_location
+            .join("Monthly Snapshots")
+            .join(today.format("%Y-%m-%d").to_string());
+        let ino = |root: &Path| fs::metadata(root.join("docs").join("a.txt")).unwrap().ino();
+        assert_eq!(ino(&daily), ino(&snapshot));
+        assert!(snapshot.join(CONFIG_SNAPSHOT_FILE_NAME).exists());
+    }
+
+    #[test]
+    fn test_striped_backup_and_snapshot_share_out_files() {
+        let temp_dir = tempdir().unwrap();
+        let watch_folder = temp_dir.path().join("watch_folder");
+        let locations = [temp_dir.path().join("one"), temp_dir.path().join("two")];
+        fs::create_dir_all(&watch_folder).unwrap();
+        for i in 0..10 {
+            fs::write(watch_folder.join(format!("{}.txt", i)), i.to_string()).unwrap();
+        }
+
+        let config = BackupWardenConfig {
+            watch_folder: watch_folder.to_str().unwrap().to_string(),
+            backup_locations: locations
+                .iter()
+                .map(|location| location.to_str().unwrap().to_string())
+                .collect(),
+            retention_days: 30,
+            striping: true,
+            link_monthly_from_daily: true,
+            ..Default::default()
+        };
+        let outcome = backup_folder(&config, None, None, false).unwrap().unwrap();
+        assert_eq!((outcome.succeeded, outcome.backups.len()), (1, 2));
+        let today = Local::now().date_naive();
+        create_monthly_snapshot(&config, today).unwrap().unwrap();
+
+        let layout = BackupLayout::new(&config).unwrap();
+        let snapshot = Path::new("Monthly Snapshots").join(today.format("%Y-%m-%d").to_string());
+        for i in 0..10 {
+            let name = format!("{}.txt", i);
+            let holders = |root: &dyn Fn(&Path) -> PathBuf| {
+                locations
+                    .iter()
+                    .filter(|location| root(location).join(&name).exists())
+                    .count()
+            };
+            let daily = |location: &Path| newest_finished_backup(&layout, location, today).unwrap();
+            assert_eq!(holders(&daily), 1, "{}", name);
+            assert_eq!(holders(&|location| location.join(&snapshot)), 1, "{}", name);
+        }
+
+        let target = temp_dir.path().join("restored");
+        restore::restore(&config, &locations[0].join(&snapshot), &target).unwrap();
+        assert_eq!(fs::read_dir(&target).unwrap().count(), 10);
+    }
 }
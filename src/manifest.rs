@@ -0,0 +1,438 @@
+use crate::copy::unlink_existing;
+use crate::index::FILE_INDEX_NAME;
+use crate::signature::{parse_hex, SIGNATURE_FILE_NAME};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+
+/// Name of the file at the root of each finished backup listing what it holds.
+pub const MANIFEST_FILE_NAME: &str = "backup_warden.manifest.json";
+
+/// Name of the manifest when written as a compressed index instead of JSON.
+pub const INDEX_FILE_NAME: &str = "backup_warden.manifest.idx";
+
+/// Leads every index, so other files are never mistaken for one.
+const INDEX_MAGIC: &[u8] = b"BWIDX\x01";
+
+/// How the manifest is stored in each backup.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ManifestFormat {
+    /// Plain JSON in `backup_warden.manifest.json`, readable by anything.
+    #[default]
+    Json,
+    /// A gzip-compressed stream of length-prefixed records in
+    /// `backup_warden.manifest.idx`, far smaller and faster to read for huge
+    /// trees.
+    Index,
+}
+
+/// Name of the file next to the manifest holding its Merkle root, with
+/// `merkle_root` set.
+pub const ROOT_FILE_NAME: &str = ".root";
+
+/// Every file in a backup with its size and SHA-256, to detect later corruption.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct Manifest {
+    pub files: Vec<ManifestEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ManifestEntry {
+    /// Path relative to the backup root, `/`-separated.
+    pub path: String,
+    pub size: u64,
+    /// Lowercase hex SHA-256 of the contents.
+    pub sha256: String,
+}
+
+/// How the files in two backups differ, by path.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ManifestDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+}
+
+impl ManifestDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+impl Manifest {
+    /// Hashes every file under `root`, in sorted order. Links are left out, since
+    /// what they point to may live outside the backup.
+    pub fn build(root: &Path) -> io::Result<Manifest> {
+        let mut manifest = Manifest::default();
+        collect(root, "", &mut manifest.files)?;
+        Ok(manifest)
+    }
+
+    /// Builds the manifest for `root` and writes it there as JSON.
+    pub fn write(root: &Path) -> io::Result<Manifest> {
+        Manifest::write_as(root, ManifestFormat::Json)
+    }
+
+    /// Builds the manifest for `root` and writes it there in `format`,
+    /// removing one left in the other format.
+    pub fn write_as(root: &Path, format: ManifestFormat) -> io::Result<Manifest> {
+        let manifest = Manifest::build(root)?;
+        unlink_existing(&root.join(MANIFEST_FILE_NAME))?;
+        unlink_existing(&root.join(INDEX_FILE_NAME))?;
+        match format {
+            ManifestFormat::Json => fs::write(
+                root.join(MANIFEST_FILE_NAME),
+                serde_json::to_string_pretty(&manifest)?,
+            )?,
+            ManifestFormat::Index => manifest.write_index(&root.join(INDEX_FILE_NAME))?,
+        }
+        Ok(manifest)
+    }
+
+    /// Writes the magic, then per file a little-endian `u32` path length, the
+    /// UTF-8 path, a little-endian `u64` size and the 32-byte SHA-256, all
+    /// gzip-compressed.
+    fn write_index(&self, path: &Path) -> io::Result<()> {
+        let mut out = GzEncoder::new(BufWriter::new(File::create(path)?), Compression::default());
+        out.write_all(INDEX_MAGIC)?;
+        for entry in &self.files {
+            let sha256: [u8; 32] = parse_hex(&entry.sha256)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let path_len = u32::try_from(entry.path.len())
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "path too long"))?;
+            out.write_all(&path_len.to_le_bytes())?;
+            out.write_all(entry.path.as_bytes())?;
+            out.write_all(&entry.size.to_le_bytes())?;
+            out.write_all(&sha256)?;
+        }
+        out.finish()?.flush()
+    }
+
+    fn read_index(raw: &[u8]) -> io::Result<Manifest> {
+        let invalid = |reason: &str| io::Error::new(io::ErrorKind::InvalidData, reason.to_string());
+        let mut data = Vec::new();
+        GzDecoder::new(raw).read_to_end(&mut data)?;
+        let mut rest = data
+            .strip_prefix(INDEX_MAGIC)
+            .ok_or_else(|| invalid("not a manifest index"))?;
+        let mut manifest = Manifest::default();
+        while !rest.is_empty() {
+            let path_len = u32::from_le_bytes(take(&mut rest)?) as usize;
+            let path = rest
+                .get(..path_len)
+                .ok_or_else(|| invalid("manifest index is truncated"))?;
+            let path = String::from_utf8(path.to_vec())
+                .map_err(|_| invalid("manifest index holds a path that isn't UTF-8"))?;
+            rest = &rest[path_len..];
+            let size = u64::from_le_bytes(take(&mut rest)?);
+            let sha256 = hex(&take::<32>(&mut rest)?);
+            manifest.files.push(ManifestEntry { path, size, sha256 });
+        }
+        Ok(manifest)
+    }
+
+    /// A single hash summarizing every entry, as lowercase hex: two manifests
+    /// have the same root exactly when they list the same files with the same
+    /// contents. Leaves are the entries in order; an odd node out is carried up
+    /// to the next level unchanged.
+    pub fn merkle_root(&self) -> String {
+        let mut level: Vec<[u8; 32]> = self
+            .files
+            .iter()
+            .map(|entry| {
+                let mut hasher = Sha256::new();
+                // Leaves and nodes are prefixed differently so one can't pass for the other
+                hasher.update([0]);
+                hasher.update(format!("{}\0{}\0{}", entry.path, entry.size, entry.sha256));
+                hasher.finalize().into()
+            })
+            .collect();
+        if level.is_empty() {
+            return hex(&Sha256::digest([]));
+        }
+        while level.len() > 1 {
+            level = level
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => {
+                        let mut hasher = Sha256::new();
+                        hasher.update([1]);
+                        hasher.update(left);
+                        hasher.update(right);
+                        hasher.finalize().into()
+                    }
+                    [single] => *single,
+                    _ => unreachable!(),
+                })
+                .collect();
+        }
+        hex(&level[0])
+    }
+
+    /// The paths added, removed and changed going from `self` to `newer`, each
+    /// sorted.
+    pub fn diff(&self, newer: &Manifest) -> ManifestDiff {
+        let old: HashMap<&str, &ManifestEntry> =
+            self.files.iter().map(|e| (e.path.as_str(), e)).collect();
+        let new: HashMap<&str, &ManifestEntry> =
+            newer.files.iter().map(|e| (e.path.as_str(), e)).collect();
+        let mut diff = ManifestDiff::default();
+        for entry in &newer.files {
+            match old.get(entry.path.as_str()) {
+                None => diff.added.push(entry.path.clone()),
+                Some(before) if *before != entry => diff.changed.push(entry.path.clone()),
+                Some(_) => (),
+            }
+        }
+        for entry in &self.files {
+            if !new.contains_key(entry.path.as_str()) {
+                diff.removed.push(entry.path.clone());
+            }
+        }
+        diff.added.sort();
+        diff.removed.sort();
+        diff.changed.sort();
+        diff
+    }
+
+    /// Writes the manifest's Merkle root to `root`'s `.root` file.
+    pub fn write_root(&self, root: &Path) -> io::Result<()> {
+        let path = root.join(ROOT_FILE_NAME);
+        unlink_existing(&path)?;
+        fs::write(path, format!("{}\n", self.merkle_root()))
+    }
+
+    /// Reads the manifest at `root` in either format, or `None` for a backup
+    /// made before manifests were written.
+    pub fn load(root: &Path) -> io::Result<Option<Manifest>> {
+        match fs::read(root.join(INDEX_FILE_NAME)) {
+            Ok(raw) => return Manifest::read_index(&raw).map(Some),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+        match fs::read_to_string(root.join(MANIFEST_FILE_NAME)) {
+            Ok(raw) => Ok(Some(serde_json::from_str(&raw)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Re-hashes the files under `root` and describes every one that is missing
+    /// or no longer matches. Files added since the manifest was written are not
+    /// reported.
+    pub fn verify(&self, root: &Path) -> Vec<String> {
+        self.files
+            .iter()
+            .filter_map(|entry| entry.check(root))
+            .collect()
+    }
+}
+
+impl ManifestEntry {
+    /// Describes how the file under `root` no longer matches this entry, or
+    /// `None` when it does.
+    pub fn check(&self, root: &Path) -> Option<String> {
+        let path = root.join(&self.path);
+        match fs::metadata(&path) {
+            Ok(metadata) if metadata.len() != self.size => Some(format!(
+                "{}: size is {} bytes, expected {}",
+                self.path,
+                metadata.len(),
+                self.size
+            )),
+            Ok(_) => match hash_file(&path) {
+                Ok(hash) if hash == self.sha256 => None,
+                Ok(_) => Some(format!("{}: contents changed", self.path)),
+                Err(e) => Some(format!("{}: unreadable ({})", self.path, e)),
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                Some(format!("{}: missing", self.path))
+            }
+            Err(e) => Some(format!("{}: unreadable ({})", self.path, e)),
+        }
+    }
+}
+
+fn collect(dir: &Path, prefix: &str, files: &mut Vec<ManifestEntry>) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if prefix.is_empty()
+            && (name == MANIFEST_FILE_NAME
+                || name == INDEX_FILE_NAME
+                || name == FILE_INDEX_NAME
+                || name == ROOT_FILE_NAME
+                || name == SIGNATURE_FILE_NAME)
+        {
+            continue;
+        }
+        let relative = if prefix.is_empty() {
+            name
+        } else {
+            format!("{}/{}", prefix, name)
+        };
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            collect(&entry.path(), &relative, files)?;
+        } else if file_type.is_file() {
+            files.push(ManifestEntry {
+                size: entry.metadata()?.len(),
+                sha256: hash_file(&entry.path())?,
+                path: relative,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Whether the files at `a` and `b` hold the same contents, going by their
+/// SHA-256. False if either can't be read.
+pub fn same_contents(a: &Path, b: &Path) -> bool {
+    match (hash_file(a), hash_file(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Lowercase hex SHA-256 of the file at `path`.
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hex(&hasher.finalize()))
+}
+
+/// The Merkle root stored in `root`'s `.root` file, or `None` for a backup made
+/// without `merkle_root`.
+pub fn read_root(root: &Path) -> io::Result<Option<String>> {
+    match fs::read_to_string(root.join(ROOT_FILE_NAME)) {
+        Ok(raw) => Ok(Some(raw.trim().to_string())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Splits the next `N` bytes off the front of `rest`.
+fn take<const N: usize>(rest: &mut &[u8]) -> io::Result<[u8; N]> {
+    let (head, tail) = rest
+        .split_first_chunk::<N>()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "manifest index is truncated"))?;
+    *rest = tail;
+    Ok(*head)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_index_format_round_trips() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::write(root.join("docs").join("ä.txt"), b"aaa").unwrap();
+        fs::write(root.join("b.txt"), b"").unwrap();
+        let json = Manifest::write(root).unwrap();
+
+        let index = Manifest::write_as(root, ManifestFormat::Index).unwrap();
+        assert_eq!(index, json);
+        assert!(!root.join(MANIFEST_FILE_NAME).exists());
+        assert_eq!(Manifest::load(root).unwrap(), Some(json));
+
+        fs::write(root.join(INDEX_FILE_NAME), b"not gzip").unwrap();
+        assert!(Manifest::load(root).is_err());
+    }
+
+    #[test]
+    fn test_verify_reports_changed_and_missing_files() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::write(root.join("docs").join("a.txt"), b"aaa").unwrap();
+        fs::write(root.join("docs").join("b.txt"), b"bbb").unwrap();
+        fs::write(root.join("c.txt"), b"ccc").unwrap();
+        Manifest::write(root).unwrap();
+
+        let manifest = Manifest::load(root).unwrap().unwrap();
+        let paths: Vec<&str> = manifest.files.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(paths, ["c.txt", "docs/a.txt", "docs/b.txt"]);
+        assert!(manifest.verify(root).is_empty());
+
+        fs::write(root.join("docs").join("a.txt"), b"AAA").unwrap();
+        fs::remove_file(root.join("c.txt")).unwrap();
+        assert_eq!(
+            manifest.verify(root),
+            ["c.txt: missing", "docs/a.txt: contents changed"]
+        );
+    }
+
+    #[test]
+    fn test_merkle_root_changes_with_any_file() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            fs::write(root.join(name), name).unwrap();
+        }
+        let manifest = Manifest::write(root).unwrap();
+        manifest.write_root(root).unwrap();
+        let original = manifest.merkle_root();
+        assert_eq!(read_root(root).unwrap(), Some(original.clone()));
+        // The root file itself is not part of what it summarizes
+        assert_eq!(Manifest::build(root).unwrap(), manifest);
+
+        fs::write(root.join("c.txt"), b"changed").unwrap();
+        assert_ne!(Manifest::build(root).unwrap().merkle_root(), original);
+        fs::write(root.join("c.txt"), b"c.txt").unwrap();
+        assert_eq!(Manifest::build(root).unwrap().merkle_root(), original);
+    }
+
+    #[test]
+    fn test_diff_lists_added_removed_and_changed() {
+        let entry = |path: &str, sha256: &str| ManifestEntry {
+            path: path.to_string(),
+            size: 1,
+            sha256: sha256.to_string(),
+        };
+        let old = Manifest {
+            files: vec![entry("a", "1"), entry("b", "2"), entry("c", "3")],
+        };
+        let new = Manifest {
+            files: vec![entry("a", "1"), entry("c", "4"), entry("d", "5")],
+        };
+        assert_eq!(
+            old.diff(&new),
+            ManifestDiff {
+                added: vec!["d".to_string()],
+                removed: vec!["b".to_string()],
+                changed: vec!["c".to_string()],
+            }
+        );
+        assert!(old.diff(&old).is_empty());
+    }
+
+    #[test]
+    fn test_missing_manifest_loads_as_none() {
+        let temp_dir = tempdir().unwrap();
+        assert!(Manifest::load(temp_dir.path()).unwrap().is_none());
+    }
+}
@@ -0,0 +1,262 @@
+use crate::config::BackupWardenConfig;
+use crate::error::{BackupWardenError, Result};
+use crate::layout::BackupLayout;
+use crate::lock::RunLock;
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::Path;
+use tracing::{info, warn};
+
+/// What `migrate` should do, parsed from its command-line arguments.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct MigrateOptions {
+    pub job: Option<String>,
+    /// The `path_template` the existing backups were written with.
+    pub from_template: Option<String>,
+    /// The `date_format` the existing backups were written with.
+    pub from_date_format: Option<String>,
+    /// Rename the folders; without it only the plan is printed.
+    pub apply: bool,
+}
+
+impl MigrateOptions {
+    /// Parses `[--from-template T] [--from-date-format F] [--job NAME] [--apply]`.
+    pub fn parse(args: &[String]) -> Result<Self> {
+        Self::parse_args(args).map_err(BackupWardenError::Usage)
+    }
+
+    fn parse_args(args: &[String]) -> std::result::Result<Self, String> {
+        let mut options = MigrateOptions::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--job" => options.job = Some(args.next().ok_or("--job needs a name")?.clone()),
+                "--from-template" => {
+                    options.from_template = Some(
+                        args.next()
+                            .ok_or("--from-template needs a template")?
+                            .clone(),
+                    )
+                }
+                "--from-date-format" => {
+                    options.from_date_format = Some(
+                        args.next()
+                            .ok_or("--from-date-format needs a format")?
+                            .clone(),
+                    )
+                }
+                "--apply" => options.apply = true,
+                other => return Err(format!("unexpected argument {}", other)),
+            }
+        }
+        if options.from_template.is_none() && options.from_date_format.is_none() {
+            return Err(
+                "give the old layout with --from-template and/or --from-date-format".to_string(),
+            );
+        }
+        Ok(options)
+    }
+}
+
+/// What one `migrate` run found.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MigrateReport {
+    /// Backups moved, or that would be moved in a dry run.
+    pub moved: usize,
+    /// Backups left in place because their new folder already exists.
+    pub conflicts: usize,
+    /// Folders in old day folders that aren't backups in the old layout.
+    pub unmapped: usize,
+}
+
+/// Moves every daily backup written with the layout in `options` to where the
+/// current config puts it, printing each move. Without `apply` nothing is
+/// changed. Backups that can't be moved are logged and left where they are.
+pub fn run(config: &BackupWardenConfig, options: &MigrateOptions) -> Result<MigrateReport> {
+    let new = BackupLayout::new(config).map_err(BackupWardenError::Config)?;
+    let mut old_config = config.clone();
+    if let Some(template) = &options.from_template {
+        old_config.path_template = Some(template.clone());
+    }
+    if let Some(date_format) = &options.from_date_format {
+        old_config.date_format = Some(date_format.clone());
+    }
+    let old = BackupLayout::new(&old_config).map_err(BackupWardenError::Config)?;
+    // Keeps a running backup from writing into folders while they move
+    let _lock = options
+        .apply
+        .then(|| RunLock::acquire(&config.backup_locations))
+        .transpose()
+        .map_err(BackupWardenError::Locked)?;
+
+    let mut report = MigrateReport::default();
+    for location in &config.backup_locations {
+        migrate_location(Path::new(location), &old, &new, options.apply, &mut report)?;
+    }
+    info!(
+        event = "migrate_finished",
+        moved = report.moved,
+        conflicts = report.conflicts,
+        unmapped = report.unmapped,
+        apply = options.apply,
+        "{} {} backup(s), {} conflict(s), {} folder(s) not mapped",
+        if options.apply { "Moved" } else { "Would move" },
+        report.moved,
+        report.conflicts,
+        report.unmapped
+    );
+    Ok(report)
+}
+
+fn migrate_location(
+    location: &Path,
+    old: &BackupLayout,
+    new: &BackupLayout,
+    apply: bool,
+    report: &mut MigrateReport,
+) -> Result<()> {
+    let io_error = |path: &Path| {
+        let path = path.to_path_buf();
+        move |source| BackupWardenError::Io { path, source }
+    };
+    let old_root = old.daily_root(location);
+    let entries = match fs::read_dir(&old_root) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(io_error(&old_root)(e)),
+    };
+    let mut days: Vec<_> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let day = old.parse_day(&entry.file_name().to_string_lossy())?;
+            Some((day, entry.path()))
+        })
+        .collect();
+    days.sort();
+
+    for (day, day_dir) in days {
+        let backups = old.backups_in_day(&day_dir);
+        // Anything in the day folder that leads to no backup can't be mapped;
+        // backups already in the new layout are fine where they are
+        let mut known: HashSet<OsString> = HashSet::new();
+        for (_, path) in backups.iter().chain(&new.backups_in_day(&day_dir)) {
+            known.extend(first_component(&day_dir, path));
+        }
+        for entry in fs::read_dir(&day_dir).map_err(io_error(&day_dir))? {
+            let entry = entry.map_err(io_error(&day_dir))?;
+            if !known.contains(&entry.file_name()) {
+                warn!(event = "migrate_unmapped", path = %entry.path().display(), "Can't map {} to the new layout; leaving it in place", entry.path().display());
+                report.unmapped += 1;
+            }
+        }
+        for (time, path) in &backups {
+            let target = new.backup_path(location, &day.and_time(*time).and_utc());
+            if target == *path {
+                continue;
+            }
+            if target.exists() {
+                warn!(event = "migrate_conflict", from = %path.display(), to = %target.display(), "Not moving {}: {} already exists", path.display(), target.display());
+                report.conflicts += 1;
+                continue;
+            }
+            println!("{} -> {}", path.display(), target.display());
+            if apply {
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent).map_err(io_error(parent))?;
+                }
+                fs::rename(path, &target).map_err(io_error(path))?;
+                remove_empty_parents(path, &day_dir);
+            }
+            report.moved += 1;
+        }
+    }
+    Ok(())
+}
+
+/// The first component of `path` below `dir`.
+fn first_component(dir: &Path, path: &Path) -> Option<OsString> {
+    let relative = path.strip_prefix(dir).ok()?;
+    relative
+        .components()
+        .next()
+        .map(|component| component.as_os_str().to_os_string())
+}
+
+/// Removes the folders that held a moved backup, from `moved` up to and
+/// including `day_dir`, stopping at the first that still holds something.
+fn remove_empty_parents(moved: &Path, day_dir: &Path) {
+    for dir in moved.ancestors().skip(1) {
+        if !dir.starts_with(day_dir) || fs::remove_dir(dir).is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Local, TimeZone};
+    use tempfile::tempdir;
+
+    fn args(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let options = MigrateOptions::parse(&args(&["--from-date-format", "%d.%m.%Y"])).unwrap();
+        assert_eq!(options.from_date_format.as_deref(), Some("%d.%m.%Y"));
+        assert!(!options.apply);
+        assert!(MigrateOptions::parse(&args(&["--apply"])).is_err());
+        assert!(MigrateOptions::parse(&args(&["--from-template"])).is_err());
+    }
+
+    #[test]
+    fn test_migrate_moves_backups_to_new_layout() {
+        let temp_dir = tempdir().unwrap();
+        let location = temp_dir.path().join("backup");
+        let config = BackupWardenConfig {
+            backup_locations: vec![location.to_str().unwrap().to_string()],
+            path_template: Some("Daily/{date}/{hour}".to_string()),
+            date_format: Some("%Y%m%d".to_string()),
+            ..Default::default()
+        };
+        let old_config = BackupWardenConfig {
+            path_template: None,
+            date_format: None,
+            ..config.clone()
+        };
+        let old = BackupLayout::new(&old_config).unwrap();
+        let time = Local.with_ymd_and_hms(2024, 1, 31, 13, 0, 0).unwrap();
+        let old_backup = old.backup_path(&location, &time);
+        fs::create_dir_all(&old_backup).unwrap();
+        fs::write(old_backup.join("a.txt"), b"aaa").unwrap();
+        let stray = old_backup.parent().unwrap().join("notes");
+        fs::create_dir_all(&stray).unwrap();
+
+        let mut options = MigrateOptions {
+            from_template: Some("Past 30 Days/{date}/@{hour}".to_string()),
+            from_date_format: Some("%Y-%m-%d".to_string()),
+            ..Default::default()
+        };
+        let expected = MigrateReport {
+            moved: 1,
+            conflicts: 0,
+            unmapped: 1,
+        };
+        assert_eq!(run(&config, &options).unwrap(), expected);
+        assert!(old_backup.join("a.txt").exists());
+
+        options.apply = true;
+        assert_eq!(run(&config, &options).unwrap(), expected);
+        let new = BackupLayout::new(&config).unwrap();
+        assert_eq!(
+            fs::read(new.backup_path(&location, &time).join("a.txt")).unwrap(),
+            b"aaa"
+        );
+        assert!(!old_backup.exists());
+        assert!(stray.exists());
+    }
+}
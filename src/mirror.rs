@@ -0,0 +1,184 @@
+use crate::config::IncrementalCompare;
+use crate::filter::PathFilter;
+use crate::manifest::same_contents;
+use filetime::{set_file_mtime, FileTime};
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Name of the always-current copy of watch_folder inside each backup location.
+pub const MIRROR_DIR_NAME: &str = "Mirror";
+
+/// Makes `dst` an exact copy of `src`: new and changed files are copied, and
+/// anything in `dst` that no longer exists in `src` (or is now filtered out) is
+/// deleted. Files count as changed when their size differs, or by `compare` their
+/// mtime or contents; copied files get the source mtime so the next sync can
+/// tell them apart.
+pub fn sync_mirror(
+    src: &Path,
+    dst: &Path,
+    filter: &PathFilter,
+    compare: IncrementalCompare,
+) -> io::Result<()> {
+    if dst.exists() && !dst.is_dir() {
+        fs::remove_file(dst)?;
+    }
+    fs::create_dir_all(dst)?;
+
+    let mut kept = HashSet::new();
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let path = entry.path();
+        let dest_path = dst.join(entry.file_name());
+
+        if path.is_dir() {
+            if !filter.allows_dir(&path) {
+                continue;
+            }
+            sync_mirror(&path, &dest_path, filter, compare)?;
+        } else {
+            let metadata = fs::metadata(&path)?;
+            if !metadata.file_type().is_file() || !filter.allows_file(&path) {
+                continue;
+            }
+            if dest_path.is_dir() {
+                fs::remove_dir_all(&dest_path)?;
+            }
+            if is_changed(&path, &metadata, &dest_path, compare) {
+                fs::copy(&path, &dest_path)?;
+                set_file_mtime(&dest_path, FileTime::from_last_modification_time(&metadata))?;
+            }
+        }
+        kept.insert(entry.file_name());
+    }
+
+    remove_extra_entries(dst, &kept)
+}
+
+fn is_changed(
+    path: &Path,
+    source: &fs::Metadata,
+    dest_path: &Path,
+    compare: IncrementalCompare,
+) -> bool {
+    match fs::metadata(dest_path) {
+        Ok(dest) if dest.len() == source.len() => match compare {
+            IncrementalCompare::Mtime => {
+                FileTime::from_last_modification_time(&dest)
+                    != FileTime::from_last_modification_time(source)
+            }
+            IncrementalCompare::Hash => !same_contents(path, dest_path),
+        },
+        _ => true,
+    }
+}
+
+fn remove_extra_entries(dst: &Path, kept: &HashSet<OsString>) -> io::Result<()> {
+    for entry in fs::read_dir(dst)? {
+        let entry = entry?;
+        if kept.contains(&entry.file_name()) {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            fs::remove_dir_all(entry.path())?;
+        } else {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BackupWardenConfig;
+    use tempfile::tempdir;
+
+    fn filter_for(src: &Path) -> PathFilter {
+        let config = BackupWardenConfig {
+            watch_folder: src.to_str().unwrap().to_string(),
+            exclude_patterns: vec!["**/*.tmp".to_string()],
+            ..Default::default()
+        };
+        PathFilter::new(&config).unwrap()
+    }
+
+    #[test]
+    fn test_sync_mirror_adds_updates_and_deletes() {
+        let temp_dir = tempdir().unwrap();
+        let src = temp_dir.path().join("src");
+        let dst = temp_dir.path().join("Mirror");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::write(src.join("keep.txt"), b"v1").unwrap();
+        fs::write(src.join("sub").join("gone.txt"), b"bye").unwrap();
+
+        let filter = filter_for(&src);
+        sync_mirror(&src, &dst, &filter, IncrementalCompare::Mtime).unwrap();
+        assert_eq!(fs::read(dst.join("keep.txt")).unwrap(), b"v1");
+        assert!(dst.join("sub").join("gone.txt").exists());
+
+        fs::write(src.join("keep.txt"), b"version 2").unwrap();
+        fs::write(src.join("new.txt"), b"new").unwrap();
+        fs::remove_dir_all(src.join("sub")).unwrap();
+        sync_mirror(&src, &dst, &filter, IncrementalCompare::Mtime).unwrap();
+
+        assert_eq!(fs::read(dst.join("keep.txt")).unwrap(), b"version 2");
+        assert_eq!(fs::read(dst.join("new.txt")).unwrap(), b"new");
+        assert!(!dst.join("sub").exists());
+    }
+
+    #[test]
+    fn test_hash_compare_catches_change_behind_same_mtime() {
+        let temp_dir = tempdir().unwrap();
+        let src = temp_dir.path().join("src");
+        let dst = temp_dir.path().join("Mirror");
+        fs::create_dir_all(&src).unwrap();
+        let file = src.join("notes.txt");
+        let mtime = FileTime::from_unix_time(1_700_000_000, 0);
+        fs::write(&file, b"v1").unwrap();
+        set_file_mtime(&file, mtime).unwrap();
+        let filter = filter_for(&src);
+        sync_mirror(&src, &dst, &filter, IncrementalCompare::Mtime).unwrap();
+
+        // An edit the filesystem's coarse mtime doesn't show
+        fs::write(&file, b"v2").unwrap();
+        set_file_mtime(&file, mtime).unwrap();
+        sync_mirror(&src, &dst, &filter, IncrementalCompare::Mtime).unwrap();
+        assert_eq!(fs::read(dst.join("notes.txt")).unwrap(), b"v1");
+        sync_mirror(&src, &dst, &filter, IncrementalCompare::Hash).unwrap();
+        assert_eq!(fs::read(dst.join("notes.txt")).unwrap(), b"v2");
+    }
+
+    #[test]
+    fn test_sync_mirror_removes_newly_excluded_files() {
+        let temp_dir = tempdir().unwrap();
+        let src = temp_dir.path().join("src");
+        let dst = temp_dir.path().join("Mirror");
+        fs::create_dir_all(&src).unwrap();
+        fs::create_dir_all(&dst).unwrap();
+        fs::write(src.join("scratch.tmp"), b"tmp").unwrap();
+        fs::write(dst.join("scratch.tmp"), b"tmp").unwrap();
+
+        sync_mirror(&src, &dst, &filter_for(&src), IncrementalCompare::Mtime).unwrap();
+        assert!(!dst.join("scratch.tmp").exists());
+    }
+
+    #[test]
+    fn test_sync_mirror_replaces_file_with_directory() {
+        let temp_dir = tempdir().unwrap();
+        let src = temp_dir.path().join("src");
+        let dst = temp_dir.path().join("Mirror");
+        fs::create_dir_all(src.join("thing")).unwrap();
+        fs::write(src.join("thing").join("inner.txt"), b"inner").unwrap();
+        fs::create_dir_all(&dst).unwrap();
+        fs::write(dst.join("thing"), b"was a file").unwrap();
+
+        sync_mirror(&src, &dst, &filter_for(&src), IncrementalCompare::Mtime).unwrap();
+        assert_eq!(
+            fs::read(dst.join("thing").join("inner.txt")).unwrap(),
+            b"inner"
+        );
+    }
+}
@@ -0,0 +1,260 @@
+use crate::config::BackupWardenConfig;
+use crate::error::{BackupWardenError, Result};
+use crate::layout::BackupLayout;
+use crate::lock::RunLock;
+use crate::schedule::SnapshotKind;
+use crate::size::{directory_size, format_bytes};
+use chrono::NaiveDate;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// What `gc-orphans` should do, parsed from its command-line arguments.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct GcOptions {
+    pub job: Option<String>,
+    /// Remove the orphans; without it they are only listed.
+    pub apply: bool,
+}
+
+impl GcOptions {
+    /// Parses `[--job NAME] [--apply]`.
+    pub fn parse(args: &[String]) -> Result<Self> {
+        let mut options = GcOptions::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--job" => {
+                    options.job = Some(
+                        args.next()
+                            .ok_or_else(|| BackupWardenError::Usage("--job needs a name".to_string()))?
+                            .clone(),
+                    )
+                }
+                "--apply" => options.apply = true,
+                other => {
+                    return Err(BackupWardenError::Usage(format!(
+                        "unexpected argument {}\nUsage: backup-warden gc-orphans [--job NAME] [--apply]",
+                        other
+                    )))
+                }
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// What one `gc-orphans` run found.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct GcReport {
+    /// Entries that aren't backups, removed unless it was a dry run.
+    pub orphans: usize,
+    pub bytes: u64,
+}
+
+/// Lists, and with `apply` removes, everything in the daily backup folders and
+/// snapshot folders of each location that isn't part of a backup: entries
+/// beside the day folders not named like a day, anything in a day folder that
+/// leads to no backup, and snapshot entries not named for a date. Backups,
+/// finished or not, are never touched.
+pub fn run(config: &BackupWardenConfig, options: &GcOptions) -> Result<GcReport> {
+    let layout = BackupLayout::new(config).map_err(BackupWardenError::Config)?;
+    // Keeps a running backup from creating folders while they are looked at
+    let _lock = options
+        .apply
+        .then(|| RunLock::acquire(&config.backup_locations))
+        .transpose()
+        .map_err(BackupWardenError::Locked)?;
+
+    let mut report = GcReport::default();
+    for location in &config.backup_locations {
+        let location = Path::new(location);
+        let orphans = find_orphans(&layout, location).map_err(|source| BackupWardenError::Io {
+            path: location.to_path_buf(),
+            source,
+        })?;
+        for orphan in orphans {
+            let bytes = directory_size(&orphan).unwrap_or(0);
+            println!("{} ({})", orphan.display(), format_bytes(bytes));
+            if options.apply {
+                remove(&orphan).map_err(|source| BackupWardenError::Io {
+                    path: orphan.clone(),
+                    source,
+                })?;
+            }
+            report.orphans += 1;
+            report.bytes += bytes;
+        }
+    }
+    info!(
+        event = "gc_orphans_finished",
+        orphans = report.orphans,
+        bytes = report.bytes,
+        apply = options.apply,
+        "{} {} orphan(s), {}",
+        if options.apply { "Removed" } else { "Found" },
+        report.orphans,
+        format_bytes(report.bytes)
+    );
+    Ok(report)
+}
+
+/// The entries in `location` that `gc-orphans` would remove, sorted.
+pub fn find_orphans(layout: &BackupLayout, location: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut orphans = Vec::new();
+    let daily_root = layout.daily_root(location);
+    // With the date first in path_template, the day folders sit beside the
+    // location's own files, which aren't any of gc-orphans' business
+    if daily_root != location {
+        for entry in read_dir(&daily_root)? {
+            let entry = entry?;
+            let path = entry.path();
+            let is_day = entry.file_type()?.is_dir()
+                && layout
+                    .parse_day(&entry.file_name().to_string_lossy())
+                    .is_some();
+            if is_day {
+                orphans_in_day(layout, &path, &mut orphans)?;
+            } else {
+                orphans.push(path);
+            }
+        }
+    } else {
+        for entry in read_dir(location)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir()
+                && layout
+                    .parse_day(&entry.file_name().to_string_lossy())
+                    .is_some()
+            {
+                orphans_in_day(layout, &entry.path(), &mut orphans)?;
+            }
+        }
+    }
+    for kind in [SnapshotKind::Weekly, SnapshotKind::Monthly] {
+        for entry in read_dir(&location.join(kind.dir_name()))? {
+            let entry = entry?;
+            let is_snapshot = entry.file_type()?.is_dir()
+                && NaiveDate::parse_from_str(&entry.file_name().to_string_lossy(), "%Y-%m-%d")
+                    .is_ok();
+            if !is_snapshot {
+                orphans.push(entry.path());
+            }
+        }
+    }
+    orphans.sort();
+    Ok(orphans)
+}
+
+/// Adds what in the day folder `day_dir` leads to no backup.
+fn orphans_in_day(
+    layout: &BackupLayout,
+    day_dir: &Path,
+    orphans: &mut Vec<PathBuf>,
+) -> io::Result<()> {
+    let backups: HashSet<PathBuf> = layout
+        .backups_in_day(day_dir)
+        .into_iter()
+        .map(|(_, path)| path)
+        .collect();
+    // The folders between the day folder and each backup
+    let parents: HashSet<&Path> = backups
+        .iter()
+        .flat_map(|backup| {
+            backup
+                .ancestors()
+                .skip(1)
+                .take_while(move |dir| *dir != day_dir)
+        })
+        .collect();
+    let mut pending = vec![day_dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if backups.contains(&path) {
+                continue;
+            }
+            if parents.contains(path.as_path()) {
+                pending.push(path);
+            } else {
+                orphans.push(path);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_dir(dir: &Path) -> io::Result<Box<dyn Iterator<Item = io::Result<fs::DirEntry>>>> {
+    match fs::read_dir(dir) {
+        Ok(entries) => Ok(Box::new(entries)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Box::new(std::iter::empty())),
+        Err(e) => Err(e),
+    }
+}
+
+fn remove(path: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(path)?.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Local, TimeZone};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_gc_orphans_removes_only_stray_entries() {
+        let temp_dir = tempdir().unwrap();
+        let location = temp_dir.path().join("backup");
+        let config = BackupWardenConfig {
+            backup_locations: vec![location.to_str().unwrap().to_string()],
+            ..Default::default()
+        };
+        let layout = BackupLayout::new(&config).unwrap();
+        let time = Local.with_ymd_and_hms(2024, 1, 31, 13, 0, 0).unwrap();
+        let backup = layout.backup_path(&location, &time);
+        fs::create_dir_all(&backup).unwrap();
+        fs::write(backup.join("a.txt"), b"aaa").unwrap();
+        let day_dir = backup.parent().unwrap();
+        let daily_root = layout.daily_root(&location);
+        let snapshot = location
+            .join(SnapshotKind::Monthly.dir_name())
+            .join("2024-01-31");
+        fs::create_dir_all(&snapshot).unwrap();
+        let strays = [
+            daily_root.join("old temp"),
+            daily_root.join("notes.txt"),
+            day_dir.join("copy of backup"),
+            day_dir.join("backup_warden.manifest.json"),
+            location
+                .join(SnapshotKind::Monthly.dir_name())
+                .join("January"),
+        ];
+        fs::create_dir_all(&strays[0]).unwrap();
+        fs::write(strays[0].join("x"), b"x").unwrap();
+        fs::write(&strays[1], b"notes").unwrap();
+        fs::create_dir_all(&strays[2]).unwrap();
+        fs::write(&strays[3], b"{}").unwrap();
+        fs::create_dir_all(&strays[4]).unwrap();
+
+        let mut expected = strays.to_vec();
+        expected.sort();
+        assert_eq!(find_orphans(&layout, &location).unwrap(), expected);
+
+        let mut options = GcOptions::default();
+        assert_eq!(run(&config, &options).unwrap().orphans, 5);
+        assert!(strays.iter().all(|stray| stray.exists()));
+        options.apply = true;
+        assert_eq!(run(&config, &options).unwrap().orphans, 5);
+        assert!(strays.iter().all(|stray| !stray.exists()));
+        assert!(backup.join("a.txt").exists());
+        assert!(snapshot.exists());
+        assert!(find_orphans(&layout, &location).unwrap().is_empty());
+    }
+}
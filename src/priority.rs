@@ -0,0 +1,76 @@
+use std::io;
+
+/// Nice value used for low-priority runs; 19 is the lowest Unix allows.
+#[cfg(unix)]
+const LOW_NICE: libc::c_int = 10;
+
+/// Lowers the CPU and I/O scheduling priority of the calling thread, and with it
+/// every thread it spawns afterwards. Call it before starting the watcher so the
+/// polling and copy work all runs at the lower priority.
+#[cfg(unix)]
+pub fn lower_priority() -> io::Result<()> {
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS as _, 0, LOW_NICE) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    lower_io_priority()
+}
+
+/// Drops to the lowest level of the best-effort I/O class, which still makes
+/// progress on a busy disk (unlike the idle class, which can starve entirely).
+#[cfg(target_os = "linux")]
+fn lower_io_priority() -> io::Result<()> {
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_BE: libc::c_int = 2;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+    const LOWEST_LEVEL: libc::c_int = 7;
+
+    let priority = (IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT) | LOWEST_LEVEL;
+    if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, priority) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Other Unixes have no portable I/O priority; the nice value is all we can do.
+#[cfg(all(unix, not(target_os = "linux")))]
+fn lower_io_priority() -> io::Result<()> {
+    Ok(())
+}
+
+/// Moves the process to the below-normal priority class and into background
+/// mode, which also lowers its I/O and memory priority.
+#[cfg(windows)]
+pub fn lower_priority() -> io::Result<()> {
+    use windows_sys::Win32::System::Threading::{
+        GetCurrentProcess, SetPriorityClass, BELOW_NORMAL_PRIORITY_CLASS,
+        PROCESS_MODE_BACKGROUND_BEGIN,
+    };
+
+    unsafe {
+        let process = GetCurrentProcess();
+        if SetPriorityClass(process, BELOW_NORMAL_PRIORITY_CLASS) == 0
+            || SetPriorityClass(process, PROCESS_MODE_BACKGROUND_BEGIN) == 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+// On Linux the nice value is per thread, so a spawned thread keeps the test
+// process itself at normal priority.
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lower_priority_sets_nice_value() {
+        std::thread::spawn(|| {
+            lower_priority().unwrap();
+            let nice = unsafe { libc::getpriority(libc::PRIO_PROCESS, 0) };
+            assert!(nice >= LOW_NICE);
+        })
+        .join()
+        .unwrap();
+    }
+}
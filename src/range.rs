@@ -0,0 +1,158 @@
+use crate::error::{BackupWardenError, Result};
+use crate::layout::{BackupLayout, DEFAULT_DATE_FORMAT};
+use chrono::NaiveDate;
+
+/// The `--since` and `--until` dates a read command was given, kept as typed
+/// until the job is known, since they may be written in its `date_format`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DateRange {
+    pub since: Option<String>,
+    pub until: Option<String>,
+}
+
+impl DateRange {
+    /// Takes the value of `flag` from `args` when it is `--since` or `--until`,
+    /// returning whether it was one of them.
+    pub fn take<'a>(
+        &mut self,
+        flag: &str,
+        args: &mut impl Iterator<Item = &'a String>,
+    ) -> std::result::Result<bool, String> {
+        let slot = match flag {
+            "--since" => &mut self.since,
+            "--until" => &mut self.until,
+            _ => return Ok(false),
+        };
+        *slot = Some(
+            args.next()
+                .ok_or_else(|| format!("{} needs a date", flag))?
+                .clone(),
+        );
+        Ok(true)
+    }
+
+    /// Reads both dates as `YYYY-MM-DD` or in the job's `date_format`.
+    pub fn resolve(&self, layout: &BackupLayout) -> Result<DateWindow> {
+        let parse = |value: &Option<String>, flag: &str| {
+            value
+                .as_deref()
+                .map(|value| {
+                    NaiveDate::parse_from_str(value, DEFAULT_DATE_FORMAT)
+                        .ok()
+                        .or_else(|| layout.parse_date(value))
+                        .ok_or_else(|| {
+                            BackupWardenError::Usage(format!(
+                                "{} expects YYYY-MM-DD or the configured date_format, got {:?}",
+                                flag, value
+                            ))
+                        })
+                })
+                .transpose()
+        };
+        let window = DateWindow {
+            since: parse(&self.since, "--since")?,
+            until: parse(&self.until, "--until")?,
+        };
+        if let (Some(since), Some(until)) = (window.since, window.until) {
+            if since > until {
+                return Err(BackupWardenError::Usage(
+                    "--since is after --until".to_string(),
+                ));
+            }
+        }
+        Ok(window)
+    }
+}
+
+/// The job and dates given to `list`, `verify` and `diff`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RangeOptions {
+    pub job: Option<String>,
+    pub range: DateRange,
+}
+
+impl RangeOptions {
+    /// Parses `[--job NAME] [--since DATE] [--until DATE]`, answering anything
+    /// else with `usage`.
+    pub fn parse(args: &[String], usage: &str) -> Result<Self> {
+        Self::parse_args(args).map_err(|e| BackupWardenError::Usage(format!("{}\n{}", e, usage)))
+    }
+
+    fn parse_args(args: &[String]) -> std::result::Result<Self, String> {
+        let mut options = RangeOptions::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if options.range.take(arg, &mut args)? {
+                continue;
+            }
+            match arg.as_str() {
+                "--job" => options.job = Some(args.next().ok_or("--job needs a name")?.clone()),
+                other => return Err(format!("unexpected argument {}", other)),
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// The days, both ends included, a read command looks at. Unbounded by default.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DateWindow {
+    pub since: Option<NaiveDate>,
+    pub until: Option<NaiveDate>,
+}
+
+impl DateWindow {
+    pub fn includes(&self, date: NaiveDate) -> bool {
+        self.since.is_none_or(|since| date >= since) && self.until.is_none_or(|until| date <= until)
+    }
+
+    /// Whether a backup whose date is unknown, `None`, or `date` is in the
+    /// window. Only an unbounded window includes backups of unknown date.
+    pub fn includes_maybe(&self, date: Option<NaiveDate>) -> bool {
+        match date {
+            Some(date) => self.includes(date),
+            None => self.since.is_none() && self.until.is_none(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BackupWardenConfig;
+
+    #[test]
+    fn test_parse_range_options() {
+        let args = |values: &[&str]| values.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+        let options =
+            RangeOptions::parse(&args(&["--since", "2024-01-02", "--job", "docs"]), "").unwrap();
+        assert_eq!(options.job.as_deref(), Some("docs"));
+        assert_eq!(options.range.since.as_deref(), Some("2024-01-02"));
+        assert_eq!(options.range.until, None);
+        assert!(RangeOptions::parse(&args(&["--until"]), "").is_err());
+        assert!(RangeOptions::parse(&args(&["extra"]), "").is_err());
+    }
+
+    #[test]
+    fn test_resolve_accepts_date_format() {
+        let config = BackupWardenConfig {
+            date_format: Some("%d.%m.%Y".to_string()),
+            ..Default::default()
+        };
+        let layout = BackupLayout::new(&config).unwrap();
+        let range = |since: &str, until: &str| DateRange {
+            since: Some(since.to_string()),
+            until: Some(until.to_string()),
+        };
+        let day = |m, d| NaiveDate::from_ymd_opt(2024, m, d).unwrap();
+
+        let window = range("2024-01-02", "31.01.2024").resolve(&layout).unwrap();
+        assert_eq!(window.since, Some(day(1, 2)));
+        assert_eq!(window.until, Some(day(1, 31)));
+        assert!(window.includes(day(1, 2)) && !window.includes(day(2, 1)));
+        assert!(!window.includes_maybe(None));
+
+        assert!(range("yesterday", "2024-01-31").resolve(&layout).is_err());
+        assert!(range("2024-02-01", "2024-01-31").resolve(&layout).is_err());
+    }
+}
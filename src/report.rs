@@ -0,0 +1,135 @@
+use crate::config::BackupWardenConfig;
+use crate::copy::ErrorCounts;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+use tracing::warn;
+
+/// Name of the file in `report_dir` holding the summary of the latest backup.
+pub const LAST_REPORT_FILE_NAME: &str = "last_backup_report.json";
+
+/// Name of the file in `report_dir` with one summary per line, oldest first.
+pub const HISTORY_FILE_NAME: &str = "backup_history.jsonl";
+
+/// How many summaries the history keeps before dropping the oldest.
+pub const HISTORY_LEN: usize = 1000;
+
+/// A concise summary of one backup run across every backup location.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BackupSummary {
+    /// When the run started and finished, in RFC 3339 local time.
+    pub started_at: String,
+    pub finished_at: String,
+    pub watch_folder: String,
+    pub duration_secs: f64,
+    pub locations: Vec<LocationSummary>,
+}
+
+/// How the backup into one location went.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LocationSummary {
+    pub location: String,
+    pub succeeded: bool,
+    pub files: u64,
+    pub bytes: u64,
+    pub duration_secs: f64,
+    /// Problems worked around during the copy.
+    pub errors: u64,
+    #[serde(default)]
+    pub error_counts: ErrorCounts,
+    /// Why the backup failed, when it did.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Writes `summary` to the job's `report_dir`, when one is set. A report that
+/// can't be written is warned about but doesn't fail the backup.
+pub fn record(config: &BackupWardenConfig, summary: &BackupSummary) {
+    let Some(dir) = &config.report_dir else {
+        return;
+    };
+    if let Err(e) = write(Path::new(dir), summary) {
+        warn!(event = "report_write_failed", path = %dir, error = %e, "Failed to write backup report");
+    }
+}
+
+/// Replaces the last report in `dir` with `summary` and appends it to the
+/// history, keeping the newest [`HISTORY_LEN`] entries. Both files are replaced
+/// atomically, so readers never see one half-written.
+pub fn write(dir: &Path, summary: &BackupSummary) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    replace(
+        &dir.join(LAST_REPORT_FILE_NAME),
+        &serde_json::to_string_pretty(summary)?,
+    )?;
+
+    let history_path = dir.join(HISTORY_FILE_NAME);
+    let existing = match fs::read_to_string(&history_path) {
+        Ok(existing) => existing,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+    let lines: Vec<&str> = existing.lines().filter(|line| !line.is_empty()).collect();
+    let keep = lines.len().saturating_sub(HISTORY_LEN - 1);
+    let mut history: String = lines[keep..]
+        .iter()
+        .map(|line| format!("{}\n", line))
+        .collect();
+    history.push_str(&serde_json::to_string(summary)?);
+    history.push('\n');
+    replace(&history_path, &history)
+}
+
+fn replace(path: &Path, contents: &str) -> io::Result<()> {
+    let mut tmp_name = path.as_os_str().to_os_string();
+    tmp_name.push(".tmp");
+    fs::write(&tmp_name, contents)?;
+    fs::rename(&tmp_name, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_write_keeps_last_report_and_bounded_history() {
+        let temp_dir = tempdir().unwrap();
+        let summary = |n: u64| BackupSummary {
+            started_at: "2024-01-31T13:00:00+00:00".to_string(),
+            finished_at: "2024-01-31T13:00:05+00:00".to_string(),
+            watch_folder: "w".to_string(),
+            duration_secs: 5.0,
+            locations: vec![LocationSummary {
+                location: "b".to_string(),
+                succeeded: true,
+                files: n,
+                bytes: 10 * n,
+                duration_secs: 5.0,
+                errors: 0,
+                error_counts: ErrorCounts::default(),
+                error: None,
+            }],
+        };
+        let full: String = (0..HISTORY_LEN as u64)
+            .map(|n| format!("{}\n", serde_json::to_string(&summary(n)).unwrap()))
+            .collect();
+        fs::write(temp_dir.path().join(HISTORY_FILE_NAME), full).unwrap();
+        write(temp_dir.path(), &summary(HISTORY_LEN as u64)).unwrap();
+
+        let last: BackupSummary = serde_json::from_str(
+            &fs::read_to_string(temp_dir.path().join(LAST_REPORT_FILE_NAME)).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(last, summary(HISTORY_LEN as u64));
+        let history = fs::read_to_string(temp_dir.path().join(HISTORY_FILE_NAME)).unwrap();
+        let lines: Vec<BackupSummary> = history
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), HISTORY_LEN);
+        assert_eq!(lines[0], summary(1));
+        assert_eq!(lines[HISTORY_LEN - 1], last);
+    }
+}
@@ -0,0 +1,478 @@
+use crate::audit::{self, AuditOutcome};
+use crate::chunks::ChunkStore;
+use crate::config::{BackupWardenConfig, CONFIG_SNAPSHOT_FILE_NAME};
+use crate::copy::{copy_dir_all, CopyOptions, CopyStats};
+use crate::error::{BackupWardenError, Result};
+use crate::filter::PathFilter;
+use crate::index::FILE_INDEX_NAME;
+use crate::layout::BackupLayout;
+use crate::ledger::LEDGER_FILE_NAME;
+use crate::lock::RunLock;
+use crate::manifest::{INDEX_FILE_NAME, MANIFEST_FILE_NAME, ROOT_FILE_NAME};
+use crate::range::DateWindow;
+use crate::schedule::SnapshotKind;
+use crate::signature::SIGNATURE_FILE_NAME;
+use crate::sink::{BackupSink, LocalSink};
+use crate::size::{directory_size, format_bytes};
+use crate::stripes::{StripeMap, STRIPE_MAP_FILE_NAME};
+use chrono::{NaiveDate, NaiveDateTime};
+use dialoguer::{Confirm, Input, Select};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Files backup-warden adds to each backup, which are not part of the data.
+const BOOKKEEPING_FILES: [&str; 8] = [
+    LEDGER_FILE_NAME,
+    MANIFEST_FILE_NAME,
+    INDEX_FILE_NAME,
+    FILE_INDEX_NAME,
+    ROOT_FILE_NAME,
+    SIGNATURE_FILE_NAME,
+    CONFIG_SNAPSHOT_FILE_NAME,
+    STRIPE_MAP_FILE_NAME,
+];
+
+/// What `restore` should do, parsed from its command-line arguments.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RestoreOptions {
+    pub backup: Option<PathBuf>,
+    pub target: Option<PathBuf>,
+    pub job: Option<String>,
+    /// Pick the backup and target from the terminal.
+    pub interactive: bool,
+}
+
+impl RestoreOptions {
+    /// Parses `[<backup> <target>] [--interactive] [--job NAME]`.
+    pub fn parse(args: &[String]) -> Result<Self> {
+        Self::parse_args(args).map_err(BackupWardenError::Usage)
+    }
+
+    fn parse_args(args: &[String]) -> std::result::Result<Self, String> {
+        let mut options = RestoreOptions::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--job" => options.job = Some(args.next().ok_or("--job needs a name")?.clone()),
+                "--interactive" => options.interactive = true,
+                flag if flag.starts_with("--") => return Err(format!("unknown option {}", flag)),
+                path if options.backup.is_none() => options.backup = Some(PathBuf::from(path)),
+                path if options.target.is_none() => options.target = Some(PathBuf::from(path)),
+                extra => return Err(format!("unexpected argument {}", extra)),
+            }
+        }
+        if !options.interactive && options.target.is_none() {
+            return Err("give a backup and a target, or use --interactive".to_string());
+        }
+        Ok(options)
+    }
+}
+
+/// One backup that can be restored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RestorePoint {
+    /// When the backup was taken; snapshots only know their day.
+    pub taken: NaiveDateTime,
+    /// Which kind of snapshot this is, or `None` for a daily backup.
+    pub snapshot: Option<SnapshotKind>,
+    pub location: String,
+    pub path: PathBuf,
+}
+
+impl RestorePoint {
+    fn describe(&self, show_location: bool) -> String {
+        let mut label = match self.snapshot {
+            Some(kind) => format!("{} {}", self.taken.date(), kind.label().to_lowercase()),
+            None => self.taken.format("%Y-%m-%d %I %p").to_string(),
+        };
+        if let Ok(size) = directory_size(&self.path) {
+            label.push_str(&format!(" ({})", format_bytes(size)));
+        }
+        if show_location {
+            label.push_str(&format!(" in {}", self.location));
+        }
+        label
+    }
+}
+
+/// Every daily backup and snapshot from a day in `window` in the job's
+/// locations, newest first.
+pub fn restore_points(
+    config: &BackupWardenConfig,
+    window: &DateWindow,
+) -> Result<Vec<RestorePoint>> {
+    let layout = BackupLayout::new(config).map_err(BackupWardenError::Config)?;
+    let mut points = Vec::new();
+    for location in &config.backup_locations {
+        let root = Path::new(location);
+        for entry in read_dirs(&layout.daily_root(root)) {
+            let Some(day) = layout
+                .parse_day(&entry.file_name().to_string_lossy())
+                .filter(|day| window.includes(*day))
+            else {
+                continue;
+            };
+            for (time, path) in layout.backups_in_day(&entry.path()) {
+                points.push(RestorePoint {
+                    taken: day.and_time(time),
+                    snapshot: None,
+                    location: location.clone(),
+                    path,
+                });
+            }
+        }
+        for kind in [SnapshotKind::Weekly, SnapshotKind::Monthly] {
+            for entry in read_dirs(&root.join(kind.dir_name())) {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let Some(day) = NaiveDate::parse_from_str(&name, "%Y-%m-%d")
+                    .ok()
+                    .filter(|day| window.includes(*day))
+                else {
+                    continue;
+                };
+                points.push(RestorePoint {
+                    taken: day.and_time(chrono::NaiveTime::MIN),
+                    snapshot: Some(kind),
+                    location: location.clone(),
+                    path: entry.path(),
+                });
+            }
+        }
+    }
+    points.sort_by_key(|point| std::cmp::Reverse((point.taken, point.snapshot)));
+    Ok(points)
+}
+
+/// Prints every backup and snapshot from a day in `window`, newest first, and
+/// returns how many there were.
+pub fn list(config: &BackupWardenConfig, window: &DateWindow) -> Result<usize> {
+    let points = restore_points(config, window)?;
+    for point in &points {
+        println!("{}\t{}", point.describe(false), point.path.display());
+    }
+    Ok(points.len())
+}
+
+fn read_dirs(dir: &Path) -> Vec<fs::DirEntry> {
+    fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .filter(|e| e.file_type().map(|ft| ft.is_dir()).unwrap_or(false))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Restores as `options` says, asking for whatever is missing when it is
+/// interactive. Returns `None` if the user backed out.
+pub fn run(config: &BackupWardenConfig, options: &RestoreOptions) -> Result<Option<CopyStats>> {
+    let (backup, target) = match (&options.backup, &options.target) {
+        (Some(backup), Some(target)) if !options.interactive => (backup.clone(), target.clone()),
+        _ => match pick(config, options)? {
+            Some(picked) => picked,
+            None => return Ok(None),
+        },
+    };
+    let result = restore(config, &backup, &target);
+    let detail = format!("{} into {}", backup.display(), target.display());
+    match &result {
+        Ok(_) => audit::record(config, "restore", AuditOutcome::Ok, &detail),
+        Err(e) => audit::record(
+            config,
+            "restore",
+            AuditOutcome::Failed,
+            &format!("{}: {}", detail, e),
+        ),
+    }
+    result.map(Some)
+}
+
+/// Asks for a restore point, then a target, then confirmation.
+fn pick(
+    config: &BackupWardenConfig,
+    options: &RestoreOptions,
+) -> Result<Option<(PathBuf, PathBuf)>> {
+    let prompt_error = |e: dialoguer::Error| {
+        let dialoguer::Error::IO(source) = e;
+        BackupWardenError::Io {
+            path: PathBuf::from("terminal"),
+            source,
+        }
+    };
+    let backup = match &options.backup {
+        Some(backup) => backup.clone(),
+        None => {
+            let points = restore_points(config, &DateWindow::default())?;
+            if points.is_empty() {
+                return Err(BackupWardenError::NoBackups);
+            }
+            let show_location = config.backup_locations.len() > 1;
+            let labels: Vec<String> = points
+                .iter()
+                .map(|point| point.describe(show_location))
+                .collect();
+            let Some(chosen) = Select::new()
+                .with_prompt("Restore which backup?")
+                .items(&labels)
+                .default(0)
+                .interact_opt()
+                .map_err(prompt_error)?
+            else {
+                return Ok(None);
+            };
+            points[chosen].path.clone()
+        }
+    };
+    let target = match &options.target {
+        Some(target) => target.clone(),
+        None => PathBuf::from(
+            Input::<String>::new()
+                .with_prompt("Restore into which folder?")
+                .default(config.watch_folder.clone())
+                .interact_text()
+                .map_err(prompt_error)?,
+        ),
+    };
+    let confirmed = Confirm::new()
+        .with_prompt(format!(
+            "Copy {} into {}? Files there with the same names are overwritten",
+            backup.display(),
+            target.display()
+        ))
+        .default(false)
+        .interact()
+        .map_err(prompt_error)?;
+    Ok(confirmed.then_some((backup, target)))
+}
+
+/// Copies the backup at `backup` into `target`, leaving out the files
+/// backup-warden adds to every backup. Files already in `target` with the same
+/// names are overwritten; others, even ones named like those files, are left
+/// alone. A striped backup is gathered
+/// from its parts in every location it was spread across.
+pub fn restore(config: &BackupWardenConfig, backup: &Path, target: &Path) -> Result<CopyStats> {
+    // Keeps a running backup or cleanup from changing the backup mid-copy
+    let _lock = RunLock::acquire(&config.backup_locations).map_err(BackupWardenError::Locked)?;
+    let layout = BackupLayout::new(config).map_err(BackupWardenError::Config)?;
+    let failed = |source| BackupWardenError::Io {
+        path: backup.to_path_buf(),
+        source,
+    };
+    if !backup.is_dir() {
+        return Err(failed(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no such backup",
+        )));
+    }
+    let parts = match StripeMap::load(backup).map_err(failed)? {
+        Some(map) => map.parts(backup).ok_or_else(|| {
+            failed(io::Error::new(
+                io::ErrorKind::NotFound,
+                "striped backup is in none of the locations it was spread across",
+            ))
+        })?,
+        None => vec![backup.to_path_buf()],
+    };
+    if let Some(missing) = parts.iter().find(|part| !part.is_dir()) {
+        return Err(BackupWardenError::Io {
+            path: missing.clone(),
+            source: io::Error::new(
+                io::ErrorKind::NotFound,
+                "part of the striped backup is missing",
+            ),
+        });
+    }
+    let mut stats = CopyStats::default();
+    for part in &parts {
+        let failed = |source| BackupWardenError::Io {
+            path: part.clone(),
+            source,
+        };
+        let filter = PathFilter::new(&BackupWardenConfig {
+            watch_folder: part.to_string_lossy().into_owned(),
+            exclude_patterns: BOOKKEEPING_FILES.map(String::from).to_vec(),
+            ..Default::default()
+        })
+        .map_err(BackupWardenError::Config)?;
+        let mut options = CopyOptions::new(config, &filter);
+        // The backup is already laid out the way the watch folder was
+        options.split_top_level = false;
+        let mut sink = LocalSink::new(&target.to_string_lossy(), layout.clone())
+            .with_copy_buffer_size(config.copy_buffer_size())
+            .with_reflink(config.allow_reflink())
+            .with_ads(config.copy_ads)
+            .with_attributes(config.preserve_attributes)
+            .with_reassembly(ChunkStore::find(part))
+            .with_bookkeeping(false);
+        sink.begin_folder(target.to_path_buf()).map_err(failed)?;
+        let part_stats = copy_dir_all(part, &options, &mut sink).map_err(failed)?;
+        sink.finish_backup().map_err(failed)?;
+        stats.absorb(part_stats);
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::Manifest;
+    use chrono::{Local, TimeZone};
+    use tempfile::tempdir;
+
+    fn args(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let options = RestoreOptions::parse(&args(&["b", "t"])).unwrap();
+        assert_eq!(options.backup.as_deref(), Some(Path::new("b")));
+        assert_eq!(options.target.as_deref(), Some(Path::new("t")));
+        assert!(
+            RestoreOptions::parse(&args(&["--interactive"]))
+                .unwrap()
+                .interactive
+        );
+        assert!(RestoreOptions::parse(&args(&["b"])).is_err());
+        assert!(RestoreOptions::parse(&args(&[])).is_err());
+    }
+
+    #[test]
+    fn test_restore_lists_points_and_copies_data_only() {
+        let temp_dir = tempdir().unwrap();
+        let location = temp_dir.path().join("backup");
+        let config = BackupWardenConfig {
+            watch_folder: temp_dir.path().join("live").to_str().unwrap().to_string(),
+            backup_locations: vec![location.to_str().unwrap().to_string()],
+            ..Default::default()
+        };
+        let layout = BackupLayout::new(&config).unwrap();
+        let older = layout.backup_path(
+            &location,
+            &Local.with_ymd_and_hms(2024, 1, 30, 9, 0, 0).unwrap(),
+        );
+        let newer = layout.backup_path(
+            &location,
+            &Local.with_ymd_and_hms(2024, 1, 31, 13, 0, 0).unwrap(),
+        );
+        let snapshot = location
+            .join(SnapshotKind::Monthly.dir_name())
+            .join("2024-01-31");
+        for root in [&older, &newer, &snapshot] {
+            fs::create_dir_all(root.join("docs")).unwrap();
+            fs::write(root.join("docs/a.txt"), b"aaa").unwrap();
+            fs::write(root.join(CONFIG_SNAPSHOT_FILE_NAME), b"{}").unwrap();
+            Manifest::write(root).unwrap();
+        }
+
+        let points = restore_points(&config, &DateWindow::default()).unwrap();
+        let paths: Vec<&Path> = points.iter().map(|p| p.path.as_path()).collect();
+        assert_eq!(
+            paths,
+            [newer.as_path(), snapshot.as_path(), older.as_path()]
+        );
+
+        let target = temp_dir.path().join("restored");
+        // Not the backup's, so restore shouldn't touch it
+        fs::create_dir_all(&target).unwrap();
+        fs::write(target.join(MANIFEST_FILE_NAME), b"mine").unwrap();
+        let stats = restore(&config, &newer, &target).unwrap();
+        assert_eq!(stats.files_copied, 1);
+        assert_eq!(fs::read(target.join("docs/a.txt")).unwrap(), b"aaa");
+        assert_eq!(fs::read(target.join(MANIFEST_FILE_NAME)).unwrap(), b"mine");
+        let mut names: Vec<_> = fs::read_dir(&target)
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        names.sort();
+        assert_eq!(names, [MANIFEST_FILE_NAME, "docs"]);
+    }
+
+    #[test]
+    fn test_restore_gathers_striped_backup_from_every_location() {
+        use crate::stripes::StripedSink;
+
+        let temp_dir = tempdir().unwrap();
+        let watch = temp_dir.path().join("live");
+        fs::create_dir_all(watch.join("docs")).unwrap();
+        for i in 0..10 {
+            fs::write(watch.join("docs").join(format!("{}.txt", i)), i.to_string()).unwrap();
+        }
+        let locations = [temp_dir.path().join("one"), temp_dir.path().join("two")];
+        let config = BackupWardenConfig {
+            watch_folder: watch.to_str().unwrap().to_string(),
+            backup_locations: locations
+                .iter()
+                .map(|location| location.to_str().unwrap().to_string())
+                .collect(),
+            striping: true,
+            ..Default::default()
+        };
+        let layout = BackupLayout::new(&config).unwrap();
+        let filter = PathFilter::new(&config).unwrap();
+        let mut sink = StripedSink::new(
+            config
+                .backup_locations
+                .iter()
+                .map(|location| LocalSink::new(location, layout.clone()))
+                .collect(),
+        );
+        let time = Local.with_ymd_and_hms(2024, 1, 31, 13, 0, 0).unwrap();
+        sink.begin_backup(&time).unwrap();
+        copy_dir_all(&watch, &CopyOptions::new(&config, &filter), &mut sink).unwrap();
+        sink.finish_backup().unwrap();
+
+        let target = temp_dir.path().join("restored");
+        let part = layout.backup_path(&locations[1], &time);
+        let stats = restore(&config, &part, &target).unwrap();
+        assert_eq!(stats.files_copied, 10);
+        for i in 0..10 {
+            let restored = target.join("docs").join(format!("{}.txt", i));
+            assert_eq!(fs::read_to_string(restored).unwrap(), i.to_string());
+        }
+        assert!(!target.join(STRIPE_MAP_FILE_NAME).exists());
+
+        fs::remove_dir_all(layout.backup_path(&locations[0], &time)).unwrap();
+        assert!(restore(&config, &part, &temp_dir.path().join("again")).is_err());
+    }
+
+    #[test]
+    fn test_restore_reassembles_chunked_files() {
+        let temp_dir = tempdir().unwrap();
+        let watch_folder = temp_dir.path().join("watch");
+        let location = temp_dir.path().join("backup");
+        fs::create_dir_all(&watch_folder).unwrap();
+        let big: Vec<u8> = (0..3 * 1024 * 1024).map(|i| (i * 7 % 251) as u8).collect();
+        fs::write(watch_folder.join("db.sqlite"), &big).unwrap();
+        fs::write(watch_folder.join("small.txt"), b"small").unwrap();
+        let config = BackupWardenConfig {
+            watch_folder: watch_folder.to_str().unwrap().to_string(),
+            backup_locations: vec![location.to_str().unwrap().to_string()],
+            chunking: true,
+            ..Default::default()
+        };
+
+        let layout = BackupLayout::new(&config).unwrap();
+        let backup = layout.backup_path(&location, &Local::now());
+        let filter = PathFilter::allow_all();
+        let mut sink = LocalSink::new(location.to_str().unwrap(), layout).with_chunking(true);
+        sink.begin_folder(backup.clone()).unwrap();
+        copy_dir_all(
+            Path::new(&config.watch_folder),
+            &CopyOptions::new(&config, &filter),
+            &mut sink,
+        )
+        .unwrap();
+        sink.finish_backup().unwrap();
+        assert!(backup.join("db.sqlite.bw-chunks").exists());
+        assert!(!backup.join("db.sqlite").exists());
+
+        let target = temp_dir.path().join("restored");
+        restore(&config, &backup, &target).unwrap();
+        assert_eq!(fs::read(target.join("db.sqlite")).unwrap(), big);
+        assert_eq!(fs::read(target.join("small.txt")).unwrap(), b"small");
+        assert!(!target.join("db.sqlite.bw-chunks").exists());
+    }
+}
@@ -0,0 +1,580 @@
+use crate::config::{BackupWardenConfig, MonthlyStrategy};
+use crate::error::{BackupWardenError, Result};
+use crate::layout::BackupLayout;
+use crate::ledger::LEDGER_FILE_NAME;
+use crate::state::WardenState;
+use crate::watch::{WatchEventKind, WatchMode, DEFAULT_WATCH_EVENTS};
+use chrono::{DateTime, Datelike, Local, Months, NaiveDate, NaiveDateTime, Weekday};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Folder in each backup location holding the monthly snapshots.
+pub const SNAPSHOT_DIR_NAME: &str = "Monthly Snapshots";
+
+/// Folder in each backup location holding the weekly snapshots.
+pub const WEEKLY_SNAPSHOT_DIR_NAME: &str = "Weekly Snapshots";
+
+/// Which kind of snapshot is being taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SnapshotKind {
+    Weekly,
+    Monthly,
+}
+
+impl SnapshotKind {
+    /// The folder in each backup location the snapshots go in.
+    pub fn dir_name(self) -> &'static str {
+        match self {
+            SnapshotKind::Weekly => WEEKLY_SNAPSHOT_DIR_NAME,
+            SnapshotKind::Monthly => SNAPSHOT_DIR_NAME,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            SnapshotKind::Weekly => "Weekly snapshot",
+            SnapshotKind::Monthly => "Monthly snapshot",
+        }
+    }
+}
+
+/// How often the watcher rescans the watch folder for changes.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// How often the watcher rescans with the network profile, where every rescan
+/// lists the whole share again.
+pub const NETWORK_POLL_INTERVAL: Duration = Duration::from_secs(4 * 3600);
+
+/// Monthly snapshots are taken on the last day of each month.
+pub fn is_last_day_of_month(date: NaiveDate) -> bool {
+    let next_day = date + chrono::Duration::days(1);
+    next_day.month() != date.month()
+}
+
+/// The first day on or after `from` that gets a monthly snapshot.
+pub fn next_snapshot_day(from: NaiveDate) -> NaiveDate {
+    let mut day = from;
+    while !is_last_day_of_month(day) {
+        day += chrono::Duration::days(1);
+    }
+    day
+}
+
+/// How `last_snapshot_month` records the month of `date`.
+pub fn snapshot_month(date: NaiveDate) -> String {
+    date.format("%Y-%m").to_string()
+}
+
+/// How `last_weekly_snapshot_week` records the ISO week of `date`.
+pub fn snapshot_week(date: NaiveDate) -> String {
+    date.format("%G-W%V").to_string()
+}
+
+/// The first day on or after `from` that falls on `weekday`.
+pub fn next_weekday(from: NaiveDate, weekday: Weekday) -> NaiveDate {
+    let ahead = (7 + weekday.num_days_from_monday() - from.weekday().num_days_from_monday()) % 7;
+    from + chrono::Duration::days(ahead.into())
+}
+
+/// Whether the weekly snapshot is due on `today`: it is `weekly_snapshot_day`
+/// and a reachable backup location has no weekly snapshot recorded for this
+/// week yet.
+pub fn weekly_snapshot_due(config: &BackupWardenConfig, today: NaiveDate) -> bool {
+    if config.weekly_snapshot_day != Some(today.weekday()) {
+        return false;
+    }
+    let week = snapshot_week(today);
+    config
+        .backup_locations
+        .iter()
+        .map(Path::new)
+        .filter(|location| location.is_dir())
+        .any(|location| {
+            WardenState::load(location)
+                .last_weekly_snapshot_week
+                .as_ref()
+                != Some(&week)
+        })
+}
+
+/// How long monthly snapshots are kept, from `monthly_retention_mode`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MonthlyRetention {
+    All,
+    /// Keep the snapshots of the newest this many months that have one.
+    LastMonths(usize),
+    /// Keep the snapshots dated within this many years before today.
+    WithinYears(u32),
+}
+
+/// Removes the oldest weekly snapshots in `location` beyond the newest `keep`,
+/// returning the removed folders. Folders not named for a date are left alone.
+pub fn prune_weekly_snapshots(location: &Path, keep: usize) -> io::Result<Vec<PathBuf>> {
+    let mut snapshots = dated_snapshots(&location.join(WEEKLY_SNAPSHOT_DIR_NAME))?;
+    snapshots.sort_by_key(|(day, _)| std::cmp::Reverse(*day));
+    remove_snapshots(snapshots.into_iter().skip(keep).map(|(_, path)| path))
+}
+
+/// Removes the monthly snapshots in `location` that `retention` no longer
+/// keeps as of `today`, returning the removed folders. Folders not named for
+/// a date are left alone.
+pub fn cleanup_monthly(
+    location: &Path,
+    retention: MonthlyRetention,
+    today: NaiveDate,
+) -> io::Result<Vec<PathBuf>> {
+    let snapshots = dated_snapshots(&location.join(SNAPSHOT_DIR_NAME))?;
+    let expired: Vec<PathBuf> = match retention {
+        MonthlyRetention::All => return Ok(Vec::new()),
+        MonthlyRetention::LastMonths(keep) => {
+            // Counted by month rather than by folder, so a month snapshotted
+            // twice still counts once
+            let mut months: Vec<(i32, u32)> = snapshots
+                .iter()
+                .map(|(day, _)| (day.year(), day.month()))
+                .collect();
+            months.sort_unstable_by(|a, b| b.cmp(a));
+            months.dedup();
+            let kept = &months[..keep.min(months.len())];
+            snapshots
+                .into_iter()
+                .filter(|(day, _)| !kept.contains(&(day.year(), day.month())))
+                .map(|(_, path)| path)
+                .collect()
+        }
+        MonthlyRetention::WithinYears(years) => {
+            let cutoff = today
+                .checked_sub_months(Months::new(years.saturating_mul(12)))
+                .unwrap_or(NaiveDate::MIN);
+            snapshots
+                .into_iter()
+                .filter(|(day, _)| *day < cutoff)
+                .map(|(_, path)| path)
+                .collect()
+        }
+    };
+    remove_snapshots(expired.into_iter())
+}
+
+/// The folders in `dir` named for a date, with that date, oldest first.
+fn dated_snapshots(dir: &Path) -> io::Result<Vec<(NaiveDate, PathBuf)>> {
+    let mut snapshots: Vec<(NaiveDate, PathBuf)> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let day = NaiveDate::parse_from_str(&name, "%Y-%m-%d").ok()?;
+                Some((day, entry.path()))
+            })
+            .collect(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    snapshots.sort();
+    Ok(snapshots)
+}
+
+fn remove_snapshots(paths: impl Iterator<Item = PathBuf>) -> io::Result<Vec<PathBuf>> {
+    let mut removed = Vec::new();
+    for path in paths {
+        fs::remove_dir_all(&path)?;
+        removed.push(path);
+    }
+    Ok(removed)
+}
+
+/// Whether a reachable backup location has no monthly snapshot recorded for
+/// the month of `today`. Unreachable locations are ignored, so one offline
+/// drive doesn't cause a new snapshot with every backup.
+pub fn month_needs_snapshot(config: &BackupWardenConfig, today: NaiveDate) -> bool {
+    let month = snapshot_month(today);
+    config
+        .backup_locations
+        .iter()
+        .map(Path::new)
+        .filter(|location| location.is_dir())
+        .any(|location| WardenState::load(location).last_snapshot_month.as_ref() != Some(&month))
+}
+
+/// Whether the monthly snapshot is due on `today`, per `monthly_strategy`.
+/// `backed_up` says whether a backup has just been made.
+pub fn snapshot_due(config: &BackupWardenConfig, today: NaiveDate, backed_up: bool) -> bool {
+    match config.monthly_strategy {
+        MonthlyStrategy::LastDayOfMonth => is_last_day_of_month(today),
+        MonthlyStrategy::FirstBackupOfNewMonth => backed_up && month_needs_snapshot(config, today),
+    }
+}
+
+/// When a watcher started at `start` next rescans the folder, `count` times.
+pub fn next_polls(start: DateTime<Local>, interval: Duration, count: u32) -> Vec<DateTime<Local>> {
+    let interval = chrono::Duration::from_std(interval).unwrap();
+    (1..=count as i32).map(|n| start + interval * n).collect()
+}
+
+/// Whether any backup location already holds daily backups. When none does, the
+/// daemon makes a backup as soon as it starts.
+pub fn backup_folders_exist(config: &BackupWardenConfig) -> Result<bool> {
+    let layout = BackupLayout::new(config).map_err(BackupWardenError::Config)?;
+    Ok(config
+        .backup_locations
+        .iter()
+        .any(|location| layout.daily_root(Path::new(location)).is_dir()))
+}
+
+/// When the newest finished backup in `location` was taken, going by the names
+/// of its day and backup folders. `None` if there is none.
+pub fn newest_backup_time(layout: &BackupLayout, location: &Path) -> Option<NaiveDateTime> {
+    let mut days: Vec<(NaiveDate, PathBuf)> = fs::read_dir(layout.daily_root(location))
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            layout
+                .parse_day(&entry.file_name().to_string_lossy())
+                .map(|day| (day, entry.path()))
+        })
+        .collect();
+    days.sort();
+    days.iter().rev().find_map(|(day, dir)| {
+        layout
+            .backups_in_day(dir)
+            .into_iter()
+            .rev()
+            .find(|(_, path)| !path.join(LEDGER_FILE_NAME).exists())
+            .map(|(time, _)| day.and_time(time))
+    })
+}
+
+/// The locations whose newest finished backup is older than
+/// `alert_if_older_than_hours` at `now`, with when it was taken, or `None` for
+/// locations with no backup at all. Empty when the setting is off.
+pub fn stale_locations(
+    config: &BackupWardenConfig,
+    layout: &BackupLayout,
+    now: NaiveDateTime,
+) -> Vec<(String, Option<NaiveDateTime>)> {
+    let Some(hours) = config.alert_if_older_than_hours else {
+        return Vec::new();
+    };
+    let limit = chrono::Duration::hours(hours as i64);
+    config
+        .backup_locations
+        .iter()
+        .filter_map(|location| {
+            let newest = newest_backup_time(layout, Path::new(location));
+            newest
+                .is_none_or(|taken| now - taken > limit)
+                .then(|| (location.clone(), newest))
+        })
+        .collect()
+}
+
+/// Whether any backup location already holds a monthly snapshot.
+pub fn snapshots_exist(config: &BackupWardenConfig) -> bool {
+    config.backup_locations.iter().any(|location| {
+        std::fs::read_dir(Path::new(location).join(SNAPSHOT_DIR_NAME))
+            .map(|mut entries| entries.next().is_some())
+            .unwrap_or(false)
+    })
+}
+
+/// Prints what the daemon would do for `config` if it were started at `now`.
+pub fn print(config: &BackupWardenConfig, now: DateTime<Local>) -> Result<()> {
+    let events: Vec<String> = config
+        .watch_events
+        .as_deref()
+        .unwrap_or(&DEFAULT_WATCH_EVENTS)
+        .iter()
+        .map(|kind| event_name(*kind).to_string())
+        .collect();
+    match config.watch_mode {
+        WatchMode::Poll => println!(
+            "Backups: on {} changes, checked every {} minutes",
+            events.join("/"),
+            config.poll_interval().as_secs() / 60
+        ),
+        WatchMode::Native => println!(
+            "Backups: on {} changes, reported by the OS as they happen",
+            events.join("/")
+        ),
+    }
+    if config.min_backup_interval_secs > 0 {
+        println!(
+            "  at least {}s after the previous backup finished",
+            config.min_backup_interval_secs
+        );
+    }
+
+    if let Some(hours) = config.cleanup_interval_hours {
+        println!(
+            "Cleanup: with each backup, and after {} hours without one",
+            hours
+        );
+    }
+
+    if let Some(hours) = config.alert_if_older_than_hours {
+        println!(
+            "Age alert: when a location's newest backup is more than {} hours old",
+            hours
+        );
+    }
+
+    if !backup_folders_exist(config)? {
+        println!("Initial backup: {} (no backups yet)", format_time(now));
+    }
+    if config.snapshot_on_first_run && !snapshots_exist(config) {
+        println!(
+            "Baseline snapshot: {} (no monthly snapshots yet)",
+            format_time(now)
+        );
+    }
+    if config.watch_mode == WatchMode::Poll {
+        println!("Next change checks, if started now:");
+        for poll in next_polls(now, config.poll_interval(), 3) {
+            println!("  {}", format_time(poll));
+        }
+    }
+    let today = now.date_naive();
+    match config.monthly_strategy {
+        MonthlyStrategy::LastDayOfMonth => {
+            println!("Next monthly snapshot: {}", next_snapshot_day(today))
+        }
+        MonthlyStrategy::FirstBackupOfNewMonth if month_needs_snapshot(config, today) => {
+            println!("Next monthly snapshot: with the next backup")
+        }
+        MonthlyStrategy::FirstBackupOfNewMonth => {
+            let next_month = next_snapshot_day(today) + chrono::Duration::days(1);
+            println!(
+                "Next monthly snapshot: with the first backup on or after {}",
+                next_month
+            )
+        }
+    }
+    if let Some(weekday) = config.weekly_snapshot_day {
+        let next = if weekly_snapshot_due(config, today) || today.weekday() != weekday {
+            next_weekday(today, weekday)
+        } else {
+            next_weekday(today + chrono::Duration::days(1), weekday)
+        };
+        println!(
+            "Next weekly snapshot: {} (keeping {})",
+            next,
+            config.weekly_retention()
+        );
+    }
+    Ok(())
+}
+
+fn event_name(kind: WatchEventKind) -> &'static str {
+    match kind {
+        WatchEventKind::Create => "create",
+        WatchEventKind::Modify => "modify",
+        WatchEventKind::Metadata => "metadata",
+        WatchEventKind::Remove => "remove",
+        WatchEventKind::Access => "access",
+    }
+}
+
+fn format_time(time: DateTime<Local>) -> String {
+    time.format("%Y-%m-%d %H:%M (UTC%:z)").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_stale_locations_by_newest_finished_backup() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let fresh = temp_dir.path().join("fresh");
+        let stale = temp_dir.path().join("stale");
+        let empty = temp_dir.path().join("empty");
+        let mut config = BackupWardenConfig {
+            backup_locations: [&fresh, &stale, &empty]
+                .iter()
+                .map(|location| location.to_str().unwrap().to_string())
+                .collect(),
+            ..Default::default()
+        };
+        let layout = BackupLayout::new(&config).unwrap();
+        let at = |d, h| Local.with_ymd_and_hms(2024, 3, d, h, 0, 0).unwrap();
+        fs::create_dir_all(layout.backup_path(&fresh, &at(10, 9))).unwrap();
+        fs::create_dir_all(layout.backup_path(&stale, &at(9, 9))).unwrap();
+        // An unfinished backup doesn't count as a newer one
+        let unfinished = layout.backup_path(&stale, &at(10, 9));
+        fs::create_dir_all(&unfinished).unwrap();
+        fs::write(unfinished.join(LEDGER_FILE_NAME), "").unwrap();
+
+        let now = at(10, 12).naive_local();
+        assert!(stale_locations(&config, &layout, now).is_empty());
+        config.alert_if_older_than_hours = Some(24);
+        assert_eq!(
+            stale_locations(&config, &layout, now),
+            [
+                (
+                    stale.to_str().unwrap().to_string(),
+                    Some(at(9, 9).naive_local())
+                ),
+                (empty.to_str().unwrap().to_string(), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_is_last_day_of_month() {
+        assert!(is_last_day_of_month(
+            NaiveDate::from_ymd_opt(2024, 1, 31).unwrap()
+        ));
+        assert!(!is_last_day_of_month(
+            NaiveDate::from_ymd_opt(2024, 1, 30).unwrap()
+        ));
+        assert!(is_last_day_of_month(
+            NaiveDate::from_ymd_opt(2024, 2, 29).unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_next_snapshot_day() {
+        let day = |m, d| NaiveDate::from_ymd_opt(2024, m, d).unwrap();
+        assert_eq!(next_snapshot_day(day(2, 10)), day(2, 29));
+        assert_eq!(next_snapshot_day(day(2, 29)), day(2, 29));
+        assert_eq!(next_snapshot_day(day(3, 1)), day(3, 31));
+    }
+
+    #[test]
+    fn test_first_backup_of_new_month_strategy() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let config = BackupWardenConfig {
+            backup_locations: vec![temp_dir.path().to_str().unwrap().to_string()],
+            monthly_strategy: MonthlyStrategy::FirstBackupOfNewMonth,
+            ..Default::default()
+        };
+        let day = |m, d| NaiveDate::from_ymd_opt(2024, m, d).unwrap();
+        assert!(snapshot_due(&config, day(2, 10), true));
+        assert!(!snapshot_due(&config, day(2, 10), false));
+
+        WardenState::update(temp_dir.path(), |state| {
+            state.last_snapshot_month = Some(snapshot_month(day(2, 1)))
+        })
+        .unwrap();
+        assert!(!snapshot_due(&config, day(2, 29), true));
+        assert!(snapshot_due(&config, day(3, 1), true));
+    }
+
+    #[test]
+    fn test_weekly_snapshot_once_per_week() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let config = BackupWardenConfig {
+            backup_locations: vec![temp_dir.path().to_str().unwrap().to_string()],
+            weekly_snapshot_day: Some(Weekday::Sun),
+            ..Default::default()
+        };
+        let day = |m, d| NaiveDate::from_ymd_opt(2024, m, d).unwrap();
+        assert_eq!(next_weekday(day(2, 7), Weekday::Sun), day(2, 11));
+        assert_eq!(next_weekday(day(2, 11), Weekday::Sun), day(2, 11));
+        assert!(!weekly_snapshot_due(&config, day(2, 10)));
+        assert!(weekly_snapshot_due(&config, day(2, 11)));
+
+        WardenState::update(temp_dir.path(), |state| {
+            state.last_weekly_snapshot_week = Some(snapshot_week(day(2, 11)))
+        })
+        .unwrap();
+        assert!(!weekly_snapshot_due(&config, day(2, 11)));
+        assert!(weekly_snapshot_due(&config, day(2, 18)));
+    }
+
+    #[test]
+    fn test_prune_weekly_snapshots_keeps_newest() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let weekly = temp_dir.path().join(WEEKLY_SNAPSHOT_DIR_NAME);
+        for name in ["2024-01-28", "2024-02-04", "2024-02-11", "notes"] {
+            std::fs::create_dir_all(weekly.join(name)).unwrap();
+        }
+        let removed = prune_weekly_snapshots(temp_dir.path(), 2).unwrap();
+        assert_eq!(removed, [weekly.join("2024-01-28")]);
+        assert!(weekly.join("2024-02-11").is_dir());
+        assert!(weekly.join("notes").is_dir());
+    }
+
+    #[test]
+    fn test_cleanup_monthly_across_years() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let monthly = temp_dir.path().join(SNAPSHOT_DIR_NAME);
+        let names = [
+            "2021-11-30",
+            "2022-12-31",
+            "2023-01-01",
+            "2023-01-31",
+            "2023-12-31",
+            "2024-01-31",
+            "notes",
+        ];
+        let make = || {
+            for name in names {
+                std::fs::create_dir_all(monthly.join(name)).unwrap();
+            }
+        };
+        let today = NaiveDate::from_ymd_opt(2024, 2, 15).unwrap();
+
+        make();
+        let removed = cleanup_monthly(temp_dir.path(), MonthlyRetention::All, today).unwrap();
+        assert!(removed.is_empty());
+
+        // January 2023 has two snapshots and counts as one month
+        let removed =
+            cleanup_monthly(temp_dir.path(), MonthlyRetention::LastMonths(3), today).unwrap();
+        assert_eq!(
+            removed,
+            [monthly.join("2021-11-30"), monthly.join("2022-12-31")]
+        );
+        assert!(monthly.join("2023-01-01").is_dir());
+        assert!(monthly.join("notes").is_dir());
+
+        make();
+        let removed =
+            cleanup_monthly(temp_dir.path(), MonthlyRetention::WithinYears(1), today).unwrap();
+        assert_eq!(
+            removed,
+            [
+                monthly.join("2021-11-30"),
+                monthly.join("2022-12-31"),
+                monthly.join("2023-01-01"),
+                monthly.join("2023-01-31")
+            ]
+        );
+        make();
+        let removed =
+            cleanup_monthly(temp_dir.path(), MonthlyRetention::WithinYears(2), today).unwrap();
+        assert_eq!(removed, [monthly.join("2021-11-30")]);
+        assert!(monthly.join("2022-12-31").is_dir());
+        assert!(monthly.join("2024-01-31").is_dir());
+    }
+
+    #[test]
+    fn test_snapshots_exist() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let config = BackupWardenConfig {
+            backup_locations: vec![temp_dir.path().to_str().unwrap().to_string()],
+            ..Default::default()
+        };
+        assert!(!snapshots_exist(&config));
+
+        let snapshots = temp_dir.path().join(SNAPSHOT_DIR_NAME);
+        std::fs::create_dir_all(&snapshots).unwrap();
+        assert!(!snapshots_exist(&config));
+
+        std::fs::create_dir_all(snapshots.join("2024-01-31")).unwrap();
+        assert!(snapshots_exist(&config));
+    }
+
+    #[test]
+    fn test_next_polls_are_an_interval_apart() {
+        let start = Local.with_ymd_and_hms(2024, 1, 31, 9, 15, 0).unwrap();
+        let polls = next_polls(start, POLL_INTERVAL, 2);
+        assert_eq!(polls.len(), 2);
+        assert_eq!((polls[0] - start).num_minutes(), 60);
+        assert_eq!((polls[1] - start).num_minutes(), 120);
+    }
+}
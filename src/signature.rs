@@ -0,0 +1,99 @@
+use crate::copy::unlink_existing;
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Name of the file next to `.root` holding its ed25519 signature, with
+/// `signing_key_path` set.
+pub const SIGNATURE_FILE_NAME: &str = ".root.sig";
+
+/// Reads the private key at `path`: the 32-byte ed25519 seed as 64 hex digits.
+pub fn load_signing_key(path: &Path) -> io::Result<SigningKey> {
+    let bytes = parse_hex::<32>(&fs::read_to_string(path)?)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(SigningKey::from_bytes(&bytes))
+}
+
+/// Parses a public key given as 64 hex digits.
+pub fn parse_public_key(hex: &str) -> Result<VerifyingKey, String> {
+    let bytes = parse_hex::<32>(hex)?;
+    VerifyingKey::from_bytes(&bytes).map_err(|e| format!("not an ed25519 public key: {}", e))
+}
+
+/// Signs `merkle_root` with `key`, storing the signature in `root`'s
+/// `.root.sig` file.
+pub fn sign_root(root: &Path, key: &SigningKey, merkle_root: &str) -> io::Result<()> {
+    let signature = key.sign(merkle_root.as_bytes());
+    let path = root.join(SIGNATURE_FILE_NAME);
+    unlink_existing(&path)?;
+    fs::write(path, format!("{}\n", hex(&signature.to_bytes())))
+}
+
+/// Checks that `root`'s `.root.sig` is `key`'s signature of `merkle_root`,
+/// describing what is wrong if not.
+pub fn verify_root(root: &Path, key: &VerifyingKey, merkle_root: &str) -> Result<(), String> {
+    let raw = match fs::read_to_string(root.join(SIGNATURE_FILE_NAME)) {
+        Ok(raw) => raw,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(format!(
+                "{}: missing, backup is unsigned",
+                SIGNATURE_FILE_NAME
+            ))
+        }
+        Err(e) => return Err(format!("{}: unreadable ({})", SIGNATURE_FILE_NAME, e)),
+    };
+    let signature = parse_hex::<64>(&raw)
+        .map(|bytes| Signature::from_bytes(&bytes))
+        .map_err(|e| format!("{}: {}", SIGNATURE_FILE_NAME, e))?;
+    key.verify_strict(merkle_root.as_bytes(), &signature)
+        .map_err(|_| format!("{}: signature does not match", SIGNATURE_FILE_NAME))
+}
+
+pub(crate) fn parse_hex<const N: usize>(raw: &str) -> Result<[u8; N], String> {
+    let raw = raw.trim();
+    if raw.len() != N * 2 || !raw.is_ascii() {
+        return Err(format!("expected {} hex digits", N * 2));
+    }
+    let mut bytes = [0; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&raw[i * 2..i * 2 + 2], 16)
+            .map_err(|_| format!("expected {} hex digits", N * 2))?;
+    }
+    Ok(bytes)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_signature_round_trip_and_tamper() {
+        let temp_dir = tempdir().unwrap();
+        let key_path = temp_dir.path().join("key");
+        fs::write(&key_path, format!("{}\n", "07".repeat(32))).unwrap();
+        let key = load_signing_key(&key_path).unwrap();
+        let public = parse_public_key(&hex(&key.verifying_key().to_bytes())).unwrap();
+
+        let root = temp_dir.path();
+        sign_root(root, &key, "abc").unwrap();
+        assert_eq!(verify_root(root, &public, "abc"), Ok(()));
+        assert!(verify_root(root, &public, "abd").is_err());
+
+        fs::remove_file(root.join(SIGNATURE_FILE_NAME)).unwrap();
+        assert!(verify_root(root, &public, "abc")
+            .unwrap_err()
+            .contains("unsigned"));
+    }
+
+    #[test]
+    fn test_parse_public_key_rejects_bad_hex() {
+        assert!(parse_public_key("abcd").is_err());
+        assert!(parse_public_key(&"zz".repeat(32)).is_err());
+    }
+}
@@ -0,0 +1,1325 @@
+use crate::attributes::{clear_archive_bit, copy_attributes};
+use crate::chunks::{self, ChunkStore, CHUNKED_FILE_MIN_BYTES};
+use crate::config::{FsyncMode, IncrementalCompare};
+use crate::copy::{
+    copy_file, link_tree, reflink_file, sync_dir, sync_file, sync_tree, unlink_existing,
+    DEFAULT_COPY_BUFFER_SIZE,
+};
+use crate::index::FILE_INDEX_NAME;
+use crate::layout::BackupLayout;
+use crate::ledger::{Ledger, LEDGER_FILE_NAME};
+use crate::manifest::{
+    Manifest, ManifestFormat, INDEX_FILE_NAME, MANIFEST_FILE_NAME, ROOT_FILE_NAME,
+};
+use crate::signature::{sign_root, SIGNATURE_FILE_NAME};
+use crate::size::{self, directory_size, format_bytes};
+use crate::streams::copy_streams;
+use chrono::{DateTime, Local, NaiveDate};
+use ed25519_dalek::SigningKey;
+use filetime::{set_file_mtime, FileTime};
+use sha2::{Digest, Sha256};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use tracing::{debug, error, info, warn};
+
+/// A destination backups are written to and pruned from.
+///
+/// The backup loop calls `begin_backup`, then `create_dir`, `write_file` and
+/// `write_link` for each entry of the watch folder that passes the filters,
+/// `write_contents` for the config snapshot, and finally `finish_backup`.
+/// Paths handed to a sink are relative to the root of the backup being
+/// written. Cleanup uses `list_backups` and `prune` to remove the days its
+/// [`Retention`] doesn't keep.
+///
+/// [`LocalSink`] writes to a folder on a local or mounted filesystem; implement
+/// this trait to send backups anywhere else.
+pub trait BackupSink {
+    /// A human-readable name for logs, such as the location path.
+    fn name(&self) -> String;
+
+    /// Starts a backup taken at `time`.
+    fn begin_backup(&mut self, time: &DateTime<Local>) -> io::Result<()>;
+
+    /// Creates the (possibly empty) directory `relative`. Parents are created first.
+    fn create_dir(&mut self, relative: &Path) -> io::Result<()>;
+
+    /// Stores the file at `source` as `relative`, returning the bytes written.
+    fn write_file(
+        &mut self,
+        relative: &Path,
+        source: &Path,
+        metadata: &fs::Metadata,
+    ) -> io::Result<u64>;
+
+    /// Stores `contents` as the file `relative`, for files backup-warden writes
+    /// itself rather than copies from the watch folder.
+    fn write_contents(&mut self, relative: &Path, contents: &[u8]) -> io::Result<()>;
+
+    /// Stores the link at `source` as a link, for `symlink_policy: "recreate"`.
+    /// Sinks without links can keep the default, which reports it as unsupported
+    /// so the link is skipped with a warning.
+    fn write_link(&mut self, relative: &Path, source: &Path) -> io::Result<()> {
+        let _ = (relative, source);
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "this destination cannot store links",
+        ))
+    }
+
+    /// Fills the backup just begun with hard links to the files of the finished
+    /// backup at `previous`, leaving out its manifest, for a selective backup to
+    /// write the changed files over. Sinks that can't keep the default, which
+    /// reports it as unsupported so a full backup is taken instead.
+    fn link_from(&mut self, previous: &Path) -> io::Result<()> {
+        let _ = previous;
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "this destination cannot link backups",
+        ))
+    }
+
+    /// Removes the file, link or folder `relative` from the backup, for a file
+    /// deleted from the watch folder since the backup it was linked from.
+    fn remove(&mut self, relative: &Path) -> io::Result<()> {
+        let _ = relative;
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "this destination cannot remove files",
+        ))
+    }
+
+    /// Called once everything inside the directory `relative` has been written,
+    /// with the metadata of the source directory, so its timestamps can be
+    /// carried over last. The default does nothing.
+    fn finish_dir(&mut self, relative: &Path, source: &fs::Metadata) -> io::Result<()> {
+        let _ = (relative, source);
+        Ok(())
+    }
+
+    /// Completes the backup started by `begin_backup`.
+    fn finish_backup(&mut self) -> io::Result<()>;
+
+    /// The days this sink holds backups for, in any order. Yielded lazily so a
+    /// sink with a long history needn't hold it all in memory at once.
+    fn list_backups(&self) -> io::Result<Box<dyn Iterator<Item = NaiveDate> + '_>>;
+
+    /// Removes every backup taken on `day`, reporting what was removed.
+    fn prune(&mut self, day: NaiveDate) -> io::Result<PrunedBackup>;
+
+    /// Describes the backups taken on `day` without removing them, so cleanup
+    /// can log what it is about to prune. The default names only the day.
+    fn describe(&self, day: NaiveDate) -> PrunedBackup {
+        PrunedBackup {
+            day,
+            path: day.to_string(),
+            bytes: 0,
+        }
+    }
+}
+
+/// A day of backups removed by cleanup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrunedBackup {
+    pub day: NaiveDate,
+    /// Where the backups were, in whatever form the sink names them.
+    pub path: String,
+    /// Space reclaimed, as far as the sink can tell.
+    pub bytes: u64,
+}
+
+/// Which days of backups cleanup keeps. A day is pruned when any limit that is
+/// set says so, but the newest `min_retained` days, and always the newest day,
+/// which holds the newest backup, are kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retention {
+    /// Keep only the newest this many days that have backups.
+    pub keep_days: Option<usize>,
+    /// Prune days more than this many calendar days before `today`.
+    pub max_age_days: Option<u64>,
+    /// Never prune the newest this many days, whatever the other limits say.
+    pub min_retained: usize,
+    pub today: NaiveDate,
+}
+
+impl Retention {
+    /// Keeps the newest `keep_days` days, however old.
+    pub fn count(keep_days: usize) -> Self {
+        Retention {
+            keep_days: Some(keep_days),
+            max_age_days: None,
+            min_retained: 0,
+            today: Local::now().date_naive(),
+        }
+    }
+
+    fn is_too_old(&self, day: NaiveDate) -> bool {
+        self.max_age_days.is_some_and(|max_age| {
+            self.today
+                .checked_sub_days(chrono::Days::new(max_age))
+                .is_some_and(|cutoff| day < cutoff)
+        })
+    }
+}
+
+/// Prunes the days of backups in `sink` that `retention` doesn't keep. Days
+/// that fail to be removed are logged and left for the next run. Returns the
+/// days that were removed.
+pub fn prune_old_backups(
+    sink: &mut dyn BackupSink,
+    retention: &Retention,
+) -> io::Result<Vec<PrunedBackup>> {
+    let floor = retention.min_retained.max(1);
+    let keep = retention.keep_days.unwrap_or(1).max(floor);
+    // Only the newest `keep` days are held while scanning; older ones drop out
+    // of the heap as newer ones arrive
+    let mut newest = BinaryHeap::new();
+    let mut dropped = Vec::new();
+    for day in sink.list_backups()? {
+        newest.push(Reverse(day));
+        if newest.len() > keep {
+            dropped.extend(newest.pop().map(|Reverse(day)| day));
+        }
+    }
+    // Without a count limit the heap only singled out the newest day
+    let mut expired: Vec<NaiveDate> = if retention.keep_days.is_some() {
+        dropped
+    } else {
+        dropped
+            .into_iter()
+            .filter(|day| retention.is_too_old(*day))
+            .collect()
+    };
+    // Sorted newest first; all but the newest `floor` days may still age out
+    let newest = newest.into_sorted_vec();
+    expired.extend(
+        newest
+            .into_iter()
+            .map(|Reverse(day)| day)
+            .skip(floor)
+            .filter(|day| retention.is_too_old(*day)),
+    );
+    expired.sort();
+
+    // Logged up front, so what cleanup meant to remove is on record even if
+    // it stops part-way
+    if !expired.is_empty() {
+        let planned: Vec<PrunedBackup> = expired.iter().map(|day| sink.describe(*day)).collect();
+        let total: u64 = planned.iter().map(|backup| backup.bytes).sum();
+        let list = planned
+            .iter()
+            .map(|backup| {
+                format!(
+                    "{} {} ({})",
+                    backup.day,
+                    backup.path,
+                    format_bytes(backup.bytes)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        info!(
+            event = "cleanup_planned",
+            location = %sink.name(),
+            count = planned.len(),
+            bytes = total,
+            backups = %list,
+            "Removing {} day(s) of backups, {}: {}",
+            planned.len(),
+            format_bytes(total),
+            list
+        );
+    }
+
+    let mut pruned = Vec::new();
+    for day in &expired {
+        match sink.prune(*day) {
+            Ok(backup) => {
+                info!(
+                    event = "backup_pruned",
+                    location = %sink.name(),
+                    day = %day,
+                    path = %backup.path,
+                    bytes = backup.bytes,
+                    "Removed old backup {} ({})",
+                    backup.path,
+                    format_bytes(backup.bytes)
+                );
+                pruned.push(backup);
+            }
+            Err(e) => error!(
+                event = "cleanup_failed",
+                location = %sink.name(),
+                day = %day,
+                error = %e,
+                "Failed to remove old backup"
+            ),
+        }
+    }
+    Ok(pruned)
+}
+
+/// A backup location on a local or mounted filesystem, laid out by `path_template`.
+///
+/// Each backup keeps a [`Ledger`] while it is written, so an interrupted backup
+/// into the same folder picks up where it left off.
+pub struct LocalSink {
+    location: PathBuf,
+    layout: BackupLayout,
+    copy_buffer_size: usize,
+    merkle_root: bool,
+    manifest_format: ManifestFormat,
+    signing_key: Option<SigningKey>,
+    /// Whether to try copy-on-write clones, until one fails.
+    reflink: bool,
+    /// Where backups are written before being moved into the location.
+    staging_dir: Option<PathBuf>,
+    /// Whether to copy each file's alternate data streams too.
+    copy_ads: bool,
+    /// Whether to copy each file's readonly, hidden, system and archive
+    /// attributes too.
+    preserve_attributes: bool,
+    /// Whether to clear the archive attribute of each source file once it
+    /// is copied, until that fails.
+    clear_archive_bit: bool,
+    /// Where large files are split into chunks instead of copied whole.
+    chunk_store: Option<ChunkStore>,
+    /// Where the chunks of chunked files being copied out of a backup are.
+    reassemble_from: Option<ChunkStore>,
+    /// How a resumed backup tells which of its files are unchanged.
+    incremental_compare: IncrementalCompare,
+    /// The Unix permission bits given to every file written, instead of the
+    /// source's.
+    destination_mode: Option<u32>,
+    fsync: FsyncMode,
+    bookkeeping: bool,
+    current: Option<InProgress>,
+}
+
+struct InProgress {
+    root: PathBuf,
+    /// Where the backup is moved once finished, when `root` is in the
+    /// staging folder.
+    dest: Option<PathBuf>,
+    /// `None` without bookkeeping, when nothing is resumed.
+    ledger: Option<Ledger>,
+    /// The root's modification time, re-applied once the ledger and manifest
+    /// stop touching the root.
+    root_mtime: Option<FileTime>,
+}
+
+impl InProgress {
+    fn is_confirmed(&self, source: &Path, metadata: &fs::Metadata, dest: &Path) -> bool {
+        self.ledger
+            .as_ref()
+            .is_some_and(|ledger| ledger.is_confirmed(source, metadata, dest))
+    }
+
+    fn confirm(&mut self, source: &Path, metadata: &fs::Metadata) -> io::Result<()> {
+        match &mut self.ledger {
+            Some(ledger) => ledger.confirm(source, metadata),
+            None => Ok(()),
+        }
+    }
+}
+
+impl LocalSink {
+    pub fn new(location: &str, layout: BackupLayout) -> Self {
+        LocalSink {
+            location: PathBuf::from(location),
+            layout,
+            copy_buffer_size: DEFAULT_COPY_BUFFER_SIZE,
+            merkle_root: false,
+            manifest_format: ManifestFormat::default(),
+            signing_key: None,
+            reflink: false,
+            staging_dir: None,
+            copy_ads: false,
+            preserve_attributes: false,
+            clear_archive_bit: false,
+            chunk_store: None,
+            reassemble_from: None,
+            incremental_compare: IncrementalCompare::default(),
+            destination_mode: None,
+            fsync: FsyncMode::Off,
+            bookkeeping: true,
+            current: None,
+        }
+    }
+
+    /// Sets the buffer each file is copied through.
+    pub fn with_copy_buffer_size(mut self, copy_buffer_size: usize) -> Self {
+        self.copy_buffer_size = copy_buffer_size;
+        self
+    }
+
+    /// Also stores the manifest's Merkle root in a `.root` file at the end of
+    /// each backup.
+    pub fn with_merkle_root(mut self, merkle_root: bool) -> Self {
+        self.merkle_root = merkle_root;
+        self
+    }
+
+    /// Writes each backup's manifest in `format`.
+    pub fn with_manifest_format(mut self, format: ManifestFormat) -> Self {
+        self.manifest_format = format;
+        self
+    }
+
+    /// Clones files copy-on-write where the filesystem allows it. The first
+    /// clone that fails, e.g. because the source is on another filesystem,
+    /// turns it off for the rest of this sink's life.
+    pub fn with_reflink(mut self, reflink: bool) -> Self {
+        self.reflink = reflink;
+        self
+    }
+
+    /// Also copies the NTFS alternate data streams of every file, on Windows.
+    pub fn with_ads(mut self, copy_ads: bool) -> Self {
+        self.copy_ads = copy_ads;
+        self
+    }
+
+    /// Also copies the readonly, hidden, system and archive attributes of every
+    /// file, on Windows.
+    pub fn with_attributes(mut self, preserve_attributes: bool) -> Self {
+        self.preserve_attributes = preserve_attributes;
+        self
+    }
+
+    /// Clears the archive attribute of every source file once it is copied,
+    /// on Windows, for tools that tell what still needs backing up by it. The
+    /// first file it can't be cleared on, e.g. because the source is a shadow
+    /// copy, turns it off for the rest of this sink's life.
+    pub fn with_clear_archive_bit(mut self, clear_archive_bit: bool) -> Self {
+        self.clear_archive_bit = clear_archive_bit;
+        self
+    }
+
+    /// Stores files of at least [`CHUNKED_FILE_MIN_BYTES`] as a list of
+    /// content-defined chunks kept once in the location's chunk store, so the
+    /// unchanged parts of large files aren't stored again by every backup.
+    pub fn with_chunking(mut self, chunking: bool) -> Self {
+        self.chunk_store = chunking
+            .then(|| ChunkStore::new(&self.location).with_fsync(self.fsync != FsyncMode::Off));
+        self
+    }
+
+    /// Writes each chunk list met while copying out of a backup as the file it
+    /// stands for, rebuilt from the chunks in `store`.
+    pub fn with_reassembly(mut self, store: Option<ChunkStore>) -> Self {
+        self.reassemble_from = store;
+        self
+    }
+
+    /// Also signs each backup's Merkle root with `key`, when there is one.
+    pub fn with_signing_key(mut self, key: Option<SigningKey>) -> Self {
+        self.signing_key = key;
+        self
+    }
+
+    /// Decides by `compare` which files an interrupted backup already holds
+    /// when it resumes.
+    pub fn with_incremental_compare(mut self, compare: IncrementalCompare) -> Self {
+        self.incremental_compare = compare;
+        self
+    }
+
+    /// Gives every file written the permission bits `mode`, and every folder
+    /// the same bits plus search permission wherever `mode` grants read, on
+    /// Unix. Ignored elsewhere.
+    pub fn with_destination_mode(mut self, mode: Option<u32>) -> Self {
+        self.destination_mode = mode;
+        self
+    }
+
+    /// Flushes what each backup writes to disk per `fsync`: each file as it is
+    /// written with `each-file`, so a file the ledger records as copied is on
+    /// disk, and in every mode but `off` the whole finished backup and the
+    /// folders leading to it, along with new chunks as they are stored.
+    pub fn with_fsync(mut self, fsync: FsyncMode) -> Self {
+        self.fsync = fsync;
+        self.chunk_store = self
+            .chunk_store
+            .take()
+            .map(|store| store.with_fsync(fsync != FsyncMode::Off));
+        self
+    }
+
+    /// Keeps a ledger while each backup is written and a manifest once it is
+    /// finished. Turned off for copies that aren't backups, such as restores,
+    /// which then leave nothing in the folder but what they copied.
+    pub fn with_bookkeeping(mut self, bookkeeping: bool) -> Self {
+        self.bookkeeping = bookkeeping;
+        self
+    }
+
+    /// Writes each backup into a folder under `staging_dir`, e.g. on a fast
+    /// local disk, and moves it into the location once it is finished. The
+    /// staging folder is named after the backup's final path, so an
+    /// interrupted backup resumes in the same one.
+    pub fn with_staging_dir(mut self, staging_dir: Option<PathBuf>) -> Self {
+        self.staging_dir = staging_dir;
+        self
+    }
+
+    /// The location backups are written into.
+    pub fn location(&self) -> &Path {
+        &self.location
+    }
+
+    /// Starts a backup into `root` instead of the layout's folder for the current
+    /// time, e.g. for monthly snapshots.
+    pub fn begin_folder(&mut self, root: PathBuf) -> io::Result<()> {
+        let (root, dest) = match &self.staging_dir {
+            Some(staging_dir) => (staging_dir.join(staging_name(&root)), Some(root)),
+            None => (root, None),
+        };
+        let ledger = if self.bookkeeping {
+            Some(Ledger::open(&root)?.with_compare(self.incremental_compare))
+        } else {
+            fs::create_dir_all(&root)?;
+            None
+        };
+        self.current = Some(InProgress {
+            root,
+            dest,
+            ledger,
+            root_mtime: None,
+        });
+        Ok(())
+    }
+
+    /// Writes `source` into the chunk store, or rebuilds it from one, when
+    /// chunking or reassembly applies to it. Returns `None` when neither does
+    /// and the file is to be copied as usual.
+    fn write_chunked(
+        &mut self,
+        relative: &Path,
+        source: &Path,
+        metadata: &fs::Metadata,
+    ) -> io::Result<Option<u64>> {
+        let chunk_store = self.chunk_store.clone();
+        let reassemble_from = self.reassemble_from.clone();
+        let current = self.current()?;
+        let (dest, store, chunking) = match (chunk_store, reassemble_from) {
+            (Some(store), _) if metadata.len() >= CHUNKED_FILE_MIN_BYTES => {
+                (chunks::list_path(&current.root.join(relative)), store, true)
+            }
+            (_, Some(store)) => match chunks::chunked_path(relative) {
+                Some(original) => (current.root.join(original), store, false),
+                None => return Ok(None),
+            },
+            _ => return Ok(None),
+        };
+        if current.is_confirmed(source, metadata, &dest) {
+            return Ok(Some(0));
+        }
+        let mut bytes = if chunking {
+            store.store_file(source, &dest)?
+        } else {
+            store.reassemble(source, &dest)?
+        };
+        // A chunked file's streams are kept on its chunk list, which restore
+        // reassembles it from
+        if self.copy_ads {
+            bytes += copy_streams(source, &dest)?;
+        }
+        self.finish_file(source, &dest)?;
+        let current = self.current()?;
+        current.confirm(source, metadata)?;
+        Ok(Some(bytes))
+    }
+
+    /// Applies what every file gets once its contents are at `dest`, whether
+    /// copied, cloned, chunked or reassembled from `source`.
+    fn finish_file(&mut self, source: &Path, dest: &Path) -> io::Result<()> {
+        apply_file_mode(dest, self.destination_mode)?;
+        if self.fsync == FsyncMode::EachFile {
+            sync_file(dest)?;
+        }
+        // Last, as a readonly attribute would keep the file from being synced
+        if self.preserve_attributes {
+            copy_attributes(source, dest)?;
+        }
+        if self.clear_archive_bit {
+            if let Err(e) = clear_archive_bit(source) {
+                warn!(event = "archive_bit_unavailable", path = %source.display(), error = %e, "Failed to clear the archive attribute, leaving it on the remaining files");
+                self.clear_archive_bit = false;
+            }
+        }
+        Ok(())
+    }
+
+    fn current(&mut self) -> io::Result<&mut InProgress> {
+        self.current
+            .as_mut()
+            .ok_or_else(|| io::Error::other("no backup in progress"))
+    }
+}
+
+impl BackupSink for LocalSink {
+    fn name(&self) -> String {
+        self.location.display().to_string()
+    }
+
+    fn begin_backup(&mut self, time: &DateTime<Local>) -> io::Result<()> {
+        let root = self.layout.backup_path(&self.location, time);
+        self.begin_folder(root)
+    }
+
+    fn create_dir(&mut self, relative: &Path) -> io::Result<()> {
+        let mode = self.destination_mode;
+        let current = self.current()?;
+        let dir = current.root.join(relative);
+        fs::create_dir_all(&dir)?;
+        match mode {
+            Some(mode) => set_mode(&dir, dir_mode(mode)),
+            None => Ok(()),
+        }
+    }
+
+    fn write_file(
+        &mut self,
+        relative: &Path,
+        source: &Path,
+        metadata: &fs::Metadata,
+    ) -> io::Result<u64> {
+        let buffer_size = self.copy_buffer_size;
+        let try_reflink = self.reflink;
+        if let Some(bytes) = self.write_chunked(relative, source, metadata)? {
+            return Ok(bytes);
+        }
+        let current = self.current()?;
+        let dest = current.root.join(relative);
+        if current.is_confirmed(source, metadata, &dest) {
+            return Ok(0);
+        }
+        let cloned = try_reflink
+            && match reflink_file(source, &dest) {
+                Ok(()) => true,
+                Err(e) => {
+                    debug!(event = "reflink_unavailable", location = %self.location.display(), error = %e, "Copy-on-write clones unavailable, copying files");
+                    self.reflink = false;
+                    false
+                }
+            };
+        let copy_ads = self.copy_ads;
+        let mut bytes = if cloned {
+            metadata.len()
+        } else {
+            copy_file(source, &dest, buffer_size)?
+        };
+        if copy_ads {
+            bytes += copy_streams(source, &dest)?;
+        }
+        self.finish_file(source, &dest)?;
+        let current = self.current()?;
+        current.confirm(source, metadata)?;
+        Ok(bytes)
+    }
+
+    fn write_contents(&mut self, relative: &Path, contents: &[u8]) -> io::Result<()> {
+        let mode = self.destination_mode;
+        let current = self.current()?;
+        let dest = current.root.join(relative);
+        unlink_existing(&dest)?;
+        fs::write(&dest, contents)?;
+        apply_file_mode(&dest, mode)?;
+        if self.fsync == FsyncMode::EachFile {
+            sync_file(&dest)?;
+        }
+        Ok(())
+    }
+
+    fn write_link(&mut self, relative: &Path, source: &Path) -> io::Result<()> {
+        let current = self.current()?;
+        let dest = current.root.join(relative);
+        let target = fs::read_link(source)?;
+        if fs::symlink_metadata(&dest).is_ok() {
+            fs::remove_file(&dest)?;
+        }
+        create_link(source, &target, &dest)
+    }
+
+    fn link_from(&mut self, previous: &Path) -> io::Result<()> {
+        if self.staging_dir.is_some() {
+            // The staging folder is rarely on the location's filesystem
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "backups can't be linked through staging_dir",
+            ));
+        }
+        let current = self.current()?;
+        let root = current.root.clone();
+        if let Err(e) = link_tree(previous, &root) {
+            // Left empty apart from the ledger, for the full backup instead
+            for entry in fs::read_dir(&root)? {
+                let entry = entry?;
+                if entry.file_name() != LEDGER_FILE_NAME {
+                    remove_entry(&entry.path())?;
+                }
+            }
+            return Err(e);
+        }
+        for name in [
+            MANIFEST_FILE_NAME,
+            INDEX_FILE_NAME,
+            ROOT_FILE_NAME,
+            SIGNATURE_FILE_NAME,
+            FILE_INDEX_NAME,
+        ] {
+            match fs::remove_file(root.join(name)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, relative: &Path) -> io::Result<()> {
+        let current = self.current()?;
+        let path = current.root.join(relative);
+        for path in [chunks::list_path(&path), path] {
+            if fs::symlink_metadata(&path).is_ok() {
+                remove_entry(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish_dir(&mut self, relative: &Path, source: &fs::Metadata) -> io::Result<()> {
+        let current = self.current()?;
+        let mtime = FileTime::from_last_modification_time(source);
+        if relative.as_os_str().is_empty() {
+            current.root_mtime = Some(mtime);
+        }
+        set_file_mtime(current.root.join(relative), mtime)
+    }
+
+    fn finish_backup(&mut self) -> io::Result<()> {
+        match self.current.take() {
+            Some(current) => {
+                if let Some(ledger) = current.ledger {
+                    ledger.finish()?;
+                }
+                if self.bookkeeping {
+                    let manifest = Manifest::write_as(&current.root, self.manifest_format)?;
+                    if self.merkle_root {
+                        manifest.write_root(&current.root)?;
+                        if let Some(key) = &self.signing_key {
+                            sign_root(&current.root, key, &manifest.merkle_root())?;
+                        }
+                    }
+                    for name in [
+                        MANIFEST_FILE_NAME,
+                        INDEX_FILE_NAME,
+                        ROOT_FILE_NAME,
+                        SIGNATURE_FILE_NAME,
+                    ] {
+                        let path = current.root.join(name);
+                        if path.exists() {
+                            apply_file_mode(&path, self.destination_mode)?;
+                        }
+                    }
+                }
+                if let Some(mtime) = current.root_mtime {
+                    set_file_mtime(&current.root, mtime)?;
+                }
+                if let Some(dest) = &current.dest {
+                    move_tree(&current.root, dest, self.copy_buffer_size)?;
+                }
+                size::forget(current.dest.as_ref().unwrap_or(&current.root));
+                if self.fsync != FsyncMode::Off {
+                    let root = current.dest.as_ref().unwrap_or(&current.root);
+                    sync_tree(root)?;
+                    // The folders between the location and the backup may
+                    // have been created for it too
+                    for dir in root.ancestors().skip(1) {
+                        if !dir.starts_with(&self.location) {
+                            break;
+                        }
+                        sync_dir(dir)?;
+                    }
+                }
+                Ok(())
+            }
+            None => Err(io::Error::other("no backup in progress")),
+        }
+    }
+
+    fn list_backups(&self) -> io::Result<Box<dyn Iterator<Item = NaiveDate> + '_>> {
+        // Only folders named like a day are backups; anything else is left alone
+        Ok(Box::new(
+            fs::read_dir(self.layout.daily_root(&self.location))?
+                .filter_map(Result::ok)
+                .filter(|e| e.file_type().map(|ft| ft.is_dir()).unwrap_or(false))
+                .filter_map(|e| self.layout.parse_day(&e.file_name().to_string_lossy())),
+        ))
+    }
+
+    fn prune(&mut self, day: NaiveDate) -> io::Result<PrunedBackup> {
+        let backup = self.describe(day);
+        let path = Path::new(&backup.path);
+        fs::remove_dir_all(path)?;
+        size::forget(path);
+        Ok(backup)
+    }
+
+    fn describe(&self, day: NaiveDate) -> PrunedBackup {
+        let path = self
+            .layout
+            .daily_root(&self.location)
+            .join(self.layout.day_folder_name(day));
+        // Best effort: a folder we can't fully measure is still removed
+        let bytes = directory_size(&path).unwrap_or(0);
+        PrunedBackup {
+            day,
+            path: path.display().to_string(),
+            bytes,
+        }
+    }
+}
+
+#[cfg(unix)]
+fn create_link(_src: &Path, target: &Path, dest: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, dest)
+}
+
+/// Junctions come back as directory symlinks, which need Developer Mode or
+/// the symlink privilege; without it the link is skipped with a warning.
+#[cfg(windows)]
+fn create_link(src: &Path, target: &Path, dest: &Path) -> io::Result<()> {
+    if fs::metadata(src).map(|m| m.is_dir()).unwrap_or(false) {
+        std::os::windows::fs::symlink_dir(target, dest)
+    } else {
+        std::os::windows::fs::symlink_file(target, dest)
+    }
+}
+
+/// Gives the file at `path` the permission bits `mode`, when there are any.
+fn apply_file_mode(path: &Path, mode: Option<u32>) -> io::Result<()> {
+    match mode {
+        Some(mode) => set_mode(path, mode),
+        None => Ok(()),
+    }
+}
+
+/// The bits for a folder given `mode` for files: search permission wherever
+/// reading is allowed, and always full access for the owner, so the warden
+/// can keep writing into it.
+fn dir_mode(mode: u32) -> u32 {
+    mode | ((mode & 0o444) >> 2) | 0o700
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
+
+fn remove_entry(path: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(path)?.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+/// The folder under `staging_dir` a backup bound for `dest` is written to.
+fn staging_name(dest: &Path) -> String {
+    let digest = Sha256::digest(dest.to_string_lossy().as_bytes());
+    digest[..8].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Moves the finished backup at `src` to `dest`. A rename does it at once when
+/// both are on the same filesystem and `dest` doesn't exist yet; otherwise the
+/// tree is copied over, keeping folder times, and `src` removed.
+fn move_tree(src: &Path, dest: &Path, buffer_size: usize) -> io::Result<()> {
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if fs::symlink_metadata(dest).is_err() && fs::rename(src, dest).is_ok() {
+        return Ok(());
+    }
+    debug!(event = "staging_copied", from = %src.display(), to = %dest.display(), "Copying staged backup into place");
+    copy_staged(src, dest, buffer_size)?;
+    fs::remove_dir_all(src)
+}
+
+fn copy_staged(src: &Path, dest: &Path, buffer_size: usize) -> io::Result<()> {
+    fs::create_dir_all(dest)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let target = dest.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            copy_staged(&entry.path(), &target, buffer_size)?;
+        } else if file_type.is_symlink() {
+            unlink_existing(&target)?;
+            create_link(&entry.path(), &fs::read_link(entry.path())?, &target)?;
+        } else {
+            copy_file(&entry.path(), &target, buffer_size)?;
+        }
+    }
+    set_file_mtime(
+        dest,
+        FileTime::from_last_modification_time(&fs::metadata(src)?),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BackupWardenConfig;
+    use crate::copy::{copy_dir_all, CopyOptions};
+    use crate::filter::PathFilter;
+    use std::collections::BTreeMap;
+    use tempfile::tempdir;
+
+    /// Keeps backups in memory, to check the copy loop only goes through the trait.
+    #[derive(Default)]
+    struct MemorySink {
+        files: BTreeMap<PathBuf, Vec<u8>>,
+        days: Vec<NaiveDate>,
+        finished: bool,
+    }
+
+    impl BackupSink for MemorySink {
+        fn name(&self) -> String {
+            "memory".to_string()
+        }
+        fn begin_backup(&mut self, time: &DateTime<Local>) -> io::Result<()> {
+            self.days.push(time.date_naive());
+            Ok(())
+        }
+        fn create_dir(&mut self, _relative: &Path) -> io::Result<()> {
+            Ok(())
+        }
+        fn write_file(
+            &mut self,
+            relative: &Path,
+            source: &Path,
+            _metadata: &fs::Metadata,
+        ) -> io::Result<u64> {
+            let contents = fs::read(source)?;
+            let len = contents.len() as u64;
+            self.files.insert(relative.to_path_buf(), contents);
+            Ok(len)
+        }
+        fn write_contents(&mut self, relative: &Path, contents: &[u8]) -> io::Result<()> {
+            self.files.insert(relative.to_path_buf(), contents.to_vec());
+            Ok(())
+        }
+        fn finish_backup(&mut self) -> io::Result<()> {
+            self.finished = true;
+            Ok(())
+        }
+        fn list_backups(&self) -> io::Result<Box<dyn Iterator<Item = NaiveDate> + '_>> {
+            Ok(Box::new(self.days.iter().copied()))
+        }
+        fn prune(&mut self, day: NaiveDate) -> io::Result<PrunedBackup> {
+            self.days.retain(|d| *d != day);
+            Ok(PrunedBackup {
+                day,
+                path: day.to_string(),
+                bytes: 0,
+            })
+        }
+    }
+
+    #[test]
+    fn test_copy_and_prune_drive_a_custom_sink() {
+        let temp_dir = tempdir().unwrap();
+        fs::create_dir_all(temp_dir.path().join("docs")).unwrap();
+        fs::write(temp_dir.path().join("docs").join("a.txt"), b"aaa").unwrap();
+
+        let config = BackupWardenConfig {
+            watch_folder: temp_dir.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let filter = PathFilter::new(&config).unwrap();
+        let mut sink = MemorySink::default();
+        sink.begin_backup(&Local::now()).unwrap();
+        let stats = copy_dir_all(
+            Path::new(&config.watch_folder),
+            &CopyOptions::new(&config, &filter),
+            &mut sink,
+        )
+        .unwrap();
+        sink.finish_backup().unwrap();
+
+        assert_eq!(stats.bytes_copied, 3);
+        assert!(sink.finished);
+        assert_eq!(sink.files[Path::new("docs/a.txt")], b"aaa");
+
+        sink.days = (1..=4)
+            .map(|d| NaiveDate::from_ymd_opt(2024, 1, d).unwrap())
+            .collect();
+        let pruned = prune_old_backups(&mut sink, &Retention::count(2)).unwrap();
+        assert_eq!(pruned.len(), 2);
+        assert_eq!(pruned[0].day, NaiveDate::from_ymd_opt(2024, 1, 1).unwrap());
+        assert_eq!(
+            sink.days,
+            [
+                NaiveDate::from_ymd_opt(2024, 1, 3).unwrap(),
+                NaiveDate::from_ymd_opt(2024, 1, 4).unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_staged_backup_moves_into_location() {
+        let temp_dir = tempdir().unwrap();
+        let src = temp_dir.path().join("src");
+        let location = temp_dir.path().join("backup");
+        let staging = temp_dir.path().join("staging");
+        fs::create_dir_all(src.join("docs")).unwrap();
+        fs::write(src.join("docs/a.txt"), b"aaa").unwrap();
+        let config = BackupWardenConfig {
+            watch_folder: src.to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let filter = PathFilter::new(&config).unwrap();
+        let layout = BackupLayout::new(&config).unwrap();
+
+        for round in 0..2 {
+            let dest = location.join(format!("backup{}", round));
+            // The second round finds its destination taken and copies instead
+            if round == 1 {
+                fs::create_dir_all(dest.join("old")).unwrap();
+            }
+            let mut sink = LocalSink::new(location.to_str().unwrap(), layout.clone())
+                .with_staging_dir(Some(staging.clone()));
+            sink.begin_folder(dest.clone()).unwrap();
+            assert!(!dest.join("docs").exists());
+            copy_dir_all(&src, &CopyOptions::new(&config, &filter), &mut sink).unwrap();
+            sink.finish_backup().unwrap();
+
+            assert_eq!(fs::read(dest.join("docs/a.txt")).unwrap(), b"aaa");
+            assert!(Manifest::load(&dest).unwrap().is_some());
+            assert_eq!(fs::read_dir(&staging).unwrap().count(), 0);
+        }
+    }
+
+    #[test]
+    fn test_fsync_modes_still_write_the_backup() {
+        let temp_dir = tempdir().unwrap();
+        let src = temp_dir.path().join("src");
+        let location = temp_dir.path().join("backup");
+        fs::create_dir_all(src.join("docs")).unwrap();
+        fs::write(src.join("docs/a.txt"), b"aaa").unwrap();
+        fs::write(
+            src.join("big.bin"),
+            vec![7; CHUNKED_FILE_MIN_BYTES as usize],
+        )
+        .unwrap();
+        let config = BackupWardenConfig {
+            watch_folder: src.to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let filter = PathFilter::new(&config).unwrap();
+        let layout = BackupLayout::new(&config).unwrap();
+
+        for fsync in [FsyncMode::EachFile, FsyncMode::EndOfBackup] {
+            let dest = location.join("day").join(format!("{:?}", fsync));
+            let mut sink = LocalSink::new(location.to_str().unwrap(), layout.clone())
+                .with_chunking(true)
+                .with_fsync(fsync);
+            sink.begin_folder(dest.clone()).unwrap();
+            copy_dir_all(&src, &CopyOptions::new(&config, &filter), &mut sink).unwrap();
+            sink.finish_backup().unwrap();
+
+            assert_eq!(fs::read(dest.join("docs/a.txt")).unwrap(), b"aaa");
+            assert!(chunks::list_path(&dest.join("big.bin")).exists());
+            assert!(Manifest::load(&dest).unwrap().is_some());
+        }
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_readonly_file_backed_up_twice_into_one_folder() {
+        let temp_dir = tempdir().unwrap();
+        let src = temp_dir.path().join("src");
+        let location = temp_dir.path().join("backup");
+        fs::create_dir_all(&src).unwrap();
+        fs::write(src.join("a.txt"), b"aaa").unwrap();
+        fs::write(
+            src.join("big.bin"),
+            vec![7; CHUNKED_FILE_MIN_BYTES as usize],
+        )
+        .unwrap();
+        for name in ["a.txt", "big.bin"] {
+            let mut permissions = fs::metadata(src.join(name)).unwrap().permissions();
+            permissions.set_readonly(true);
+            fs::set_permissions(src.join(name), permissions).unwrap();
+        }
+        let config = BackupWardenConfig {
+            watch_folder: src.to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let filter = PathFilter::new(&config).unwrap();
+        let layout = BackupLayout::new(&config).unwrap();
+
+        let dest = location.join("day").join("01 PM");
+        for _ in 0..2 {
+            let mut sink = LocalSink::new(location.to_str().unwrap(), layout.clone())
+                .with_attributes(true)
+                .with_chunking(true);
+            sink.begin_folder(dest.clone()).unwrap();
+            copy_dir_all(&src, &CopyOptions::new(&config, &filter), &mut sink).unwrap();
+            sink.finish_backup().unwrap();
+            // Chunk lists carry the attributes too, for restore to put back
+            for path in [dest.join("a.txt"), chunks::list_path(&dest.join("big.bin"))] {
+                assert!(fs::metadata(path).unwrap().permissions().readonly());
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_destination_mode_overrides_source_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = tempdir().unwrap();
+        let src = temp_dir.path().join("src");
+        let dest = temp_dir.path().join("backup").join("b");
+        fs::create_dir_all(src.join("docs")).unwrap();
+        fs::write(src.join("docs/a.txt"), b"aaa").unwrap();
+        fs::set_permissions(src.join("docs/a.txt"), fs::Permissions::from_mode(0o644)).unwrap();
+        let config = BackupWardenConfig {
+            watch_folder: src.to_str().unwrap().to_string(),
+            destination_mode: Some("0640".to_string()),
+            ..Default::default()
+        };
+        for (raw, mode) in [("600", Some(0o600)), ("0o700", Some(0o700)), ("0800", None)] {
+            let config = BackupWardenConfig {
+                destination_mode: Some(raw.to_string()),
+                ..Default::default()
+            };
+            assert_eq!(config.destination_mode(), mode);
+        }
+        let filter = PathFilter::new(&config).unwrap();
+        let mut sink = LocalSink::new(dest.to_str().unwrap(), BackupLayout::new(&config).unwrap())
+            .with_destination_mode(config.destination_mode());
+        sink.begin_folder(dest.clone()).unwrap();
+        copy_dir_all(&src, &CopyOptions::new(&config, &filter), &mut sink).unwrap();
+        sink.finish_backup().unwrap();
+
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&dest.join("docs/a.txt")), 0o640);
+        assert_eq!(mode(&dest.join(MANIFEST_FILE_NAME)), 0o640);
+        assert_eq!(mode(&dest.join("docs")), 0o750);
+        assert_eq!(mode(&dest), 0o750);
+    }
+
+    #[test]
+    fn test_prune_keeps_newest_days_from_unordered_listing() {
+        let first = NaiveDate::from_ymd_opt(2024, 1, 1).unwrap();
+        let mut sink = MemorySink {
+            days: (0..50)
+                .rev()
+                .map(|d| first + chrono::Days::new(d))
+                .collect(),
+            ..Default::default()
+        };
+        let pruned = prune_old_backups(&mut sink, &Retention::count(7)).unwrap();
+
+        let pruned_days: Vec<_> = pruned.iter().map(|p| p.day).collect();
+        assert_eq!(
+            pruned_days,
+            (0..43)
+                .map(|d| first + chrono::Days::new(d))
+                .collect::<Vec<_>>()
+        );
+        sink.days.sort();
+        assert_eq!(
+            sink.days,
+            (43..50)
+                .map(|d| first + chrono::Days::new(d))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_local_sink_describes_day_before_pruning_it() {
+        let temp_dir = tempdir().unwrap();
+        let config = BackupWardenConfig::default();
+        let layout = BackupLayout::new(&config).unwrap();
+        let day = NaiveDate::from_ymd_opt(2024, 1, 31).unwrap();
+        let day_dir = layout
+            .daily_root(temp_dir.path())
+            .join(layout.day_folder_name(day));
+        fs::create_dir_all(day_dir.join("@01 PM")).unwrap();
+        fs::write(day_dir.join("@01 PM").join("a.txt"), b"aaaa").unwrap();
+
+        let mut sink = LocalSink::new(temp_dir.path().to_str().unwrap(), layout);
+        let described = sink.describe(day);
+        assert_eq!(described.path, day_dir.display().to_string());
+        assert_eq!(described.bytes, 4);
+        assert!(day_dir.exists());
+        assert_eq!(sink.prune(day).unwrap(), described);
+        assert!(!day_dir.exists());
+    }
+
+    #[test]
+    fn test_rerun_into_same_backup_is_measured_afresh() {
+        let temp_dir = tempdir().unwrap();
+        let src = temp_dir.path().join("src");
+        let location = temp_dir.path().join("backup");
+        fs::create_dir_all(src.join("docs")).unwrap();
+        fs::write(src.join("docs/a.txt"), b"aaa").unwrap();
+        let settled = FileTime::from_unix_time(1_700_000_000, 0);
+        set_file_mtime(src.join("docs"), settled).unwrap();
+        set_file_mtime(&src, settled).unwrap();
+        let config = BackupWardenConfig {
+            watch_folder: src.to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let filter = PathFilter::new(&config).unwrap();
+        let layout = BackupLayout::new(&config).unwrap();
+        let time = Local::now();
+
+        for contents in [&b"aaa"[..], &[0u8; 100][..]] {
+            // Rewritten within the same second, leaving the folder's mtime be
+            fs::write(src.join("docs/a.txt"), contents).unwrap();
+            let mut sink = LocalSink::new(location.to_str().unwrap(), layout.clone());
+            sink.begin_backup(&time).unwrap();
+            copy_dir_all(&src, &CopyOptions::new(&config, &filter), &mut sink).unwrap();
+            sink.finish_backup().unwrap();
+            let uncached = size::source_totals(&location, &PathFilter::allow_all()).unwrap();
+            assert_eq!(directory_size(&location).unwrap(), uncached.bytes);
+        }
+    }
+
+    #[test]
+    fn test_prune_always_keeps_newest_backup() {
+        let newest = NaiveDate::from_ymd_opt(2024, 1, 4).unwrap();
+        for retention_days in [0, 1] {
+            let mut sink = MemorySink {
+                days: [3, 4, 1, 2]
+                    .iter()
+                    .map(|d| NaiveDate::from_ymd_opt(2024, 1, *d).unwrap())
+                    .collect(),
+                ..Default::default()
+            };
+            prune_old_backups(&mut sink, &Retention::count(retention_days)).unwrap();
+            assert_eq!(sink.days, [newest]);
+        }
+    }
+
+    #[test]
+    fn test_prune_keeps_all_when_fewer_days_than_retention() {
+        let days: Vec<NaiveDate> = [1, 2, 3]
+            .iter()
+            .map(|d| NaiveDate::from_ymd_opt(2024, 1, *d).unwrap())
+            .collect();
+        let mut sink = MemorySink {
+            days: days.clone(),
+            ..Default::default()
+        };
+        let pruned = prune_old_backups(&mut sink, &Retention::count(30)).unwrap();
+        assert!(pruned.is_empty());
+        assert_eq!(sink.days, days);
+    }
+
+    #[test]
+    fn test_prune_by_age_and_count() {
+        let day = |d| NaiveDate::from_ymd_opt(2024, 1, d).unwrap();
+        let listing = [1, 2, 3, 5, 8, 9].map(day);
+        let remaining = |retention: Retention| {
+            let mut sink = MemorySink {
+                days: listing.to_vec(),
+                ..Default::default()
+            };
+            prune_old_backups(&mut sink, &retention).unwrap();
+            sink.days.sort();
+            sink.days
+        };
+        let by_age = Retention {
+            keep_days: None,
+            max_age_days: Some(7),
+            min_retained: 0,
+            today: day(10),
+        };
+        // The 3rd is exactly 7 days old and stays
+        assert_eq!(remaining(by_age), [3, 5, 8, 9].map(day));
+        assert_eq!(
+            remaining(Retention {
+                keep_days: Some(3),
+                ..by_age
+            }),
+            [5, 8, 9].map(day)
+        );
+        assert_eq!(
+            remaining(Retention {
+                keep_days: Some(10),
+                ..by_age
+            }),
+            [3, 5, 8, 9].map(day)
+        );
+        // Long after the last backup, the newest is still kept
+        assert_eq!(
+            remaining(Retention {
+                today: day(31),
+                ..by_age
+            }),
+            [day(9)]
+        );
+    }
+
+    #[test]
+    fn test_min_retained_floor() {
+        let day = |d| NaiveDate::from_ymd_opt(2024, 1, d).unwrap();
+        let listing = [1, 2, 3, 5, 8, 9].map(day);
+        let remaining = |retention: Retention| {
+            let mut sink = MemorySink {
+                days: listing.to_vec(),
+                ..Default::default()
+            };
+            prune_old_backups(&mut sink, &retention).unwrap();
+            sink.days.sort();
+            sink.days
+        };
+        let by_age = Retention {
+            keep_days: None,
+            max_age_days: Some(7),
+            min_retained: 3,
+            today: day(31),
+        };
+        // After a long gap age alone would leave only the newest day
+        assert_eq!(remaining(by_age), [5, 8, 9].map(day));
+        // A lower count doesn't go below the floor
+        assert_eq!(
+            remaining(Retention {
+                keep_days: Some(1),
+                ..by_age
+            }),
+            [5, 8, 9].map(day)
+        );
+        // Above the floor the other limits apply as usual
+        assert_eq!(
+            remaining(Retention {
+                keep_days: Some(5),
+                today: day(10),
+                ..by_age
+            }),
+            [3, 5, 8, 9].map(day)
+        );
+        assert_eq!(
+            remaining(Retention {
+                keep_days: Some(4),
+                max_age_days: None,
+                ..by_age
+            }),
+            [3, 5, 8, 9].map(day)
+        );
+    }
+}
@@ -0,0 +1,245 @@
+use crate::filter::PathFilter;
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+
+/// Directories modified this recently are not cached, because a write landing in
+/// the same mtime tick would otherwise go unnoticed.
+const RACY_WINDOW: Duration = Duration::from_secs(2);
+
+/// What we remember about a single directory: the total size of the files directly
+/// inside it and the subdirectories we need to descend into.
+struct CachedDir {
+    mtime: SystemTime,
+    file_bytes: u64,
+    subdirs: Vec<PathBuf>,
+}
+
+fn cache() -> &'static Mutex<HashMap<PathBuf, CachedDir>> {
+    static CACHE: OnceLock<Mutex<HashMap<PathBuf, CachedDir>>> = OnceLock::new();
+    CACHE.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Returns the total size in bytes of all files under `path`.
+///
+/// Subdirectories are walked in parallel and each directory's listing is cached
+/// against its mtime, so repeated calls only re-read directories that changed.
+/// A directory's mtime only moves when entries are added, removed or renamed,
+/// and backups set theirs back to the source's, so whatever writes into a tree
+/// must [`forget`] it afterwards. Symlinks are not followed.
+pub fn directory_size(path: &Path) -> io::Result<u64> {
+    let (file_bytes, subdirs) = scan_dir(path)?;
+
+    subdirs
+        .par_iter()
+        .map(|subdir| directory_size(subdir))
+        .try_reduce(|| file_bytes, |a, b| Ok(a + b))
+}
+
+/// Drops what [`directory_size`] remembers about `path` and everything below
+/// it, after it has been written to or removed.
+pub fn forget(path: &Path) {
+    cache()
+        .lock()
+        .unwrap()
+        .retain(|cached, _| !cached.starts_with(path));
+}
+
+/// What a backup of the watch folder would copy.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SourceTotals {
+    pub bytes: u64,
+    pub files: u64,
+}
+
+/// Adds up the files under `root` that `filter` lets through. Unlike
+/// [`directory_size`] nothing is cached, since the watch folder changes all the
+/// time. Folders that can't be listed are left out, as the copy leaves them out.
+pub fn source_totals(root: &Path, filter: &PathFilter) -> io::Result<SourceTotals> {
+    let mut totals = SourceTotals::default();
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) if dir != root => continue,
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let path = entry.path();
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                if filter.allows_dir(&path) {
+                    pending.push(path);
+                }
+            } else if file_type.is_file() && filter.allows_file(&path) {
+                totals.bytes += entry.metadata()?.len();
+                totals.files += 1;
+            }
+        }
+    }
+    Ok(totals)
+}
+
+/// Formats a byte count for humans, e.g. `1536` as `1.5 KiB`.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+fn scan_dir(path: &Path) -> io::Result<(u64, Vec<PathBuf>)> {
+    let mtime = fs::metadata(path)?.modified()?;
+
+    let vanished: Vec<PathBuf> = match cache().lock().unwrap().get(path) {
+        Some(cached) if cached.mtime == mtime => {
+            return Ok((cached.file_bytes, cached.subdirs.clone()))
+        }
+        Some(cached) => cached.subdirs.clone(),
+        None => Vec::new(),
+    };
+
+    let mut file_bytes = 0;
+    let mut subdirs = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            subdirs.push(entry.path());
+        } else if file_type.is_file() {
+            file_bytes += entry.metadata()?.len();
+        }
+    }
+
+    // Subdirectories removed since the last walk, such as pruned backups,
+    // would otherwise stay cached for good
+    for subdir in vanished.iter().filter(|subdir| !subdirs.contains(subdir)) {
+        forget(subdir);
+    }
+
+    let settled = SystemTime::now()
+        .duration_since(mtime)
+        .map(|age| age > RACY_WINDOW)
+        .unwrap_or(false);
+    if settled {
+        cache().lock().unwrap().insert(
+            path.to_path_buf(),
+            CachedDir {
+                mtime,
+                file_bytes,
+                subdirs: subdirs.clone(),
+            },
+        );
+    }
+
+    Ok((file_bytes, subdirs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use filetime::{set_file_mtime, FileTime};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_source_totals_honors_filter() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("logs")).unwrap();
+        fs::write(root.join("a.txt"), b"aaa").unwrap();
+        fs::write(root.join("logs/run.log"), vec![0; 100]).unwrap();
+        let config = crate::config::BackupWardenConfig {
+            watch_folder: root.to_str().unwrap().to_string(),
+            exclude_patterns: vec!["logs".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            source_totals(root, &PathFilter::allow_all()).unwrap(),
+            SourceTotals {
+                bytes: 103,
+                files: 2
+            }
+        );
+        assert_eq!(
+            source_totals(root, &PathFilter::new(&config).unwrap()).unwrap(),
+            SourceTotals { bytes: 3, files: 1 }
+        );
+    }
+
+    #[test]
+    fn test_directory_size_sums_nested_files() {
+        let temp_dir = tempdir().unwrap();
+        let nested = temp_dir.path().join("a").join("b");
+        fs::create_dir_all(&nested).unwrap();
+
+        fs::write(temp_dir.path().join("top.txt"), vec![0u8; 10]).unwrap();
+        fs::write(temp_dir.path().join("a").join("mid.txt"), vec![0u8; 20]).unwrap();
+        fs::write(nested.join("deep.txt"), vec![0u8; 30]).unwrap();
+        fs::write(nested.join("empty.txt"), b"").unwrap();
+
+        assert_eq!(directory_size(temp_dir.path()).unwrap(), 60);
+    }
+
+    #[test]
+    fn test_directory_size_sees_new_files() {
+        let temp_dir = tempdir().unwrap();
+        fs::write(temp_dir.path().join("first.txt"), vec![0u8; 5]).unwrap();
+        assert_eq!(directory_size(temp_dir.path()).unwrap(), 5);
+
+        fs::write(temp_dir.path().join("second.txt"), vec![0u8; 7]).unwrap();
+        assert_eq!(directory_size(temp_dir.path()).unwrap(), 12);
+    }
+
+    #[test]
+    fn test_directory_size_forgets_rewritten_and_removed_trees() {
+        let temp_dir = tempdir().unwrap();
+        let backup = temp_dir.path().join("backup");
+        fs::create_dir_all(backup.join("old")).unwrap();
+        fs::write(backup.join("file.txt"), vec![0u8; 5]).unwrap();
+        fs::write(backup.join("old/file.txt"), vec![0u8; 3]).unwrap();
+        let settled = FileTime::from_unix_time(1_700_000_000, 0);
+        set_file_mtime(&backup, settled).unwrap();
+        set_file_mtime(backup.join("old"), settled).unwrap();
+        assert_eq!(directory_size(&backup).unwrap(), 8);
+
+        // Rewritten in place with the folder's mtime put back, as a rerun
+        // into the same backup does
+        fs::write(backup.join("file.txt"), vec![0u8; 9]).unwrap();
+        set_file_mtime(&backup, settled).unwrap();
+        assert_eq!(directory_size(&backup).unwrap(), 8);
+        forget(&backup);
+        assert_eq!(directory_size(&backup).unwrap(), 12);
+
+        fs::remove_dir_all(backup.join("old")).unwrap();
+        assert_eq!(directory_size(&backup).unwrap(), 9);
+        assert!(!cache().lock().unwrap().contains_key(&backup.join("old")));
+    }
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(3 * 1024 * 1024 * 1024), "3.0 GiB");
+    }
+
+    #[test]
+    fn test_directory_size_missing_path_errors() {
+        let temp_dir = tempdir().unwrap();
+        assert!(directory_size(&temp_dir.path().join("missing")).is_err());
+    }
+}
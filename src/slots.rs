@@ -0,0 +1,94 @@
+use std::sync::{Condvar, Mutex};
+
+/// Limits how many backups run at the same time across all jobs, so jobs that
+/// trigger together queue up instead of all hammering the disks at once.
+pub struct BackupSlots {
+    limit: usize,
+    running: Mutex<usize>,
+    freed: Condvar,
+}
+
+/// A claimed slot, given back on drop.
+pub struct BackupSlot<'a> {
+    slots: &'a BackupSlots,
+}
+
+impl BackupSlots {
+    pub fn new(limit: usize) -> Self {
+        BackupSlots {
+            limit: limit.max(1),
+            running: Mutex::new(0),
+            freed: Condvar::new(),
+        }
+    }
+
+    /// Claims a slot, waiting for one to free up if all are taken.
+    pub fn acquire(&self) -> BackupSlot<'_> {
+        let mut running = self.running.lock().unwrap();
+        while *running >= self.limit {
+            running = self.freed.wait(running).unwrap();
+        }
+        *running += 1;
+        BackupSlot { slots: self }
+    }
+
+    /// Claims a slot only if one is free right now.
+    pub fn try_acquire(&self) -> Option<BackupSlot<'_>> {
+        let mut running = self.running.lock().unwrap();
+        if *running >= self.limit {
+            return None;
+        }
+        *running += 1;
+        Some(BackupSlot { slots: self })
+    }
+}
+
+impl Drop for BackupSlot<'_> {
+    fn drop(&mut self) {
+        *self.slots.running.lock().unwrap() -= 1;
+        self.slots.freed.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_try_acquire_respects_limit() {
+        let slots = BackupSlots::new(2);
+        let first = slots.try_acquire().unwrap();
+        let _second = slots.try_acquire().unwrap();
+        assert!(slots.try_acquire().is_none());
+        drop(first);
+        assert!(slots.try_acquire().is_some());
+    }
+
+    #[test]
+    fn test_acquire_never_exceeds_limit() {
+        let slots = Arc::new(BackupSlots::new(2));
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+
+        let workers: Vec<_> = (0..6)
+            .map(|_| {
+                let (slots, running, peak) = (slots.clone(), running.clone(), peak.clone());
+                thread::spawn(move || {
+                    let _slot = slots.acquire();
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(now, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(20));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,209 @@
+use chrono::DateTime;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+/// Name of the file in each backup location that remembers what the warden did there.
+pub const STATE_FILE_NAME: &str = ".backup_warden_state.json";
+
+/// Persisted per-location state, kept across restarts.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct WardenState {
+    #[serde(default)]
+    pub last_backup: Option<RunMetrics>,
+    #[serde(default)]
+    pub last_snapshot: Option<RunMetrics>,
+    /// The month, as `YYYY-MM`, of the last monthly snapshot made here.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_snapshot_month: Option<String>,
+    /// The ISO week, as `YYYY-Www`, of the last weekly snapshot made here.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_weekly_snapshot_week: Option<String>,
+    /// How much space the location used after each recent backup, oldest first.
+    #[serde(default)]
+    pub usage_history: Vec<UsageSample>,
+}
+
+/// How many backups `usage_history` remembers.
+pub const USAGE_HISTORY_LEN: usize = 30;
+
+/// Space used by a location right after a backup and its cleanup.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UsageSample {
+    /// When the sample was taken, in RFC 3339 local time.
+    pub at: String,
+    /// Bytes the backup added.
+    pub added_bytes: u64,
+    /// Total bytes used by the location afterwards.
+    pub used_bytes: u64,
+}
+
+/// Timing of a single copy into one location.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RunMetrics {
+    /// When the run finished, in RFC 3339 local time.
+    pub finished_at: String,
+    pub duration_secs: f64,
+    pub bytes: u64,
+    /// Folders left out of the copy because they couldn't be listed.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unreadable_dirs: Vec<String>,
+}
+
+impl RunMetrics {
+    pub fn new(finished_at: String, duration: Duration, bytes: u64) -> Self {
+        RunMetrics {
+            finished_at,
+            duration_secs: duration.as_secs_f64(),
+            bytes,
+            unreadable_dirs: Vec::new(),
+        }
+    }
+
+    /// Average copy speed in bytes per second.
+    pub fn bytes_per_sec(&self) -> u64 {
+        if self.duration_secs > 0.0 {
+            (self.bytes as f64 / self.duration_secs) as u64
+        } else {
+            self.bytes
+        }
+    }
+}
+
+impl WardenState {
+    /// Loads the state for `location`, or an empty state if there is none yet.
+    /// A corrupt state file is treated as empty rather than stopping backups.
+    pub fn load(location: &Path) -> WardenState {
+        fs::read_to_string(location.join(STATE_FILE_NAME))
+            .ok()
+            .and_then(|raw| serde_json::from_str(&raw).ok())
+            .unwrap_or_default()
+    }
+
+    /// Writes the state for `location`, replacing the previous file atomically.
+    pub fn save(&self, location: &Path) -> io::Result<()> {
+        let raw = serde_json::to_string_pretty(self)?;
+        let tmp_path = location.join(format!("{}.tmp", STATE_FILE_NAME));
+        fs::write(&tmp_path, raw)?;
+        fs::rename(&tmp_path, location.join(STATE_FILE_NAME))
+    }
+
+    /// Loads, modifies and saves the state for `location` in one go.
+    pub fn update(location: &Path, change: impl FnOnce(&mut WardenState)) -> io::Result<()> {
+        let mut state = WardenState::load(location);
+        change(&mut state);
+        state.save(location)
+    }
+
+    /// Appends a usage sample, dropping the oldest beyond `USAGE_HISTORY_LEN`.
+    pub fn record_usage(&mut self, sample: UsageSample) {
+        self.usage_history.push(sample);
+        let excess = self.usage_history.len().saturating_sub(USAGE_HISTORY_LEN);
+        self.usage_history.drain(..excess);
+    }
+}
+
+/// Growth of a location in bytes per day, from a least-squares line through
+/// `history`. `None` until there are two samples at different times.
+pub fn growth_per_day(history: &[UsageSample]) -> Option<f64> {
+    let points: Vec<(f64, f64)> = history
+        .iter()
+        .filter_map(|sample| {
+            let at = DateTime::parse_from_rfc3339(&sample.at).ok()?;
+            Some((at.timestamp() as f64 / 86_400.0, sample.used_bytes as f64))
+        })
+        .collect();
+    if points.len() < 2 {
+        return None;
+    }
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / n;
+    let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / n;
+    let spread: f64 = points.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+    if spread == 0.0 {
+        return None;
+    }
+    let covariance: f64 = points
+        .iter()
+        .map(|(x, y)| (x - mean_x) * (y - mean_y))
+        .sum();
+    Some(covariance / spread)
+}
+
+/// Rough number of days until `free_bytes` run out at the recent growth rate.
+/// `None` if there is not enough history or the location is not growing.
+pub fn days_until_full(history: &[UsageSample], free_bytes: u64) -> Option<f64> {
+    let growth = growth_per_day(history)?;
+    (growth > 0.0).then(|| free_bytes as f64 / growth)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_state_round_trips() {
+        let temp_dir = tempdir().unwrap();
+        WardenState::update(temp_dir.path(), |state| {
+            state.last_backup = Some(RunMetrics::new(
+                "2024-01-31T12:00:00+00:00".to_string(),
+                Duration::from_secs(4),
+                4096,
+            ));
+        })
+        .unwrap();
+
+        let state = WardenState::load(temp_dir.path());
+        let last_backup = state.last_backup.unwrap();
+        assert_eq!(last_backup.bytes, 4096);
+        assert_eq!(last_backup.bytes_per_sec(), 1024);
+        assert!(state.last_snapshot.is_none());
+    }
+
+    #[test]
+    fn test_corrupt_state_loads_as_empty() {
+        let temp_dir = tempdir().unwrap();
+        fs::write(temp_dir.path().join(STATE_FILE_NAME), "{ not json").unwrap();
+        assert_eq!(WardenState::load(temp_dir.path()), WardenState::default());
+    }
+
+    fn sample(at: &str, used_bytes: u64) -> UsageSample {
+        UsageSample {
+            at: at.to_string(),
+            added_bytes: 0,
+            used_bytes,
+        }
+    }
+
+    #[test]
+    fn test_record_usage_keeps_recent_samples() {
+        let mut state = WardenState::default();
+        for i in 0..USAGE_HISTORY_LEN + 5 {
+            state.record_usage(sample("2024-01-01T00:00:00+00:00", i as u64));
+        }
+        assert_eq!(state.usage_history.len(), USAGE_HISTORY_LEN);
+        assert_eq!(state.usage_history[0].used_bytes, 5);
+    }
+
+    #[test]
+    fn test_days_until_full_projects_growth() {
+        let history = [
+            sample("2024-01-01T00:00:00+00:00", 1000),
+            sample("2024-01-02T00:00:00+00:00", 2000),
+            sample("2024-01-03T00:00:00+00:00", 3000),
+        ];
+        assert_eq!(growth_per_day(&history), Some(1000.0));
+        assert_eq!(days_until_full(&history, 10_000), Some(10.0));
+
+        // Shrinking or flat usage never fills up
+        let history = [
+            sample("2024-01-01T00:00:00+00:00", 3000),
+            sample("2024-01-02T00:00:00+00:00", 2000),
+        ];
+        assert_eq!(days_until_full(&history, 10_000), None);
+        assert_eq!(days_until_full(&history[..1], 10_000), None);
+    }
+}
@@ -0,0 +1,119 @@
+use crate::config::BackupWardenConfig;
+use crate::disk::free_space;
+use crate::error::{BackupWardenError, Result};
+use crate::layout::BackupLayout;
+use crate::manifest::read_root;
+use crate::size::{directory_size, format_bytes};
+use crate::state::{days_until_full, growth_per_day, RunMetrics, WardenState};
+use chrono::NaiveDate;
+use std::fs;
+use std::path::Path;
+
+/// Prints a summary of the watch folder and every backup location.
+pub fn run(config: &BackupWardenConfig) -> Result<()> {
+    match directory_size(Path::new(&config.watch_folder)) {
+        Ok(size) => println!(
+            "Watch folder: {} ({})",
+            config.watch_folder,
+            format_bytes(size)
+        ),
+        Err(e) => println!("Watch folder: {} (unreadable: {})", config.watch_folder, e),
+    }
+
+    let layout = BackupLayout::new(config).map_err(BackupWardenError::Config)?;
+    for location in &config.backup_locations {
+        let path = Path::new(location);
+        println!();
+        println!("Location: {}", location);
+
+        match directory_size(path) {
+            Ok(size) => match free_space(path) {
+                Ok(free) => println!(
+                    "  Size: {} ({} free)",
+                    format_bytes(size),
+                    format_bytes(free)
+                ),
+                Err(_) => println!("  Size: {}", format_bytes(size)),
+            },
+            Err(e) => {
+                println!("  Unavailable: {}", e);
+                continue;
+            }
+        }
+
+        // Counted as they are read, so a long history is never held in memory
+        let (count, newest) = fs::read_dir(layout.daily_root(path))
+            .map(|entries| {
+                entries
+                    .filter_map(Result::ok)
+                    .filter_map(|e| layout.parse_day(&e.file_name().to_string_lossy()))
+                    .fold((0, None::<NaiveDate>), |(count, newest), day| {
+                        (count + 1, newest.max(Some(day)))
+                    })
+            })
+            .unwrap_or_default();
+        match newest {
+            Some(newest) => println!("  Daily backups: {} (newest {})", count, newest),
+            None => println!("  Daily backups: none"),
+        }
+        let newest_backup = newest.and_then(|day| {
+            let day_dir = layout.daily_root(path).join(layout.day_folder_name(day));
+            layout.backups_in_day(&day_dir).pop()
+        });
+        if let Some((_, backup)) = newest_backup {
+            match read_root(&backup) {
+                Ok(Some(root)) => println!("  Merkle root of newest backup: {}", root),
+                Ok(None) => (),
+                Err(e) => println!("  Merkle root of newest backup: unreadable ({})", e),
+            }
+        }
+
+        let state = WardenState::load(path);
+        println!("  Last backup: {}", describe(state.last_backup.as_ref()));
+        println!(
+            "  Last monthly snapshot: {}",
+            describe(state.last_snapshot.as_ref())
+        );
+        println!("  Growth: {}", describe_growth(&state, path));
+    }
+    Ok(())
+}
+
+fn describe_growth(state: &WardenState, location: &Path) -> String {
+    let Some(growth) = growth_per_day(&state.usage_history) else {
+        return "not enough history yet".to_string();
+    };
+    if growth <= 0.0 {
+        return "not growing".to_string();
+    }
+    let per_day = format!("{}/day", format_bytes(growth as u64));
+    match free_space(location) {
+        Ok(free) => match days_until_full(&state.usage_history, free) {
+            Some(days) => format!("{}, full in about {:.0} days", per_day, days),
+            None => per_day,
+        },
+        Err(_) => per_day,
+    }
+}
+
+fn describe(metrics: Option<&RunMetrics>) -> String {
+    match metrics {
+        Some(metrics) if !metrics.unreadable_dirs.is_empty() => format!(
+            "{}, took {:.1}s ({} at {}/s), skipped {} unreadable folder(s): {}",
+            metrics.finished_at,
+            metrics.duration_secs,
+            format_bytes(metrics.bytes),
+            format_bytes(metrics.bytes_per_sec()),
+            metrics.unreadable_dirs.len(),
+            metrics.unreadable_dirs.join(", ")
+        ),
+        Some(metrics) => format!(
+            "{}, took {:.1}s ({} at {}/s)",
+            metrics.finished_at,
+            metrics.duration_secs,
+            format_bytes(metrics.bytes),
+            format_bytes(metrics.bytes_per_sec())
+        ),
+        None => "never".to_string(),
+    }
+}
@@ -0,0 +1,121 @@
+use std::io;
+use std::path::Path;
+
+/// Copies every NTFS alternate data stream of the file at `source`, such as the
+/// `Zone.Identifier` Windows attaches to downloads, onto `dest`, returning the
+/// bytes copied. `dest` must already exist; its main contents aren't touched.
+#[cfg(windows)]
+pub fn copy_streams(source: &Path, dest: &Path) -> io::Result<u64> {
+    let mut bytes = 0;
+    for name in windows::alternate_streams(source)? {
+        let with_stream = |path: &Path| {
+            let mut path = path.as_os_str().to_os_string();
+            path.push(":");
+            path.push(&name);
+            path
+        };
+        let mut reader = std::fs::File::open(with_stream(source))?;
+        let mut writer = std::fs::File::create(with_stream(dest))?;
+        bytes += io::copy(&mut reader, &mut writer)?;
+    }
+    Ok(bytes)
+}
+
+/// Alternate data streams only exist on Windows, so there is nothing to copy.
+#[cfg(not(windows))]
+pub fn copy_streams(_source: &Path, _dest: &Path) -> io::Result<u64> {
+    Ok(0)
+}
+
+#[cfg(windows)]
+mod windows {
+    use std::ffi::OsString;
+    use std::io;
+    use std::os::windows::ffi::OsStrExt;
+    use std::path::Path;
+    use windows_sys::Win32::Foundation::{ERROR_HANDLE_EOF, INVALID_HANDLE_VALUE};
+    use windows_sys::Win32::Storage::FileSystem::{
+        FindClose, FindFirstStreamW, FindNextStreamW, FindStreamInfoStandard,
+        WIN32_FIND_STREAM_DATA,
+    };
+
+    /// The names of the alternate data streams of the file at `path`, without
+    /// the main, unnamed stream.
+    pub fn alternate_streams(path: &Path) -> io::Result<Vec<OsString>> {
+        let wide: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+        // SAFETY: an all-zero WIN32_FIND_STREAM_DATA is valid plain data
+        let mut data: WIN32_FIND_STREAM_DATA = unsafe { std::mem::zeroed() };
+        // SAFETY: `wide` is NUL-terminated and `data` outlives the call
+        let find = unsafe {
+            FindFirstStreamW(
+                wide.as_ptr(),
+                FindStreamInfoStandard,
+                &mut data as *mut _ as *mut _,
+                0,
+            )
+        };
+        if find == INVALID_HANDLE_VALUE {
+            let e = io::Error::last_os_error();
+            // Reported for files, such as those on FAT volumes, with no streams at all
+            return if e.raw_os_error() == Some(ERROR_HANDLE_EOF as i32) {
+                Ok(Vec::new())
+            } else {
+                Err(e)
+            };
+        }
+
+        let mut names = Vec::new();
+        let result = loop {
+            names.extend(stream_name(&data));
+            // SAFETY: `find` is an open stream search and `data` outlives the call
+            if unsafe { FindNextStreamW(find, &mut data as *mut _ as *mut _) } == 0 {
+                let e = io::Error::last_os_error();
+                break if e.raw_os_error() == Some(ERROR_HANDLE_EOF as i32) {
+                    Ok(names)
+                } else {
+                    Err(e)
+                };
+            }
+        };
+        // SAFETY: `find` is open and not used after this
+        unsafe { FindClose(find) };
+        result
+    }
+
+    /// The name in `data`, which comes as `:name:$DATA`, or `None` for the
+    /// main stream, `::$DATA`.
+    fn stream_name(data: &WIN32_FIND_STREAM_DATA) -> Option<OsString> {
+        let len = data
+            .cStreamName
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(data.cStreamName.len());
+        let full = String::from_utf16_lossy(&data.cStreamName[..len]);
+        let name = full.strip_prefix(':')?.strip_suffix(":$DATA")?;
+        (!name.is_empty()).then(|| OsString::from(name))
+    }
+}
+
+#[cfg(all(test, windows))]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_copy_streams_copies_named_streams() {
+        let temp_dir = tempdir().unwrap();
+        let source = temp_dir.path().join("source.txt");
+        let dest = temp_dir.path().join("dest.txt");
+        fs::write(&source, b"main").unwrap();
+        fs::write(temp_dir.path().join("source.txt:tag"), b"tagged").unwrap();
+        fs::write(&dest, b"main").unwrap();
+
+        assert_eq!(copy_streams(&source, &dest).unwrap(), 6);
+        assert_eq!(
+            fs::read(temp_dir.path().join("dest.txt:tag")).unwrap(),
+            b"tagged"
+        );
+        assert_eq!(fs::read(&dest).unwrap(), b"main");
+    }
+}
@@ -0,0 +1,264 @@
+use crate::sink::{BackupSink, LocalSink, PrunedBackup};
+use chrono::{DateTime, Local, NaiveDate};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Written into every part of a striped backup, recording where each of its
+/// files went.
+pub const STRIPE_MAP_FILE_NAME: &str = "backup_warden.stripes.json";
+
+/// Which location each file of a striped backup was written to.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct StripeMap {
+    /// The locations the backup is spread across, in `backup_locations` order.
+    pub locations: Vec<String>,
+    /// Each file and link, by its `/`-separated path in the backup, with the
+    /// index in `locations` of the one holding it.
+    pub files: BTreeMap<String, usize>,
+}
+
+impl StripeMap {
+    /// Reads the map of the backup at `root`, or `None` if it isn't striped.
+    pub fn load(root: &Path) -> io::Result<Option<Self>> {
+        match fs::read(root.join(STRIPE_MAP_FILE_NAME)) {
+            Ok(raw) => Ok(Some(serde_json::from_slice(&raw)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Where every part of the striped backup whose part in one of the
+    /// locations is at `root` should be: the same folder below each location.
+    /// `None` when `root` is in none of them, as when it has been moved.
+    pub fn parts(&self, root: &Path) -> Option<Vec<PathBuf>> {
+        let relative = self
+            .locations
+            .iter()
+            .find_map(|location| root.strip_prefix(location).ok())?;
+        Some(
+            self.locations
+                .iter()
+                .map(|location| Path::new(location).join(relative))
+                .collect(),
+        )
+    }
+}
+
+/// Which of `parts` locations the file at `relative` goes to, from the hash of
+/// its path so the same file lands in the same place in every backup.
+pub fn stripe_for(relative: &Path, parts: usize) -> usize {
+    let digest = Sha256::digest(map_key(relative).as_bytes());
+    let hash = u64::from_be_bytes(digest[..8].try_into().expect("SHA-256 has 8 bytes"));
+    (hash % parts as u64) as usize
+}
+
+fn map_key(relative: &Path) -> String {
+    relative
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Spreads one backup across several locations for `striping`: each file and
+/// link is written to the one [`stripe_for`] picks, while folders and the
+/// files backup-warden writes itself go to all of them. Every part gets its
+/// own manifest and a [`StripeMap`] of the whole backup.
+pub struct StripedSink {
+    parts: Vec<LocalSink>,
+    files: BTreeMap<String, usize>,
+}
+
+impl StripedSink {
+    pub fn new(parts: Vec<LocalSink>) -> Self {
+        StripedSink {
+            parts,
+            files: BTreeMap::new(),
+        }
+    }
+
+    /// Starts a backup into the folder `relative` below each location instead
+    /// of the layout's folder for the current time, e.g. for monthly snapshots.
+    pub fn begin_folder(&mut self, relative: &Path) -> io::Result<()> {
+        self.files.clear();
+        for part in &mut self.parts {
+            let root = part.location().join(relative);
+            part.begin_folder(root)?;
+        }
+        Ok(())
+    }
+
+    fn part_for(&mut self, relative: &Path) -> &mut LocalSink {
+        let index = stripe_for(relative, self.parts.len());
+        self.files.insert(map_key(relative), index);
+        &mut self.parts[index]
+    }
+}
+
+impl BackupSink for StripedSink {
+    fn name(&self) -> String {
+        self.parts
+            .iter()
+            .map(|part| part.name())
+            .collect::<Vec<_>>()
+            .join(" + ")
+    }
+
+    fn begin_backup(&mut self, time: &DateTime<Local>) -> io::Result<()> {
+        self.files.clear();
+        self.parts
+            .iter_mut()
+            .try_for_each(|part| part.begin_backup(time))
+    }
+
+    fn create_dir(&mut self, relative: &Path) -> io::Result<()> {
+        self.parts
+            .iter_mut()
+            .try_for_each(|part| part.create_dir(relative))
+    }
+
+    fn write_file(
+        &mut self,
+        relative: &Path,
+        source: &Path,
+        metadata: &fs::Metadata,
+    ) -> io::Result<u64> {
+        self.part_for(relative)
+            .write_file(relative, source, metadata)
+    }
+
+    fn write_contents(&mut self, relative: &Path, contents: &[u8]) -> io::Result<()> {
+        self.parts
+            .iter_mut()
+            .try_for_each(|part| part.write_contents(relative, contents))
+    }
+
+    fn write_link(&mut self, relative: &Path, source: &Path) -> io::Result<()> {
+        self.part_for(relative).write_link(relative, source)
+    }
+
+    fn finish_dir(&mut self, relative: &Path, source: &fs::Metadata) -> io::Result<()> {
+        self.parts
+            .iter_mut()
+            .try_for_each(|part| part.finish_dir(relative, source))
+    }
+
+    fn finish_backup(&mut self) -> io::Result<()> {
+        let map = StripeMap {
+            locations: self.parts.iter().map(|part| part.name()).collect(),
+            files: std::mem::take(&mut self.files),
+        };
+        let json = serde_json::to_vec_pretty(&map).map_err(io::Error::other)?;
+        for part in &mut self.parts {
+            part.write_contents(Path::new(STRIPE_MAP_FILE_NAME), &json)?;
+            part.finish_backup()?;
+        }
+        Ok(())
+    }
+
+    fn list_backups(&self) -> io::Result<Box<dyn Iterator<Item = NaiveDate> + '_>> {
+        // Every part is written on the same days
+        self.parts[0].list_backups()
+    }
+
+    fn prune(&mut self, day: NaiveDate) -> io::Result<PrunedBackup> {
+        let mut pruned = self.describe(day);
+        pruned.bytes = 0;
+        for part in &mut self.parts {
+            pruned.bytes += part.prune(day)?.bytes;
+        }
+        Ok(pruned)
+    }
+
+    fn describe(&self, day: NaiveDate) -> PrunedBackup {
+        let parts: Vec<PrunedBackup> = self.parts.iter().map(|part| part.describe(day)).collect();
+        PrunedBackup {
+            day,
+            path: parts
+                .iter()
+                .map(|part| part.path.as_str())
+                .collect::<Vec<_>>()
+                .join(" + "),
+            bytes: parts.iter().map(|part| part.bytes).sum(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BackupWardenConfig;
+    use crate::copy::{copy_dir_all, CopyOptions};
+    use crate::filter::PathFilter;
+    use crate::layout::BackupLayout;
+    use crate::manifest::Manifest;
+    use chrono::TimeZone;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_striped_backup_spreads_files_across_locations() {
+        let temp_dir = tempdir().unwrap();
+        let watch = temp_dir.path().join("watch");
+        fs::create_dir_all(watch.join("docs")).unwrap();
+        for i in 0..20 {
+            fs::write(watch.join("docs").join(format!("{}.txt", i)), i.to_string()).unwrap();
+        }
+        let locations: Vec<PathBuf> = (0..3)
+            .map(|i| temp_dir.path().join(format!("drive{}", i)))
+            .collect();
+        let config = BackupWardenConfig {
+            watch_folder: watch.to_str().unwrap().to_string(),
+            backup_locations: locations
+                .iter()
+                .map(|location| location.to_str().unwrap().to_string())
+                .collect(),
+            ..Default::default()
+        };
+        let layout = BackupLayout::new(&config).unwrap();
+        let filter = PathFilter::new(&config).unwrap();
+        let mut sink = StripedSink::new(
+            config
+                .backup_locations
+                .iter()
+                .map(|location| LocalSink::new(location, layout.clone()))
+                .collect(),
+        );
+        let time = Local.with_ymd_and_hms(2024, 1, 31, 13, 0, 0).unwrap();
+        sink.begin_backup(&time).unwrap();
+        let stats = copy_dir_all(&watch, &CopyOptions::new(&config, &filter), &mut sink).unwrap();
+        sink.finish_backup().unwrap();
+        assert_eq!(stats.files_copied, 20);
+
+        let roots: Vec<PathBuf> = locations
+            .iter()
+            .map(|location| layout.backup_path(location, &time))
+            .collect();
+        let map = StripeMap::load(&roots[1]).unwrap().unwrap();
+        assert_eq!(map.locations, config.backup_locations);
+        assert_eq!(map.files.len(), 20);
+        assert_eq!(map.parts(&roots[1]).unwrap(), roots);
+        for (path, index) in &map.files {
+            for (i, root) in roots.iter().enumerate() {
+                assert_eq!(root.join(path).exists(), i == *index, "{}", path);
+            }
+        }
+        // Twenty files hashed into three parts leave none of them empty
+        for root in &roots {
+            assert!(fs::read_dir(root.join("docs")).unwrap().next().is_some());
+            let manifest = Manifest::load(root).unwrap().unwrap();
+            assert!(manifest
+                .files
+                .iter()
+                .all(|entry| entry.check(root).is_none()));
+        }
+
+        let day = time.date_naive();
+        assert_eq!(sink.list_backups().unwrap().collect::<Vec<_>>(), [day]);
+        sink.prune(day).unwrap();
+        assert!(roots.iter().all(|root| !root.exists()));
+    }
+}
@@ -0,0 +1,108 @@
+use std::time::{Duration, Instant};
+
+/// Coalesces backup requests so that no matter how many watch events arrive, a
+/// backup starts at most once per `min_interval`, measured from the end of the
+/// previous backup.
+pub struct BackupThrottle {
+    min_interval: Duration,
+    last_backup: Option<Instant>,
+    not_before: Option<Instant>,
+    pending: bool,
+}
+
+impl BackupThrottle {
+    pub fn new(min_interval: Duration) -> Self {
+        BackupThrottle {
+            min_interval,
+            last_backup: None,
+            not_before: None,
+            pending: false,
+        }
+    }
+
+    /// Records that something changed and a backup is wanted.
+    pub fn request(&mut self) {
+        self.pending = true;
+    }
+
+    /// How long until a pending backup may run, or `None` if nothing is pending.
+    pub fn time_until_ready(&self, now: Instant) -> Option<Duration> {
+        if !self.pending {
+            return None;
+        }
+        let earliest = match self.last_backup {
+            Some(last) => last + self.min_interval,
+            None => now,
+        };
+        let earliest = self.not_before.map_or(earliest, |t| t.max(earliest));
+        Some(earliest.saturating_duration_since(now))
+    }
+
+    /// Whether a pending backup may run right now.
+    pub fn is_ready(&self, now: Instant) -> bool {
+        self.time_until_ready(now) == Some(Duration::ZERO)
+    }
+
+    /// Records that a backup just finished, clearing any pending request.
+    pub fn mark_done(&mut self, now: Instant) {
+        self.pending = false;
+        self.last_backup = Some(now);
+        self.not_before = None;
+    }
+
+    /// Keeps a backup pending but holds it off until `until`, e.g. while another
+    /// run holds the lock.
+    pub fn postpone(&mut self, until: Instant) {
+        self.pending = true;
+        self.not_before = Some(until);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nothing_pending_is_not_ready() {
+        let throttle = BackupThrottle::new(Duration::from_secs(60));
+        assert!(!throttle.is_ready(Instant::now()));
+        assert_eq!(throttle.time_until_ready(Instant::now()), None);
+    }
+
+    #[test]
+    fn test_first_request_is_ready_immediately() {
+        let mut throttle = BackupThrottle::new(Duration::from_secs(60));
+        throttle.request();
+        assert!(throttle.is_ready(Instant::now()));
+    }
+
+    #[test]
+    fn test_requests_coalesce_until_interval_elapses() {
+        let start = Instant::now();
+        let mut throttle = BackupThrottle::new(Duration::from_secs(60));
+        throttle.mark_done(start);
+
+        for _ in 0..1000 {
+            throttle.request();
+        }
+        assert!(!throttle.is_ready(start + Duration::from_secs(30)));
+        assert_eq!(
+            throttle.time_until_ready(start + Duration::from_secs(30)),
+            Some(Duration::from_secs(30))
+        );
+        assert!(throttle.is_ready(start + Duration::from_secs(60)));
+
+        throttle.mark_done(start + Duration::from_secs(60));
+        assert!(!throttle.is_ready(start + Duration::from_secs(200)));
+    }
+
+    #[test]
+    fn test_postponed_backup_stays_pending() {
+        let start = Instant::now();
+        let mut throttle = BackupThrottle::new(Duration::ZERO);
+        throttle.postpone(start + Duration::from_secs(10));
+
+        assert!(!throttle.is_ready(start));
+        assert!(throttle.is_ready(start + Duration::from_secs(10)));
+    }
+}
@@ -0,0 +1,211 @@
+use crate::config::BackupWardenConfig;
+use crate::error::{BackupWardenError, Result};
+use crate::layout::BackupLayout;
+use crate::manifest::{read_root, Manifest, ROOT_FILE_NAME};
+use crate::range::DateWindow;
+use crate::schedule::SnapshotKind;
+use crate::signature::{parse_public_key, verify_root};
+use chrono::NaiveDate;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::{error, info, warn};
+
+/// What one verification pass over a job's backup locations found.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Backups whose manifest was checked.
+    pub verified: usize,
+    /// Backups without a manifest, made before manifests were written.
+    pub unverifiable: usize,
+    /// Backups with at least one missing or changed file.
+    pub corrupt: usize,
+}
+
+/// Every daily backup, weekly snapshot and monthly snapshot in `location` from
+/// a day in `window`, oldest day first within each.
+pub fn backup_roots(layout: &BackupLayout, location: &Path, window: &DateWindow) -> Vec<PathBuf> {
+    let mut days: Vec<_> = fs::read_dir(layout.daily_root(location))
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .filter(|e| {
+                    layout
+                        .parse_day(&e.file_name().to_string_lossy())
+                        .is_some_and(|day| window.includes(day))
+                })
+                .map(|e| e.path())
+                .collect()
+        })
+        .unwrap_or_default();
+    days.sort();
+    let mut roots: Vec<PathBuf> = days
+        .iter()
+        .flat_map(|day| layout.backups_in_day(day).into_iter().map(|(_, path)| path))
+        .collect();
+
+    for kind in [SnapshotKind::Weekly, SnapshotKind::Monthly] {
+        let mut snapshots: Vec<_> = fs::read_dir(location.join(kind.dir_name()))
+            .map(|entries| {
+                entries
+                    .filter_map(Result::ok)
+                    .filter(|e| e.file_type().map(|ft| ft.is_dir()).unwrap_or(false))
+                    .filter(|e| {
+                        let name = e.file_name().to_string_lossy().into_owned();
+                        window.includes_maybe(NaiveDate::parse_from_str(&name, "%Y-%m-%d").ok())
+                    })
+                    .map(|e| e.path())
+                    .collect()
+            })
+            .unwrap_or_default();
+        snapshots.sort();
+        roots.extend(snapshots);
+    }
+    roots
+}
+
+/// Checks every backup from a day in `window` in every location against its
+/// manifest, logging each problem. Only reads; nothing in the locations is
+/// changed.
+pub fn verify_backups(config: &BackupWardenConfig, window: &DateWindow) -> Result<VerifyReport> {
+    let layout = BackupLayout::new(config).map_err(BackupWardenError::Config)?;
+    let public_key = config
+        .signing_public_key
+        .as_deref()
+        .map(parse_public_key)
+        .transpose()
+        .map_err(BackupWardenError::Config)?;
+    let mut report = VerifyReport::default();
+    for location in &config.backup_locations {
+        for root in backup_roots(&layout, Path::new(location), window) {
+            let manifest = match Manifest::load(&root) {
+                Ok(Some(manifest)) => manifest,
+                Ok(None) => {
+                    report.unverifiable += 1;
+                    continue;
+                }
+                Err(e) => {
+                    error!(event = "manifest_unreadable", path = %root.display(), error = %e, "Failed to read manifest");
+                    report.corrupt += 1;
+                    continue;
+                }
+            };
+            let mut problems = manifest.verify(&root);
+            match read_root(&root) {
+                Ok(Some(stored)) if stored != manifest.merkle_root() => {
+                    problems.push(format!("{} does not match the manifest", ROOT_FILE_NAME))
+                }
+                Ok(_) => (),
+                Err(e) => problems.push(format!("{}: unreadable ({})", ROOT_FILE_NAME, e)),
+            }
+            if let Some(key) = &public_key {
+                if let Err(problem) = verify_root(&root, key, &manifest.merkle_root()) {
+                    problems.push(problem);
+                }
+            }
+            report.verified += 1;
+            if !problems.is_empty() {
+                report.corrupt += 1;
+                for problem in &problems {
+                    error!(event = "backup_corrupt", path = %root.display(), problem = %problem, "Backup corrupt: {}", problem);
+                }
+            }
+        }
+    }
+    if report.unverifiable > 0 {
+        warn!(
+            event = "verify_skipped",
+            count = report.unverifiable,
+            "{} backup(s) have no manifest and were not verified",
+            report.unverifiable
+        );
+    }
+    info!(
+        event = "verify_finished",
+        verified = report.verified,
+        corrupt = report.corrupt,
+        "Verified {} backup(s), {} corrupt",
+        report.verified,
+        report.corrupt
+    );
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Local, TimeZone};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_verify_backups_finds_corrupt_backup() {
+        let temp_dir = tempdir().unwrap();
+        let location = temp_dir.path().join("backup");
+        let config = BackupWardenConfig {
+            backup_locations: vec![location.to_str().unwrap().to_string()],
+            ..Default::default()
+        };
+        let layout = BackupLayout::new(&config).unwrap();
+
+        let time = Local.with_ymd_and_hms(2024, 1, 31, 13, 0, 0).unwrap();
+        let daily = layout.backup_path(&location, &time);
+        let snapshot = location
+            .join(SnapshotKind::Monthly.dir_name())
+            .join("2024-01-31");
+        let legacy = layout.backup_path(&location, &(time - chrono::Duration::hours(1)));
+        for root in [&daily, &snapshot, &legacy] {
+            fs::create_dir_all(root).unwrap();
+            fs::write(root.join("a.txt"), b"aaa").unwrap();
+        }
+        Manifest::write(&daily).unwrap();
+        Manifest::write(&snapshot).unwrap();
+        fs::write(snapshot.join("a.txt"), b"bit rot").unwrap();
+
+        assert_eq!(
+            verify_backups(&config, &DateWindow::default()).unwrap(),
+            VerifyReport {
+                verified: 2,
+                unverifiable: 1,
+                corrupt: 1,
+            }
+        );
+
+        let february = DateWindow {
+            since: chrono::NaiveDate::from_ymd_opt(2024, 2, 1),
+            until: None,
+        };
+        assert_eq!(
+            verify_backups(&config, &february).unwrap(),
+            VerifyReport::default()
+        );
+    }
+
+    #[test]
+    fn test_verify_backups_checks_merkle_root() {
+        let temp_dir = tempdir().unwrap();
+        let location = temp_dir.path().join("backup");
+        let config = BackupWardenConfig {
+            backup_locations: vec![location.to_str().unwrap().to_string()],
+            ..Default::default()
+        };
+        let layout = BackupLayout::new(&config).unwrap();
+        let time = Local.with_ymd_and_hms(2024, 1, 31, 13, 0, 0).unwrap();
+        let daily = layout.backup_path(&location, &time);
+        fs::create_dir_all(&daily).unwrap();
+        fs::write(daily.join("a.txt"), b"aaa").unwrap();
+        Manifest::write(&daily).unwrap().write_root(&daily).unwrap();
+        assert_eq!(
+            verify_backups(&config, &DateWindow::default())
+                .unwrap()
+                .corrupt,
+            0
+        );
+
+        fs::write(daily.join(ROOT_FILE_NAME), "0".repeat(64)).unwrap();
+        assert_eq!(
+            verify_backups(&config, &DateWindow::default())
+                .unwrap()
+                .corrupt,
+            1
+        );
+    }
+}
@@ -0,0 +1,195 @@
+use crate::filter::PathFilter;
+use crate::manifest::same_contents;
+use chrono::{DateTime, Local, NaiveDateTime};
+use filetime::{set_file_mtime, FileTime};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Folder in each backup location holding the versions kept by
+/// `mode: "per-file-versions"`.
+pub const VERSIONS_DIR_NAME: &str = "versions";
+
+/// How each version is named after the time it was stored. It sorts by time
+/// and avoids the colons Windows doesn't allow in names.
+const VERSION_TIME_FORMAT: &str = "%Y-%m-%d_%H-%M-%S%.3f";
+
+/// The folder holding every version of the file at `relative` in the watch
+/// folder.
+pub fn version_dir(location: &Path, relative: &Path) -> PathBuf {
+    location.join(VERSIONS_DIR_NAME).join(relative)
+}
+
+/// The versions of the file at `relative`, oldest first. Entries not named
+/// like a version, such as the version folders of files below a folder that
+/// once had the same name, are left out.
+pub fn list_versions(location: &Path, relative: &Path) -> io::Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(version_dir(location, relative)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut versions = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let is_version = entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| NaiveDateTime::parse_from_str(name, VERSION_TIME_FORMAT).is_ok());
+        if is_version && entry.file_type()?.is_file() {
+            versions.push(entry.path());
+        }
+    }
+    versions.sort();
+    Ok(versions)
+}
+
+/// Stores the file at `source`, found at `relative` in the watch folder, as a
+/// new version taken at `time`. Returns `None` without storing anything when
+/// its newest version already has the same contents, as when several events
+/// arrive for one save.
+pub fn store_version(
+    location: &Path,
+    relative: &Path,
+    source: &Path,
+    time: &DateTime<Local>,
+) -> io::Result<Option<PathBuf>> {
+    let versions = list_versions(location, relative)?;
+    if versions
+        .last()
+        .is_some_and(|newest| same_contents(source, newest))
+    {
+        return Ok(None);
+    }
+    let dir = version_dir(location, relative);
+    fs::create_dir_all(&dir)?;
+    let path = dir.join(time.format(VERSION_TIME_FORMAT).to_string());
+    let metadata = fs::metadata(source)?;
+    // Written aside and renamed, so a version is never seen half-written
+    let mut tmp = tempfile::NamedTempFile::new_in(&dir)?;
+    io::copy(&mut fs::File::open(source)?, tmp.as_file_mut())?;
+    tmp.as_file_mut().flush()?;
+    tmp.persist(&path).map_err(|e| e.error)?;
+    set_file_mtime(&path, FileTime::from_last_modification_time(&metadata))?;
+    Ok(Some(path))
+}
+
+/// Where the changed file at `path` goes in the versions, or `None` when it
+/// isn't a file under `watch_folder` that `filter` lets through.
+pub fn versioned_path(watch_folder: &Path, filter: &PathFilter, path: &Path) -> Option<PathBuf> {
+    let relative = path.strip_prefix(watch_folder).ok()?;
+    if relative.as_os_str().is_empty() || !path.is_file() || !filter.allows_file(path) {
+        return None;
+    }
+    let mut dir = path.parent();
+    while let Some(current) = dir.filter(|dir| *dir != watch_folder) {
+        if !filter.allows_dir(current) {
+            return None;
+        }
+        dir = current.parent();
+    }
+    Some(relative.to_path_buf())
+}
+
+/// Stores a version of every file under `watch_folder` whose newest version
+/// differs from it, or that has none, for changes made while nothing was
+/// watching. Returns how many versions were stored.
+pub fn store_changed(
+    watch_folder: &Path,
+    location: &Path,
+    filter: &PathFilter,
+    time: &DateTime<Local>,
+) -> io::Result<usize> {
+    let mut stored = 0;
+    store_changed_in(
+        watch_folder,
+        Path::new(""),
+        location,
+        filter,
+        time,
+        &mut stored,
+    )?;
+    Ok(stored)
+}
+
+fn store_changed_in(
+    dir: &Path,
+    relative: &Path,
+    location: &Path,
+    filter: &PathFilter,
+    time: &DateTime<Local>,
+    stored: &mut usize,
+) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let relative = relative.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            if filter.allows_dir(&path) {
+                store_changed_in(&path, &relative, location, filter, time, stored)?;
+            }
+        } else if file_type.is_file()
+            && filter.allows_file(&path)
+            && store_version(location, &relative, &path, time)?.is_some()
+        {
+            *stored += 1;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BackupWardenConfig;
+    use chrono::TimeZone;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_changed_files_get_new_versions() {
+        let temp_dir = tempdir().unwrap();
+        let watch = temp_dir.path().join("watch");
+        let location = temp_dir.path().join("backup");
+        fs::create_dir_all(watch.join("docs")).unwrap();
+        fs::create_dir_all(watch.join("cache")).unwrap();
+        fs::write(watch.join("docs").join("a.txt"), b"one").unwrap();
+        fs::write(watch.join("cache").join("b.txt"), b"cached").unwrap();
+        let config = BackupWardenConfig {
+            watch_folder: watch.to_str().unwrap().to_string(),
+            exclude_patterns: vec!["cache".to_string()],
+            ..Default::default()
+        };
+        let filter = PathFilter::new(&config).unwrap();
+        let at = |secs: i64| Local.timestamp_opt(1_700_000_000 + secs, 0).unwrap();
+
+        assert_eq!(
+            store_changed(&watch, &location, &filter, &at(0)).unwrap(),
+            1
+        );
+        assert_eq!(
+            store_changed(&watch, &location, &filter, &at(1)).unwrap(),
+            0
+        );
+        assert!(!version_dir(&location, Path::new("cache/b.txt")).exists());
+
+        let changed = watch.join("docs").join("a.txt");
+        fs::write(&changed, b"two").unwrap();
+        let relative = versioned_path(&watch, &filter, &changed).unwrap();
+        assert_eq!(relative, Path::new("docs").join("a.txt"));
+        assert!(store_version(&location, &relative, &changed, &at(2))
+            .unwrap()
+            .is_some());
+        assert!(store_version(&location, &relative, &changed, &at(3))
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            versioned_path(&watch, &filter, &watch.join("cache").join("b.txt")),
+            None
+        );
+
+        let versions = list_versions(&location, &relative).unwrap();
+        let contents: Vec<Vec<u8>> = versions.iter().map(|v| fs::read(v).unwrap()).collect();
+        assert_eq!(contents, [b"one".to_vec(), b"two".to_vec()]);
+    }
+}
@@ -0,0 +1,112 @@
+use std::io;
+
+/// A Volume Shadow Copy of the volume holding the watch folder, deleted on drop.
+/// Copying from the shadow rather than the live folder reaches files other
+/// programs hold open (Outlook PSTs, SQLite databases) and gives a consistent
+/// point-in-time view of the whole folder.
+pub struct ShadowCopy {
+    #[cfg_attr(not(windows), allow(dead_code))]
+    id: String,
+    source: String,
+}
+
+impl ShadowCopy {
+    /// Shadows the volume holding `folder`. Needs administrator rights.
+    #[cfg(windows)]
+    pub fn create(folder: &str) -> io::Result<ShadowCopy> {
+        let (volume, rest) = windows::split_volume(std::path::Path::new(folder))?;
+        let script = format!(
+            "$r = ([WMICLASS]'root\\cimv2:Win32_ShadowCopy').Create('{}', 'ClientAccessible'); \
+             if ($r.ReturnValue -ne 0) {{ [Console]::Error.WriteLine(\"Win32_ShadowCopy.Create returned $($r.ReturnValue)\"); exit 1 }}; \
+             $s = Get-CimInstance Win32_ShadowCopy | Where-Object {{ $_.ID -eq $r.ShadowID }}; \
+             Write-Output $s.ID; Write-Output $s.DeviceObject",
+            volume
+        );
+        let output = windows::powershell(&script)?;
+        let mut lines = output.lines().map(str::trim).filter(|l| !l.is_empty());
+        let (Some(id), Some(device)) = (lines.next(), lines.next()) else {
+            return Err(io::Error::other(format!(
+                "unexpected output from shadow copy creation: {}",
+                output
+            )));
+        };
+        Ok(ShadowCopy {
+            id: id.to_string(),
+            source: format!("{}\\{}", device, rest),
+        })
+    }
+
+    /// Shadow copies only exist on Windows.
+    #[cfg(not(windows))]
+    pub fn create(_folder: &str) -> io::Result<ShadowCopy> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Volume Shadow Copy is only available on Windows",
+        ))
+    }
+
+    /// The watch folder as seen inside the shadow copy.
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+}
+
+#[cfg(windows)]
+impl Drop for ShadowCopy {
+    fn drop(&mut self) {
+        let script = format!(
+            "Get-CimInstance Win32_ShadowCopy | Where-Object {{ $_.ID -eq '{}' }} | Remove-CimInstance",
+            self.id
+        );
+        if let Err(e) = windows::powershell(&script) {
+            tracing::warn!(event = "vss_delete_failed", shadow = %self.id, error = %e, "Failed to delete shadow copy");
+        }
+    }
+}
+
+#[cfg(windows)]
+mod windows {
+    use std::io;
+    use std::path::{Component, Path, Prefix};
+    use std::process::Command;
+
+    /// Splits `folder` into its volume root (`C:\`) and the path below it.
+    pub fn split_volume(folder: &Path) -> io::Result<(String, String)> {
+        let folder = folder.canonicalize()?;
+        let mut components = folder.components();
+        let letter = match components.next() {
+            Some(Component::Prefix(prefix)) => match prefix.kind() {
+                Prefix::Disk(letter) | Prefix::VerbatimDisk(letter) => letter as char,
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        "shadow copies need a folder on a local drive letter",
+                    ))
+                }
+            },
+            _ => return Err(io::Error::other("watch folder has no drive letter")),
+        };
+        let rest: Vec<String> = components
+            .filter_map(|c| match c {
+                Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+                _ => None,
+            })
+            .collect();
+        Ok((format!("{}:\\", letter), rest.join("\\")))
+    }
+
+    /// Runs `script` in PowerShell and returns its standard output.
+    pub fn powershell(script: &str) -> io::Result<String> {
+        let output = Command::new("powershell")
+            .args(["-NoProfile", "-NonInteractive", "-Command", script])
+            .output()?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!(
+                "powershell failed ({}): {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+}
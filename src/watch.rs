@@ -0,0 +1,368 @@
+use notify::event::ModifyKind;
+use notify::{Event, EventKind};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How the watcher learns about changes.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WatchMode {
+    /// Rescan the whole folder every poll interval, comparing contents. Works
+    /// everywhere, including network shares.
+    #[default]
+    Poll,
+    /// Have the OS report changes as they happen (inotify, FSEvents,
+    /// ReadDirectoryChangesW), falling back to polling where that fails.
+    Native,
+}
+
+/// A kind of filesystem event that can be configured to trigger a backup.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WatchEventKind {
+    /// A file or folder was created.
+    Create,
+    /// File contents changed, or a file was renamed.
+    Modify,
+    /// Only metadata changed: permissions, timestamps, ownership, ...
+    Metadata,
+    /// A file or folder was deleted.
+    Remove,
+    /// A file was read or opened without being changed.
+    Access,
+}
+
+/// The event kinds that trigger a backup when `watch_events` isn't set.
+pub const DEFAULT_WATCH_EVENTS: [WatchEventKind; 4] = [
+    WatchEventKind::Create,
+    WatchEventKind::Modify,
+    WatchEventKind::Metadata,
+    WatchEventKind::Remove,
+];
+
+impl WatchEventKind {
+    fn of(kind: &EventKind) -> Option<WatchEventKind> {
+        match kind {
+            EventKind::Create(_) => Some(WatchEventKind::Create),
+            EventKind::Modify(ModifyKind::Metadata(_)) => Some(WatchEventKind::Metadata),
+            EventKind::Modify(_) => Some(WatchEventKind::Modify),
+            EventKind::Remove(_) => Some(WatchEventKind::Remove),
+            EventKind::Access(_) => Some(WatchEventKind::Access),
+            EventKind::Any | EventKind::Other => None,
+        }
+    }
+}
+
+/// The paths the watch events reported changed since the last backup, for
+/// `selective_backups`. They only tell what changed since the backups in
+/// `bases`: a location without one, or a run that saw an event without
+/// reliable paths, gets a full backup.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ChangedPaths {
+    paths: BTreeSet<PathBuf>,
+    /// Whether every change since the bases has a path here.
+    complete: bool,
+    /// The backup each location got in the last run.
+    bases: HashMap<String, PathBuf>,
+}
+
+impl ChangedPaths {
+    /// Changes since no backup in particular, as when the warden starts.
+    pub fn unknown() -> Self {
+        ChangedPaths::default()
+    }
+
+    /// Adds the paths of `event`. One without paths, or that asks for a
+    /// rescan because events were lost, leaves what changed unknown.
+    pub fn record(&mut self, event: &Event) {
+        match WatchEventKind::of(&event.kind) {
+            Some(WatchEventKind::Access) => (),
+            Some(_) if !event.paths.is_empty() && !event.need_rescan() => {
+                self.paths.extend(event.paths.iter().cloned())
+            }
+            _ => self.complete = false,
+        }
+    }
+
+    /// The changed paths and the backup of `location` they are changes since,
+    /// or `None` when they don't tell everything that changed.
+    pub fn since(&self, location: &str) -> Option<(&BTreeSet<PathBuf>, &Path)> {
+        let base = self.bases.get(location).filter(|_| self.complete)?;
+        Some((&self.paths, base))
+    }
+
+    /// Starts collecting anew after a run that wrote the backups in `bases`.
+    pub fn reset(&mut self, bases: HashMap<String, PathBuf>) {
+        self.paths.clear();
+        self.complete = true;
+        self.bases = bases;
+    }
+}
+
+/// Whether an event of `kind` should trigger a backup, given the configured
+/// `watch_events` (or the defaults when unset).
+pub fn triggers_backup(watch_events: Option<&[WatchEventKind]>, kind: &EventKind) -> bool {
+    let watched = watch_events.unwrap_or(&DEFAULT_WATCH_EVENTS);
+    WatchEventKind::of(kind).is_some_and(|kind| watched.contains(&kind))
+}
+
+/// Whether an event on `paths` concerns a file with one of `extensions`, given
+/// with or without the leading dot and matched case-insensitively. Without
+/// `watch_extensions` every event qualifies.
+pub fn matches_extensions(paths: &[PathBuf], extensions: Option<&[String]>) -> bool {
+    let Some(extensions) = extensions else {
+        return true;
+    };
+    paths.iter().any(|path| {
+        path.extension().is_some_and(|ext| {
+            let ext = ext.to_string_lossy();
+            extensions
+                .iter()
+                .any(|wanted| wanted.trim_start_matches('.').eq_ignore_ascii_case(&ext))
+        })
+    })
+}
+
+/// Where the watcher of `watch_folder` reports changes to those of `locations`
+/// that lie inside it. In a multi-job setup one job's backup location can sit
+/// inside another job's watch folder; without this, every backup written there
+/// would trigger another backup of the folder holding it.
+pub fn ignored_prefixes(watch_folder: &str, locations: &[&str]) -> Vec<PathBuf> {
+    let resolve = |path: &str| {
+        Path::new(path)
+            .canonicalize()
+            .unwrap_or_else(|_| PathBuf::from(path))
+    };
+    let resolved_folder = resolve(watch_folder);
+    locations
+        .iter()
+        .filter_map(|location| {
+            // Events carry paths under watch_folder as it was given, not resolved
+            let relative = resolve(location)
+                .strip_prefix(&resolved_folder)
+                .ok()?
+                .to_path_buf();
+            Some(Path::new(watch_folder).join(relative))
+        })
+        .collect()
+}
+
+/// Whether every path of an event lies under one of the `ignored` prefixes.
+pub fn is_ignored(paths: &[PathBuf], ignored: &[PathBuf]) -> bool {
+    !paths.is_empty()
+        && paths
+            .iter()
+            .all(|path| ignored.iter().any(|prefix| path.starts_with(prefix)))
+}
+
+/// How long after the warden stops writing into a folder the events from it
+/// still count as its own. Watchers deliver events a little after the write,
+/// and those of a long snapshot queue up until it is done.
+pub const SELF_WRITE_WINDOW: Duration = Duration::from_secs(10);
+
+/// A folder the warden is writing into, with when its events stop counting as
+/// the warden's own once the writing is done.
+struct SelfWrite {
+    root: PathBuf,
+    resolved: PathBuf,
+    until: Option<Instant>,
+}
+
+/// The folders being written by every job in this process, so one job's
+/// snapshot landing in another job's watch folder doesn't trigger backups.
+static SELF_WRITES: Mutex<Vec<SelfWrite>> = Mutex::new(Vec::new());
+
+/// Marks folders as being written by the warden until dropped, and for
+/// [`SELF_WRITE_WINDOW`] after.
+pub struct SelfWriteGuard {
+    roots: Vec<PathBuf>,
+}
+
+impl Drop for SelfWriteGuard {
+    fn drop(&mut self) {
+        let until = Instant::now() + SELF_WRITE_WINDOW;
+        let mut writes = SELF_WRITES.lock().unwrap();
+        for root in &self.roots {
+            if let Some(write) = writes
+                .iter_mut()
+                .find(|write| write.until.is_none() && write.root == *root)
+            {
+                write.until = Some(until);
+            }
+        }
+    }
+}
+
+/// Marks `roots` as being written by the warden, so [`is_own_write`] tells
+/// their events apart from real changes.
+pub fn writing_into(roots: impl IntoIterator<Item = PathBuf>) -> SelfWriteGuard {
+    let roots: Vec<PathBuf> = roots.into_iter().collect();
+    let mut writes = SELF_WRITES.lock().unwrap();
+    for root in &roots {
+        writes.push(SelfWrite {
+            root: root.clone(),
+            resolved: root.canonicalize().unwrap_or_else(|_| root.clone()),
+            until: None,
+        });
+    }
+    SelfWriteGuard { roots }
+}
+
+/// Whether every path of an event lies in a folder the warden is writing
+/// into or just wrote. Unlike [`ignored_prefixes`], this also catches
+/// locations that only lie inside the watch folder through a link or a mount,
+/// or that weren't there when the watcher started.
+pub fn is_own_write(paths: &[PathBuf]) -> bool {
+    own_write_at(paths, Instant::now())
+}
+
+fn own_write_at(paths: &[PathBuf], now: Instant) -> bool {
+    let mut writes = SELF_WRITES.lock().unwrap();
+    writes.retain(|write| write.until.is_none_or(|until| until > now));
+    if writes.is_empty() || paths.is_empty() {
+        return false;
+    }
+    paths.iter().all(|path| {
+        if writes.iter().any(|write| path.starts_with(&write.root)) {
+            return true;
+        }
+        let resolved = resolve_existing(path);
+        writes
+            .iter()
+            .any(|write| resolved.starts_with(&write.resolved))
+    })
+}
+
+/// `path` with its longest existing ancestor resolved, since the file an
+/// event is about may be gone by now.
+fn resolve_existing(path: &Path) -> PathBuf {
+    for ancestor in path.ancestors() {
+        if let Ok(resolved) = ancestor.canonicalize() {
+            let rest = path.strip_prefix(ancestor).unwrap_or(Path::new(""));
+            return resolved.join(rest);
+        }
+    }
+    path.to_path_buf()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::{AccessKind, CreateKind, DataChange, MetadataKind, RemoveKind};
+
+    #[test]
+    fn test_defaults_match_create_modify_and_remove() {
+        assert!(triggers_backup(None, &EventKind::Create(CreateKind::File)));
+        assert!(triggers_backup(
+            None,
+            &EventKind::Modify(ModifyKind::Data(DataChange::Content))
+        ));
+        assert!(triggers_backup(
+            None,
+            &EventKind::Modify(ModifyKind::Metadata(MetadataKind::WriteTime))
+        ));
+        assert!(triggers_backup(None, &EventKind::Remove(RemoveKind::File)));
+        assert!(!triggers_backup(None, &EventKind::Access(AccessKind::Read)));
+        assert!(!triggers_backup(None, &EventKind::Other));
+    }
+
+    #[test]
+    fn test_matches_extensions() {
+        let wanted = ["docx".to_string(), ".XLSX".to_string()];
+        let paths = |names: &[&str]| names.iter().map(PathBuf::from).collect::<Vec<_>>();
+        assert!(matches_extensions(
+            &paths(&["w/report.DOCX"]),
+            Some(&wanted)
+        ));
+        assert!(matches_extensions(
+            &paths(&["w/~lock.tmp", "w/sheet.xlsx"]),
+            Some(&wanted)
+        ));
+        assert!(!matches_extensions(
+            &paths(&["w/notes.txt", "w/docx"]),
+            Some(&wanted)
+        ));
+        assert!(matches_extensions(&paths(&["w/notes.txt"]), None));
+    }
+
+    #[test]
+    fn test_events_in_backup_locations_are_ignored() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let watched = temp_dir.path().join("data");
+        let location = watched.join("other-job-backups");
+        std::fs::create_dir_all(&location).unwrap();
+        let elsewhere = temp_dir.path().join("elsewhere");
+        let ignored = ignored_prefixes(
+            watched.to_str().unwrap(),
+            &[location.to_str().unwrap(), elsewhere.to_str().unwrap()],
+        );
+        assert_eq!(ignored, std::slice::from_ref(&location));
+
+        assert!(is_ignored(&[location.join("2024-01-31/a.txt")], &ignored));
+        assert!(!is_ignored(
+            &[location.join("a.txt"), watched.join("b.txt")],
+            &ignored
+        ));
+        assert!(!is_ignored(
+            &[watched.join("other-job-backups.txt")],
+            &ignored
+        ));
+        assert!(!is_ignored(&[], &ignored));
+    }
+
+    #[test]
+    fn test_own_writes_are_recognized_for_a_while() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let watched = temp_dir.path().join("data");
+        let location = temp_dir.path().join("backups");
+        std::fs::create_dir_all(&watched).unwrap();
+        std::fs::create_dir_all(&location).unwrap();
+        let snapshot_file = location.join("Monthly Snapshots/2024-01-31/a.txt");
+        // A location reached through a link inside the watch folder
+        #[cfg(unix)]
+        let linked = {
+            std::os::unix::fs::symlink(&location, watched.join("linked")).unwrap();
+            watched.join("linked/Monthly Snapshots/2024-01-31/a.txt")
+        };
+        assert!(!is_own_write(std::slice::from_ref(&snapshot_file)));
+
+        let guard = writing_into([location.clone()]);
+        assert!(is_own_write(std::slice::from_ref(&snapshot_file)));
+        #[cfg(unix)]
+        assert!(is_own_write(std::slice::from_ref(&linked)));
+        assert!(!is_own_write(&[
+            snapshot_file.clone(),
+            watched.join("b.txt")
+        ]));
+        assert!(!is_own_write(&[]));
+
+        drop(guard);
+        assert!(is_own_write(std::slice::from_ref(&snapshot_file)));
+        let later = Instant::now() + SELF_WRITE_WINDOW + Duration::from_secs(1);
+        assert!(!own_write_at(std::slice::from_ref(&snapshot_file), later));
+    }
+
+    #[test]
+    fn test_configured_kinds_only() {
+        let watched = [WatchEventKind::Create, WatchEventKind::Modify];
+        assert!(triggers_backup(
+            Some(&watched),
+            &EventKind::Create(CreateKind::File)
+        ));
+        assert!(triggers_backup(
+            Some(&watched),
+            &EventKind::Modify(ModifyKind::Data(DataChange::Any))
+        ));
+        assert!(!triggers_backup(
+            Some(&watched),
+            &EventKind::Modify(ModifyKind::Metadata(MetadataKind::AccessTime))
+        ));
+        assert!(!triggers_backup(
+            Some(&watched),
+            &EventKind::Remove(RemoveKind::Any)
+        ));
+    }
+}